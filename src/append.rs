@@ -0,0 +1,208 @@
+use pgrx::prelude::*;
+
+use aws_sdk_s3::types::CompletedPart;
+
+use crate::{
+    error::{note_request_ids, precondition_failed, sdk_error, S3Error},
+    head_object,
+    multipart::{abort_upload, complete_upload, create_upload, MIN_PART_SIZE},
+    quote_etag, read_body, run, uri, ClientArgs, URI_PATH,
+};
+
+/// Largest part UploadPartCopy accepts.
+const MAX_COPY_PART_SIZE: i64 = 5 * 1024 * 1024 * 1024;
+
+/// Append `data` to an object, creating it if missing, and return the new ETag.
+///
+/// S3 has no append: objects below the multipart minimum part size are
+/// downloaded and uploaded again with `data` added; larger ones are rebuilt
+/// server-side from copied parts plus `data` as the last part. The content
+/// type is kept, other metadata and tags are not.
+///
+/// The append is not atomic, and without `if_match` a concurrent writer's
+/// changes can be lost. With `if_match`, the append fails with
+/// `PreconditionFailed` unless the object still has that ETag when it is
+/// replaced; a missing object is then never created.
+#[pg_extern]
+pub(crate) fn s3_append_object(
+    bucket: Option<&str>,
+    object_key: &str,
+    data: Vec<u8>,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    if_match: default!(Option<&str>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let if_match = if_match.map(quote_etag);
+
+    run(&args, |client| {
+        let data = data.clone();
+        let if_match = if_match.clone();
+        async move {
+            let head = head_object(&client, bucket, object_key, None).await?;
+            let Some(head) = head else {
+                if if_match.is_some() {
+                    return Err(precondition_failed(bucket, object_key));
+                }
+                return put(&client, bucket, object_key, data, None, None).await;
+            };
+
+            let etag = head.e_tag().unwrap_or_default().to_owned();
+            if if_match.as_ref().is_some_and(|expected| *expected != etag) {
+                return Err(precondition_failed(bucket, object_key));
+            }
+            let size = head.content_length().unwrap_or_default();
+            let content_type = head.content_type();
+            if data.is_empty() {
+                return Ok(etag.trim_matches('"').to_owned());
+            }
+
+            if (size as usize) < MIN_PART_SIZE {
+                let out = client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(object_key)
+                    .if_match(&etag)
+                    .send()
+                    .await
+                    .map_err(|err| sdk_error("GetObject", err))?;
+                note_request_ids("GetObject", &out);
+                // Room for `data` too, so appending it does not reallocate.
+                let mut body = read_body(out.body, Some(size + data.len() as i64))
+                    .await
+                    .map_err(|e| S3Error::new(format!("Collect error: {e:?}")))?;
+                body.extend_from_slice(&data);
+                return put(
+                    &client,
+                    bucket,
+                    object_key,
+                    body,
+                    content_type,
+                    if_match.as_deref(),
+                )
+                .await;
+            }
+
+            let upload_id = create_upload(&client, bucket, object_key, content_type).await?;
+            let result = async {
+                let mut parts =
+                    copy_parts(&client, bucket, object_key, &upload_id, &etag, size).await?;
+                let part_number = parts.len() as i32 + 1;
+                let out = client
+                    .upload_part()
+                    .bucket(bucket)
+                    .key(object_key)
+                    .upload_id(&upload_id)
+                    .part_number(part_number)
+                    .body(data.into())
+                    .send()
+                    .await
+                    .map_err(|err| sdk_error("UploadPart", err))?;
+                note_request_ids("UploadPart", &out);
+                parts.push(
+                    CompletedPart::builder()
+                        .part_number(part_number)
+                        .set_e_tag(out.e_tag().map(str::to_owned))
+                        .build(),
+                );
+                complete_upload(
+                    &client,
+                    bucket,
+                    object_key,
+                    &upload_id,
+                    parts,
+                    if_match.as_deref(),
+                )
+                .await
+            }
+            .await;
+            if result.is_err() {
+                // Keep the original error; a failed abort only leaves parts behind.
+                let _ = abort_upload(&client, bucket, object_key, &upload_id).await;
+            }
+            result
+        }
+    })
+}
+
+/// Copy the `size` bytes of the object with `etag` into the first parts of an upload.
+async fn copy_parts(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    upload_id: &str,
+    etag: &str,
+    size: i64,
+) -> Result<Vec<CompletedPart>, S3Error> {
+    let copy_source = format!(
+        "{bucket}/{}",
+        percent_encoding::utf8_percent_encode(object_key, URI_PATH)
+    );
+    let count = (size + MAX_COPY_PART_SIZE - 1) / MAX_COPY_PART_SIZE;
+    let part_size = (size + count - 1) / count;
+
+    let mut parts = Vec::new();
+    for (part_number, start) in (1..).zip((0..size).step_by(part_size as usize)) {
+        let end = (start + part_size).min(size) - 1;
+        let out = client
+            .upload_part_copy()
+            .bucket(bucket)
+            .key(object_key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .copy_source(&copy_source)
+            .copy_source_if_match(etag)
+            .copy_source_range(format!("bytes={start}-{end}"))
+            .send()
+            .await
+            .map_err(|err| sdk_error("UploadPartCopy", err))?;
+        note_request_ids("UploadPartCopy", &out);
+        parts.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(
+                    out.copy_part_result()
+                        .and_then(|r| r.e_tag())
+                        .map(str::to_owned),
+                )
+                .build(),
+        );
+    }
+    Ok(parts)
+}
+
+/// PutObject of `data`, only replacing an object with ETag `if_match` if given.
+async fn put(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    data: Vec<u8>,
+    content_type: Option<&str>,
+    if_match: Option<&str>,
+) -> Result<String, S3Error> {
+    let out = client
+        .put_object()
+        .bucket(bucket)
+        .key(object_key)
+        .set_content_type(content_type.map(str::to_owned))
+        .set_if_match(if_match.map(str::to_owned))
+        .body(data.into())
+        .send()
+        .await
+        .map_err(|err| sdk_error("PutObject", err))?;
+    note_request_ids("PutObject", &out);
+    Ok(out.e_tag().unwrap_or_default().trim_matches('"').to_owned())
+}
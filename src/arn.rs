@@ -0,0 +1,45 @@
+use aws_sdk_s3::config::endpoint::{DefaultResolver, Params, ResolveEndpoint};
+use aws_smithy_runtime_api::client::endpoint::EndpointFuture;
+
+/// Whether `bucket` is an access point or multi-region access point ARN rather
+/// than a bucket name, e.g. `arn:aws:s3:us-west-2:123456789012:accesspoint/reports`.
+pub(crate) fn is_arn(bucket: &str) -> bool {
+    bucket.starts_with("arn:")
+}
+
+/// The SDK's endpoint resolver, except that access point ARNs are always
+/// addressed virtual-hosted-style: S3 rejects them path-style, which is the
+/// `s3_io.force_path_style` default.
+#[derive(Debug, Default)]
+pub(crate) struct ArnAwareResolver(DefaultResolver);
+
+impl ResolveEndpoint for ArnAwareResolver {
+    fn resolve_endpoint<'a>(&'a self, params: &'a Params) -> EndpointFuture<'a> {
+        if !params.bucket().is_some_and(is_arn) || params.force_path_style() != Some(true) {
+            return self.0.resolve_endpoint(params);
+        }
+        let params = Params::builder()
+            .set_bucket(params.bucket().map(str::to_owned))
+            .set_region(params.region().map(str::to_owned))
+            .set_use_fips(params.use_fips())
+            .set_use_dual_stack(params.use_dual_stack())
+            .set_endpoint(params.endpoint().map(str::to_owned))
+            .set_force_path_style(Some(false))
+            .set_accelerate(params.accelerate())
+            .set_use_global_endpoint(params.use_global_endpoint())
+            .set_use_object_lambda_endpoint(params.use_object_lambda_endpoint())
+            .set_key(params.key().map(str::to_owned))
+            .set_prefix(params.prefix().map(str::to_owned))
+            .set_copy_source(params.copy_source().map(str::to_owned))
+            .set_disable_access_points(params.disable_access_points())
+            .set_disable_multi_region_access_points(params.disable_multi_region_access_points())
+            .set_use_arn_region(params.use_arn_region())
+            .set_use_s3_express_control_endpoint(params.use_s3_express_control_endpoint())
+            .set_disable_s3_express_session_auth(params.disable_s3_express_session_auth())
+            .build();
+        EndpointFuture::new(async move {
+            let params = params?;
+            self.0.resolve_endpoint(&params).await
+        })
+    }
+}
@@ -0,0 +1,267 @@
+//! Audit trail of S3 operations, for `s3_io.audit_table`.
+//!
+//! An interceptor records every operation the SDK runs: its name, bucket and
+//! key, bytes transferred, duration and outcome. [`block_on`](crate::block_on)
+//! then inserts the records into the table on the backend thread. Neither
+//! credentials nor request URLs, headers or query strings are recorded.
+
+use pgrx::prelude::*;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use aws_smithy_runtime_api::{
+    box_error::BoxError,
+    client::{
+        endpoint::EndpointResolverParams,
+        interceptors::{
+            context::{BeforeSerializationInterceptorContextRef, FinalizerInterceptorContextRef},
+            Intercept,
+        },
+        orchestrator::Metadata,
+        runtime_components::RuntimeComponents,
+    },
+};
+use aws_smithy_types::config_bag::{ConfigBag, Storable, StoreReplace};
+
+use crate::{guc, retries};
+
+/// When an operation started.
+#[derive(Debug)]
+struct Started {
+    at: Instant,
+    wall: SystemTime,
+}
+
+impl Storable for Started {
+    type Storer = StoreReplace<Self>;
+}
+
+/// One row of the audit table.
+struct Record {
+    timestamp: f64,
+    operation: String,
+    bucket: Option<String>,
+    key: Option<String>,
+    bytes: Option<i64>,
+    duration_ms: f64,
+    result: &'static str,
+    error_code: Option<String>,
+}
+
+// Whether `s3_io.audit_table` is set, for the interceptor off the backend thread.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Operations seen by the interceptor, written on the backend thread.
+static PENDING: Mutex<Vec<Record>> = Mutex::new(Vec::new());
+
+/// The configured audit table, if any.
+fn table() -> Option<String> {
+    guc::AUDIT_TABLE
+        .get()
+        .map(|s| s.to_string_lossy().into_owned())
+        .filter(|s| !s.is_empty())
+}
+
+/// Start or stop recording operations, as `s3_io.audit_table` is set now.
+pub(crate) fn arm() {
+    ENABLED.store(table().is_some(), Ordering::Relaxed);
+}
+
+/// Records every operation for [`flush`].
+#[derive(Debug)]
+pub(crate) struct AuditLog;
+
+impl Intercept for AuditLog {
+    fn name(&self) -> &'static str {
+        "AuditLog"
+    }
+
+    fn read_before_execution(
+        &self,
+        _context: &BeforeSerializationInterceptorContextRef<'_>,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        if ENABLED.load(Ordering::Relaxed) {
+            cfg.interceptor_state().store_put(Started {
+                at: Instant::now(),
+                wall: SystemTime::now(),
+            });
+        }
+        Ok(())
+    }
+
+    fn read_after_execution(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        // Presigning stops before sending and has no outcome.
+        let (Some(started), Some(outcome)) = (cfg.load::<Started>(), context.output_or_error())
+        else {
+            return Ok(());
+        };
+        let operation = cfg
+            .load::<Metadata>()
+            .map_or("Unknown", |m| m.name())
+            .to_owned();
+        let bucket = cfg
+            .load::<EndpointResolverParams>()
+            .and_then(|p| p.get::<aws_sdk_s3::config::endpoint::Params>())
+            .and_then(|p| p.bucket())
+            .map(str::to_owned);
+        let key = context
+            .request()
+            .and_then(|r| object_key(r.uri(), bucket.as_deref()));
+        let bytes = match context.request().map(|r| r.method()) {
+            Some("PUT" | "POST") => context.request().and_then(|r| {
+                length(r.headers().get("x-amz-decoded-content-length"))
+                    .or_else(|| length(r.headers().get("content-length")))
+            }),
+            Some("GET") => context
+                .response()
+                .filter(|r| r.status().is_success())
+                .and_then(|r| length(r.headers().get("content-length"))),
+            _ => None,
+        };
+        let error_code = match outcome {
+            Ok(_) => None,
+            Err(err) => Some(retries::failure_code(context, err)),
+        };
+        let record = Record {
+            timestamp: started
+                .wall
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            operation,
+            bucket,
+            key,
+            bytes,
+            duration_ms: started.at.elapsed().as_secs_f64() * 1000.0,
+            result: if error_code.is_some() { "error" } else { "ok" },
+            error_code,
+        };
+        PENDING.lock().unwrap().push(record);
+        Ok(())
+    }
+}
+
+/// A byte count header.
+fn length(header: Option<&str>) -> Option<i64> {
+    header.and_then(|h| h.parse().ok())
+}
+
+/// The object key a request URI addresses, without the bucket of a path-style
+/// request; `None` for bucket-level requests.
+fn object_key(uri: &str, bucket: Option<&str>) -> Option<String> {
+    let url = url::Url::parse(uri).ok()?;
+    let mut path = url.path().strip_prefix('/').unwrap_or(url.path());
+    if let Some(bucket) = bucket {
+        let virtual_hosted = url
+            .host_str()
+            .is_some_and(|h| h.starts_with(&format!("{bucket}.")));
+        if !virtual_hosted {
+            path = match path.strip_prefix(bucket) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                    rest.strip_prefix('/').unwrap_or(rest)
+                }
+                _ => path,
+            };
+        }
+    }
+    let key = percent_encoding::percent_decode_str(path).decode_utf8_lossy();
+    (!key.is_empty()).then(|| key.into_owned())
+}
+
+/// Insert the operations recorded since the last call into `s3_io.audit_table`.
+///
+/// Best-effort: the insert runs in a subtransaction, and a failure is reported
+/// as a WARNING instead of failing the calling statement.
+pub(crate) fn flush() {
+    let records = std::mem::take(&mut *PENDING.lock().unwrap());
+    if records.is_empty() {
+        return;
+    }
+    let Some(table) = table() else {
+        return;
+    };
+
+    // SAFETY: on the backend thread, inside a transaction; the subtransaction
+    // is released or rolled back before returning, and the caller's memory
+    // context and resource owner are restored as PL/pgSQL does.
+    unsafe {
+        let context = pg_sys::CurrentMemoryContext;
+        let owner = pg_sys::CurrentResourceOwner;
+        pg_sys::BeginInternalSubTransaction(std::ptr::null());
+        pg_sys::MemoryContextSwitchTo(context);
+
+        let failure = PgTryBuilder::new(|| insert(&table, &records).err())
+            .catch_others(|e| {
+                Some(match e {
+                    pg_sys::panic::CaughtError::PostgresError(report)
+                    | pg_sys::panic::CaughtError::ErrorReport(report)
+                    | pg_sys::panic::CaughtError::RustPanic {
+                        ereport: report, ..
+                    } => report.message().to_owned(),
+                })
+            })
+            .execute();
+
+        match &failure {
+            None => pg_sys::ReleaseCurrentSubTransaction(),
+            Some(_) => pg_sys::RollbackAndReleaseCurrentSubTransaction(),
+        }
+        pg_sys::MemoryContextSwitchTo(context);
+        pg_sys::CurrentResourceOwner = owner;
+
+        if let Some(message) = failure {
+            warning!(
+                "Cannot write {} S3 operations to audit table {table}: {message}",
+                records.len()
+            );
+        }
+    }
+}
+
+/// Insert `records` into `table` in one statement.
+fn insert(table: &str, records: &[Record]) -> Result<(), String> {
+    let column = |f: fn(&Record) -> Option<String>| records.iter().map(f).collect::<Vec<_>>();
+    let timestamps: Vec<f64> = records.iter().map(|r| r.timestamp).collect();
+    let operations = column(|r| Some(r.operation.clone()));
+    let buckets = column(|r| r.bucket.clone());
+    let keys = column(|r| r.key.clone());
+    let bytes: Vec<Option<i64>> = records.iter().map(|r| r.bytes).collect();
+    let durations: Vec<f64> = records.iter().map(|r| r.duration_ms).collect();
+    let results = column(|r| Some(r.result.to_owned()));
+    let error_codes = column(|r| r.error_code.clone());
+
+    let table = Spi::get_one_with_args::<String>("SELECT $1::regclass::text", &[table.into()])
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| "table not found".to_owned())?;
+    Spi::run_with_args(
+        &format!(
+            "INSERT INTO {table} \
+                 (timestamp, operation, bucket, key, bytes, duration_ms, result, error_code) \
+             SELECT to_timestamp(t), o, b, k, n, d, r, e \
+             FROM unnest($1::float8[], $2::text[], $3::text[], $4::text[], $5::int8[], \
+                         $6::float8[], $7::text[], $8::text[]) AS u(t, o, b, k, n, d, r, e)"
+        ),
+        &[
+            timestamps.into(),
+            operations.into(),
+            buckets.into(),
+            keys.into(),
+            bytes.into(),
+            durations.into(),
+            results.into(),
+            error_codes.into(),
+        ],
+    )
+    .map_err(|e| e.to_string())
+}
@@ -0,0 +1,181 @@
+use pgrx::prelude::*;
+
+use aws_sdk_s3::types::{
+    ServerSideEncryptionByDefault, ServerSideEncryptionConfiguration, ServerSideEncryptionRule,
+};
+
+use crate::{
+    error::{note_request_ids, sdk_error},
+    run, sse, ClientArgs,
+};
+
+/// Set the default encryption of new objects in `bucket`.
+///
+/// `sse_algorithm` is `AES256` (SSE-S3), `aws:kms` (SSE-KMS) or `aws:kms:dsse`
+/// (DSSE-KMS); the KMS algorithms require `kms_key_id`, a key id or ARN.
+#[pg_extern]
+pub(crate) fn s3_put_bucket_encryption(
+    bucket: &str,
+    sse_algorithm: &str,
+    kms_key_id: default!(Option<&str>, "NULL"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> bool {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let algorithm = sse::parse_algorithm(sse_algorithm).unwrap_or_else(|e| pgrx::error!("{e}"));
+    match (sse::is_kms(&algorithm), kms_key_id) {
+        (false, Some(_)) => pgrx::error!("kms_key_id is only allowed with aws:kms or aws:kms:dsse"),
+        (true, None) => pgrx::error!("sse_algorithm {sse_algorithm} requires a kms_key_id"),
+        _ => {}
+    }
+
+    let default = ServerSideEncryptionByDefault::builder()
+        .sse_algorithm(algorithm)
+        .set_kms_master_key_id(kms_key_id.map(str::to_owned))
+        .build()
+        .unwrap_or_else(|e| pgrx::error!("{e}"));
+    let configuration = ServerSideEncryptionConfiguration::builder()
+        .rules(
+            ServerSideEncryptionRule::builder()
+                .apply_server_side_encryption_by_default(default)
+                .build(),
+        )
+        .build()
+        .unwrap_or_else(|e| pgrx::error!("{e}"));
+
+    run(&args, |client| {
+        let configuration = configuration.clone();
+        async move {
+            let out = client
+                .put_bucket_encryption()
+                .bucket(bucket)
+                .server_side_encryption_configuration(configuration)
+                .send()
+                .await
+                .map_err(|err| sdk_error("PutBucketEncryption", err))?;
+            note_request_ids("PutBucketEncryption", &out);
+            Ok(true)
+        }
+    })
+}
+
+/// The default encryption of `bucket`, or no row if none is configured.
+#[pg_extern]
+pub(crate) fn s3_get_bucket_encryption(
+    bucket: &str,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(sse_algorithm, String),
+        name!(kms_key_id, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+
+    let rule = run(&args, |client| async move {
+        match client.get_bucket_encryption().bucket(bucket).send().await {
+            Ok(out) => {
+                note_request_ids("GetBucketEncryption", &out);
+                Ok(out
+                    .server_side_encryption_configuration()
+                    .and_then(|c| c.rules().first())
+                    .and_then(|r| r.apply_server_side_encryption_by_default())
+                    .map(|d| {
+                        (
+                            d.sse_algorithm().as_str().to_owned(),
+                            d.kms_master_key_id().map(str::to_owned),
+                        )
+                    }))
+            }
+            Err(err) => {
+                let err = sdk_error("GetBucketEncryption", err);
+                match err.code.as_deref() {
+                    Some("ServerSideEncryptionConfigurationNotFoundError") => Ok(None),
+                    _ => Err(err),
+                }
+            }
+        }
+    });
+    TableIterator::new(rule)
+}
+
+/// The region `bucket` lives in, e.g. to configure the client for it.
+///
+/// Asks GetBucketLocation, which only the bucket owner may call; for other
+/// callers the region is taken from the `x-amz-bucket-region` header of a
+/// HeadBucket, which S3 sends even when it denies access.
+#[pg_extern]
+pub(crate) fn s3_get_bucket_region(
+    bucket: &str,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+
+    run(&args, |client| async move {
+        let location_err = match client.get_bucket_location().bucket(bucket).send().await {
+            Ok(out) => {
+                note_request_ids("GetBucketLocation", &out);
+                let constraint = out.location_constraint().map(|c| c.as_str());
+                return Ok(location_region(constraint).to_owned());
+            }
+            Err(err) => sdk_error("GetBucketLocation", err),
+        };
+        let header = match client.head_bucket().bucket(bucket).send().await {
+            Ok(out) => {
+                note_request_ids("HeadBucket", &out);
+                out.bucket_region().map(str::to_owned)
+            }
+            Err(err) => err
+                .raw_response()
+                .and_then(|r| r.headers().get("x-amz-bucket-region"))
+                .map(str::to_owned),
+        };
+        header.filter(|r| !r.is_empty()).ok_or(location_err)
+    })
+}
+
+/// Region of a bucket's LocationConstraint: none means us-east-1, and `EU` is
+/// the legacy name of eu-west-1.
+pub(crate) fn location_region(constraint: Option<&str>) -> &str {
+    match constraint {
+        None | Some("") => "us-east-1",
+        Some("EU") => "eu-west-1",
+        Some(region) => region,
+    }
+}
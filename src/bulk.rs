@@ -0,0 +1,582 @@
+use pgrx::prelude::*;
+use std::future::Future;
+use tokio::task::JoinSet;
+
+use crate::{
+    compression::Decompress,
+    error::{note_request_ids, sdk_error, S3Error},
+    get_object, guc, head_object, put_object, run, source_storage_class, string_map, uri,
+    ClientArgs, URI_PATH,
+};
+
+/// What a batch function does when some of its items fail.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OnError {
+    /// Fail the statement once all items are done.
+    Raise,
+    /// Log a WARNING summarizing the failures.
+    Warn,
+    /// Only report failures in the `error` column.
+    Ignore,
+}
+
+impl OnError {
+    pub(crate) fn parse(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "raise" => Ok(Self::Raise),
+            "warn" => Ok(Self::Warn),
+            "ignore" => Ok(Self::Ignore),
+            _ => Err(format!(
+                "on_error must be 'raise', 'warn' or 'ignore', got {name:?}"
+            )),
+        }
+    }
+
+    /// Apply the policy to the `(key, error)` of the failed items among `total`.
+    pub(crate) fn check<'a>(
+        self,
+        what: &str,
+        bucket: &str,
+        total: usize,
+        failures: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) {
+        let mut failures = failures.into_iter();
+        let Some((key, error)) = failures.next() else {
+            return;
+        };
+        let message = format!(
+            "{} of {total} {what} failed, first s3://{bucket}/{key}: {error}",
+            failures.count() + 1
+        );
+        match self {
+            Self::Raise => pgrx::error!("{message}"),
+            Self::Warn => pgrx::warning!("{message}"),
+            Self::Ignore => {}
+        }
+    }
+}
+
+/// Run `op` on the key and item of each of `items` concurrently, at most
+/// `s3_io.bulk_concurrency` at a time, and return each key with its outcome in
+/// input order.
+///
+/// A failed item does not stop the others; once all are done, the failures
+/// are handled per `on_error`, with `what` naming the operations.
+pub(crate) fn fan_out<T, R, F, Fut>(
+    args: &ClientArgs,
+    bucket: &str,
+    what: &str,
+    on_error: OnError,
+    items: Vec<(String, T)>,
+    op: F,
+) -> Vec<(String, Result<R, S3Error>)>
+where
+    T: Clone,
+    R: Send + 'static,
+    F: Fn(aws_sdk_s3::Client, String, T) -> Fut,
+    Fut: Future<Output = Result<R, S3Error>> + Send + 'static,
+{
+    let concurrency = guc::BULK_CONCURRENCY.get() as usize;
+    let mut results = run(args, |client| {
+        let items = items.clone();
+        let op = &op;
+        async move {
+            let mut tasks = JoinSet::new();
+            let mut results = Vec::with_capacity(items.len());
+            for (i, (key, item)) in items.into_iter().enumerate() {
+                if tasks.len() == concurrency {
+                    results.extend(tasks.join_next().await);
+                }
+                let task = op(client.clone(), key.clone(), item);
+                tasks.spawn(async move { (i, key, task.await) });
+            }
+            while let Some(result) = tasks.join_next().await {
+                results.push(result);
+            }
+            results
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| S3Error::new(format!("Task of {what} failed: {e}")))
+        }
+    });
+
+    results.sort_by_key(|(i, ..)| *i);
+    on_error.check(
+        what,
+        bucket,
+        results.len(),
+        results
+            .iter()
+            .filter_map(|(_, key, out)| Some((key.as_str(), out.as_ref().err()?.message.as_str()))),
+    );
+    results
+        .into_iter()
+        .map(|(_, key, out)| (key, out))
+        .collect()
+}
+
+/// Upload many objects concurrently, at most `s3_io.bulk_concurrency` at a time.
+///
+/// `keys` and `data` are parallel arrays. Returns one row per key in input
+/// order, with either the ETag or the error of its upload; a failed upload
+/// does not stop the others. Afterwards, failures are handled per `on_error`:
+/// `raise` fails the statement, `warn` logs a WARNING and `ignore` does neither.
+#[pg_extern]
+pub(crate) fn s3_put_objects(
+    bucket: Option<&str>,
+    keys: Vec<String>,
+    data: Array<'_, &[u8]>,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    content_type: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    on_error: default!(&str, "'warn'"),
+) -> TableIterator<
+    'static,
+    (
+        name!(key, String),
+        name!(etag, Option<String>),
+        name!(error, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let bucket = uri::bucket_or_default(bucket);
+    let bucket = bucket.as_str();
+    let on_error = OnError::parse(on_error).unwrap_or_else(|e| pgrx::error!("{e}"));
+    let data: Vec<_> = data
+        .iter()
+        .map(|d| d.unwrap_or_else(|| pgrx::error!("data must not contain NULLs")))
+        .map(bytes::Bytes::copy_from_slice)
+        .collect();
+    if keys.len() != data.len() {
+        pgrx::error!(
+            "keys and data must have the same length, got {} and {}",
+            keys.len(),
+            data.len()
+        );
+    }
+    let items: Vec<_> = keys.into_iter().zip(data).collect();
+
+    let results = fan_out(
+        &args,
+        bucket,
+        "uploads",
+        on_error,
+        items,
+        |client, key, body| {
+            let (bucket, content_type) = (bucket.to_owned(), content_type.map(str::to_owned));
+            async move {
+                put_object(
+                    client,
+                    &bucket,
+                    &key,
+                    body,
+                    content_type.as_deref(),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                )
+                .await
+            }
+        },
+    );
+    TableIterator::new(results.into_iter().map(|(key, out)| match out {
+        Ok(out) => {
+            let etag = out
+                .e_tag()
+                .unwrap_or_default()
+                .trim_matches('"')
+                .to_string();
+            (key, Some(etag), None)
+        }
+        Err(e) => (key, None, Some(e.message)),
+    }))
+}
+
+/// Download many objects concurrently, at most `s3_io.bulk_concurrency` at a time.
+///
+/// Returns one row per key in input order, with either the (decompressed)
+/// body or the error of its download; a failed download does not stop the
+/// others. Failures are then handled per `on_error`, as in [`s3_put_objects`].
+#[pg_extern]
+pub(crate) fn s3_get_objects(
+    bucket: Option<&str>,
+    keys: Vec<String>,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    on_error: default!(&str, "'warn'"),
+) -> TableIterator<
+    'static,
+    (
+        name!(key, String),
+        name!(data, Option<Vec<u8>>),
+        name!(error, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let bucket = uri::bucket_or_default(bucket);
+    let bucket = bucket.as_str();
+    let on_error = OnError::parse(on_error).unwrap_or_else(|e| pgrx::error!("{e}"));
+
+    let items = keys.into_iter().map(|key| (key, ())).collect();
+    let results = fan_out(
+        &args,
+        bucket,
+        "downloads",
+        on_error,
+        items,
+        |client, key, ()| {
+            let bucket = bucket.to_owned();
+            async move {
+                get_object(
+                    &client,
+                    &bucket,
+                    &key,
+                    Decompress::Metadata,
+                    None,
+                    false,
+                    None,
+                )
+                .await
+            }
+        },
+    );
+    TableIterator::new(results.into_iter().map(|(key, out)| match out {
+        Ok((data, _)) => (key, Some(data), None),
+        Err(e) => (key, None, Some(e.message)),
+    }))
+}
+
+/// HeadObject of many objects concurrently, at most `s3_io.bulk_concurrency`
+/// at a time, e.g. to check an upload manifest.
+///
+/// Returns one row per key in input order. Missing objects have `exists`
+/// false; objects whose HEAD failed otherwise have `exists` NULL and the
+/// `error`, and are handled per `on_error`, as in [`s3_put_objects`].
+#[pg_extern]
+pub(crate) fn s3_head_objects(
+    bucket: Option<&str>,
+    keys: Vec<String>,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    on_error: default!(&str, "'warn'"),
+) -> TableIterator<
+    'static,
+    (
+        name!(key, String),
+        name!(exists, Option<bool>),
+        name!(size, Option<i64>),
+        name!(etag, Option<String>),
+        name!(error, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let bucket = uri::bucket_or_default(bucket);
+    let bucket = bucket.as_str();
+    let on_error = OnError::parse(on_error).unwrap_or_else(|e| pgrx::error!("{e}"));
+
+    let items = keys.into_iter().map(|key| (key, ())).collect();
+    let results = fan_out(
+        &args,
+        bucket,
+        "HEAD requests",
+        on_error,
+        items,
+        |client, key, ()| {
+            let (bucket, owner) = (bucket.to_owned(), expected_bucket_owner.map(str::to_owned));
+            async move {
+                let head = head_object(&client, &bucket, &key, owner.as_deref()).await?;
+                Ok(head.map(|h| {
+                    let etag = h.e_tag().map(|e| e.trim_matches('"').to_owned());
+                    (h.content_length(), etag)
+                }))
+            }
+        },
+    );
+    TableIterator::new(results.into_iter().map(|(key, out)| match out {
+        Ok(Some((size, etag))) => (key, Some(true), size, etag, None),
+        Ok(None) => (key, Some(false), None, None, None),
+        Err(e) => (key, None, None, None, Some(e.message)),
+    }))
+}
+
+/// Copy many objects from `src_bucket` to `dst_bucket` server-side, at most
+/// `s3_io.bulk_concurrency` at a time.
+///
+/// Each key is copied under the same key, unless `key_prefix_map` (a JSON
+/// object of source prefix to destination prefix) has a prefix it starts with;
+/// then the longest such prefix is replaced. Returns one row per key in input
+/// order, with the ETag of the copy or the error; failures are then handled
+/// per `on_error`, as in [`s3_put_objects`]. Like `s3_copy_object`, each copy
+/// keeps the storage class of its source unless `preserve_storage_class` is
+/// false.
+#[pg_extern]
+pub(crate) fn s3_copy_objects(
+    src_bucket: Option<&str>,
+    dst_bucket: Option<&str>,
+    keys: Vec<String>,
+    key_prefix_map: default!(Option<pgrx::JsonB>, "NULL"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    on_error: default!(&str, "'warn'"),
+    preserve_storage_class: default!(bool, "true"),
+) -> TableIterator<
+    'static,
+    (
+        name!(key, String),
+        name!(etag, Option<String>),
+        name!(error, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (src_bucket, dst_bucket) = (
+        uri::bucket_or_default(src_bucket),
+        uri::bucket_or_default(dst_bucket),
+    );
+    let (src_bucket, dst_bucket) = (src_bucket.as_str(), dst_bucket.as_str());
+    let on_error = OnError::parse(on_error).unwrap_or_else(|e| pgrx::error!("{e}"));
+    let prefix_map: Vec<_> = key_prefix_map
+        .map(|m| string_map(&m.0, "key_prefix_map").unwrap_or_else(|e| pgrx::error!("{e}")))
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    let items: Vec<_> = keys
+        .into_iter()
+        .map(|key| {
+            let dst_key = destination_key(&key, &prefix_map);
+            (key, dst_key)
+        })
+        .collect();
+
+    let results = fan_out(
+        &args,
+        src_bucket,
+        "copies",
+        on_error,
+        items,
+        |client, key, dst_key| {
+            let (src_bucket, dst_bucket) = (src_bucket.to_owned(), dst_bucket.to_owned());
+            async move {
+                copy_object(
+                    &client,
+                    &src_bucket,
+                    &key,
+                    &dst_bucket,
+                    &dst_key,
+                    preserve_storage_class,
+                )
+                .await
+            }
+        },
+    );
+    TableIterator::new(results.into_iter().map(|(key, out)| match out {
+        Ok(etag) => (key, Some(etag), None),
+        Err(e) => (key, None, Some(e.message)),
+    }))
+}
+
+/// `key` with the longest of the source prefixes in `prefix_map` that it
+/// starts with replaced by its destination prefix.
+pub(crate) fn destination_key(key: &str, prefix_map: &[(String, String)]) -> String {
+    prefix_map
+        .iter()
+        .filter(|(from, _)| key.starts_with(from.as_str()))
+        .max_by_key(|(from, _)| from.len())
+        .map_or_else(
+            || key.to_owned(),
+            |(from, to)| format!("{to}{}", &key[from.len()..]),
+        )
+}
+
+/// CopyObject with the default directives, returning the ETag of the copy.
+async fn copy_object(
+    client: &aws_sdk_s3::Client,
+    src_bucket: &str,
+    src_key: &str,
+    dst_bucket: &str,
+    dst_key: &str,
+    preserve_storage_class: bool,
+) -> Result<String, S3Error> {
+    let storage_class = if preserve_storage_class {
+        source_storage_class(client, src_bucket, src_key, None).await?
+    } else {
+        None
+    };
+    let copy_source = format!(
+        "{src_bucket}/{}",
+        percent_encoding::utf8_percent_encode(src_key, URI_PATH)
+    );
+    let out = client
+        .copy_object()
+        .copy_source(copy_source)
+        .bucket(dst_bucket)
+        .key(dst_key)
+        .set_storage_class(storage_class)
+        .send()
+        .await
+        .map_err(|err| sdk_error("CopyObject", err))?;
+    note_request_ids("CopyObject", &out);
+    Ok(out
+        .copy_object_result()
+        .and_then(|r| r.e_tag())
+        .unwrap_or_default()
+        .trim_matches('"')
+        .to_owned())
+}
+
+/// Delete every object whose key starts with `prefix` and return how many were deleted.
+///
+/// The prefix is listed a page of up to 1000 keys at a time, and each page is
+/// deleted with one DeleteObjects request. An empty prefix names the whole
+/// bucket and is refused unless `confirm` is set. Objects that could not be
+/// deleted are handled per `on_error` once the listing is done, as in
+/// [`s3_put_objects`]; the others stay deleted.
+#[pg_extern]
+pub(crate) fn s3_delete_prefix(
+    bucket: Option<&str>,
+    prefix: &str,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    confirm: default!(bool, "false"),
+    on_error: default!(&str, "'warn'"),
+) -> i64 {
+    use aws_sdk_s3::types::{Delete, ObjectIdentifier};
+
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let bucket = uri::bucket_or_default(bucket);
+    let bucket = bucket.as_str();
+    let on_error = OnError::parse(on_error).unwrap_or_else(|e| pgrx::error!("{e}"));
+    if prefix.is_empty() && !confirm {
+        pgrx::error!(
+            "an empty prefix deletes every object in bucket {bucket:?}; pass confirm => true to do that"
+        );
+    }
+    let owner = expected_bucket_owner.map(str::to_owned);
+
+    let (deleted, failures) = run(&args, |client| {
+        let owner = owner.clone();
+        async move {
+            let mut pages = client
+                .list_objects_v2()
+                .bucket(bucket)
+                .prefix(prefix)
+                .set_expected_bucket_owner(owner.clone())
+                .into_paginator()
+                .send();
+
+            let mut deleted = 0;
+            let mut failures = Vec::new();
+            while let Some(page) = pages.next().await {
+                let page = page.map_err(|err| sdk_error("ListObjectsV2", err))?;
+                note_request_ids("ListObjectsV2", &page);
+                let objects = page
+                    .contents()
+                    .iter()
+                    .filter_map(|o| o.key())
+                    .map(|key| ObjectIdentifier::builder().key(key).build())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| S3Error::new(e.to_string()))?;
+                if objects.is_empty() {
+                    continue;
+                }
+                let listed = objects.len();
+                let delete = Delete::builder()
+                    .set_objects(Some(objects))
+                    .quiet(true)
+                    .build()
+                    .map_err(|e| S3Error::new(e.to_string()))?;
+                let out = client
+                    .delete_objects()
+                    .bucket(bucket)
+                    .delete(delete)
+                    .set_expected_bucket_owner(owner.clone())
+                    .send()
+                    .await
+                    .map_err(|err| sdk_error("DeleteObjects", err))?;
+                note_request_ids("DeleteObjects", &out);
+                // In quiet mode, only the objects that could not be deleted are reported.
+                deleted += (listed - out.errors().len()) as i64;
+                failures.extend(out.errors().iter().map(|e| {
+                    (
+                        e.key().unwrap_or_default().to_owned(),
+                        format!(
+                            "{}: {}",
+                            e.code().unwrap_or("Error"),
+                            e.message().unwrap_or_default()
+                        ),
+                    )
+                }));
+            }
+            Ok((deleted, failures))
+        }
+    });
+
+    on_error.check(
+        "deletions",
+        bucket,
+        deleted as usize + failures.len(),
+        failures.iter().map(|(key, e)| (key.as_str(), e.as_str())),
+    );
+    deleted
+}
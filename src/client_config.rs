@@ -0,0 +1,195 @@
+use pgrx::prelude::*;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::{sync::Mutex, time::Duration};
+
+use crate::{client_with, normalize_endpoint, ClientArgs};
+
+/// Fields a client config may have.
+const FIELDS: [&str; 11] = [
+    "endpoint_url",
+    "region",
+    "force_path_style",
+    "access_key",
+    "secret_key",
+    "session_token",
+    "connect_timeout",
+    "read_timeout",
+    "operation_timeout",
+    "max_attempts",
+    "proxy",
+];
+
+// The config set by `s3_client_from_config`, for calls without connection args.
+static CURRENT: Mutex<Option<ClientConfig>> = Mutex::new(None);
+
+/// The config set by `s3_client_from_config`, if any.
+pub(crate) fn current() -> Option<ClientConfig> {
+    CURRENT.lock().unwrap().clone()
+}
+
+/// All settings of a client in one structured value, parsed from the jsonb
+/// argument of `s3_client_from_config`.
+#[derive(Clone, PartialEq)]
+pub(crate) struct ClientConfig {
+    pub(crate) endpoint_url: Option<String>,
+    pub(crate) region: String,
+    pub(crate) force_path_style: Option<bool>,
+    pub(crate) access_key: Option<String>,
+    pub(crate) secret_key: Option<String>,
+    pub(crate) session_token: Option<String>,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) operation_timeout: Option<Duration>,
+    pub(crate) max_attempts: Option<u32>,
+    pub(crate) proxy: Option<String>,
+}
+
+impl ClientConfig {
+    /// Validate a config object; `region` is required, and credentials are
+    /// given as both keys or not at all.
+    pub(crate) fn parse(config: &Value) -> Result<Self, String> {
+        let obj = config
+            .as_object()
+            .ok_or_else(|| "config must be a JSON object".to_owned())?;
+        if let Some(field) = obj.keys().find(|k| !FIELDS.contains(&k.as_str())) {
+            return Err(format!(
+                "config has unknown field {field:?}; expected one of {}",
+                FIELDS.join(", ")
+            ));
+        }
+
+        let endpoint_url = string(obj, "endpoint_url")?
+            .map(|ep| normalize_endpoint(&ep))
+            .transpose()?;
+        let region = string(obj, "region")?
+            .filter(|r| !r.is_empty())
+            .ok_or_else(|| "config needs a \"region\"".to_owned())?;
+        let force_path_style = match obj.get("force_path_style") {
+            None | Some(Value::Null) => None,
+            Some(Value::Bool(b)) => Some(*b),
+            Some(_) => return Err("config field \"force_path_style\" must be a boolean".to_owned()),
+        };
+        let (access_key, secret_key) =
+            match (string(obj, "access_key")?, string(obj, "secret_key")?) {
+                (Some(ak), Some(sk)) => (Some(ak), Some(sk)),
+                (None, None) => (None, None),
+                _ => {
+                    return Err(
+                        "config needs both \"access_key\" and \"secret_key\", or neither"
+                            .to_owned(),
+                    )
+                }
+            };
+        let session_token = string(obj, "session_token")?;
+        if session_token.is_some() && access_key.is_none() {
+            return Err(
+                "config field \"session_token\" requires \"access_key\" and \"secret_key\""
+                    .to_owned(),
+            );
+        }
+        let max_attempts = match obj.get("max_attempts") {
+            None | Some(Value::Null) => None,
+            Some(n) => Some(
+                n.as_u64()
+                    .and_then(|n| u32::try_from(n).ok())
+                    .filter(|n| *n >= 1)
+                    .ok_or_else(|| {
+                        "config field \"max_attempts\" must be a positive integer".to_owned()
+                    })?,
+            ),
+        };
+        let proxy = string(obj, "proxy")?;
+        if let Some(proxy) = &proxy {
+            aws_smithy_http_client::proxy::ProxyConfig::all(proxy.as_str())
+                .map_err(|e| format!("Invalid proxy URL {proxy:?}: {e}"))?;
+        }
+
+        Ok(Self {
+            endpoint_url,
+            region,
+            force_path_style,
+            access_key,
+            secret_key,
+            session_token,
+            connect_timeout: seconds(obj, "connect_timeout")?,
+            read_timeout: seconds(obj, "read_timeout")?,
+            operation_timeout: seconds(obj, "operation_timeout")?,
+            max_attempts,
+            proxy,
+        })
+    }
+
+    /// Hex SHA-256 of all settings, identifying the config in the client cache.
+    pub(crate) fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        let fields = [
+            self.endpoint_url.clone(),
+            Some(self.region.clone()),
+            self.force_path_style.map(|b| b.to_string()),
+            self.access_key.clone(),
+            self.secret_key.clone(),
+            self.session_token.clone(),
+            self.connect_timeout.map(|d| d.as_nanos().to_string()),
+            self.read_timeout.map(|d| d.as_nanos().to_string()),
+            self.operation_timeout.map(|d| d.as_nanos().to_string()),
+            self.max_attempts.map(|n| n.to_string()),
+            self.proxy.clone(),
+        ];
+        for field in fields {
+            match field {
+                Some(value) => {
+                    hasher.update([1]);
+                    hasher.update((value.len() as u64).to_be_bytes());
+                    hasher.update(value);
+                }
+                None => hasher.update([0]),
+            }
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+/// An optional string field of `obj`.
+fn string(obj: &Map<String, Value>, field: &str) -> Result<Option<String>, String> {
+    match obj.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(format!("config field {field:?} must be a string")),
+    }
+}
+
+/// An optional timeout field of `obj`, in seconds.
+fn seconds(obj: &Map<String, Value>, field: &str) -> Result<Option<Duration>, String> {
+    match obj.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(n) => n
+            .as_f64()
+            .and_then(|s| Duration::try_from_secs_f64(s).ok())
+            .filter(|d| !d.is_zero())
+            .map(Some)
+            .ok_or_else(|| format!("config field {field:?} must be a positive number of seconds")),
+    }
+}
+
+/// Build the client described by `config` and make it the default for the
+/// rest of the session; returns the hash identifying it in the client cache.
+///
+/// Connection arguments a call does pass override the config's fields. NULL
+/// goes back to the arguments, environment and settings alone.
+#[pg_extern]
+pub(crate) fn s3_client_from_config(config: Option<pgrx::JsonB>) -> Option<String> {
+    let Some(config) = config else {
+        *CURRENT.lock().unwrap() = None;
+        return None;
+    };
+    let config = ClientConfig::parse(&config.0).unwrap_or_else(|e| pgrx::error!("{e}"));
+    client_with(&ClientArgs::default(), Some(&config), false);
+    let hash = config.hash();
+    *CURRENT.lock().unwrap() = Some(config);
+    Some(hash)
+}
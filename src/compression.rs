@@ -0,0 +1,172 @@
+use std::io::{BufRead, BufReader, Read, Write};
+
+/// User metadata key recording the codec an object was compressed with.
+///
+/// Not every client knows the `zstd` Content-Encoding, and a Content-Encoding
+/// is left out for compressed files stored as such, so the codec is stored as
+/// `x-amz-meta-s3-io-codec` for every compressed object.
+pub(crate) const CODEC_METADATA_KEY: &str = "s3-io-codec";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            other => Err(format!("Unknown compression {other:?}, expected gzip or zstd")),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// The Content-Encoding of a body compressed with this codec, so that
+    /// browsers and CDNs decompress it to the `content_type` it is labelled
+    /// with. `None` if `content_type` is the compressed format itself, e.g. an
+    /// `application/gzip` download meant to be saved as a `.gz` file.
+    pub fn content_encoding(self, content_type: Option<&str>) -> Option<&'static str> {
+        let media_type = content_type
+            .and_then(|ct| ct.split(';').next())
+            .map(|ct| ct.trim().to_ascii_lowercase());
+        let own_types: &[&str] = match self {
+            Self::Gzip => &["application/gzip", "application/x-gzip"],
+            Self::Zstd => &["application/zstd"],
+        };
+        match media_type {
+            Some(ct) if own_types.contains(&ct.as_str()) => None,
+            _ => Some(self.name()),
+        }
+    }
+
+    pub fn compress(self, data: &[u8], level: Option<i32>) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Gzip => {
+                let level = match level {
+                    None => flate2::Compression::default(),
+                    Some(l @ 0..=9) => flate2::Compression::new(l as u32),
+                    Some(l) => return Err(format!("gzip level must be 0..9, got {l}")),
+                };
+                let mut enc = flate2::write::GzEncoder::new(Vec::new(), level);
+                enc.write_all(data)
+                    .and_then(|_| enc.finish())
+                    .map_err(|e| format!("gzip compression failed: {e}"))
+            }
+            Self::Zstd => {
+                let range = zstd::compression_level_range();
+                let level = match level {
+                    None => zstd::DEFAULT_COMPRESSION_LEVEL,
+                    Some(l) if range.contains(&l) => l,
+                    Some(l) => {
+                        return Err(format!(
+                            "zstd level must be {}..{}, got {l}",
+                            range.start(),
+                            range.end()
+                        ))
+                    }
+                };
+                zstd::encode_all(data, level).map_err(|e| format!("zstd compression failed: {e}"))
+            }
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut out = Vec::new();
+        decoder(Some(self), data)?
+            .read_to_end(&mut out)
+            .map_err(|e| format!("{} decompression failed: {e}", self.name()))?;
+        Ok(out)
+    }
+}
+
+/// `reader` decoded with `codec` as it is read, or unchanged without one.
+///
+/// Every streaming read goes through this, so a new codec only needs a case here.
+pub(crate) fn decoder<'a>(
+    codec: Option<Codec>,
+    reader: impl Read + 'a,
+) -> Result<Box<dyn Read + 'a>, String> {
+    Ok(match codec {
+        None => Box::new(reader),
+        Some(Codec::Gzip) => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+        Some(Codec::Zstd) => Box::new(
+            zstd::stream::read::Decoder::new(reader)
+                .map_err(|e| format!("zstd decompression failed: {e}"))?,
+        ),
+    })
+}
+
+/// How `s3_get_object` decodes the object body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Decompress {
+    /// Return the stored bytes unchanged.
+    None,
+    /// Decompress objects whose codec marker or Content-Encoding names a known codec.
+    Metadata,
+    /// Like `Metadata`, and also gunzip unmarked bodies starting with the gzip magic bytes.
+    Auto,
+}
+
+/// The first two bytes of every gzip member.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+impl Decompress {
+    pub fn parse(mode: &str) -> Result<Self, String> {
+        match mode.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "metadata" => Ok(Self::Metadata),
+            "auto" => Ok(Self::Auto),
+            other => Err(format!(
+                "Unknown decompress mode {other:?}, expected none, metadata or auto"
+            )),
+        }
+    }
+
+    /// `body` decoded as it is read, with the codec [`Self::codec`] picks from
+    /// the object's codec marker, Content-Encoding and first bytes.
+    pub fn stream<'a>(
+        self,
+        marker: Option<&str>,
+        content_encoding: Option<&str>,
+        body: impl Read + 'a,
+    ) -> Result<Box<dyn Read + 'a>, String> {
+        let mut body = BufReader::new(body);
+        let start = match self {
+            Self::Auto => body
+                .fill_buf()
+                .map_err(|e| format!("Cannot read the object: {e}"))?,
+            _ => &[],
+        };
+        let codec = self.codec(marker, content_encoding, start)?;
+        decoder(codec, body)
+    }
+
+    /// Codec to decode `body` with, given the object's codec marker and Content-Encoding.
+    pub fn codec(
+        self,
+        marker: Option<&str>,
+        content_encoding: Option<&str>,
+        body: &[u8],
+    ) -> Result<Option<Codec>, String> {
+        match self {
+            Self::None => Ok(None),
+            Self::Metadata => match (marker, content_encoding) {
+                (Some(codec), _) => Codec::parse(codec).map(Some),
+                (None, Some(enc)) => Ok(Codec::parse(enc).ok()),
+                (None, None) => Ok(None),
+            },
+            Self::Auto => match Self::Metadata.codec(marker, content_encoding, body)? {
+                Some(codec) => Ok(Some(codec)),
+                None => Ok(body.starts_with(&GZIP_MAGIC).then_some(Codec::Gzip)),
+            },
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use pgrx::prelude::*;
+
+use crate::{
+    error::{note_request_ids, sdk_error, S3Error},
+    try_run, ClientArgs,
+};
+
+/// Whether S3 accepts the credentials, checked with a minimal authenticated
+/// request. Network and other errors are raised rather than taken for invalid
+/// credentials.
+#[pg_extern]
+fn s3_check_credentials(
+    bucket: default!(Option<&str>, "NULL"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> bool {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    check(&args, bucket).0
+}
+
+/// Like `s3_check_credentials`, with the S3 error code behind the verdict as
+/// `reason`: why credentials are invalid, or `AccessDenied` for valid
+/// credentials that may not make the request. NULL when the request succeeded.
+#[pg_extern]
+fn s3_check_credentials_detail(
+    bucket: default!(Option<&str>, "NULL"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> TableIterator<'static, (name!(valid, bool), name!(reason, Option<String>))> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    TableIterator::once(check(&args, bucket))
+}
+
+/// ListBuckets, or an empty ListObjectsV2 of `bucket` for credentials only
+/// allowed to use that bucket, judged by [`verdict`].
+fn check(args: &ClientArgs, bucket: Option<&str>) -> (bool, Option<String>) {
+    let result = try_run(args, |client| async move {
+        match bucket {
+            Some(bucket) => {
+                let out = client
+                    .list_objects_v2()
+                    .bucket(bucket)
+                    .max_keys(0)
+                    .send()
+                    .await
+                    .map_err(|err| sdk_error("ListObjectsV2", err))?;
+                note_request_ids("ListObjectsV2", &out);
+            }
+            None => {
+                let out = client
+                    .list_buckets()
+                    .max_buckets(1)
+                    .send()
+                    .await
+                    .map_err(|err| sdk_error("ListBuckets", err))?;
+                note_request_ids("ListBuckets", &out);
+            }
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => (true, None),
+        Err(e) => verdict(e).unwrap_or_else(|e| e.raise()),
+    }
+}
+
+/// What a failed check says about the credentials; errors that do not tell,
+/// such as network failures, are returned to be raised.
+pub(crate) fn verdict(err: S3Error) -> Result<(bool, Option<String>), S3Error> {
+    match err.code.as_deref() {
+        Some(
+            code @ ("InvalidAccessKeyId"
+            | "SignatureDoesNotMatch"
+            | "InvalidToken"
+            | "ExpiredToken"
+            | "TokenRefreshRequired"),
+        ) => Ok((false, Some(code.to_owned()))),
+        // S3 checks the signature before permissions.
+        Some(code @ "AccessDenied") => Ok((true, Some(code.to_owned()))),
+        _ => Err(err),
+    }
+}
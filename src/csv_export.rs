@@ -0,0 +1,227 @@
+use pgrx::prelude::*;
+
+use crate::{csv_gz::single_byte, error::S3Error, multipart::ObjectWriter, uri, ClientArgs};
+
+/// Rows fetched from the query's cursor at a time.
+const BATCH_ROWS: i64 = 1000;
+
+/// Content type of exports without a `content_type` argument.
+const CONTENT_TYPE: &str = "text/csv";
+
+/// Export the result of `query` as a CSV object and return its ETag.
+///
+/// The options mean what they mean for COPY: fields are separated by
+/// `delimiter`, NULLs written as `null_as`, and fields that contain the
+/// delimiter, `quote`, a line break, or equal `null_as` are quoted, with
+/// `quote` and `escape` inside them preceded by `escape` (by default `quote`,
+/// i.e. doubled). Columns named in `force_quote` (`'{*}'` for all) are always
+/// quoted unless NULL.
+///
+/// Rows are formatted as they are fetched: up to 5 MiB the object is sent with
+/// one PutObject, larger exports are streamed as a multipart upload. The
+/// object's content type is `text/csv` unless `content_type` is given.
+#[pg_extern]
+pub(crate) fn s3_export_csv(
+    bucket: Option<&str>,
+    object_key: &str,
+    query: &str,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    header: default!(bool, "true"),
+    delimiter: default!(&str, "','"),
+    null_as: default!(&str, "''"),
+    quote: default!(&str, "'\"'"),
+    escape: default!(Option<&str>, "NULL"),
+    force_quote: default!(Option<Vec<String>>, "NULL"),
+    content_type: default!(Option<&str>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let query = query.trim_end().trim_end_matches(';');
+    let columns = column_names(query).unwrap_or_else(|e| pgrx::error!("{e}"));
+    let format = CsvFormat::new(
+        single_byte("delimiter", delimiter),
+        single_byte("quote", quote),
+        escape.map(|e| single_byte("escape", e)),
+        null_as,
+        &columns,
+        force_quote.as_deref().unwrap_or_default(),
+    )
+    .unwrap_or_else(|e| pgrx::error!("{e}"));
+
+    // Every column as text, under names that cannot clash.
+    let aliases: Vec<String> = (1..=columns.len()).map(|i| format!("c{i}")).collect();
+    let text_query = format!(
+        "SELECT {} FROM ({query}) AS q({})",
+        aliases
+            .iter()
+            .map(|a| format!("{a}::text"))
+            .collect::<Vec<_>>()
+            .join(", "),
+        aliases.join(", ")
+    );
+
+    let mut export = ObjectWriter::new(
+        args,
+        bucket,
+        object_key,
+        content_type.unwrap_or(CONTENT_TYPE),
+    );
+    if header {
+        format.write_header(export.buffer(), &columns);
+    }
+    let result = Spi::connect(|client| {
+        let mut cursor = client.open_cursor(text_query.as_str(), &[]);
+        loop {
+            let rows = cursor
+                .fetch(BATCH_ROWS)
+                .map_err(|e| S3Error::new(format!("Cannot run query: {e}")))?;
+            if rows.is_empty() {
+                return Ok(());
+            }
+            for row in rows {
+                let fields = (1..=columns.len())
+                    .map(|i| row.get::<String>(i))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| S3Error::new(format!("Cannot read query result: {e}")))?;
+                format.write_record(export.buffer(), fields.iter().map(Option::as_deref));
+            }
+            export.flush()?;
+        }
+    })
+    .and_then(|()| export.finish());
+    if result.is_err() {
+        export.abort();
+    }
+    result.unwrap_or_else(|e| e.raise())
+}
+
+/// Names of the columns `query` returns, in order.
+fn column_names(query: &str) -> Result<Vec<String>, String> {
+    Spi::connect(|client| {
+        let table = client.select(&format!("SELECT * FROM ({query}) AS q LIMIT 0"), None, &[])?;
+        (1..=table.columns()?)
+            .map(|i| table.column_name(i))
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .map_err(|e| format!("Invalid query: {e}"))
+}
+
+/// How fields are separated, quoted and escaped, and which columns are always quoted.
+pub(crate) struct CsvFormat {
+    delimiter: u8,
+    quote: u8,
+    escape: u8,
+    null_as: String,
+    force_quote: Vec<bool>,
+}
+
+impl CsvFormat {
+    /// Check the options the way COPY does; `force_quote` names `columns`, or is `["*"]`.
+    pub(crate) fn new(
+        delimiter: u8,
+        quote: u8,
+        escape: Option<u8>,
+        null_as: &str,
+        columns: &[String],
+        force_quote: &[String],
+    ) -> Result<Self, String> {
+        if delimiter == quote {
+            return Err("delimiter and quote must differ".to_owned());
+        }
+        if [delimiter, quote]
+            .iter()
+            .any(|b| matches!(b, b'\r' | b'\n'))
+        {
+            return Err("delimiter and quote must not be a line break".to_owned());
+        }
+        if null_as
+            .bytes()
+            .any(|b| b == delimiter || b == quote || matches!(b, b'\r' | b'\n'))
+        {
+            return Err(format!(
+                "null_as must not contain the delimiter, quote or a line break, got {null_as:?}"
+            ));
+        }
+        let all = force_quote.iter().any(|c| c == "*");
+        if let Some(unknown) = force_quote
+            .iter()
+            .find(|c| *c != "*" && !columns.contains(c))
+        {
+            return Err(format!(
+                "force_quote column {unknown:?} is not in the query"
+            ));
+        }
+        Ok(Self {
+            delimiter,
+            quote,
+            escape: escape.unwrap_or(quote),
+            null_as: null_as.to_owned(),
+            force_quote: columns
+                .iter()
+                .map(|c| all || force_quote.contains(c))
+                .collect(),
+        })
+    }
+
+    /// Append the header line to `out`; as in COPY, `force_quote` does not apply to it.
+    pub(crate) fn write_header(&self, out: &mut Vec<u8>, columns: &[String]) {
+        self.write_line(out, columns.iter().map(|c| Some(c.as_str())), &[]);
+    }
+
+    /// Append one line of `fields` to `out`, `None` being NULL.
+    pub(crate) fn write_record<'a>(
+        &self,
+        out: &mut Vec<u8>,
+        fields: impl IntoIterator<Item = Option<&'a str>>,
+    ) {
+        self.write_line(out, fields, &self.force_quote);
+    }
+
+    fn write_line<'a>(
+        &self,
+        out: &mut Vec<u8>,
+        fields: impl IntoIterator<Item = Option<&'a str>>,
+        force_quote: &[bool],
+    ) {
+        for (i, field) in fields.into_iter().enumerate() {
+            if i > 0 {
+                out.push(self.delimiter);
+            }
+            let Some(value) = field else {
+                out.extend_from_slice(self.null_as.as_bytes());
+                continue;
+            };
+            let quoted = force_quote.get(i).copied().unwrap_or_default()
+                || value == self.null_as
+                || value
+                    .bytes()
+                    .any(|b| b == self.delimiter || b == self.quote || matches!(b, b'\r' | b'\n'));
+            if !quoted {
+                out.extend_from_slice(value.as_bytes());
+                continue;
+            }
+            out.push(self.quote);
+            for b in value.bytes() {
+                if b == self.quote || b == self.escape {
+                    out.push(self.escape);
+                }
+                out.push(b);
+            }
+            out.push(self.quote);
+        }
+        out.push(b'\n');
+    }
+}
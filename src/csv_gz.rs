@@ -0,0 +1,166 @@
+use pgrx::prelude::*;
+use std::io::Read;
+
+use aws_sdk_s3::primitives::ByteStream;
+use bytes::{Buf, Bytes};
+
+use crate::{
+    block_on,
+    compression::{self, Codec},
+    error::{note_request_ids, sdk_error},
+    run, uri, ClientArgs,
+};
+
+/// Records typed per SPI call.
+const BATCH_ROWS: usize = 1000;
+
+/// Blocking reader over an object body, fetching chunks as they are consumed.
+pub(crate) struct BodyReader {
+    body: ByteStream,
+    chunk: Bytes,
+}
+
+impl BodyReader {
+    pub(crate) fn new(body: ByteStream) -> Self {
+        Self {
+            body,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while !self.chunk.has_remaining() {
+            match block_on(self.body.next()) {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(e)) => return Err(std::io::Error::other(e)),
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        self.chunk.copy_to_slice(&mut buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Read a gzipped CSV object as rows typed by `columns_def` (e.g. `id int, name text`),
+/// each returned as a JSON object.
+///
+/// Fields map to the columns by position; fields equal to `null_string` (empty
+/// by default) are NULL. With `header`, the first line is skipped. As in COPY,
+/// fields are quoted with `quote`, and quotes inside them are escaped with
+/// `escape`, by default by doubling them.
+///
+/// The body is downloaded, gunzipped and parsed as rows are consumed, so
+/// neither it nor the decompressed content is held in memory.
+#[pg_extern]
+pub(crate) fn s3_read_csv_gz(
+    bucket: Option<&str>,
+    object_key: &str,
+    columns_def: &str,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    header: default!(bool, "true"),
+    delimiter: default!(&str, "','"),
+    null_string: default!(&str, "''"),
+    quote: default!(&str, "'\"'"),
+    escape: default!(Option<&str>, "NULL"),
+) -> SetOfIterator<'static, pgrx::JsonB> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let delimiter = single_byte("delimiter", delimiter);
+    let quote = single_byte("quote", quote);
+    let escape = escape.map(|e| single_byte("escape", e)).unwrap_or(quote);
+    let null_string = null_string.to_owned();
+    let query = format!("SELECT to_jsonb(t) FROM jsonb_to_recordset($1) AS t({columns_def})");
+    let columns = column_names(&query).unwrap_or_else(|e| pgrx::error!("{e}"));
+
+    let body = run(&args, |client| async move {
+        let out = client
+            .get_object()
+            .bucket(bucket)
+            .key(object_key)
+            .send()
+            .await
+            .map_err(|err| sdk_error("GetObject", err))?;
+        note_request_ids("GetObject", &out);
+        Ok(out.body)
+    });
+    let body = BodyReader::new(body);
+    let mut records = csv::ReaderBuilder::new()
+        .has_headers(header)
+        .delimiter(delimiter)
+        .quote(quote)
+        .double_quote(escape == quote)
+        .escape((escape != quote).then_some(escape))
+        .flexible(true)
+        .from_reader(
+            compression::decoder(Some(Codec::Gzip), body).unwrap_or_else(|e| pgrx::error!("{e}")),
+        )
+        .into_records();
+
+    let uri = format!("s3://{bucket}/{object_key}");
+    let batches = std::iter::from_fn(move || {
+        let batch: Vec<_> = records
+            .by_ref()
+            .take(BATCH_ROWS)
+            .map(|record| {
+                let record = record.unwrap_or_else(|e| pgrx::error!("Invalid CSV in {uri}: {e}"));
+                let row: serde_json::Map<_, _> = columns
+                    .iter()
+                    .zip(record.iter())
+                    .filter(|(_, v)| *v != null_string)
+                    .map(|(name, v)| (name.clone(), serde_json::Value::from(v)))
+                    .collect();
+                serde_json::Value::Object(row)
+            })
+            .collect();
+        (!batch.is_empty()).then(|| typed(&query, batch).unwrap_or_else(|e| pgrx::error!("{e}")))
+    });
+    SetOfIterator::new(batches.flatten().map(pgrx::JsonB))
+}
+
+pub(crate) fn single_byte(what: &str, value: &str) -> u8 {
+    match value.as_bytes() {
+        [b] => *b,
+        _ => pgrx::error!("{what} must be a single byte, got {value:?}"),
+    }
+}
+
+/// Names of the columns `query` defines, in order.
+fn column_names(query: &str) -> Result<Vec<String>, String> {
+    Spi::connect(|client| {
+        let empty = pgrx::JsonB(serde_json::Value::Array(Vec::new()));
+        let table = client.select(query, Some(0), &[empty.into()])?;
+        (1..=table.columns()?)
+            .map(|i| table.column_name(i))
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .map_err(|e| format!("Invalid columns_def: {e}"))
+}
+
+/// Convert rows of text values to the column types through `query`.
+fn typed(query: &str, rows: Vec<serde_json::Value>) -> Result<Vec<serde_json::Value>, String> {
+    Spi::connect(|client| {
+        let rows = pgrx::JsonB(serde_json::Value::Array(rows));
+        client
+            .select(query, None, &[rows.into()])?
+            .map(|row| row.get::<pgrx::JsonB>(1).map(|v| v.map(|j| j.0)))
+            .filter_map(Result::transpose)
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .map_err(|e| format!("CSV value does not match columns_def: {e}"))
+}
@@ -0,0 +1,176 @@
+//! Dry runs of S3 requests, for debugging signature mismatches against
+//! S3-compatible stores.
+
+use pgrx::prelude::*;
+use std::sync::{Arc, Mutex};
+
+use aws_smithy_runtime_api::{
+    box_error::BoxError,
+    client::{
+        interceptors::{context::BeforeTransmitInterceptorContextRef, Intercept},
+        runtime_components::RuntimeComponents,
+    },
+};
+use aws_smithy_types::config_bag::ConfigBag;
+
+use crate::{arn, error::S3Error, guc, run, uri, ClientArgs};
+
+const REDACTED: &str = "<redacted>";
+
+/// Captures the signed request and stops it from being sent.
+#[derive(Debug, Default)]
+struct DryRun {
+    request: Arc<Mutex<Option<String>>>,
+}
+
+impl Intercept for DryRun {
+    fn name(&self) -> &'static str {
+        "DryRun"
+    }
+
+    fn read_before_transmit(
+        &self,
+        context: &BeforeTransmitInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let request = context.request();
+        let mut headers: Vec<_> = request
+            .headers()
+            .iter()
+            .map(|(name, value)| format!("{name}: {}", redact(name, value)))
+            .collect();
+        headers.sort();
+        *self.request.lock().unwrap() = Some(format!(
+            "{} {}\n{}",
+            request.method(),
+            request.uri(),
+            headers.join("\n")
+        ));
+        Err("dry run, request not sent".into())
+    }
+}
+
+/// Hide the access key, signature and session token of a request header.
+fn redact(name: &str, value: &str) -> String {
+    match name.to_ascii_lowercase().as_str() {
+        "authorization" => value
+            .split(", ")
+            .map(|part| {
+                if let Some((head, credential)) = part.split_once("Credential=") {
+                    // Keep the scope (date/region/service), which mismatches often hinge on.
+                    let scope = credential.split_once('/').map_or("", |(_, scope)| scope);
+                    format!("{head}Credential={REDACTED}/{scope}")
+                } else if part.starts_with("Signature=") {
+                    format!("Signature={REDACTED}")
+                } else {
+                    part.to_owned()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+        "x-amz-security-token" => REDACTED.to_owned(),
+        _ => value.to_owned(),
+    }
+}
+
+/// Build and sign `operation` (GetObject, HeadObject, PutObject or DeleteObject)
+/// on an object without sending it, and describe the request: addressing style,
+/// method and URL, then the headers with credentials and signature redacted.
+#[pg_extern]
+pub(crate) fn s3_debug_request(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    operation: default!(&str, "'GetObject'"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let path_style = force_path_style.unwrap_or_else(|| guc::FORCE_PATH_STYLE.get());
+    let operation = match operation.to_ascii_lowercase().as_str() {
+        "getobject" => "GetObject",
+        "headobject" => "HeadObject",
+        "putobject" => "PutObject",
+        "deleteobject" => "DeleteObject",
+        _ => pgrx::error!(
+            "Unknown operation {operation:?}, expected GetObject, HeadObject, PutObject or DeleteObject"
+        ),
+    };
+
+    let request = run(&args, |client| async move {
+        let dry_run = DryRun::default();
+        let captured = dry_run.request.clone();
+        let sent = match operation {
+            "GetObject" => client
+                .get_object()
+                .bucket(bucket)
+                .key(object_key)
+                .customize()
+                .interceptor(dry_run)
+                .send()
+                .await
+                .map(drop)
+                .map_err(|err| format!("{err:?}")),
+            "HeadObject" => client
+                .head_object()
+                .bucket(bucket)
+                .key(object_key)
+                .customize()
+                .interceptor(dry_run)
+                .send()
+                .await
+                .map(drop)
+                .map_err(|err| format!("{err:?}")),
+            "PutObject" => client
+                .put_object()
+                .bucket(bucket)
+                .key(object_key)
+                .customize()
+                .interceptor(dry_run)
+                .send()
+                .await
+                .map(drop)
+                .map_err(|err| format!("{err:?}")),
+            _ => client
+                .delete_object()
+                .bucket(bucket)
+                .key(object_key)
+                .customize()
+                .interceptor(dry_run)
+                .send()
+                .await
+                .map(drop)
+                .map_err(|err| format!("{err:?}")),
+        };
+        let captured = captured.lock().unwrap().take();
+        match (captured, sent) {
+            (Some(request), _) => Ok(request),
+            (None, Err(e)) => Err(S3Error::new(format!(
+                "Could not build {operation} request: {e}"
+            ))),
+            (None, Ok(())) => Err(S3Error::new(format!(
+                "{operation} request was not captured"
+            ))),
+        }
+    });
+
+    let addressing = if path_style && !arn::is_arn(bucket) {
+        "path-style"
+    } else {
+        "virtual-hosted-style"
+    };
+    format!("addressing: {addressing}\n{request}")
+}
@@ -0,0 +1,298 @@
+use pgrx::{guc::GucSetting, prelude::*};
+use std::{
+    ffi::CString,
+    os::unix::fs::FileExt,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use tokio::task::JoinSet;
+
+use crate::{
+    error::{note_request_ids, sdk_error, S3Error},
+    guc, head_object, run, stored_checksum, uri, write_body, ClientArgs,
+};
+
+/// Download an object to `dest_path` on the database server for staging, and
+/// return its size and the checksum it was verified against.
+///
+/// Only superusers may call this, and only for paths inside the directories
+/// listed in `s3_io.download_paths`. The object is written to
+/// `<dest_path>.part`, which is renamed to `dest_path` once complete and removed
+/// on failure. With `verify`, the size written must match the Content-Length,
+/// and the body must match the full-object checksum S3 stores, if it has one.
+#[pg_extern]
+pub(crate) fn s3_download_file(
+    bucket: Option<&str>,
+    object_key: &str,
+    dest_path: &str,
+    verify: default!(bool, "true"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(size, i64),
+        name!(checksum_algorithm, Option<String>),
+        name!(checksum, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    check_server_file(
+        "s3_download_file",
+        dest_path,
+        &guc::DOWNLOAD_PATHS,
+        "s3_io.download_paths",
+    );
+    let part_path = format!("{dest_path}.part");
+
+    let result = run(&args, |client| {
+        let part_path = part_path.clone();
+        async move {
+            let _ = std::fs::remove_file(&part_path);
+            let result = async {
+                let mut req = client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(object_key)
+                    .set_expected_bucket_owner(expected_bucket_owner.map(str::to_owned));
+                if verify {
+                    req = req.checksum_mode(aws_sdk_s3::types::ChecksumMode::Enabled);
+                }
+                let out = req
+                    .send()
+                    .await
+                    .map_err(|err| sdk_error("GetObject", err))?;
+                note_request_ids("GetObject", &out);
+
+                // A checksum of part checksums ("...-<parts>") is not checked by the SDK.
+                let checksum = stored_checksum(&out)
+                    .filter(|(_, value)| {
+                        verify
+                            && value
+                                .rsplit_once('-')
+                                .is_none_or(|(_, n)| n.parse::<u32>().is_err())
+                    })
+                    .map(|(algorithm, value)| (algorithm.to_owned(), value.to_owned()));
+                let content_length = out.content_length();
+                let file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&part_path)
+                    .map_err(|e| S3Error::new(format!("Cannot create {part_path}: {e}")))?;
+                let size =
+                    write_body(out.body, file, &part_path)
+                        .await
+                        .map_err(|e| match &checksum {
+                            Some((algorithm, _)) => S3Error::new(format!(
+                                "Download of s3://{bucket}/{object_key} failed {algorithm} \
+                             verification or was interrupted: {e}"
+                            )),
+                            None => e,
+                        })?;
+                if verify && content_length.is_some_and(|len| len != size) {
+                    return Err(S3Error::new(format!(
+                        "Downloaded {size} bytes of s3://{bucket}/{object_key}, \
+                         but its Content-Length is {}",
+                        content_length.unwrap_or_default()
+                    )));
+                }
+                std::fs::rename(&part_path, dest_path).map_err(|e| {
+                    S3Error::new(format!("Cannot rename {part_path} to {dest_path}: {e}"))
+                })?;
+                Ok((size, checksum))
+            }
+            .await;
+            if result.is_err() {
+                let _ = std::fs::remove_file(&part_path);
+            }
+            result
+        }
+    });
+    let (size, checksum) = result;
+    let (algorithm, checksum) = checksum.unzip();
+    TableIterator::once((size, algorithm, checksum))
+}
+
+/// Raise unless the current user is a superuser and `path` is inside one of
+/// the directories listed in `paths`, the setting named `setting`.
+///
+/// For functions reading or writing files on the database server, which would
+/// otherwise give any caller the file access of the postgres OS user.
+pub(crate) fn check_server_file(
+    function: &str,
+    path: &str,
+    paths: &GucSetting<Option<CString>>,
+    setting: &str,
+) {
+    // SAFETY: only reads the current user's role.
+    if !unsafe { pg_sys::superuser() } {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+            format!("{function} requires superuser")
+        );
+    }
+    let allowed = paths
+        .get()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    check_path(Path::new(path), &allowed, setting).unwrap_or_else(|e| {
+        ereport!(ERROR, PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE, e);
+    });
+}
+
+/// Check that `path` names a file inside one of the comma-separated directories
+/// in `allowed`, after resolving symbolic links in its directory. `setting`
+/// names where `allowed` comes from, for the error message.
+pub(crate) fn check_path(path: &Path, allowed: &str, setting: &str) -> Result<(), String> {
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return Err(format!(
+            "File path must be absolute and without \"..\", got {path:?}"
+        ));
+    }
+    let (Some(dir), Some(_)) = (path.parent(), path.file_name()) else {
+        return Err(format!("File path must name a file, got {path:?}"));
+    };
+    let dir = dir
+        .canonicalize()
+        .map_err(|e| format!("Cannot resolve the directory of {path:?}: {e}"))?;
+    let inside = allowed
+        .split(',')
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .filter_map(|a| PathBuf::from(a).canonicalize().ok())
+        .any(|a| dir.starts_with(a));
+    if !inside {
+        return Err(format!(
+            "{path:?} is not inside a directory listed in {setting}"
+        ));
+    }
+    Ok(())
+}
+
+/// Download an object into `dest_path` with concurrent range GETs of
+/// `s3_io.download_part_size` bytes, at most `s3_io.download_concurrency` at a
+/// time, each writing to its own offset of the file, which is first sized to
+/// the object's Content-Length.
+///
+/// The ranges are written to `<dest_path>.part`, which is renamed to
+/// `dest_path` once complete and removed if the download fails or is
+/// cancelled, so `dest_path` is never left partly filled.
+///
+/// Returns `None` without downloading anything if parallel downloads are off,
+/// or the object is missing or fits in one part; the caller then downloads it
+/// in one stream. All ranges are requested with the ETag of the HEAD as
+/// `If-Match`, so an object that changes midway fails the download.
+pub(crate) async fn download_in_parts(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    dest_path: &str,
+    expected_bucket_owner: Option<&str>,
+) -> Result<Option<i64>, S3Error> {
+    let concurrency = guc::DOWNLOAD_CONCURRENCY.get() as usize;
+    let part_size = guc::DOWNLOAD_PART_SIZE.get() as u64 * 1024;
+    if concurrency < 2 {
+        return Ok(None);
+    }
+    let Some(head) = head_object(client, bucket, object_key, expected_bucket_owner).await? else {
+        return Ok(None);
+    };
+    let size = head.content_length().unwrap_or_default() as u64;
+    let (Some(etag), true) = (head.e_tag(), size > part_size) else {
+        return Ok(None);
+    };
+
+    let mut part = PartFile(format!("{dest_path}.part"));
+    let part_path = part.0.as_str();
+    let io_error = |e: std::io::Error| S3Error::new(format!("Cannot write {part_path}: {e}"));
+    let file = std::fs::File::create(part_path).map_err(io_error)?;
+    file.set_len(size).map_err(io_error)?;
+    let file = Arc::new(file);
+
+    let mut parts = JoinSet::new();
+    for start in (0..size).step_by(part_size as usize) {
+        if parts.len() == concurrency {
+            join_part(&mut parts).await?;
+        }
+        let end = (start + part_size).min(size) - 1;
+        let (client, file) = (client.clone(), file.clone());
+        let (bucket, object_key, part_path, etag) = (
+            bucket.to_owned(),
+            object_key.to_owned(),
+            part_path.to_owned(),
+            etag.to_owned(),
+        );
+        let expected_bucket_owner = expected_bucket_owner.map(str::to_owned);
+        parts.spawn(async move {
+            let out = client
+                .get_object()
+                .bucket(&bucket)
+                .key(&object_key)
+                .set_expected_bucket_owner(expected_bucket_owner)
+                .range(format!("bytes={start}-{end}"))
+                .if_match(etag)
+                .send()
+                .await
+                .map_err(|err| sdk_error("GetObject", err))?;
+            note_request_ids("GetObject", &out);
+            let mut body = out.body;
+            let mut offset = start;
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk.map_err(|e| S3Error::new(format!("Collect error: {e:?}")))?;
+                file.write_all_at(&chunk, offset)
+                    .map_err(|e| S3Error::new(format!("Cannot write {part_path}: {e}")))?;
+                offset += chunk.len() as u64;
+            }
+            if offset != end + 1 {
+                return Err(S3Error::new(format!(
+                    "Range {start}-{end} of s3://{bucket}/{object_key} returned {} bytes",
+                    offset - start
+                )));
+            }
+            Ok(())
+        });
+    }
+    while !parts.is_empty() {
+        join_part(&mut parts).await?;
+    }
+    file.sync_data().map_err(io_error)?;
+    std::fs::rename(part_path, dest_path)
+        .map_err(|e| S3Error::new(format!("Cannot rename {part_path} to {dest_path}: {e}")))?;
+    part.0.clear();
+    Ok(Some(size as i64))
+}
+
+/// A partly downloaded file, removed when dropped unless its path was cleared:
+/// on errors, and when the download is cancelled.
+struct PartFile(String);
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.0.is_empty() {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+}
+
+/// Wait for the next range GET of [`download_in_parts`] to finish.
+async fn join_part(parts: &mut JoinSet<Result<(), S3Error>>) -> Result<(), S3Error> {
+    match parts.join_next().await {
+        Some(joined) => joined.map_err(|e| S3Error::new(format!("Download task failed: {e}")))?,
+        None => Ok(()),
+    }
+}
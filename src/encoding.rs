@@ -0,0 +1,141 @@
+use pgrx::pg_sys;
+use std::ffi::{CStr, CString};
+
+/// Decode `data` from the Postgres `encoding` (e.g. "UTF8", "LATIN1") to a string.
+///
+/// With `truncated`, an incomplete multibyte character at the end (as left by
+/// a range read) is dropped instead of being reported as invalid.
+pub(crate) fn decode(data: &[u8], encoding: &str, truncated: bool) -> Result<String, String> {
+    let name = CString::new(encoding).map_err(|_| format!("Invalid encoding {encoding:?}"))?;
+    let len = i32::try_from(data.len()).map_err(|_| "Text too large".to_string())?;
+
+    // SAFETY: `name` and `data` outlive the calls; lengths are passed explicitly.
+    unsafe {
+        let enc = pg_sys::pg_valid_client_encoding(name.as_ptr());
+        if enc < 0 {
+            return Err(format!("Unknown encoding {encoding:?}"));
+        }
+
+        let ptr = data.as_ptr().cast();
+        let valid = pg_sys::pg_encoding_verifymbstr(enc, ptr, len);
+        let partial_tail = truncated
+            && data[valid as usize] != 0
+            && len - valid < pg_sys::pg_encoding_max_length(enc);
+        if valid < len && !partial_tail {
+            return Err(format!("Invalid {encoding} data at byte {valid}"));
+        }
+
+        let converted = pg_sys::pg_any_to_server(ptr, valid, enc);
+        let text = if converted.cast_const() == ptr {
+            String::from_utf8_lossy(&data[..valid as usize]).into_owned()
+        } else {
+            let text = CStr::from_ptr(converted).to_string_lossy().into_owned();
+            pg_sys::pfree(converted.cast());
+            text
+        };
+        Ok(text)
+    }
+}
+
+/// The encoding of text `data` for `encoding => 'auto'`, how it was found, and
+/// `data` without its byte order mark.
+///
+/// A byte order mark wins over the `charset` of `content_type`; without
+/// either, UTF-8 is assumed.
+pub(crate) fn sniff<'a>(
+    data: &'a [u8],
+    content_type: Option<&str>,
+) -> (String, &'static str, &'a [u8]) {
+    const BOMS: [(&[u8], &str); 5] = [
+        (&[0xEF, 0xBB, 0xBF], "UTF8"),
+        (&[0xFF, 0xFE, 0x00, 0x00], "UTF32LE"),
+        (&[0x00, 0x00, 0xFE, 0xFF], "UTF32BE"),
+        (&[0xFF, 0xFE], "UTF16LE"),
+        (&[0xFE, 0xFF], "UTF16BE"),
+    ];
+    if let Some((bom, encoding)) = BOMS.iter().find(|(bom, _)| data.starts_with(bom)) {
+        return (
+            (*encoding).to_owned(),
+            "from the byte order mark",
+            &data[bom.len()..],
+        );
+    }
+    match content_type.and_then(charset) {
+        Some(charset) => (charset, "from the Content-Type charset", data),
+        None => ("UTF8".to_owned(), "by default", data),
+    }
+}
+
+/// The `charset` parameter of a Content-Type, as an encoding name for [`decode_text`].
+fn charset(content_type: &str) -> Option<String> {
+    let value = content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"'))
+    })?;
+    let name: String = value
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_uppercase();
+    Some(match name.as_str() {
+        "" => return None,
+        // ASCII is a subset of UTF-8; Postgres' SQL_ASCII would not check it.
+        "ASCII" | "USASCII" => "UTF8".to_owned(),
+        // Without a byte order mark, UTF-16 and UTF-32 are big-endian.
+        "UTF16" => "UTF16BE".to_owned(),
+        "UTF32" => "UTF32BE".to_owned(),
+        _ => match name.strip_prefix("WINDOWS") {
+            Some(code_page) => format!("WIN{code_page}"),
+            None => value.to_owned(),
+        },
+    })
+}
+
+/// Decode a whole text object from `encoding`: a Postgres encoding, or one of
+/// `UTF16LE`, `UTF16BE`, `UTF32LE` and `UTF32BE`, which Postgres lacks.
+pub(crate) fn decode_text(data: &[u8], encoding: &str) -> Result<String, String> {
+    let wide = encoding.to_ascii_uppercase().replace(['-', '_'], "");
+    let chars: Vec<char> = match wide.as_str() {
+        "UTF16LE" | "UTF16BE" => {
+            if !data.len().is_multiple_of(2) {
+                return Err(format!("odd length {} for UTF-16", data.len()));
+            }
+            let units = data.chunks_exact(2).map(|u| match wide.as_str() {
+                "UTF16LE" => u16::from_le_bytes([u[0], u[1]]),
+                _ => u16::from_be_bytes([u[0], u[1]]),
+            });
+            char::decode_utf16(units)
+                .enumerate()
+                .map(|(i, c)| c.map_err(|_| format!("unpaired surrogate at byte {}", 2 * i)))
+                .collect::<Result<_, _>>()?
+        }
+        "UTF32LE" | "UTF32BE" => {
+            if !data.len().is_multiple_of(4) {
+                return Err(format!(
+                    "length {} is not a multiple of 4 for UTF-32",
+                    data.len()
+                ));
+            }
+            data.chunks_exact(4)
+                .enumerate()
+                .map(|(i, u)| {
+                    let u = [u[0], u[1], u[2], u[3]];
+                    let n = match wide.as_str() {
+                        "UTF32LE" => u32::from_le_bytes(u),
+                        _ => u32::from_be_bytes(u),
+                    };
+                    char::from_u32(n).ok_or_else(|| format!("invalid code point at byte {}", 4 * i))
+                })
+                .collect::<Result<_, _>>()?
+        }
+        _ => return decode(data, encoding, false),
+    };
+    // Converted to the server encoding, and checked for NULs, like any other text.
+    decode(
+        chars.into_iter().collect::<String>().as_bytes(),
+        "UTF8",
+        false,
+    )
+}
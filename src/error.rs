@@ -0,0 +1,247 @@
+use aws_sdk_s3::{
+    config::http::HttpResponse,
+    error::SdkError,
+    operation::{RequestId, RequestIdExt},
+};
+use aws_smithy_types::error::metadata::ProvideErrorMetadata;
+use pgrx::prelude::*;
+use std::{fmt, sync::Mutex};
+
+/// Error returned by an S3 operation before it is raised as a Postgres error.
+#[derive(Debug)]
+pub(crate) struct S3Error {
+    pub message: String,
+    /// S3 error code such as "NoSuchKey", or the kind of failure if S3 did not answer.
+    pub code: Option<String>,
+    /// Region S3 reported for the bucket when answering with a 301 PermanentRedirect.
+    pub bucket_region: Option<String>,
+    /// HTTP status of S3's answer, if there was one.
+    pub status: Option<u16>,
+}
+
+impl S3Error {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            code: None,
+            bucket_region: None,
+            status: None,
+        }
+    }
+
+    /// What kind of failure this is, if known.
+    pub(crate) fn category(&self) -> Option<ErrorCategory> {
+        use ErrorCategory::*;
+
+        let code = self.code.as_deref().unwrap_or_default();
+        Some(match (code, self.status) {
+            ("DispatchFailure" | "Timeout", _) => Network,
+            ("PreconditionFailed", _) | (_, Some(412)) => Precondition,
+            (
+                "AccessDenied"
+                | "AllAccessDisabled"
+                | "ExpiredToken"
+                | "InvalidAccessKeyId"
+                | "InvalidToken"
+                | "SignatureDoesNotMatch"
+                | "TokenRefreshRequired",
+                _,
+            )
+            | (_, Some(401 | 403)) => Auth,
+            ("NoSuchBucket" | "NoSuchKey" | "NoSuchUpload" | "NoSuchVersion" | "NotFound", _)
+            | (_, Some(404)) => NotFound,
+            (
+                "RequestLimitExceeded"
+                | "SlowDown"
+                | "Throttling"
+                | "ThrottlingException"
+                | "TooManyRequests",
+                _,
+            )
+            | (_, Some(429)) => Throttling,
+            ("InternalError" | "ServiceUnavailable", _) | (_, Some(500..=599)) => ServerError,
+            (_, Some(400..=499)) => ClientError,
+            _ => return None,
+        })
+    }
+
+    /// Raise this error as a Postgres error with the SQLSTATE of its category,
+    /// so PL/pgSQL handlers can tell e.g. an unreachable endpoint from bad credentials.
+    pub(crate) fn raise(self) -> ! {
+        match self.category() {
+            Some(category) => {
+                ereport!(
+                    ERROR,
+                    category.sqlstate(),
+                    self.message,
+                    format!("S3 error category: {}", category.name())
+                );
+            }
+            None => pgrx::error!("{self}"),
+        }
+    }
+}
+
+/// Kinds of S3 failures, each raised with its own SQLSTATE.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ErrorCategory {
+    /// No answer: the endpoint is unreachable, or the request timed out.
+    Network,
+    /// Missing, wrong or expired credentials, or a denying policy.
+    Auth,
+    NotFound,
+    Throttling,
+    /// A failed `if_match` precondition.
+    Precondition,
+    /// S3 failed (5xx).
+    ServerError,
+    /// S3 rejected the request (other 4xx).
+    ClientError,
+}
+
+impl ErrorCategory {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Network => "network",
+            Self::Auth => "auth",
+            Self::NotFound => "not_found",
+            Self::Throttling => "throttling",
+            Self::Precondition => "precondition",
+            Self::ServerError => "server_error",
+            Self::ClientError => "client_error",
+        }
+    }
+
+    pub(crate) fn sqlstate(self) -> PgSqlErrorCode {
+        match self {
+            Self::Network => PgSqlErrorCode::ERRCODE_CONNECTION_FAILURE,
+            Self::Auth => PgSqlErrorCode::ERRCODE_INVALID_AUTHORIZATION_SPECIFICATION,
+            Self::NotFound => PgSqlErrorCode::ERRCODE_UNDEFINED_OBJECT,
+            Self::Throttling => PgSqlErrorCode::ERRCODE_INSUFFICIENT_RESOURCES,
+            Self::Precondition => PgSqlErrorCode::ERRCODE_T_R_SERIALIZATION_FAILURE,
+            Self::ServerError => PgSqlErrorCode::ERRCODE_SYSTEM_ERROR,
+            Self::ClientError => PgSqlErrorCode::ERRCODE_EXTERNAL_ROUTINE_EXCEPTION,
+        }
+    }
+}
+
+impl fmt::Display for S3Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<String> for S3Error {
+    fn from(message: String) -> Self {
+        Self::new(message)
+    }
+}
+
+/// Turn an SDK error of operation `op` (e.g. "PutObject") into an [`S3Error`].
+///
+/// The S3 request ids are appended to the message, as AWS support asks for them.
+pub(crate) fn sdk_error<E>(op: &str, err: SdkError<E, HttpResponse>) -> S3Error
+where
+    E: ProvideErrorMetadata + fmt::Debug,
+{
+    let code = match &err {
+        SdkError::DispatchFailure(_) => Some("DispatchFailure"),
+        SdkError::TimeoutError(_) => Some("Timeout"),
+        // HEAD-like answers carry no error body to take the code from.
+        _ if err
+            .raw_response()
+            .is_some_and(|r| r.status().as_u16() == 412) =>
+        {
+            Some(err.code().unwrap_or("PreconditionFailed"))
+        }
+        _ => err.code(),
+    }
+    .map(str::to_owned);
+    let status = err.raw_response().map(|r| r.status().as_u16());
+
+    if let SdkError::DispatchFailure(e) = &err {
+        return S3Error {
+            code,
+            ..S3Error::new(format!("Dispatch failure: {e:?}"))
+        };
+    }
+
+    let ids = request_ids(&err);
+
+    if let Some(region) = redirect_region(&err) {
+        return S3Error {
+            message: format!(
+                "{op} failed: bucket is in {region}, set region accordingly \
+                 (or enable s3_io.follow_region_redirect){ids}"
+            ),
+            code,
+            bucket_region: Some(region),
+            status,
+        };
+    }
+
+    S3Error {
+        code,
+        status,
+        ..S3Error::new(format!("{op} failed: {err:?}{ids}"))
+    }
+}
+
+/// Error for a request S3 refused because the object does not have the
+/// `if_match` ETag, raised as a `serialization_failure` by [`crate::run`].
+pub(crate) fn precondition_failed(bucket: &str, object_key: &str) -> S3Error {
+    S3Error {
+        code: Some("PreconditionFailed".to_owned()),
+        ..S3Error::new(format!(
+            "s3://{bucket}/{object_key} does not have the expected ETag"
+        ))
+    }
+}
+
+/// Format `x-amz-request-id` and `x-amz-id-2` as a message suffix, or "" if absent.
+pub(crate) fn request_ids<R: RequestId + RequestIdExt>(r: &R) -> String {
+    match (r.request_id(), r.extended_request_id()) {
+        (None, None) => String::new(),
+        (id, id2) => format!(
+            " (request id: {}, id-2: {})",
+            id.unwrap_or("-"),
+            id2.unwrap_or("-")
+        ),
+    }
+}
+
+// Request ids of successful responses, logged by the caller once the
+// operation is back on the backend thread.
+static SUCCESS_IDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Remember the request ids of a successful `op` response for [`take_request_ids`].
+pub(crate) fn note_request_ids<R: RequestId + RequestIdExt>(op: &str, r: &R) {
+    let ids = request_ids(r);
+    if !ids.is_empty() {
+        SUCCESS_IDS
+            .lock()
+            .unwrap()
+            .push(format!("{op} succeeded{ids}"));
+    }
+}
+
+/// Drain the request ids noted since the last call.
+pub(crate) fn take_request_ids() -> Vec<String> {
+    std::mem::take(&mut *SUCCESS_IDS.lock().unwrap())
+}
+
+/// Region of the bucket if `err` is a PermanentRedirect to another region.
+fn redirect_region<E>(err: &SdkError<E, HttpResponse>) -> Option<String>
+where
+    E: ProvideErrorMetadata,
+{
+    let raw = err.raw_response()?;
+    let is_redirect = raw.status().as_u16() == 301 || err.code() == Some("PermanentRedirect");
+    if !is_redirect {
+        return None;
+    }
+    raw.headers()
+        .get("x-amz-bucket-region")
+        .filter(|r| !r.is_empty())
+        .map(str::to_owned)
+}
@@ -0,0 +1,319 @@
+use pgrx::guc::{GucContext, GucFlags, GucRegistry, GucSetting};
+use pgrx::PostgresGucEnum;
+use std::ffi::CString;
+
+/// Kind of Tokio runtime each backend runs S3 requests on.
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum RuntimeFlavor {
+    /// Everything on the backend's own thread.
+    #[name = c"current_thread"]
+    CurrentThread,
+    /// A pool of worker threads, so concurrent requests are also processed in parallel.
+    #[name = c"multi_thread"]
+    MultiThread,
+}
+
+/// Retry strategy of the SDK.
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum RetryMode {
+    /// Exponential backoff with jitter.
+    #[name = c"standard"]
+    Standard,
+    /// Standard retries, plus a client-side rate limit that kicks in on throttling.
+    #[name = c"adaptive"]
+    Adaptive,
+}
+
+impl From<RetryMode> for aws_sdk_s3::config::retry::RetryMode {
+    fn from(mode: RetryMode) -> Self {
+        match mode {
+            RetryMode::Standard => Self::Standard,
+            RetryMode::Adaptive => Self::Adaptive,
+        }
+    }
+}
+
+pub(crate) static FOLLOW_REGION_REDIRECT: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub(crate) static LOG_REQUEST_IDS: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub(crate) static RETRY_NOTICES: GucSetting<bool> = GucSetting::<bool>::new(true);
+pub(crate) static REQUIRE_REGION: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub(crate) static FORCE_PATH_STYLE: GucSetting<bool> = GucSetting::<bool>::new(true);
+pub(crate) static USER_AGENT_SUFFIX: GucSetting<Option<CString>> =
+    GucSetting::<Option<CString>>::new(None);
+pub(crate) static SIGNING_NAME: GucSetting<Option<CString>> =
+    GucSetting::<Option<CString>>::new(None);
+pub(crate) static DOWNLOAD_PATHS: GucSetting<Option<CString>> =
+    GucSetting::<Option<CString>>::new(None);
+pub(crate) static UPLOAD_PATHS: GucSetting<Option<CString>> =
+    GucSetting::<Option<CString>>::new(None);
+pub(crate) static DEFAULT_BUCKET: GucSetting<Option<CString>> =
+    GucSetting::<Option<CString>>::new(None);
+pub(crate) static AUDIT_TABLE: GucSetting<Option<CString>> =
+    GucSetting::<Option<CString>>::new(None);
+pub(crate) static LIST_MAX_PAGES: GucSetting<i32> = GucSetting::<i32>::new(0);
+pub(crate) static BULK_CONCURRENCY: GucSetting<i32> = GucSetting::<i32>::new(16);
+pub(crate) static DOWNLOAD_CONCURRENCY: GucSetting<i32> = GucSetting::<i32>::new(1);
+pub(crate) static DOWNLOAD_PART_SIZE: GucSetting<i32> = GucSetting::<i32>::new(16 * 1024);
+pub(crate) static CLIENT_TTL: GucSetting<i32> = GucSetting::<i32>::new(3600);
+pub(crate) static CLIENT_CACHE_SIZE: GucSetting<i32> = GucSetting::<i32>::new(32);
+pub(crate) static STALL_TIMEOUT: GucSetting<i32> = GucSetting::<i32>::new(0);
+pub(crate) static POOL_IDLE_TIMEOUT: GucSetting<i32> = GucSetting::<i32>::new(90);
+pub(crate) static POOL_MAX_IDLE_PER_HOST: GucSetting<i32> = GucSetting::<i32>::new(-1);
+pub(crate) static RUNTIME_FLAVOR: GucSetting<RuntimeFlavor> =
+    GucSetting::<RuntimeFlavor>::new(RuntimeFlavor::CurrentThread);
+pub(crate) static RETRY_MODE: GucSetting<RetryMode> =
+    GucSetting::<RetryMode>::new(RetryMode::Standard);
+pub(crate) static RUNTIME_THREADS: GucSetting<i32> = GucSetting::<i32>::new(0);
+
+pub(crate) fn init() {
+    GucRegistry::define_bool_guc(
+        c"s3_io.follow_region_redirect",
+        c"Retry a request in the bucket's region after a PermanentRedirect.",
+        c"When S3 answers with 301 PermanentRedirect, repeat the request once \
+          against the region reported in the x-amz-bucket-region header.",
+        &FOLLOW_REGION_REDIRECT,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_bool_guc(
+        c"s3_io.log_request_ids",
+        c"Log S3 request ids of successful operations at DEBUG1.",
+        c"S3 request ids (x-amz-request-id and x-amz-id-2) are always part of error \
+          messages; this also logs them for successful operations.",
+        &LOG_REQUEST_IDS,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_bool_guc(
+        c"s3_io.retry_notices",
+        c"Report retried S3 requests as NOTICE rather than DEBUG1.",
+        c"Each retry is reported with its attempt number, the error that caused it and \
+          the backoff delay, at most once per second; retries in between are counted.",
+        &RETRY_NOTICES,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_bool_guc(
+        c"s3_io.require_region",
+        c"Reject S3 calls without a region argument instead of assuming us-east-1.",
+        c"Providers that validate the signing region reject requests signed for the \
+          wrong one; this catches a missing region before anything is sent.",
+        &REQUIRE_REGION,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_bool_guc(
+        c"s3_io.force_path_style",
+        c"Address buckets path-style (endpoint/bucket/key) by default.",
+        c"Turn off for virtual-hosted-style addressing (bucket.endpoint/key). Functions \
+          taking a force_path_style argument override this per call.",
+        &FORCE_PATH_STYLE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        c"s3_io.client_ttl",
+        c"Age after which a cached S3 client is rebuilt.",
+        c"Clients are cached per endpoint and credentials for the lifetime of the backend; \
+          older ones are dropped and rebuilt on next use. 0 keeps them forever.",
+        &CLIENT_TTL,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_S,
+    );
+
+    GucRegistry::define_int_guc(
+        c"s3_io.client_cache_size",
+        c"Maximum number of cached S3 clients per backend.",
+        c"When exceeded, the least recently used client is dropped. 0 means no limit.",
+        &CLIENT_CACHE_SIZE,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        c"s3_io.stall_timeout",
+        c"Give up on S3 requests that send or receive no data for this long.",
+        c"Unlike an overall timeout, long transfers are fine as long as data keeps \
+          moving. Waiting for S3 to answer counts as stalled too. 0 disables it.",
+        &STALL_TIMEOUT,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_S,
+    );
+
+    GucRegistry::define_enum_guc(
+        c"s3_io.retry_mode",
+        c"Retry strategy of S3 clients: standard or adaptive.",
+        c"adaptive also rate-limits requests on the client once S3 throttles them, which \
+          eases throttling during sustained bulk loads at the cost of added latency.",
+        &RETRY_MODE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        c"s3_io.pool_idle_timeout",
+        c"How long an idle HTTP connection is kept open for reuse.",
+        c"Each cached client keeps a pool of keep-alive connections, so later calls \
+          skip the TCP and TLS handshakes. 0 keeps idle connections until the server \
+          closes them.",
+        &POOL_IDLE_TIMEOUT,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_S,
+    );
+
+    GucRegistry::define_int_guc(
+        c"s3_io.pool_max_idle_per_host",
+        c"Maximum number of idle HTTP connections kept per host and client.",
+        c"-1 means no limit, 0 opens a new connection for every request.",
+        &POOL_MAX_IDLE_PER_HOST,
+        -1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        c"s3_io.user_agent_suffix",
+        c"Application name appended to the User-Agent of S3 requests.",
+        c"Sent as app/<name>, to tell this extension's traffic apart in S3 access logs. \
+          Letters, digits and !#$%&'*+-.^_`|~ only.",
+        &USER_AGENT_SUFFIX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        c"s3_io.signing_name",
+        c"Service name S3 requests are signed for, instead of s3.",
+        c"For gateways in front of S3 that verify a different SigV4 service name, \
+          e.g. execute-api for API Gateway. Empty signs for s3.",
+        &SIGNING_NAME,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        c"s3_io.bulk_concurrency",
+        c"Maximum number of concurrent requests of the bulk functions.",
+        c"Applies to s3_put_objects, s3_get_objects, s3_head_objects, s3_copy_objects \
+          and the HeadObjects of s3_list_objects with fetch_metadata.",
+        &BULK_CONCURRENCY,
+        1,
+        1024,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        c"s3_io.download_concurrency",
+        c"Maximum number of concurrent range GETs of s3_get_object_to_file.",
+        c"Objects larger than s3_io.download_part_size are downloaded in parts of that \
+          size, this many at a time. 1 downloads every object in one stream.",
+        &DOWNLOAD_CONCURRENCY,
+        1,
+        1024,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        c"s3_io.download_part_size",
+        c"Size of the range GETs of a parallel s3_get_object_to_file.",
+        c"Only used when s3_io.download_concurrency is above 1. Objects no larger than \
+          this are downloaded in one stream.",
+        &DOWNLOAD_PART_SIZE,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::UNIT_KB,
+    );
+
+    GucRegistry::define_int_guc(
+        c"s3_io.list_max_pages",
+        c"Default maximum number of ListObjectsV2 pages a listing function fetches.",
+        c"Each page holds up to 1000 keys. When a listing stops at the limit, a WARNING \
+          says its result is incomplete. 0 means no limit.",
+        &LIST_MAX_PAGES,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_enum_guc(
+        c"s3_io.runtime_flavor",
+        c"Tokio runtime that runs S3 requests: current_thread or multi_thread.",
+        c"Read when a backend makes its first S3 call; changing it later has no effect \
+          until the next connection.",
+        &RUNTIME_FLAVOR,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        c"s3_io.runtime_threads",
+        c"Worker threads of a multi_thread runtime.",
+        c"0 uses one per CPU core. Ignored by the current_thread runtime, and read when a \
+          backend makes its first S3 call.",
+        &RUNTIME_THREADS,
+        0,
+        1024,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        c"s3_io.download_paths",
+        c"Comma-separated directories s3_download_file and s3_get_object_to_file may write into.",
+        c"Files can only be downloaded into these directories or below them. Empty \
+          allows none.",
+        &DOWNLOAD_PATHS,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        c"s3_io.upload_paths",
+        c"Comma-separated directories s3_put_object_from_file may read from.",
+        c"Only files in these directories or below them can be uploaded. Empty \
+          allows none.",
+        &UPLOAD_PATHS,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        c"s3_io.default_bucket",
+        c"Bucket of object functions called with a NULL bucket.",
+        c"Used by every function on objects or prefixes, including the bulk and copy \
+          functions, but not by the bucket functions such as s3_create_bucket. Empty \
+          means a bucket must be passed.",
+        &DEFAULT_BUCKET,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        c"s3_io.audit_table",
+        c"Table every S3 operation is logged to.",
+        c"Each operation adds a row (timestamp, operation, bucket, key, bytes, \
+          duration_ms, result, error_code). Failing to write it only raises a WARNING. \
+          Empty disables auditing.",
+        &AUDIT_TABLE,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+}
@@ -0,0 +1,137 @@
+use pgrx::prelude::*;
+
+use aws_sdk_s3::types::{ObjectLockLegalHold, ObjectLockLegalHoldStatus};
+use aws_smithy_types::error::metadata::ProvideErrorMetadata;
+
+use crate::{
+    error::{note_request_ids, sdk_error, S3Error},
+    run, uri, ClientArgs,
+};
+
+/// Place (`hold` true) or remove a legal hold on an object, or one of its versions.
+///
+/// A legal hold keeps the object version from being deleted or overwritten
+/// until it is removed, independent of any retention period. The bucket must
+/// have object lock enabled.
+#[pg_extern]
+pub(crate) fn s3_put_object_legal_hold(
+    bucket: Option<&str>,
+    object_key: &str,
+    hold: bool,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    version_id: default!(Option<&str>, "NULL"),
+) -> bool {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let status = match hold {
+        true => ObjectLockLegalHoldStatus::On,
+        false => ObjectLockLegalHoldStatus::Off,
+    };
+
+    run(&args, |client| {
+        let status = status.clone();
+        async move {
+            let out = client
+                .put_object_legal_hold()
+                .bucket(bucket)
+                .key(object_key)
+                .set_version_id(version_id.map(str::to_owned))
+                .legal_hold(ObjectLockLegalHold::builder().status(status).build())
+                .send()
+                .await
+                .map_err(|err| lock_error("PutObjectLegalHold", bucket, err))?;
+            note_request_ids("PutObjectLegalHold", &out);
+            Ok(true)
+        }
+    })
+}
+
+/// Whether an object, or one of its versions, is under a legal hold.
+#[pg_extern]
+pub(crate) fn s3_get_object_legal_hold(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    version_id: default!(Option<&str>, "NULL"),
+) -> bool {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+
+    run(&args, |client| async move {
+        let result = client
+            .get_object_legal_hold()
+            .bucket(bucket)
+            .key(object_key)
+            .set_version_id(version_id.map(str::to_owned))
+            .send()
+            .await;
+        match result {
+            Ok(out) => {
+                note_request_ids("GetObjectLegalHold", &out);
+                let status = out.legal_hold().and_then(|h| h.status());
+                Ok(status == Some(&ObjectLockLegalHoldStatus::On))
+            }
+            // Never held in an object lock bucket.
+            Err(err) if err.code() == Some("NoSuchObjectLockConfiguration") => Ok(false),
+            Err(err) => Err(lock_error("GetObjectLegalHold", bucket, err)),
+        }
+    })
+}
+
+/// Like [`sdk_error`], but explain errors caused by a bucket without object lock.
+fn lock_error<E>(
+    op: &str,
+    bucket: &str,
+    err: aws_sdk_s3::error::SdkError<E, aws_sdk_s3::config::http::HttpResponse>,
+) -> S3Error
+where
+    E: ProvideErrorMetadata + std::fmt::Debug,
+{
+    let missing = lock_missing(err.code(), err.message());
+    let err = sdk_error(op, err);
+    if !missing {
+        return err;
+    }
+    S3Error {
+        message: format!("{op} failed: bucket {bucket} does not have object lock enabled"),
+        ..err
+    }
+}
+
+/// Whether S3 rejected a request because the bucket has no object lock configuration.
+///
+/// AWS answers "Bucket is missing Object Lock Configuration", MinIO
+/// "Bucket is missing ObjectLockConfiguration", both as InvalidRequest.
+pub(crate) fn lock_missing(code: Option<&str>, message: Option<&str>) -> bool {
+    let message = message
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+        .replace(' ', "");
+    code == Some("InvalidRequest") && message.contains("missingobjectlock")
+}
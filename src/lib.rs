@@ -1,66 +1,533 @@
+// SQL functions take connection args positionally and spell out their result columns.
+#![allow(clippy::too_many_arguments, clippy::type_complexity)]
+
 use pgrx::prelude::*;
 use std::{
     collections::HashMap,
+    future::Future,
     sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
 };
 
+use aws_sdk_s3::types::ObjectCannedAcl;
+use compression::{Codec, Decompress, CODEC_METADATA_KEY};
+use error::{note_request_ids, precondition_failed, sdk_error, S3Error};
+
+mod append;
+mod arn;
+mod audit;
+mod bucket;
+mod bulk;
+mod client_config;
+mod compression;
+mod credentials;
+mod csv_export;
+mod csv_gz;
+mod diagnostics;
+mod download;
+mod encoding;
+mod error;
+mod guc;
+mod legal_hold;
+mod lines;
+mod list;
+mod multipart;
+mod parquet_export;
+mod parquet_read;
+mod presign;
+mod retries;
+mod row_json;
+mod select;
+mod signing;
+mod sse;
+mod stall;
+mod try_ops;
+mod uri;
+
 ::pgrx::pg_module_magic!(name, version);
 
-// One Tokio runtime per backend (session), built lazily.
+#[pg_guard]
+pub extern "C-unwind" fn _PG_init() {
+    guc::init();
+}
+
+/// Version of this extension and of the AWS SDK it was built with,
+/// e.g. `s3_io 0.1.0 (aws-sdk-s3 1.152.0)`.
+#[pg_extern(stable, parallel_safe)]
+fn s3_io_version() -> String {
+    format!(
+        "s3_io {} (aws-sdk-s3 {})",
+        env!("CARGO_PKG_VERSION"),
+        aws_sdk_s3::meta::PKG_VERSION
+    )
+}
+
+// One Tokio runtime per backend (session), built lazily as configured by
+// `s3_io.runtime_flavor` and `s3_io.runtime_threads` at that point.
 fn rt() -> &'static tokio::runtime::Runtime {
     static RT: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
     RT.get_or_init(|| {
-        tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .expect("tokio runtime")
+        let mut builder = match guc::RUNTIME_FLAVOR.get() {
+            guc::RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+            guc::RuntimeFlavor::MultiThread => {
+                let mut builder = tokio::runtime::Builder::new_multi_thread();
+                if let threads @ 1.. = guc::RUNTIME_THREADS.get() {
+                    builder.worker_threads(threads as usize);
+                }
+                builder
+            }
+        };
+        builder.enable_all().build().expect("tokio runtime")
     })
 }
 
+/// Connection arguments shared by all S3 functions.
+#[derive(Clone, Copy, Default)]
+struct ClientArgs<'a> {
+    endpoint_url: Option<&'a str>,
+    access_key: Option<&'a str>,
+    secret_key: Option<&'a str>,
+    session_token: Option<&'a str>,
+    region: Option<&'a str>,
+    /// Overrides `s3_io.force_path_style`.
+    force_path_style: Option<bool>,
+}
+
+/// Run `op` with the client for `args` and raise a Postgres error on failure.
+///
+/// If the bucket lives in another region and `s3_io.follow_region_redirect`
+/// is enabled, the operation is retried once in the reported region.
+fn run<T, F, Fut>(args: &ClientArgs, op: F) -> T
+where
+    F: Fn(aws_sdk_s3::Client) -> Fut,
+    Fut: Future<Output = Result<T, S3Error>>,
+{
+    try_run(args, op).unwrap_or_else(|e| e.raise())
+}
+
+/// Like [`run`], but with an unsigned client ignoring all credentials if `anonymous`.
+fn run_as<T, F, Fut>(args: &ClientArgs, anonymous: bool, op: F) -> T
+where
+    F: Fn(aws_sdk_s3::Client) -> Fut,
+    Fut: Future<Output = Result<T, S3Error>>,
+{
+    try_run_as(args, anonymous, op).unwrap_or_else(|e| e.raise())
+}
+
+/// `etag` in the quoted form S3 compares `If-Match` headers with.
+fn quote_etag(etag: &str) -> String {
+    format!("\"{}\"", etag.trim_matches('"'))
+}
+
+/// Like [`run`], but return the error instead of raising it.
+fn try_run<T, F, Fut>(args: &ClientArgs, op: F) -> Result<T, S3Error>
+where
+    F: Fn(aws_sdk_s3::Client) -> Fut,
+    Fut: Future<Output = Result<T, S3Error>>,
+{
+    try_run_as(args, false, op)
+}
+
+fn try_run_as<T, F, Fut>(args: &ClientArgs, anonymous: bool, op: F) -> Result<T, S3Error>
+where
+    F: Fn(aws_sdk_s3::Client) -> Fut,
+    Fut: Future<Output = Result<T, S3Error>>,
+{
+    let err = match block_on(op(client_for(args, anonymous))) {
+        Ok(v) => return Ok(v),
+        Err(e) => e,
+    };
+
+    match err.bucket_region.as_deref() {
+        Some(region) if guc::FOLLOW_REGION_REDIRECT.get() && args.region != Some(region) => {
+            let endpoint = args.endpoint_url.map(|ep| regional_endpoint(ep, region));
+            let redirected = ClientArgs {
+                endpoint_url: endpoint.as_deref(),
+                region: Some(region),
+                ..*args
+            };
+            block_on(op(client_for(&redirected, anonymous)))
+        }
+        _ => Err(err),
+    }
+}
+
+/// How long `block_on` drives a future before checking for query cancellation.
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Block on an S3 future, then log the request ids of its successful calls.
+///
+/// The future is driven in short slices with `CHECK_FOR_INTERRUPTS` in between,
+/// so a hung endpoint can be cancelled (Ctrl-C, `statement_timeout`), or given
+/// up on once no data moved for `s3_io.stall_timeout`. On cancellation the
+/// future is dropped, which aborts the in-flight request.
+fn block_on<Fut: Future>(fut: Fut) -> Fut::Output {
+    let deadline = statement_deadline();
+    let stall_limit = stall::limit();
+    stall::reset();
+    audit::arm();
+    let mut fut = std::pin::pin!(fut);
+    let out = loop {
+        let slice = match deadline {
+            Some(d) => INTERRUPT_POLL_INTERVAL.min(d.saturating_duration_since(Instant::now())),
+            None => INTERRUPT_POLL_INTERVAL,
+        };
+        match rt().block_on(tokio::time::timeout(slice, fut.as_mut())) {
+            Ok(out) => break out,
+            Err(_) => {
+                retries::log_retries();
+                check_for_interrupts!();
+                // The timeout signal may not have been handled yet; don't wait for it.
+                if deadline.is_some_and(|d| Instant::now() >= d) {
+                    ereport!(
+                        ERROR,
+                        PgSqlErrorCode::ERRCODE_QUERY_CANCELED,
+                        "canceling statement due to statement timeout"
+                    );
+                }
+                if let Some(limit) = stall_limit.filter(|l| stall::idle() >= *l) {
+                    let mut err = S3Error::new(format!(
+                        "S3 transfer stalled: no data sent or received for {} s (s3_io.stall_timeout)",
+                        limit.as_secs()
+                    ));
+                    err.code = Some("Timeout".to_owned());
+                    err.raise();
+                }
+            }
+        }
+    };
+    retries::log_retries();
+    let ids = error::take_request_ids();
+    if guc::LOG_REQUEST_IDS.get() {
+        for line in ids {
+            debug1!("{line}");
+        }
+    }
+    audit::flush();
+    out
+}
+
+/// When the running statement hits `statement_timeout`, if it is set.
+fn statement_deadline() -> Option<Instant> {
+    // SAFETY: plain reads of backend globals on the backend thread.
+    let (timeout_ms, started) = unsafe {
+        (
+            pg_sys::StatementTimeout,
+            pg_sys::GetCurrentStatementStartTimestamp(),
+        )
+    };
+    if timeout_ms <= 0 {
+        return None;
+    }
+    let elapsed_us = unsafe { pg_sys::GetCurrentTimestamp() } - started;
+    let remaining_us = i64::from(timeout_ms) * 1000 - elapsed_us;
+    Some(Instant::now() + Duration::from_micros(remaining_us.max(0) as u64))
+}
+
+/// Point an AWS S3 endpoint at `region`; other endpoints are returned as-is.
+fn regional_endpoint(endpoint_url: &str, region: &str) -> String {
+    let ep = normalize_endpoint(endpoint_url).unwrap_or_else(|e| pgrx::error!("{e}"));
+    let host = ep.split("://").nth(1).unwrap_or_default();
+    let host = host.split(['/', ':']).next().unwrap_or_default();
+    if host == "s3.amazonaws.com" || (host.starts_with("s3.") && host.ends_with(".amazonaws.com")) {
+        format!("https://s3.{region}.amazonaws.com")
+    } else {
+        ep
+    }
+}
+
 #[pg_extern]
 fn s3_object_exists_lazy(
-    bucket: &str,
-    object_key: &str,
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
     endpoint_url: default!(Option<&str>, "NULL"),
     access_key: default!(Option<&str>, "NULL"),
     secret_key: default!(Option<&str>, "NULL"),
     session_token: default!(Option<&str>, "NULL"),
     region: default!(Option<&str>, "NULL"), // used ONLY on the first call in this backend
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    anonymous: default!(bool, "false"),
 ) -> bool {
-    let client = get_or_init_client(endpoint_url, access_key, secret_key, session_token, region);
-    let fut = async move {
-        match client
-            .head_object()
-            .bucket(bucket)
-            .key(object_key)
-            .send()
-            .await
-        {
-            Ok(_) => Ok(true),
-            Err(err) => {
-                use aws_smithy_types::error::metadata::ProvideErrorMetadata;
-                let code = err.code().unwrap_or_default();
-                if matches!(code, "NotFound" | "NoSuchKey" | "404")
-                    || err.to_string().contains("NotFound")
-                    || err.to_string().contains("NoSuchKey")
-                    || err.to_string().contains("404")
-                {
-                    Ok(false)
-                } else if code == "AccessDenied" {
-                    Err(format!(
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    run_as(&args, anonymous, |client| async move {
+        Ok(
+            head_object(&client, bucket, object_key, expected_bucket_owner)
+                .await?
+                .is_some(),
+        )
+    })
+}
+
+/// Whether the object exists and is at least `min_size` bytes, e.g. to wait for
+/// the real upload behind a zero-byte placeholder; false for missing objects.
+#[pg_extern]
+fn s3_object_ready(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    min_size: default!(i64, "1"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> bool {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    if min_size < 0 {
+        pgrx::error!("min_size must not be negative, got {min_size}");
+    }
+
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    run(&args, |client| async move {
+        let head = head_object(&client, bucket, object_key, expected_bucket_owner).await?;
+        Ok(head.is_some_and(|head| head.content_length().unwrap_or_default() >= min_size))
+    })
+}
+
+#[pg_extern]
+fn s3_head_object_version(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    sse_customer_key: default!(Option<Vec<u8>>, "NULL"),
+    sse_customer_algorithm: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(version_id, Option<String>),
+        name!(content_length, Option<i64>),
+        name!(content_type, Option<String>),
+        name!(etag, Option<String>),
+        name!(last_modified, Option<TimestampWithTimeZone>),
+        name!(expiry_date, Option<TimestampWithTimeZone>),
+        name!(expiration_rule_id, Option<String>),
+        name!(website_redirect_location, Option<String>),
+        name!(expires, Option<TimestampWithTimeZone>),
+        name!(replication_status, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+
+    let customer_key = sse::CustomerKey::new(sse_customer_key.as_deref(), sse_customer_algorithm)
+        .unwrap_or_else(|e| pgrx::error!("{e}"));
+    let customer_key = customer_key.as_ref();
+
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let head = run(&args, |client| async move {
+        head_object_with(
+            &client,
+            bucket,
+            object_key,
+            expected_bucket_owner,
+            customer_key,
+        )
+        .await
+    });
+
+    // No row when the object does not exist.
+    TableIterator::new(head.map(|head| {
+        let (expiry_date, expiration_rule_id) = parse_expiration(head.expiration());
+        (
+            head.version_id().map(str::to_owned),
+            head.content_length(),
+            head.content_type().map(str::to_owned),
+            head.e_tag().map(|e| e.trim_matches('"').to_owned()),
+            head.last_modified().and_then(to_timestamptz),
+            expiry_date,
+            expiration_rule_id,
+            head.website_redirect_location().map(str::to_owned),
+            head.expires_string().and_then(parse_http_date),
+            head.replication_status().map(|s| s.as_str().to_owned()),
+        )
+    }))
+}
+
+/// Restore state of an archived object (Glacier, Deep Archive).
+///
+/// `expiry` is set once the restored copy can be read. No row is returned if
+/// the object does not exist or is not archived.
+#[pg_extern]
+fn s3_restore_status(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(ongoing, bool),
+        name!(expiry, Option<TimestampWithTimeZone>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+
+    let head = run(&args, |client| async move {
+        head_object(&client, bucket, object_key, expected_bucket_owner).await
+    });
+
+    TableIterator::new(head.and_then(|head| {
+        use aws_sdk_s3::types::StorageClass;
+        let archived = matches!(
+            head.storage_class(),
+            Some(StorageClass::Glacier | StorageClass::DeepArchive)
+        );
+        match head.restore() {
+            Some(header) => Some(parse_restore(header)),
+            None if archived => Some((false, None)),
+            None => None,
+        }
+    }))
+}
+
+/// Parse `x-amz-restore`, e.g. `ongoing-request="false", expiry-date="Fri, 21 Dec 2012 00:00:00 GMT"`.
+fn parse_restore(header: &str) -> (bool, Option<TimestampWithTimeZone>) {
+    let ongoing = header_field(header, "ongoing-request") == Some("true");
+    let expiry = header_field(header, "expiry-date").and_then(parse_http_date);
+    (ongoing, expiry)
+}
+
+/// Parse `x-amz-expiration`, e.g. `expiry-date="Fri, 23 Dec 2012 00:00:00 GMT", rule-id="tmp"`,
+/// into the lifecycle expiry date and rule id.
+pub(crate) fn parse_expiration(
+    header: Option<&str>,
+) -> (Option<TimestampWithTimeZone>, Option<String>) {
+    let Some(header) = header else {
+        return (None, None);
+    };
+    (
+        header_field(header, "expiry-date").and_then(parse_http_date),
+        header_field(header, "rule-id").map(str::to_owned),
+    )
+}
+
+/// Value of `name="value"` in a header of comma separated fields.
+fn header_field<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    let start = header.find(&format!("{name}=\""))? + name.len() + 2;
+    let len = header[start..].find('"')?;
+    Some(&header[start..start + len])
+}
+
+/// `ts` to the second, for a header sent as an HTTP date (RFC 7231), e.g.
+/// `Fri, 23 Dec 2012 00:00:00 GMT`.
+fn http_date(ts: TimestampWithTimeZone) -> Result<aws_smithy_types::DateTime, String> {
+    if ts.is_infinity() || ts.is_neg_infinity() {
+        return Err("expires must be a finite timestamp".to_owned());
+    }
+    let micros = ts.into_inner();
+    let secs = micros.div_euclid(1_000_000) + PG_EPOCH_OFFSET_SECS;
+    let date = aws_smithy_types::DateTime::from_secs(secs);
+    date.fmt(aws_smithy_types::date_time::Format::HttpDate)
+        .map_err(|e| format!("expires cannot be sent as an HTTP date: {e}"))?;
+    Ok(date)
+}
+
+fn parse_http_date(date: &str) -> Option<TimestampWithTimeZone> {
+    let date =
+        aws_smithy_types::DateTime::from_str(date, aws_smithy_types::date_time::Format::HttpDate)
+            .ok()?;
+    to_timestamptz(&date)
+}
+
+/// HeadObject, returning `None` if the object does not exist.
+async fn head_object(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    expected_bucket_owner: Option<&str>,
+) -> Result<Option<aws_sdk_s3::operation::head_object::HeadObjectOutput>, S3Error> {
+    head_object_with(client, bucket, object_key, expected_bucket_owner, None).await
+}
+
+/// [`head_object`] of an object that may be encrypted with `customer_key` (SSE-C).
+async fn head_object_with(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    expected_bucket_owner: Option<&str>,
+    customer_key: Option<&sse::CustomerKey>,
+) -> Result<Option<aws_sdk_s3::operation::head_object::HeadObjectOutput>, S3Error> {
+    let mut req = client
+        .head_object()
+        .bucket(bucket)
+        .key(object_key)
+        .set_expected_bucket_owner(expected_bucket_owner.map(str::to_owned));
+    if let Some(customer_key) = customer_key {
+        req = customer_key.head(req);
+    }
+    match req.send().await {
+        Ok(out) => {
+            note_request_ids("HeadObject", &out);
+            Ok(Some(out))
+        }
+        Err(err) => {
+            use aws_smithy_types::error::metadata::ProvideErrorMetadata;
+            let code = err.code().unwrap_or_default();
+            if matches!(code, "NotFound" | "NoSuchKey" | "404")
+                || err.to_string().contains("NotFound")
+                || err.to_string().contains("NoSuchKey")
+                || err.to_string().contains("404")
+            {
+                Ok(None)
+            } else if code == "AccessDenied" {
+                Err(S3Error {
+                    code: Some(code.to_owned()),
+                    ..S3Error::new(format!(
                         "AccessDenied for s3://{}/{} (check credentials/policy)",
                         bucket, object_key
                     ))
-                } else {
-                    Err(format!("S3 HeadObject error: {}", err))
-                }
+                })
+            } else {
+                Err(sdk_error("HeadObject", err))
             }
         }
-    };
-
-    match rt().block_on(fut) {
-        Ok(b) => b,
-        Err(e) => pgrx::error!("{e}"),
     }
 }
 
@@ -72,28 +539,111 @@ fn s3_create_bucket(
     secret_key: default!(Option<&str>, "NULL"),
     session_token: default!(Option<&str>, "NULL"),
     region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
 ) -> bool {
-    let client = get_or_init_client(endpoint_url, access_key, secret_key, session_token, region);
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
 
-    let fut = async move {
-        match client.create_bucket().bucket(bucket).send().await {
-            Ok(_) => Ok(true),
-            Err(aws_sdk_s3::error::SdkError::DispatchFailure(e)) => {
-                Err(format!("Dispatch failure: {e:?}"))
+    let configuration = bucket_configuration(region.unwrap_or("us-east-1"));
+
+    run(&args, |client| {
+        let req = client
+            .create_bucket()
+            .bucket(bucket)
+            .set_create_bucket_configuration(configuration.clone());
+        async move {
+            match req.send().await {
+                Ok(out) => {
+                    note_request_ids("CreateBucket", &out);
+                    Ok(true)
+                }
+                Err(err) => Err(sdk_error("CreateBucket", err)),
             }
-            Err(other) => Err(format!("CreateBucket failed: {other:?}")),
         }
-    };
+    })
+}
+
+/// The CreateBucket configuration for `region`; outside us-east-1, AWS needs
+/// the region as location constraint.
+fn bucket_configuration(region: &str) -> Option<aws_sdk_s3::types::CreateBucketConfiguration> {
+    location_constraint(region).map(|constraint| {
+        aws_sdk_s3::types::CreateBucketConfiguration::builder()
+            .location_constraint(constraint)
+            .build()
+    })
+}
 
-    match rt().block_on(fut) {
-        Ok(v) => v,
-        Err(e) => pgrx::error!("{e}"),
+/// Create `bucket` in the client's region unless it exists, for
+/// `ensure_bucket`.
+///
+/// A concurrent creation by the same account counts as success: S3 answers
+/// `BucketAlreadyOwnedByYou`, or `OperationAborted` while it is in progress, in
+/// which case the bucket is looked up again.
+async fn ensure_bucket(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    expected_bucket_owner: Option<&str>,
+) -> Result<(), S3Error> {
+    match head_bucket(client, bucket, expected_bucket_owner).await {
+        Err(e) if e.status == Some(404) => {}
+        found => return found,
+    }
+    let region = client.config().region().map_or("us-east-1", |r| r.as_ref());
+    let created = client
+        .create_bucket()
+        .bucket(bucket)
+        .set_create_bucket_configuration(bucket_configuration(region))
+        .send()
+        .await;
+    match created {
+        Ok(out) => {
+            note_request_ids("CreateBucket", &out);
+            Ok(())
+        }
+        Err(err) => match sdk_error("CreateBucket", err) {
+            e if e.code.as_deref() == Some("BucketAlreadyOwnedByYou") => Ok(()),
+            e if e.code.as_deref() == Some("OperationAborted") => {
+                head_bucket(client, bucket, expected_bucket_owner)
+                    .await
+                    .map_err(|_| e)
+            }
+            e => Err(e),
+        },
     }
 }
 
+/// The CreateBucket location constraint for `region`; us-east-1 takes none,
+/// and neither does `auto`, which lets providers like R2 choose.
+fn location_constraint(region: &str) -> Option<aws_sdk_s3::types::BucketLocationConstraint> {
+    (!matches!(region, "us-east-1" | "auto")).then(|| region.into())
+}
+
+/// Upload `data` and return the object's ETag.
+///
+/// With `dry_run`, nothing is uploaded: the arguments are checked and a
+/// HeadBucket raises what would fail the upload. A dry run that passes returns
+/// NULL, as no object was written and there is no ETag.
+///
+/// With `idempotency_key`, the key is stored as user metadata
+/// (`x-amz-meta-s3-io-idempotency-key`) and an object that already carries it
+/// is not uploaded again; see [`put_idempotent`].
+///
+/// `website_redirect_location` (a path such as `/new-page.html` or a full URL)
+/// makes the object redirect there when served by an S3 static website.
+/// `expires` sets the HTTP `Expires` header caches and CDNs go by, which is
+/// unrelated to lifecycle expiration.
+///
+/// With `ensure_bucket`, a missing bucket is created first; see
+/// [`ensure_bucket`].
 #[pg_extern]
 fn s3_put_object(
-    bucket: &str,
+    bucket: Option<&str>,
     object_key: &str,
     data: Vec<u8>,
     endpoint_url: default!(Option<&str>, "NULL"),
@@ -102,227 +652,6167 @@ fn s3_put_object(
     session_token: default!(Option<&str>, "NULL"),
     region: default!(Option<&str>, "NULL"),
     content_type: default!(Option<&str>, "NULL"),
-) -> String {
-    let client = get_or_init_client(endpoint_url, access_key, secret_key, session_token, region);
-    let body = aws_sdk_s3::primitives::ByteStream::from(data);
-
-    let fut = async move {
-        let mut req = client
-            .put_object()
-            .bucket(bucket)
-            .key(object_key)
-            .body(body);
+    compress: default!(Option<&str>, "NULL"),
+    compression_level: default!(Option<i32>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    server_side_encryption: default!(Option<&str>, "NULL"),
+    sse_kms_key_id: default!(Option<&str>, "NULL"),
+    sse_kms_encryption_context: default!(Option<pgrx::JsonB>, "NULL"),
+    dry_run: default!(bool, "false"),
+    canned_acl: default!(Option<&str>, "NULL"),
+    idempotency_key: default!(Option<&str>, "NULL"),
+    website_redirect_location: default!(Option<&str>, "NULL"),
+    expires: default!(Option<TimestampWithTimeZone>, "NULL"),
+    sse_customer_key: default!(Option<Vec<u8>>, "NULL"),
+    sse_customer_algorithm: default!(Option<&str>, "NULL"),
+    ensure_bucket: default!(bool, "false"),
+) -> Option<String> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
 
-        if let Some(ct) = content_type {
-            req = req.content_type(ct);
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let (data, codec) = compress_body(data, compress, compression_level);
+    let encryption = sse::Encryption::new(
+        server_side_encryption,
+        sse_kms_key_id,
+        sse_kms_encryption_context.as_ref().map(|c| &c.0),
+    )
+    .unwrap_or_else(|e| pgrx::error!("{e}"));
+    let customer_key = sse::CustomerKey::new(sse_customer_key.as_deref(), sse_customer_algorithm)
+        .unwrap_or_else(|e| pgrx::error!("{e}"));
+    if encryption.is_some() && customer_key.is_some() {
+        pgrx::error!("server_side_encryption and sse_customer_key cannot be combined");
+    }
+    let acl = canned_acl.map(|acl| parse_canned_acl(acl).unwrap_or_else(|e| pgrx::error!("{e}")));
+    if let Some(key) = idempotency_key {
+        if key.is_empty() || !key.bytes().all(|b| b.is_ascii_graphic()) {
+            pgrx::error!("idempotency_key must be non-empty printable ASCII without spaces");
         }
+    }
 
-        match req.send().await {
-            Ok(out) => {
-                let etag = out
-                    .e_tag()
-                    .unwrap_or_default()
-                    .trim_matches('"')
-                    .to_string();
-                Ok(etag)
+    let expires = expires.map(|e| http_date(e).unwrap_or_else(|e| pgrx::error!("{e}")));
+
+    if dry_run {
+        run(&args, |client| async move {
+            match head_bucket(&client, bucket, expected_bucket_owner).await {
+                // It would be created.
+                Err(e) if ensure_bucket && e.status == Some(404) => Ok(()),
+                result => result,
             }
-            Err(aws_sdk_s3::error::SdkError::DispatchFailure(e)) => {
-                Err(format!("Dispatch failure: {e:?}"))
+        });
+        return None;
+    }
+    let etag = run(&args, |client| {
+        let (data, acl) = (data.clone(), acl.clone());
+        let (encryption, customer_key) = (encryption.as_ref(), customer_key.as_ref());
+        async move {
+            if ensure_bucket {
+                self::ensure_bucket(&client, bucket, expected_bucket_owner).await?;
             }
-            Err(other) => Err(format!("PutObject failed: {other:?}")),
+            let put = |idempotent| {
+                put_object(
+                    client.clone(),
+                    bucket,
+                    object_key,
+                    data,
+                    content_type,
+                    codec,
+                    expected_bucket_owner,
+                    encryption,
+                    acl,
+                    idempotent,
+                    website_redirect_location,
+                    expires,
+                    customer_key,
+                )
+            };
+            let out = match idempotency_key {
+                Some(key) => {
+                    put_idempotent(
+                        &client,
+                        bucket,
+                        object_key,
+                        expected_bucket_owner,
+                        customer_key,
+                        key,
+                        put,
+                    )
+                    .await?
+                }
+                None => put(None).await?.e_tag().map(str::to_owned),
+            };
+            Ok(out.unwrap_or_default())
         }
+    });
+    Some(etag.trim_matches('"').to_string())
+}
+
+/// User metadata holding the idempotency key an object was uploaded with.
+const IDEMPOTENCY_METADATA_KEY: &str = "s3-io-idempotency-key";
+
+/// The idempotency key to store with an upload, and what the upload replaces.
+struct IdempotentPut {
+    key: String,
+    /// ETag of the object the upload may replace; `None` if there must be none.
+    replaces: Option<String>,
+}
+
+/// Upload with `put` unless the object already carries idempotency `key`,
+/// returning the ETag of the object that does.
+///
+/// The object is looked up first; if its `s3-io-idempotency-key` metadata is
+/// `key`, an earlier attempt succeeded and nothing is uploaded. Otherwise the
+/// upload is conditional on the object being unchanged since (If-Match on its
+/// ETag, or If-None-Match `*` if there was none). If that fails because a
+/// concurrent attempt with the same key won, its object is returned.
+async fn put_idempotent<F, Fut>(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    expected_bucket_owner: Option<&str>,
+    customer_key: Option<&sse::CustomerKey>,
+    key: &str,
+    put: F,
+) -> Result<Option<String>, S3Error>
+where
+    F: FnOnce(Option<IdempotentPut>) -> Fut,
+    Fut: std::future::Future<
+        Output = Result<aws_sdk_s3::operation::put_object::PutObjectOutput, S3Error>,
+    >,
+{
+    let carries_key = |head: &aws_sdk_s3::operation::head_object::HeadObjectOutput| {
+        head.metadata()
+            .and_then(|m| m.get(IDEMPOTENCY_METADATA_KEY))
+            .is_some_and(|k| k == key)
+    };
+    let head = head_object_with(
+        client,
+        bucket,
+        object_key,
+        expected_bucket_owner,
+        customer_key,
+    )
+    .await?;
+    if let Some(head) = head.as_ref().filter(|h| carries_key(h)) {
+        return Ok(head.e_tag().map(str::to_owned));
+    }
+    let idempotent = IdempotentPut {
+        key: key.to_owned(),
+        replaces: head.and_then(|h| h.e_tag().map(str::to_owned)),
     };
+    match put(Some(idempotent)).await {
+        Ok(out) => Ok(out.e_tag().map(str::to_owned)),
+        Err(e) if e.code.as_deref() == Some("PreconditionFailed") => {
+            match head_object_with(
+                client,
+                bucket,
+                object_key,
+                expected_bucket_owner,
+                customer_key,
+            )
+            .await?
+            {
+                Some(head) if carries_key(&head) => Ok(head.e_tag().map(str::to_owned)),
+                _ => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
 
-    match rt().block_on(fut) {
-        Ok(etag) => etag,
-        Err(e) => pgrx::error!("{e}"),
+/// Parse a canned ACL such as `private` or `bucket-owner-full-control`.
+fn parse_canned_acl(name: &str) -> Result<ObjectCannedAcl, String> {
+    if !ObjectCannedAcl::values().contains(&name) {
+        return Err(format!(
+            "canned_acl must be one of {}, got {name:?}",
+            ObjectCannedAcl::values().join(", ")
+        ));
     }
+    Ok(ObjectCannedAcl::from(name))
 }
 
+/// `s3_put_object` for text, uploaded as UTF-8, e.g. JSON or CSV built in SQL
+/// without a `convert_to(..., 'UTF8')`.
 #[pg_extern]
-fn s3_get_object(
-    bucket: &str,
+fn s3_put_object_text(
+    bucket: Option<&str>,
     object_key: &str,
+    content: &str,
     endpoint_url: default!(Option<&str>, "NULL"),
     access_key: default!(Option<&str>, "NULL"),
     secret_key: default!(Option<&str>, "NULL"),
     session_token: default!(Option<&str>, "NULL"),
     region: default!(Option<&str>, "NULL"),
-) -> Vec<u8> {
-    let client = get_or_init_client(endpoint_url, access_key, secret_key, session_token, region);
+    content_type: default!(Option<&str>, "'text/plain; charset=utf-8'"),
+    compress: default!(Option<&str>, "NULL"),
+    compression_level: default!(Option<i32>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    server_side_encryption: default!(Option<&str>, "NULL"),
+    sse_kms_key_id: default!(Option<&str>, "NULL"),
+    sse_kms_encryption_context: default!(Option<pgrx::JsonB>, "NULL"),
+    dry_run: default!(bool, "false"),
+    canned_acl: default!(Option<&str>, "NULL"),
+    idempotency_key: default!(Option<&str>, "NULL"),
+    website_redirect_location: default!(Option<&str>, "NULL"),
+    expires: default!(Option<TimestampWithTimeZone>, "NULL"),
+    sse_customer_key: default!(Option<Vec<u8>>, "NULL"),
+    sse_customer_algorithm: default!(Option<&str>, "NULL"),
+) -> Option<String> {
+    s3_put_object(
+        bucket,
+        object_key,
+        content.as_bytes().to_vec(),
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        content_type,
+        compress,
+        compression_level,
+        expected_bucket_owner,
+        force_path_style,
+        server_side_encryption,
+        sse_kms_key_id,
+        sse_kms_encryption_context,
+        dry_run,
+        canned_acl,
+        idempotency_key,
+        website_redirect_location,
+        expires,
+        sse_customer_key,
+        sse_customer_algorithm,
+        false,
+    )
+}
 
-    let fut = async move {
-        let req = client.get_object().bucket(bucket).key(object_key);
+/// Compress `data` with the `compress` codec, if any.
+fn compress_body(
+    data: Vec<u8>,
+    compress: Option<&str>,
+    compression_level: Option<i32>,
+) -> (bytes::Bytes, Option<Codec>) {
+    let codec = compress.map(|c| Codec::parse(c).unwrap_or_else(|e| pgrx::error!("{e}")));
+    let data = match codec {
+        Some(codec) => codec
+            .compress(&data, compression_level)
+            .unwrap_or_else(|e| pgrx::error!("{e}")),
+        None => data,
+    };
+    (bytes::Bytes::from(data), codec)
+}
 
-        match req.send().await {
-            Ok(out) => out
-                .body
-                .collect()
-                .await
-                .map_err(|e| format!("Collect error: {e:?}")),
-            Err(aws_sdk_s3::error::SdkError::DispatchFailure(e)) => {
-                Err(format!("Dispatch failure: {e:?}"))
-            }
-            Err(other) => Err(format!("PutObject failed: {other:?}")),
+/// PutObject of `data`, recording `codec` and an idempotency key in the object metadata
+/// and `codec` as the Content-Encoding of `content_type`,
+/// optionally as a website redirect to `website_redirect_location` and with an
+/// `Expires` header.
+async fn put_object(
+    client: aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    data: bytes::Bytes,
+    content_type: Option<&str>,
+    codec: Option<Codec>,
+    expected_bucket_owner: Option<&str>,
+    encryption: Option<&sse::Encryption>,
+    acl: Option<ObjectCannedAcl>,
+    idempotent: Option<IdempotentPut>,
+    website_redirect_location: Option<&str>,
+    expires: Option<aws_smithy_types::DateTime>,
+    customer_key: Option<&sse::CustomerKey>,
+) -> Result<aws_sdk_s3::operation::put_object::PutObjectOutput, S3Error> {
+    let mut req = client
+        .put_object()
+        .bucket(bucket)
+        .key(object_key)
+        .set_expected_bucket_owner(expected_bucket_owner.map(str::to_owned))
+        .set_acl(acl)
+        .set_website_redirect_location(website_redirect_location.map(str::to_owned))
+        .set_expires(expires)
+        .body(aws_sdk_s3::primitives::ByteStream::from(data));
+
+    if let Some(ct) = content_type {
+        req = req.content_type(ct);
+    }
+    if let Some(codec) = codec {
+        req = req
+            .metadata(CODEC_METADATA_KEY, codec.name())
+            .set_content_encoding(codec.content_encoding(content_type).map(str::to_owned));
+    }
+    if let Some(encryption) = encryption {
+        req = encryption.apply(req);
+    }
+    if let Some(customer_key) = customer_key {
+        req = customer_key.put(req);
+    }
+    if let Some(idempotent) = idempotent {
+        req = req.metadata(IDEMPOTENCY_METADATA_KEY, idempotent.key);
+        req = match idempotent.replaces {
+            Some(etag) => req.if_match(etag),
+            None => req.if_none_match("*"),
+        };
+    }
+
+    match req.send().await {
+        Ok(out) => {
+            note_request_ids("PutObject", &out);
+            Ok(out)
         }
+        Err(err) => Err(sdk_error("PutObject", err)),
+    }
+}
+
+/// Delete an object.
+///
+/// With `dry_run`, nothing is deleted; a HeadBucket raises what would fail the
+/// deletion, or the result is `true` for "would succeed".
+#[pg_extern]
+fn s3_delete_object(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    dry_run: default!(bool, "false"),
+    if_match: default!(Option<&str>, "NULL"),
+) -> bool {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
     };
 
-    match rt().block_on(fut) {
-        Ok(data) => data.to_vec(),
-        Err(e) => pgrx::error!("{e}"),
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    if dry_run {
+        run(&args, |client| async move {
+            head_bucket(&client, bucket, expected_bucket_owner).await
+        });
+        return true;
     }
+    let if_match = if_match.map(quote_etag);
+    run(&args, |client| {
+        delete_object(
+            client,
+            bucket,
+            object_key,
+            expected_bucket_owner,
+            if_match.as_deref(),
+        )
+    })
 }
 
-#[derive(Eq, PartialEq, Hash)]
-struct ClientKey {
-    endpoint_url: String,
-    access_key: String,
-    secret_key: String,
-    region: String,
+/// HeadBucket, to check that `bucket` exists and the credentials may access it.
+async fn head_bucket(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    expected_bucket_owner: Option<&str>,
+) -> Result<(), S3Error> {
+    let out = client
+        .head_bucket()
+        .bucket(bucket)
+        .set_expected_bucket_owner(expected_bucket_owner.map(str::to_owned))
+        .send()
+        .await
+        .map_err(|err| sdk_error("HeadBucket", err))?;
+    note_request_ids("HeadBucket", &out);
+    Ok(())
 }
 
-impl ClientKey {
-    fn new(endpoint_url: &str, access_key: &str, secret_key: &str, region: &str) -> Self {
-        Self {
-            endpoint_url: endpoint_url.to_owned(),
-            access_key: access_key.to_owned(),
-            secret_key: secret_key.to_owned(),
-            region: region.to_owned(),
+/// DeleteObject. S3 also reports success if the object did not exist.
+async fn delete_object(
+    client: aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    expected_bucket_owner: Option<&str>,
+    if_match: Option<&str>,
+) -> Result<bool, S3Error> {
+    match client
+        .delete_object()
+        .bucket(bucket)
+        .key(object_key)
+        .set_expected_bucket_owner(expected_bucket_owner.map(str::to_owned))
+        .set_if_match(if_match.map(str::to_owned))
+        .send()
+        .await
+    {
+        Ok(out) => {
+            note_request_ids("DeleteObject", &out);
+            Ok(true)
         }
+        Err(err) => Err(match sdk_error("DeleteObject", err) {
+            e if e.code.as_deref() == Some("PreconditionFailed") => {
+                precondition_failed(bucket, object_key)
+            }
+            e => e,
+        }),
     }
 }
 
-fn get_or_init_client(
-    endpoint_url: Option<&str>,
-    access_key: Option<&str>,
-    secret_key: Option<&str>,
-    session_token: Option<&str>,
-    region: Option<&str>,
-) -> aws_sdk_s3::Client {
-    // Session-lifetime S3 client, initialized on first use.
-    // static S3_CLIENT: OnceLock<aws_sdk_s3::Client> = OnceLock::new();
-    static S3_CLIENTS: OnceLock<Mutex<HashMap<ClientKey, aws_sdk_s3::Client>>> = OnceLock::new();
-
-    let ep = normalize_endpoint(
-        endpoint_url.unwrap_or(
-            &std::env::var("S3_ENDPOINT_URL")
-                .map_err(|_| pgrx::error!("AWS_SECRET_ACCESS_KEY not set"))
-                .unwrap(),
-        ),
+/// Upload the file at `file_path` on the database server, streamed from disk,
+/// and return the object's ETag.
+///
+/// Only superusers may call this, and only for files inside the directories
+/// listed in `s3_io.upload_paths`.
+#[pg_extern]
+fn s3_put_object_from_file(
+    bucket: Option<&str>,
+    object_key: &str,
+    file_path: &str,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    content_type: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    download::check_server_file(
+        "s3_put_object_from_file",
+        file_path,
+        &guc::UPLOAD_PATHS,
+        "s3_io.upload_paths",
     );
-    let ak = access_key
-        .unwrap_or(
-            &std::env::var("AWS_ACCESS_KEY_ID")
-                .map_err(|_| pgrx::error!("AWS_ACCESS_KEY_ID not set"))
-                .unwrap(),
-        )
-        .to_string();
-    let sk = secret_key
-        .unwrap_or(
-            &std::env::var("AWS_SECRET_ACCESS_KEY")
-                .map_err(|_| pgrx::error!("AWS_SECRET_ACCESS_KEY not set"))
-                .unwrap(),
-        )
-        .to_string();
-    let st = session_token
-        .map(|x| x.to_string())
-        .or(std::env::var("AWS_SESSION_TOKEN").ok());
-    let rg = region.unwrap_or("us-east-1").to_string();
-
-    let client_key = ClientKey::new(&ep, &ak, &sk, &rg);
-
-    S3_CLIENTS
-        .get_or_init(|| Mutex::new(HashMap::new()))
-        .lock()
-        .unwrap()
-        .entry(client_key)
-        .or_insert(rt().block_on(async {
-            use aws_config::meta::region::RegionProviderChain;
-            use aws_config::BehaviorVersion;
-            use aws_credential_types::Credentials;
-            use aws_sdk_s3::{
-                config::{Builder, SharedCredentialsProvider},
-                Client,
-            };
-            use aws_types::region::Region;
 
-            let region_provider = RegionProviderChain::first_try(Region::new(rg))
-                .or_default_provider()
-                .or_else(Region::new("us-east-1"));
+    // Stream the file from disk with a known length instead of buffering it.
+    // Sending an explicit Content-Length avoids chunked transfer encoding,
+    // which some S3-compatible stores (MinIO, Ceph) reject.
+    let file_len = match std::fs::metadata(file_path) {
+        Ok(meta) if meta.is_file() => meta.len(),
+        Ok(_) => pgrx::error!("{file_path} is not a regular file"),
+        Err(e) => pgrx::error!("Cannot read {file_path}: {e}"),
+    };
 
-            let base = aws_config::defaults(BehaviorVersion::latest())
-                .region(region_provider)
-                .load()
-                .await;
+    run(&args, |client| async move {
+        use aws_smithy_types::byte_stream::Length;
 
-            let mut cfg = Builder::from(&base).force_path_style(true);
-            cfg = cfg.endpoint_url(ep);
+        let body = aws_sdk_s3::primitives::ByteStream::read_from()
+            .path(file_path)
+            .length(Length::Exact(file_len))
+            .build()
+            .await
+            .map_err(|e| S3Error::new(format!("Cannot open {file_path}: {e}")))?;
 
-            let creds = Credentials::from_keys(ak, sk, st);
-            cfg = cfg.credentials_provider(SharedCredentialsProvider::new(creds));
+        let mut req = client
+            .put_object()
+            .bucket(bucket)
+            .key(object_key)
+            .set_expected_bucket_owner(expected_bucket_owner.map(str::to_owned))
+            .content_length(file_len as i64)
+            .body(body);
 
-            Client::from_conf(cfg.build())
-        }))
-        .clone()
-}
+        if let Some(ct) = content_type {
+            req = req.content_type(ct);
+        }
 
-fn normalize_endpoint(ep: &str) -> String {
-    if ep.starts_with("http://") || ep.starts_with("https://") {
-        ep.to_string()
+        match req.send().await {
+            Ok(out) => {
+                note_request_ids("PutObject", &out);
+                let etag = out
+                    .e_tag()
+                    .unwrap_or_default()
+                    .trim_matches('"')
+                    .to_string();
+                Ok(etag)
+            }
+            Err(err) => Err(sdk_error("PutObject", err)),
+        }
+    })
+}
+
+/// Download an object to `dest_path` on the database server, streaming it to
+/// disk, and return the number of bytes written by this call.
+///
+/// With `resume`, a download that failed earlier continues from the length of
+/// the partial file, if the object still has the ETag it had when the download
+/// started; otherwise it starts over. That ETag is kept in `<dest_path>.s3etag`
+/// until the download completes.
+///
+/// With `s3_io.download_concurrency` above 1, objects larger than
+/// `s3_io.download_part_size` are downloaded with concurrent range GETs
+/// instead. Such downloads are not resumed but start over.
+///
+/// Only superusers may call this, and only for paths inside the directories
+/// listed in `s3_io.download_paths`.
+#[pg_extern]
+fn s3_get_object_to_file(
+    bucket: Option<&str>,
+    object_key: &str,
+    dest_path: &str,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    resume: default!(bool, "true"),
+) -> i64 {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    download::check_server_file(
+        "s3_get_object_to_file",
+        dest_path,
+        &guc::DOWNLOAD_PATHS,
+        "s3_io.download_paths",
+    );
+    let etag_path = format!("{dest_path}.s3etag");
+    let partial = resume
+        .then(|| resume_point(dest_path, &etag_path))
+        .flatten();
+
+    run(&args, |client| {
+        let (etag_path, partial) = (etag_path.clone(), partial.clone());
+        async move {
+            let download = |from| {
+                download_to_file(
+                    &client,
+                    bucket,
+                    object_key,
+                    dest_path,
+                    &etag_path,
+                    from,
+                    expected_bucket_owner,
+                )
+            };
+            if let Some((offset, etag)) = &partial {
+                let head = head_object(&client, bucket, object_key, expected_bucket_owner).await?;
+                if let Some(head) = head.filter(|h| h.e_tag() == Some(etag)) {
+                    let size = head.content_length().unwrap_or_default() as u64;
+                    if size == *offset {
+                        let _ = std::fs::remove_file(&etag_path);
+                        return Ok(0);
+                    }
+                    if size > *offset {
+                        match download(Some((*offset, etag.as_str()))).await {
+                            // Changed since the HEAD; start over.
+                            Err(e) if e.code.as_deref() == Some("PreconditionFailed") => {}
+                            result => return result,
+                        }
+                    }
+                }
+            }
+            let _ = std::fs::remove_file(&etag_path);
+            if let Some(written) = download::download_in_parts(
+                &client,
+                bucket,
+                object_key,
+                dest_path,
+                expected_bucket_owner,
+            )
+            .await?
+            {
+                return Ok(written);
+            }
+            download(None).await
+        }
+    })
+}
+
+/// Length of the partial file at `dest_path` and the ETag its download started
+/// with, if there is a download to resume.
+fn resume_point(dest_path: &str, etag_path: &str) -> Option<(u64, String)> {
+    let etag = std::fs::read_to_string(etag_path).ok()?;
+    let len = std::fs::metadata(dest_path).ok()?.len();
+    (!etag.is_empty()).then_some((len, etag))
+}
+
+/// GetObject into `dest_path`, from the start or, given `from`, appending the
+/// bytes after the offset as long as the object has that ETag.
+async fn download_to_file(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    dest_path: &str,
+    etag_path: &str,
+    from: Option<(u64, &str)>,
+    expected_bucket_owner: Option<&str>,
+) -> Result<i64, S3Error> {
+    let mut req = client
+        .get_object()
+        .bucket(bucket)
+        .key(object_key)
+        .set_expected_bucket_owner(expected_bucket_owner.map(str::to_owned));
+    if let Some((offset, etag)) = from {
+        req = req.range(format!("bytes={offset}-")).if_match(etag);
+    }
+    let out = req
+        .send()
+        .await
+        .map_err(|err| sdk_error("GetObject", err))?;
+    note_request_ids("GetObject", &out);
+
+    let io_error = |e: std::io::Error| S3Error::new(format!("Cannot write {dest_path}: {e}"));
+    let file = match from {
+        Some(_) => std::fs::OpenOptions::new().append(true).open(dest_path),
+        None => {
+            std::fs::write(etag_path, out.e_tag().unwrap_or_default()).map_err(io_error)?;
+            std::fs::File::create(dest_path)
+        }
+    }
+    .map_err(io_error)?;
+    let written = write_body(out.body, file, dest_path).await?;
+    let _ = std::fs::remove_file(etag_path);
+    Ok(written)
+}
+
+/// Stream `body` into `file` at `path` and return the number of bytes written.
+async fn write_body(
+    mut body: aws_sdk_s3::primitives::ByteStream,
+    file: std::fs::File,
+    path: &str,
+) -> Result<i64, S3Error> {
+    use std::io::Write;
+
+    let io_error = |e: std::io::Error| S3Error::new(format!("Cannot write {path}: {e}"));
+    let mut file = std::io::BufWriter::new(file);
+    let mut written = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| S3Error::new(format!("Collect error: {e:?}")))?;
+        file.write_all(&chunk).map_err(io_error)?;
+        written += chunk.len() as i64;
+    }
+    file.flush().map_err(io_error)?;
+    Ok(written)
+}
+
+#[pg_extern]
+fn s3_get_object(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    decompress: default!(&str, "'metadata'"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    verify_checksum: default!(bool, "false"),
+    anonymous: default!(bool, "false"),
+    sse_customer_key: default!(Option<Vec<u8>>, "NULL"),
+    sse_customer_algorithm: default!(Option<&str>, "NULL"),
+) -> Vec<u8> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let decompress = Decompress::parse(decompress).unwrap_or_else(|e| pgrx::error!("{e}"));
+    let customer_key = sse::CustomerKey::new(sse_customer_key.as_deref(), sse_customer_algorithm)
+        .unwrap_or_else(|e| pgrx::error!("{e}"));
+    let customer_key = customer_key.as_ref();
+
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let (data, _) = run_as(&args, anonymous, |client| async move {
+        get_object(
+            &client,
+            bucket,
+            object_key,
+            decompress,
+            expected_bucket_owner,
+            verify_checksum,
+            customer_key,
+        )
+        .await
+    });
+    data
+}
+
+/// `s3_get_object` for text, decoded from `encoding` (a Postgres encoding
+/// name such as `UTF8` or `LATIN1`, or `UTF16LE`, `UTF16BE`, `UTF32LE`, `UTF32BE`).
+///
+/// With `'auto'` the encoding is taken from a byte order mark, else from the
+/// `charset` of the object's Content-Type, else UTF-8 is assumed.
+#[pg_extern]
+fn s3_get_object_text(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    encoding: default!(&str, "'UTF8'"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    decompress: default!(&str, "'metadata'"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    sse_customer_key: default!(Option<Vec<u8>>, "NULL"),
+    sse_customer_algorithm: default!(Option<&str>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let decompress = Decompress::parse(decompress).unwrap_or_else(|e| pgrx::error!("{e}"));
+    let customer_key = sse::CustomerKey::new(sse_customer_key.as_deref(), sse_customer_algorithm)
+        .unwrap_or_else(|e| pgrx::error!("{e}"));
+    let customer_key = customer_key.as_ref();
+
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let (data, _, content_type) = run(&args, |client| async move {
+        get_object_typed(
+            &client,
+            bucket,
+            object_key,
+            decompress,
+            expected_bucket_owner,
+            false,
+            customer_key,
+        )
+        .await
+    });
+
+    let (text, encoding) = if encoding.eq_ignore_ascii_case("auto") {
+        let (detected, source, body) = encoding::sniff(&data, content_type.as_deref());
+        let encoding = format!("{detected} ({source})");
+        (encoding::decode_text(body, &detected), encoding)
+    } else {
+        (encoding::decode_text(&data, encoding), encoding.to_owned())
+    };
+    text.unwrap_or_else(|e| {
+        pgrx::error!("Cannot decode s3://{bucket}/{object_key} as {encoding}: {e}")
+    })
+}
+
+/// Like `s3_get_object` with `verify_checksum => true`, also returning the
+/// checksum the data was verified against.
+#[pg_extern]
+fn s3_get_object_verified(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    decompress: default!(&str, "'metadata'"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(data, Vec<u8>),
+        name!(checksum_algorithm, String),
+        name!(checksum, String),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let decompress = Decompress::parse(decompress).unwrap_or_else(|e| pgrx::error!("{e}"));
+
+    let (data, checksum) = run(&args, |client| async move {
+        get_object(
+            &client,
+            bucket,
+            object_key,
+            decompress,
+            expected_bucket_owner,
+            true,
+            None,
+        )
+        .await
+    });
+    let (algorithm, checksum) = checksum.expect("verified download has a checksum");
+    TableIterator::once((data, algorithm.to_owned(), checksum))
+}
+
+/// An object's body as stored, with the length, type and ETag S3 reported for it.
+///
+/// With `range` (e.g. `bytes=0-1023`), only that range is downloaded and
+/// `content_length` is the length of the range.
+#[pg_extern]
+fn s3_get_object_info(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    range: default!(Option<&str>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(data, Vec<u8>),
+        name!(content_length, Option<i64>),
+        name!(content_type, Option<String>),
+        name!(etag, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    if range.is_some_and(|r| !r.starts_with("bytes=")) {
+        pgrx::error!("range must be an HTTP byte range like bytes=0-1023");
+    }
+
+    let row = run(&args, |client| async move {
+        let out = client
+            .get_object()
+            .bucket(bucket)
+            .key(object_key)
+            .set_range(range.map(str::to_owned))
+            .set_expected_bucket_owner(expected_bucket_owner.map(str::to_owned))
+            .send()
+            .await
+            .map_err(|err| sdk_error("GetObject", err))?;
+        note_request_ids("GetObject", &out);
+        // Taken from the headers, independent of how much of the body arrives.
+        let content_length = out.content_length();
+        let content_type = out.content_type().map(str::to_owned);
+        let etag = out.e_tag().map(|e| e.trim_matches('"').to_owned());
+        let data = read_body(out.body, content_length)
+            .await
+            .map_err(|e| S3Error::new(format!("Collect error: {e:?}")))?;
+        Ok((data, content_length, content_type, etag))
+    });
+    TableIterator::once(row)
+}
+
+/// A byte range of an object, with the position of the returned bytes and the
+/// object's total size taken from the `Content-Range` S3 answers with.
+///
+/// Give `range_start` and `range_end` for a closed range, `range_start` alone
+/// for everything from there on, or `suffix_length` alone for the last bytes,
+/// e.g. `suffix_length => 500` for `bytes=-500`. Positions are zero-based and
+/// inclusive, as in HTTP; `range_end` is NULL if no bytes were returned.
+#[pg_extern]
+fn s3_get_object_range(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    range_start: default!(Option<i64>, "NULL"),
+    range_end: default!(Option<i64>, "NULL"),
+    suffix_length: default!(Option<i64>, "NULL"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(data, Vec<u8>),
+        name!(range_start, i64),
+        name!(range_end, Option<i64>),
+        name!(total_size, Option<i64>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let range =
+        range_header(range_start, range_end, suffix_length).unwrap_or_else(|e| pgrx::error!("{e}"));
+
+    let row = run(&args, |client| {
+        let range = range.clone();
+        async move {
+            let out = client
+                .get_object()
+                .bucket(bucket)
+                .key(object_key)
+                .range(range)
+                .set_expected_bucket_owner(expected_bucket_owner.map(str::to_owned))
+                .send()
+                .await
+                .map_err(|err| sdk_error("GetObject", err))?;
+            note_request_ids("GetObject", &out);
+            let content_range = out.content_range().and_then(parse_content_range);
+            let data = read_body(out.body, out.content_length)
+                .await
+                .map_err(|e| S3Error::new(format!("Collect error: {e:?}")))?;
+            let (start, end, total) = returned_range(content_range, data.len() as i64);
+            Ok((data, start, end, total))
+        }
+    });
+    TableIterator::once(row)
+}
+
+/// First and last byte and total size of a range GET that returned `len` bytes,
+/// from its parsed `Content-Range`. Without one, the provider ignored the range
+/// and sent the whole object; the last byte is `None` if that was empty.
+pub(crate) fn returned_range(
+    content_range: Option<(i64, i64, Option<i64>)>,
+    len: i64,
+) -> (i64, Option<i64>, Option<i64>) {
+    match content_range {
+        Some((start, end, total)) => (start, Some(end), total),
+        None => (0, (len > 0).then(|| len - 1), Some(len)),
+    }
+}
+
+/// The `Range` header for a closed, open-ended (`end` NULL) or suffix range.
+pub(crate) fn range_header(
+    start: Option<i64>,
+    end: Option<i64>,
+    suffix_length: Option<i64>,
+) -> Result<String, String> {
+    match (start, end, suffix_length) {
+        (Some(start), _, _) if start < 0 => {
+            Err(format!("range_start must not be negative, got {start}"))
+        }
+        (Some(start), Some(end), None) if end < start => Err(format!(
+            "range_end must not be before range_start, got {start} to {end}"
+        )),
+        (Some(start), Some(end), None) => Ok(format!("bytes={start}-{end}")),
+        (Some(start), None, None) => Ok(format!("bytes={start}-")),
+        (None, None, Some(len)) if len < 1 => {
+            Err(format!("suffix_length must be positive, got {len}"))
+        }
+        (None, None, Some(len)) => Ok(format!("bytes=-{len}")),
+        (None, Some(_), None) => Err("range_end requires range_start".to_owned()),
+        (None, None, None) => {
+            Err("give range_start (and optionally range_end) or suffix_length".to_owned())
+        }
+        _ => Err("suffix_length cannot be combined with range_start or range_end".to_owned()),
+    }
+}
+
+/// First and last byte and total size from a `Content-Range` such as
+/// `bytes 1000-1499/5000`; the size is `None` if unknown (`*`).
+pub(crate) fn parse_content_range(value: &str) -> Option<(i64, i64, Option<i64>)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let total = match total {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    Some((start.parse().ok()?, end.parse().ok()?, total))
+}
+
+/// GetObject of the whole body, decompressed as requested.
+///
+/// With `verify_checksum`, S3 is asked for the checksum stored at upload and
+/// the SDK checks the body against it; the checksum is returned as
+/// `(algorithm, base64 value)`. Objects encrypted with a customer key (SSE-C)
+/// need `customer_key`.
+async fn get_object(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    decompress: Decompress,
+    expected_bucket_owner: Option<&str>,
+    verify_checksum: bool,
+    customer_key: Option<&sse::CustomerKey>,
+) -> Result<(Vec<u8>, Option<(&'static str, String)>), S3Error> {
+    let (data, checksum, _) = get_object_typed(
+        client,
+        bucket,
+        object_key,
+        decompress,
+        expected_bucket_owner,
+        verify_checksum,
+        customer_key,
+    )
+    .await?;
+    Ok((data, checksum))
+}
+
+/// [`get_object`], also returning the object's Content-Type.
+async fn get_object_typed(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    decompress: Decompress,
+    expected_bucket_owner: Option<&str>,
+    verify_checksum: bool,
+    customer_key: Option<&sse::CustomerKey>,
+) -> Result<(Vec<u8>, Option<(&'static str, String)>, Option<String>), S3Error> {
+    let mut req = client
+        .get_object()
+        .bucket(bucket)
+        .key(object_key)
+        .set_expected_bucket_owner(expected_bucket_owner.map(str::to_owned));
+    if verify_checksum {
+        req = req.checksum_mode(aws_sdk_s3::types::ChecksumMode::Enabled);
+    }
+    if let Some(customer_key) = customer_key {
+        req = customer_key.get(req);
+    }
+
+    let out = match req.send().await {
+        Ok(out) => out,
+        Err(err) => return Err(sdk_error("GetObject", err)),
+    };
+    note_request_ids("GetObject", &out);
+
+    let checksum = if verify_checksum {
+        let (algorithm, value) = stored_checksum(&out).ok_or_else(|| {
+            S3Error::new(format!(
+                "s3://{bucket}/{object_key} has no stored checksum to verify"
+            ))
+        })?;
+        // Multipart uploads may store a checksum of part checksums ("...-<parts>"),
+        // which says nothing about the body as a whole.
+        if value
+            .rsplit_once('-')
+            .is_some_and(|(_, n)| n.parse::<u32>().is_ok())
+        {
+            return Err(S3Error::new(format!(
+                "s3://{bucket}/{object_key} only has a per-part {algorithm} checksum, \
+                 which cannot be verified on download"
+            )));
+        }
+        Some((algorithm, value.to_owned()))
     } else {
-        format!("https://{ep}")
+        None
+    };
+
+    let marker = out
+        .metadata()
+        .and_then(|m| m.get(CODEC_METADATA_KEY))
+        .cloned();
+    let content_encoding = out.content_encoding().map(str::to_owned);
+    let content_type = out.content_type().map(str::to_owned);
+    let data = read_body(out.body, out.content_length).await;
+    let data = match (data, &checksum) {
+        (Ok(data), _) => data,
+        (Err(e), Some((algorithm, _))) => {
+            return Err(S3Error::new(format!(
+                "Download of s3://{bucket}/{object_key} failed {algorithm} verification: {e:?}"
+            )))
+        }
+        (Err(e), None) => return Err(S3Error::new(format!("Collect error: {e:?}"))),
+    };
+    let codec = decompress.codec(marker.as_deref(), content_encoding.as_deref(), &data)?;
+    let data = match codec {
+        Some(codec) => codec.decompress(&data)?,
+        None => data,
+    };
+    Ok((data, checksum, content_type))
+}
+
+/// Read a response body into a single buffer sized by `content_length`.
+///
+/// `collect()` followed by `to_vec()` holds the received chunks and their copy
+/// at the same time, more than twice the body size at peak; this stays close
+/// to the body size.
+async fn read_body(
+    mut body: aws_sdk_s3::primitives::ByteStream,
+    content_length: Option<i64>,
+) -> Result<Vec<u8>, aws_sdk_s3::primitives::ByteStreamError> {
+    let mut data = Vec::with_capacity(content_length.unwrap_or_default().max(0) as usize);
+    while let Some(chunk) = body.try_next().await? {
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// The full-object checksum S3 returned with a GetObject, as `(algorithm, base64 value)`.
+fn stored_checksum(
+    out: &aws_sdk_s3::operation::get_object::GetObjectOutput,
+) -> Option<(&'static str, &str)> {
+    [
+        ("CRC32C", out.checksum_crc32_c()),
+        ("CRC32", out.checksum_crc32()),
+        ("CRC64NVME", out.checksum_crc64_nvme()),
+        ("SHA1", out.checksum_sha1()),
+        ("SHA256", out.checksum_sha256()),
+    ]
+    .into_iter()
+    .find_map(|(algorithm, value)| Some((algorithm, value?)))
+}
+
+/// The first `max_bytes` of an object decoded as text, e.g. to sniff a file header.
+#[pg_extern]
+fn s3_peek_text(
+    bucket: Option<&str>,
+    object_key: &str,
+    max_bytes: i32,
+    encoding: default!(&str, "'UTF8'"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    if max_bytes <= 0 {
+        pgrx::error!("max_bytes must be positive");
+    }
+
+    let data = run(&args, |client| async move {
+        get_range(
+            &client,
+            bucket,
+            object_key,
+            0,
+            max_bytes as u64,
+            expected_bucket_owner,
+        )
+        .await
+    });
+
+    let truncated = data.len() == max_bytes as usize;
+    encoding::decode(&data, encoding, truncated).unwrap_or_else(|e| pgrx::error!("{e}"))
+}
+
+/// The last `last_bytes` of an object, or all of it if it is shorter, e.g. to
+/// read a Parquet footer or the end of a log without downloading the rest.
+#[pg_extern]
+fn s3_tail(
+    bucket: Option<&str>,
+    object_key: &str,
+    last_bytes: i32,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> Vec<u8> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    if last_bytes <= 0 {
+        pgrx::error!("last_bytes must be positive");
+    }
+
+    run(&args, |client| async move {
+        let head = head_object(&client, bucket, object_key, expected_bucket_owner)
+            .await?
+            .ok_or_else(|| S3Error {
+                code: Some("NoSuchKey".to_owned()),
+                ..S3Error::new(format!("s3://{bucket}/{object_key} does not exist"))
+            })?;
+        let size = head.content_length().unwrap_or_default() as u64;
+        let len = size.min(last_bytes as u64);
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        get_range(
+            &client,
+            bucket,
+            object_key,
+            size - len,
+            len,
+            expected_bucket_owner,
+        )
+        .await
+    })
+}
+
+/// GetObject of `len` bytes starting at `offset`, shorter if the object ends
+/// earlier and empty if it starts at or past the end.
+async fn get_range(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    offset: u64,
+    len: u64,
+    expected_bucket_owner: Option<&str>,
+) -> Result<Vec<u8>, S3Error> {
+    let range = format!("bytes={offset}-{}", offset + len - 1);
+    match client
+        .get_object()
+        .bucket(bucket)
+        .key(object_key)
+        .range(range)
+        .set_expected_bucket_owner(expected_bucket_owner.map(str::to_owned))
+        .send()
+        .await
+    {
+        Ok(out) => {
+            note_request_ids("GetObject", &out);
+            read_body(out.body, out.content_length)
+                .await
+                .map_err(|e| S3Error::new(format!("Collect error: {e:?}")))
+        }
+        Err(err) => {
+            use aws_smithy_types::error::metadata::ProvideErrorMetadata;
+            if err.code() == Some("InvalidRange") {
+                Ok(Vec::new())
+            } else {
+                Err(sdk_error("GetObject", err))
+            }
+        }
+    }
+}
+
+#[pg_extern]
+fn s3_copy_object(
+    src_bucket: Option<&str>,
+    src_key: &str,
+    dst_bucket: Option<&str>,
+    dst_key: &str,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    metadata_directive: default!(&str, "'COPY'"),
+    metadata: default!(Option<pgrx::JsonB>, "NULL"),
+    content_type: default!(Option<&str>, "NULL"),
+    tagging_directive: default!(&str, "'COPY'"),
+    tagging: default!(Option<pgrx::JsonB>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    checksum_algorithm: default!(Option<&str>, "NULL"),
+    if_match: default!(Option<&str>, "NULL"),
+    sse_customer_key: default!(Option<Vec<u8>>, "NULL"),
+    copy_source_sse_customer_key: default!(Option<Vec<u8>>, "NULL"),
+    sse_customer_algorithm: default!(Option<&str>, "NULL"),
+    storage_class: default!(Option<&str>, "NULL"),
+    preserve_storage_class: default!(bool, "true"),
+) -> String {
+    use aws_sdk_s3::types::{MetadataDirective, TaggingDirective};
+
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (src_bucket, src_key) = uri::resolve(src_bucket, src_key);
+    let (dst_bucket, dst_key) = uri::resolve(dst_bucket, dst_key);
+    let (src_bucket, src_key) = (src_bucket.as_str(), src_key.as_str());
+    let (dst_bucket, dst_key) = (dst_bucket.as_str(), dst_key.as_str());
+
+    // S3 defaults both directives to COPY, but spell them out so callers
+    // never lose tags or metadata by accident.
+    let metadata_directive = match metadata_directive.to_ascii_uppercase().as_str() {
+        "COPY" => MetadataDirective::Copy,
+        "REPLACE" => MetadataDirective::Replace,
+        other => pgrx::error!("metadata_directive must be COPY or REPLACE, got {other:?}"),
+    };
+    let tagging_directive = match tagging_directive.to_ascii_uppercase().as_str() {
+        "COPY" => TaggingDirective::Copy,
+        "REPLACE" => TaggingDirective::Replace,
+        other => pgrx::error!("tagging_directive must be COPY or REPLACE, got {other:?}"),
+    };
+
+    let metadata =
+        metadata.map(|m| string_map(&m.0, "metadata").unwrap_or_else(|e| pgrx::error!("{e}")));
+    if metadata_directive == MetadataDirective::Copy
+        && (metadata.is_some() || content_type.is_some())
+    {
+        pgrx::error!("metadata and content_type require metadata_directive => 'REPLACE'");
+    }
+
+    let tagging = tagging.map(|t| {
+        let tags = string_map(&t.0, "tagging").unwrap_or_else(|e| pgrx::error!("{e}"));
+        encode_tagging(&tags)
+    });
+    if tagging_directive == TaggingDirective::Copy && tagging.is_some() {
+        pgrx::error!("tagging requires tagging_directive => 'REPLACE'");
+    }
+    let checksum_algorithm = checksum_algorithm
+        .map(|a| parse_checksum_algorithm(a).unwrap_or_else(|e| pgrx::error!("{e}")));
+    // Guards the object being replaced at the destination, not the source.
+    let if_match = if_match.map(quote_etag);
+    if sse_customer_algorithm.is_some()
+        && sse_customer_key.is_none()
+        && copy_source_sse_customer_key.is_none()
+    {
+        pgrx::error!("sse_customer_algorithm requires sse_customer_key");
+    }
+    let customer_key = |key: Option<Vec<u8>>| {
+        let algorithm = key.as_ref().and(sse_customer_algorithm);
+        sse::CustomerKey::new(key.as_deref(), algorithm).unwrap_or_else(|e| pgrx::error!("{e}"))
+    };
+    let (target_key, source_key) = (
+        customer_key(sse_customer_key),
+        customer_key(copy_source_sse_customer_key),
+    );
+    let storage_class =
+        storage_class.map(|name| parse_storage_class(name).unwrap_or_else(|e| pgrx::error!("{e}")));
+
+    let copy_source = format!(
+        "{src_bucket}/{}",
+        percent_encoding::utf8_percent_encode(src_key, URI_PATH)
+    );
+
+    run(&args, |client| {
+        let (storage_class, source_key_ref) = (storage_class.clone(), source_key.as_ref());
+        let req = client
+            .copy_object()
+            .copy_source(&copy_source)
+            .bucket(dst_bucket)
+            .key(dst_key)
+            .metadata_directive(metadata_directive.clone())
+            .set_metadata(metadata.clone())
+            .set_content_type(content_type.map(str::to_owned))
+            .tagging_directive(tagging_directive.clone())
+            .set_tagging(tagging.clone())
+            .set_checksum_algorithm(checksum_algorithm.clone())
+            .set_if_match(if_match.clone());
+        let req = match &target_key {
+            Some(key) => key.copy_to(req),
+            None => req,
+        };
+        let req = match &source_key {
+            Some(key) => key.copy_from(req),
+            None => req,
+        };
+
+        async move {
+            let storage_class = match storage_class {
+                None if preserve_storage_class => {
+                    source_storage_class(&client, src_bucket, src_key, source_key_ref).await?
+                }
+                storage_class => storage_class,
+            };
+            match req.set_storage_class(storage_class).send().await {
+                Ok(out) => {
+                    note_request_ids("CopyObject", &out);
+                    Ok(out
+                        .copy_object_result()
+                        .and_then(|r| r.e_tag())
+                        .unwrap_or_default()
+                        .trim_matches('"')
+                        .to_string())
+                }
+                Err(err) => Err(match sdk_error("CopyObject", err) {
+                    e if e.code.as_deref() == Some("PreconditionFailed") => {
+                        precondition_failed(dst_bucket, dst_key)
+                    }
+                    e => e,
+                }),
+            }
+        }
+    })
+}
+
+/// The storage class of the source of a copy, to keep it for the copy: copies
+/// are STANDARD unless told otherwise, which would take archived objects out
+/// of their archive tier.
+pub(crate) async fn source_storage_class(
+    client: &aws_sdk_s3::Client,
+    src_bucket: &str,
+    src_key: &str,
+    customer_key: Option<&sse::CustomerKey>,
+) -> Result<Option<aws_sdk_s3::types::StorageClass>, S3Error> {
+    Ok(
+        head_object_with(client, src_bucket, src_key, None, customer_key)
+            .await?
+            .and_then(|head| head.storage_class().cloned()),
+    )
+}
+
+/// Parse a storage class name such as `STANDARD_IA` or `GLACIER`.
+fn parse_storage_class(name: &str) -> Result<aws_sdk_s3::types::StorageClass, String> {
+    use aws_sdk_s3::types::StorageClass;
+
+    if !StorageClass::values().contains(&name) {
+        return Err(format!(
+            "storage_class must be one of {}, got {name:?}",
+            StorageClass::values().join(", ")
+        ));
+    }
+    Ok(StorageClass::from(name))
+}
+
+/// Like `s3_copy_object` with the default directives, having S3 compute and
+/// store a `checksum_algorithm` checksum of the copy, and returning it.
+#[pg_extern]
+fn s3_copy_object_verified(
+    src_bucket: Option<&str>,
+    src_key: &str,
+    dst_bucket: Option<&str>,
+    dst_key: &str,
+    checksum_algorithm: default!(&str, "'CRC32C'"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(etag, String),
+        name!(checksum_algorithm, String),
+        name!(checksum, String),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (src_bucket, src_key) = uri::resolve(src_bucket, src_key);
+    let (dst_bucket, dst_key) = uri::resolve(dst_bucket, dst_key);
+    let (src_bucket, src_key) = (src_bucket.as_str(), src_key.as_str());
+    let (dst_bucket, dst_key) = (dst_bucket.as_str(), dst_key.as_str());
+    let algorithm =
+        parse_checksum_algorithm(checksum_algorithm).unwrap_or_else(|e| pgrx::error!("{e}"));
+    let copy_source = format!(
+        "{src_bucket}/{}",
+        percent_encoding::utf8_percent_encode(src_key, URI_PATH)
+    );
+
+    let row = run(&args, |client| {
+        let req = client
+            .copy_object()
+            .copy_source(&copy_source)
+            .bucket(dst_bucket)
+            .key(dst_key)
+            .checksum_algorithm(algorithm.clone());
+        let algorithm = algorithm.clone();
+        async move {
+            let out = req
+                .send()
+                .await
+                .map_err(|err| sdk_error("CopyObject", err))?;
+            note_request_ids("CopyObject", &out);
+            let result = out.copy_object_result();
+            let checksum = result
+                .and_then(|r| copy_checksum(r, &algorithm))
+                .ok_or_else(|| {
+                    S3Error::new(format!(
+                        "CopyObject returned no {} checksum for s3://{dst_bucket}/{dst_key}; \
+                     the provider may not support checksums on copies",
+                        algorithm.as_str()
+                    ))
+                })?;
+            let etag = result.and_then(|r| r.e_tag()).unwrap_or_default();
+            Ok((
+                etag.trim_matches('"').to_owned(),
+                algorithm.as_str().to_owned(),
+                checksum.to_owned(),
+            ))
+        }
+    });
+    TableIterator::once(row)
+}
+
+/// Parse a checksum algorithm: `CRC32`, `CRC32C`, `CRC64NVME`, `SHA1` or `SHA256`.
+fn parse_checksum_algorithm(name: &str) -> Result<aws_sdk_s3::types::ChecksumAlgorithm, String> {
+    use aws_sdk_s3::types::ChecksumAlgorithm;
+
+    let name = name.to_ascii_uppercase();
+    if !ChecksumAlgorithm::values().contains(&name.as_str()) {
+        return Err(format!(
+            "checksum_algorithm must be one of {}, got {name:?}",
+            ChecksumAlgorithm::values().join(", ")
+        ));
+    }
+    Ok(ChecksumAlgorithm::from(name.as_str()))
+}
+
+/// The `algorithm` checksum S3 computed for a copy, if it returned one.
+fn copy_checksum<'a>(
+    result: &'a aws_sdk_s3::types::CopyObjectResult,
+    algorithm: &aws_sdk_s3::types::ChecksumAlgorithm,
+) -> Option<&'a str> {
+    use aws_sdk_s3::types::ChecksumAlgorithm;
+
+    match algorithm {
+        ChecksumAlgorithm::Crc32 => result.checksum_crc32(),
+        ChecksumAlgorithm::Crc32C => result.checksum_crc32_c(),
+        ChecksumAlgorithm::Crc64Nvme => result.checksum_crc64_nvme(),
+        ChecksumAlgorithm::Sha1 => result.checksum_sha1(),
+        ChecksumAlgorithm::Sha256 => result.checksum_sha256(),
+        _ => None,
+    }
+}
+
+/// Change an object's content type, user metadata or cache control in place
+/// and return its new ETag.
+///
+/// S3 cannot edit metadata, so the object is copied onto itself with the
+/// REPLACE directive; the body is not downloaded. Everything not given is
+/// carried over from the current object: the other headers, `metadata` (pass
+/// `'{}'` to remove it), the storage class, server-side encryption and, unless
+/// `tagging` is given, the tags. If the object changes during the update, it
+/// fails with a `serialization_failure`.
+#[pg_extern]
+fn s3_update_object_metadata(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    content_type: default!(Option<&str>, "NULL"),
+    metadata: default!(Option<pgrx::JsonB>, "NULL"),
+    cache_control: default!(Option<&str>, "NULL"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    storage_class: default!(Option<&str>, "NULL"),
+    tagging: default!(Option<pgrx::JsonB>, "NULL"),
+) -> String {
+    use aws_sdk_s3::types::{MetadataDirective, TaggingDirective};
+
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let metadata =
+        metadata.map(|m| string_map(&m.0, "metadata").unwrap_or_else(|e| pgrx::error!("{e}")));
+    let storage_class =
+        storage_class.map(|name| parse_storage_class(name).unwrap_or_else(|e| pgrx::error!("{e}")));
+    let tagging = tagging.map(|t| {
+        let tags = string_map(&t.0, "tagging").unwrap_or_else(|e| pgrx::error!("{e}"));
+        encode_tagging(&tags)
+    });
+    let copy_source = format!(
+        "{bucket}/{}",
+        percent_encoding::utf8_percent_encode(object_key, URI_PATH)
+    );
+
+    run(&args, |client| {
+        let (metadata, storage_class, tagging) =
+            (metadata.clone(), storage_class.clone(), tagging.clone());
+        let copy_source = copy_source.clone();
+        async move {
+            let head = head_object(&client, bucket, object_key, None)
+                .await?
+                .ok_or_else(|| S3Error {
+                    code: Some("NoSuchKey".to_owned()),
+                    ..S3Error::new(format!("s3://{bucket}/{object_key} does not exist"))
+                })?;
+            let tagging_directive = match tagging {
+                Some(_) => TaggingDirective::Replace,
+                None => TaggingDirective::Copy,
+            };
+            let out = client
+                .copy_object()
+                .copy_source(copy_source)
+                .set_copy_source_if_match(head.e_tag().map(str::to_owned))
+                .bucket(bucket)
+                .key(object_key)
+                .metadata_directive(MetadataDirective::Replace)
+                .set_metadata(metadata.or_else(|| head.metadata().cloned()))
+                .set_content_type(content_type.or(head.content_type()).map(str::to_owned))
+                .set_cache_control(cache_control.or(head.cache_control()).map(str::to_owned))
+                .set_content_disposition(head.content_disposition().map(str::to_owned))
+                .set_content_encoding(head.content_encoding().map(str::to_owned))
+                .set_content_language(head.content_language().map(str::to_owned))
+                .set_website_redirect_location(head.website_redirect_location().map(str::to_owned))
+                .set_storage_class(storage_class.or_else(|| head.storage_class().cloned()))
+                .set_server_side_encryption(head.server_side_encryption().cloned())
+                .set_ssekms_key_id(head.ssekms_key_id().map(str::to_owned))
+                .set_bucket_key_enabled(head.bucket_key_enabled())
+                .tagging_directive(tagging_directive)
+                .set_tagging(tagging)
+                .send()
+                .await
+                .map_err(|err| match sdk_error("CopyObject", err) {
+                    e if e.code.as_deref() == Some("PreconditionFailed") => {
+                        precondition_failed(bucket, object_key)
+                    }
+                    e => e,
+                })?;
+            note_request_ids("CopyObject", &out);
+            Ok(out
+                .copy_object_result()
+                .and_then(|r| r.e_tag())
+                .unwrap_or_default()
+                .trim_matches('"')
+                .to_string())
+        }
+    })
+}
+
+/// Unsigned URL of an object, for buckets that allow public reads.
+#[pg_extern]
+fn s3_object_url(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> String {
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let path_style = force_path_style.unwrap_or_else(|| guc::FORCE_PATH_STYLE.get());
+    let endpoint = endpoint(endpoint_url).unwrap_or_else(|| AWS_GLOBAL_ENDPOINT.to_owned());
+    object_url(&endpoint, bucket, object_key, path_style)
+}
+
+/// Endpoint of AWS S3 that serves buckets of all regions.
+const AWS_GLOBAL_ENDPOINT: &str = "https://s3.amazonaws.com";
+
+/// Format the URL of `key` in `bucket`, path-style or virtual-hosted-style.
+fn object_url(endpoint: &str, bucket: &str, key: &str, path_style: bool) -> String {
+    let key = percent_encoding::utf8_percent_encode(key, URI_PATH);
+    if path_style {
+        return format!("{endpoint}/{bucket}/{key}");
+    }
+    let (scheme, host) = endpoint.split_once("://").unwrap_or(("https", endpoint));
+    format!("{scheme}://{bucket}.{host}/{key}")
+}
+
+/// The endpoint argument, falling back to `S3_ENDPOINT_URL`.
+///
+/// `None` means AWS, where the SDK resolves the endpoint of the region.
+fn endpoint(endpoint_url: Option<&str>) -> Option<String> {
+    let ep = match endpoint_url {
+        Some(ep) => ep.to_owned(),
+        None => std::env::var("S3_ENDPOINT_URL").ok()?,
+    };
+    Some(normalize_endpoint(&ep).unwrap_or_else(|e| pgrx::error!("{e}")))
+}
+
+#[derive(Clone, Eq, PartialEq, Hash)]
+struct ClientKey {
+    endpoint_url: Option<String>,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+    region: String,
+    force_path_style: bool,
+    app_name: Option<String>,
+    signing_name: Option<String>,
+    pool: PoolSettings,
+    retry_mode: guc::RetryMode,
+    anonymous: bool,
+    /// Hash of the `s3_client_from_config` config, which may set more than the above.
+    config: Option<String>,
+}
+
+impl ClientKey {
+    fn new(
+        endpoint_url: Option<&str>,
+        access_key: &str,
+        secret_key: &str,
+        session_token: Option<&str>,
+        region: &str,
+        force_path_style: bool,
+        app_name: Option<&str>,
+        signing_name: Option<&str>,
+        pool: PoolSettings,
+        retry_mode: guc::RetryMode,
+        anonymous: bool,
+        config: Option<String>,
+    ) -> Self {
+        Self {
+            endpoint_url: endpoint_url.map(str::to_owned),
+            access_key: access_key.to_owned(),
+            secret_key: secret_key.to_owned(),
+            session_token: session_token.map(str::to_owned),
+            region: region.to_owned(),
+            force_path_style,
+            app_name: app_name.map(str::to_owned),
+            signing_name: signing_name.map(str::to_owned),
+            pool,
+            retry_mode,
+            anonymous,
+            config,
+        }
+    }
+}
+
+/// Keep-alive policy of the connection pool of a client's HTTP connector.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+struct PoolSettings {
+    /// How long an idle connection is kept for reuse; `None` keeps it forever.
+    idle_timeout: Option<Duration>,
+    /// Idle connections kept per host; `None` means no limit.
+    max_idle_per_host: Option<usize>,
+}
+
+impl PoolSettings {
+    fn from_gucs() -> Self {
+        let idle_timeout = guc::POOL_IDLE_TIMEOUT.get();
+        let max_idle = guc::POOL_MAX_IDLE_PER_HOST.get();
+        Self {
+            idle_timeout: (idle_timeout > 0).then(|| Duration::from_secs(idle_timeout as u64)),
+            max_idle_per_host: (max_idle >= 0).then_some(max_idle as usize),
+        }
+    }
+
+    /// The SDK's default HTTPS client (rustls, `proxy` or else the proxy from
+    /// the environment) with this pool policy.
+    fn http_client(
+        self,
+        proxy: Option<&str>,
+    ) -> aws_smithy_runtime_api::client::http::SharedHttpClient {
+        use aws_smithy_http_client::{proxy::ProxyConfig, tls, Builder, ConnectorBuilder};
+
+        let proxy = match proxy {
+            Some(url) => ProxyConfig::all(url).unwrap_or_else(|e| pgrx::error!("{e}")),
+            None => ProxyConfig::from_env(),
+        };
+        Builder::new().build_with_connector_fn(move |settings, runtime_components| {
+            let mut conn = ConnectorBuilder::default()
+                .pool_idle_timeout(self.idle_timeout)
+                .tls_provider(tls::Provider::Rustls(
+                    tls::rustls_provider::CryptoMode::AwsLc,
+                ));
+            conn.set_pool_max_idle_per_host(self.max_idle_per_host);
+            conn.set_connector_settings(settings.cloned());
+            if let Some(components) = runtime_components {
+                conn.set_sleep_impl(components.sleep_impl());
+            }
+            conn.set_proxy_config(Some(proxy.clone()));
+            conn.build()
+        })
+    }
+}
+
+struct CachedClient {
+    client: aws_sdk_s3::Client,
+    created: Instant,
+    last_used: Instant,
+}
+
+/// Session-lifetime S3 clients, built on first use per endpoint and credentials.
+fn clients() -> &'static Mutex<HashMap<ClientKey, CachedClient>> {
+    static S3_CLIENTS: OnceLock<Mutex<HashMap<ClientKey, CachedClient>>> = OnceLock::new();
+    S3_CLIENTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Drop all cached clients, returning how many there were.
+#[pg_extern]
+fn s3_reset_clients() -> i32 {
+    let mut clients = clients().lock().unwrap();
+    let n = clients.len();
+    clients.clear();
+    n as i32
+}
+
+/// Make the next S3 call resolve its credentials again, e.g. after rotating
+/// temporary credentials; returns whether any cached client was dropped.
+///
+/// Clients keep the credentials they were built with until `s3_io.client_ttl`
+/// expires them; this drops them all at once.
+#[pg_extern]
+fn s3_refresh_credentials() -> bool {
+    s3_reset_clients() > 0
+}
+
+/// The cached client for `args`, built on first use; an `anonymous` client
+/// sends unsigned requests and is cached apart from signing ones.
+fn client_for(args: &ClientArgs, anonymous: bool) -> aws_sdk_s3::Client {
+    client_with(args, client_config::current().as_ref(), anonymous)
+}
+
+/// Like [`client_for`], with `config` for the connection arguments `args`
+/// leaves out and for the settings only a config has.
+fn client_with(
+    args: &ClientArgs,
+    config: Option<&client_config::ClientConfig>,
+    anonymous: bool,
+) -> aws_sdk_s3::Client {
+    let from_config =
+        |field: fn(&client_config::ClientConfig) -> Option<&str>| config.and_then(field);
+    let ep = endpoint(
+        args.endpoint_url
+            .or(from_config(|c| c.endpoint_url.as_deref())),
+    );
+    let (ak, sk, st) = if anonymous {
+        (String::new(), String::new(), None)
+    } else {
+        let ak = args
+            .access_key
+            .or(from_config(|c| c.access_key.as_deref()))
+            .map(str::to_owned)
+            .unwrap_or_else(|| {
+                std::env::var("AWS_ACCESS_KEY_ID")
+                    .unwrap_or_else(|_| pgrx::error!("AWS_ACCESS_KEY_ID not set"))
+            });
+        let sk = args
+            .secret_key
+            .or(from_config(|c| c.secret_key.as_deref()))
+            .map(str::to_owned)
+            .unwrap_or_else(|| {
+                std::env::var("AWS_SECRET_ACCESS_KEY")
+                    .unwrap_or_else(|_| pgrx::error!("AWS_SECRET_ACCESS_KEY not set"))
+            });
+        let st = args
+            .session_token
+            .or(from_config(|c| c.session_token.as_deref()))
+            .map(|x| x.to_string())
+            .or(std::env::var("AWS_SESSION_TOKEN").ok());
+        (ak, sk, st)
+    };
+    let region = args.region.or(from_config(|c| Some(c.region.as_str())));
+    if region.is_none() && guc::REQUIRE_REGION.get() {
+        pgrx::error!("No region given, and s3_io.require_region is on");
+    }
+    let rg = region.unwrap_or("us-east-1").to_string();
+    let path_style = args
+        .force_path_style
+        .or(config.and_then(|c| c.force_path_style))
+        .unwrap_or_else(|| guc::FORCE_PATH_STYLE.get());
+
+    let app_name = guc::USER_AGENT_SUFFIX
+        .get()
+        .map(|s| s.to_string_lossy().into_owned())
+        .filter(|s| !s.is_empty());
+    // Validated before taking the cache lock, which an error would poison.
+    let valid_app_name = app_name.clone().map(|name| {
+        aws_types::app_name::AppName::new(name).unwrap_or_else(|_| {
+            pgrx::error!(
+                "s3_io.user_agent_suffix may only contain letters, digits and !#$%&'*+-.^_`|~"
+            )
+        })
+    });
+    let signing_name = guc::SIGNING_NAME
+        .get()
+        .map(|s| s.to_string_lossy().into_owned())
+        .filter(|s| !s.is_empty());
+    let pool = PoolSettings::from_gucs();
+    let retry_mode = guc::RETRY_MODE.get();
+
+    let client_key = ClientKey::new(
+        ep.as_deref(),
+        &ak,
+        &sk,
+        st.as_deref(),
+        &rg,
+        path_style,
+        app_name.as_deref(),
+        signing_name.as_deref(),
+        pool,
+        retry_mode,
+        anonymous,
+        config.map(client_config::ClientConfig::hash),
+    );
+
+    let ttl = Duration::from_secs(guc::CLIENT_TTL.get() as u64);
+    let max_clients = guc::CLIENT_CACHE_SIZE.get() as usize;
+    let now = Instant::now();
+
+    {
+        let mut clients = clients().lock().unwrap();
+        if !ttl.is_zero() {
+            // Rebuild expired clients, e.g. ones holding expired STS credentials.
+            clients.retain(|_, c| now.duration_since(c.created) < ttl);
+        }
+        if let Some(cached) = clients.get_mut(&client_key) {
+            cached.last_used = now;
+            return cached.client.clone();
+        }
+    }
+
+    // Built without holding the cache lock, so an error raised meanwhile
+    // cannot poison it.
+    let client = rt().block_on(async {
+        use aws_config::meta::region::RegionProviderChain;
+        use aws_config::BehaviorVersion;
+        use aws_credential_types::Credentials;
+        use aws_sdk_s3::{
+            config::{Builder, SharedCredentialsProvider},
+            Client,
+        };
+        use aws_types::region::Region;
+
+        let region_provider = RegionProviderChain::first_try(Region::new(rg))
+            .or_default_provider()
+            .or_else(Region::new("us-east-1"));
+
+        let base = aws_config::defaults(BehaviorVersion::latest())
+            .region(region_provider)
+            .load()
+            .await;
+
+        let mut cfg = Builder::from(&base)
+            .force_path_style(path_style)
+            .http_client(pool.http_client(from_config(|c| c.proxy.as_deref())))
+            .interceptor(retries::RetryLog)
+            .interceptor(stall::ProgressLog)
+            .interceptor(audit::AuditLog)
+            // Access point ARNs may name another region than the client's.
+            .use_arn_region(true);
+        cfg = match signing_name {
+            Some(name) => cfg.endpoint_resolver(signing::SigningNameResolver::new(
+                name,
+                arn::ArnAwareResolver::default(),
+            )),
+            None => cfg.endpoint_resolver(arn::ArnAwareResolver::default()),
+        };
+        if let Some(app_name) = valid_app_name {
+            cfg = cfg.app_name(app_name);
+        }
+        if let Some(config) = config {
+            let mut timeouts = aws_sdk_s3::config::timeout::TimeoutConfig::builder();
+            if let Some(timeout) = config.connect_timeout {
+                timeouts = timeouts.connect_timeout(timeout);
+            }
+            if let Some(timeout) = config.read_timeout {
+                timeouts = timeouts.read_timeout(timeout);
+            }
+            if let Some(timeout) = config.operation_timeout {
+                timeouts = timeouts.operation_timeout(timeout);
+            }
+            // Timeouts the config leaves out keep the SDK's defaults.
+            if let Some(defaults) = base.timeout_config() {
+                timeouts = timeouts.take_unset_from(defaults.to_builder());
+            }
+            cfg = cfg.timeout_config(timeouts.build());
+        }
+        let mut retry = base
+            .retry_config()
+            .cloned()
+            .unwrap_or_else(aws_sdk_s3::config::retry::RetryConfig::standard)
+            .with_retry_mode(retry_mode.into());
+        if let Some(max_attempts) = config.and_then(|c| c.max_attempts) {
+            retry = retry.with_max_attempts(max_attempts);
+        }
+        cfg = cfg.retry_config(retry);
+        if let Some(ep) = ep {
+            cfg = cfg.endpoint_url(ep);
+        }
+
+        if anonymous {
+            cfg.set_credentials_provider(None);
+            cfg = cfg.allow_no_auth();
+        } else {
+            let creds = Credentials::from_keys(ak, sk, st);
+            cfg = cfg.credentials_provider(SharedCredentialsProvider::new(creds));
+        }
+
+        Client::from_conf(cfg.build())
+    });
+
+    let mut clients = clients().lock().unwrap();
+    if max_clients > 0 {
+        while clients.len() >= max_clients {
+            let lru = clients
+                .iter()
+                .min_by_key(|(_, c)| c.last_used)
+                .map(|(k, _)| k.clone());
+            match lru {
+                Some(k) => clients.remove(&k),
+                None => break,
+            };
+        }
+    }
+    clients.insert(
+        client_key,
+        CachedClient {
+            client: client.clone(),
+            created: now,
+            last_used: now,
+        },
+    );
+    client
+}
+
+/// Seconds from the Unix epoch to the Postgres epoch, 2000-01-01 UTC.
+const PG_EPOCH_OFFSET_SECS: i64 = 946_684_800;
+
+/// Convert an S3 timestamp to `timestamptz` (microseconds since 2000-01-01 UTC).
+fn to_timestamptz(dt: &aws_smithy_types::DateTime) -> Option<TimestampWithTimeZone> {
+    let micros = (dt.secs() - PG_EPOCH_OFFSET_SECS)
+        .checked_mul(1_000_000)?
+        .checked_add(i64::from(dt.subsec_nanos() / 1_000))?;
+    TimestampWithTimeZone::try_from(micros).ok()
+}
+
+/// Characters escaped in URL components: everything but RFC 3986 unreserved characters.
+const URI_COMPONENT: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Like [`URI_COMPONENT`], but keeps `/` so object keys stay readable paths.
+const URI_PATH: &percent_encoding::AsciiSet = &URI_COMPONENT.remove(b'/');
+
+/// Read a flat JSON object of string values, as used for metadata and tags.
+fn string_map(value: &serde_json::Value, what: &str) -> Result<HashMap<String, String>, String> {
+    let obj = value
+        .as_object()
+        .ok_or_else(|| format!("{what} must be a JSON object"))?;
+    obj.iter()
+        .map(|(k, v)| match v {
+            serde_json::Value::String(s) => Ok((k.clone(), s.clone())),
+            _ => Err(format!("{what} value for {k:?} must be a string")),
+        })
+        .collect()
+}
+
+/// Encode tags the way S3 expects them in `x-amz-tagging`: as URL query parameters.
+fn encode_tagging(tags: &HashMap<String, String>) -> String {
+    let mut pairs: Vec<_> = tags.iter().collect();
+    pairs.sort();
+    pairs
+        .into_iter()
+        .map(|(k, v)| {
+            format!(
+                "{}={}",
+                percent_encoding::utf8_percent_encode(k, URI_COMPONENT),
+                percent_encoding::utf8_percent_encode(v, URI_COMPONENT)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Add `https://` to a schemeless endpoint and strip trailing slashes, which
+/// would otherwise end up in request paths and break signatures.
+fn normalize_endpoint(ep: &str) -> Result<String, String> {
+    let trimmed = ep.trim().trim_end_matches('/');
+    let ep = if trimmed.contains("://") {
+        trimmed.to_string()
+    } else {
+        format!("https://{trimmed}")
+    };
+
+    let url = url::Url::parse(&ep).map_err(|e| format!("Invalid endpoint URL {ep:?}: {e}"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!(
+            "Invalid endpoint URL {ep:?}: scheme must be http or https"
+        ));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err(format!("Invalid endpoint URL {ep:?}: missing host"));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(format!(
+            "Invalid endpoint URL {ep:?}: must not have a query or fragment"
+        ));
+    }
+    Ok(ep)
+}
+
+mod testutils;
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::testutils::minio_test::MinioServer;
+    use pgrx::prelude::*;
+
+    fn put(bucket: &str, key: &str, data: &[u8]) -> String {
+        crate::s3_put_object(
+            Some(bucket),
+            key,
+            data.to_vec(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+        )
+        .unwrap()
+    }
+
+    fn get(bucket: &str, key: &str) -> Vec<u8> {
+        crate::s3_get_object(
+            Some(bucket),
+            key,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "metadata",
+            None,
+            None,
+            false,
+            false,
+            None,
+            None,
+        )
+    }
+
+    #[pg_test]
+    fn exists_true_and_false() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "test-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "hello.txt", "Hi".as_bytes());
+        assert!(crate::s3_object_exists_lazy(
+            Some(bucket),
+            "hello.txt",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false
+        ));
+        assert!(!crate::s3_object_exists_lazy(
+            Some(bucket),
+            "nope.txt",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false
+        ));
+
+        log!("tests done");
+    }
+
+    #[pg_test]
+    fn create_bucket() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "tbk";
+        assert!(crate::s3_create_bucket(
+            bucket, None, None, None, None, None, None
+        ));
+    }
+
+    /// Peak resident set size of this backend in bytes, from `/proc`.
+    fn peak_rss() -> u64 {
+        let status = std::fs::read_to_string("/proc/self/status").expect("status");
+        let kib = status
+            .lines()
+            .find_map(|l| l.strip_prefix("VmHWM:"))
+            .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .expect("VmHWM");
+        kib * 1024
+    }
+
+    #[pg_test]
+    fn put_object_from_file_streams_in_bounded_memory() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "file-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+
+        // A sparse file keeps the test cheap on disk; the file is still read
+        // in full, so buffering it would show in the peak RSS.
+        let size: u64 = 256 * 1024 * 1024;
+        let file = tempfile::NamedTempFile::new().expect("temp file");
+        file.as_file().set_len(size).expect("set_len");
+        let path = file.path().to_str().unwrap();
+        let dir = file.path().parent().unwrap().to_str().unwrap();
+        Spi::run(&format!("SET s3_io.upload_paths = '{dir}'")).unwrap();
+
+        // Reset the peak to the current RSS (Linux clear_refs "5").
+        std::fs::write("/proc/self/clear_refs", "5").expect("clear_refs");
+        let before = peak_rss();
+        let etag = crate::s3_put_object_from_file(
+            Some(bucket),
+            "big.bin",
+            path,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert!(!etag.is_empty());
+        let grown = peak_rss().saturating_sub(before);
+        assert!(
+            grown < size / 4,
+            "uploading {size} bytes grew the peak RSS by {grown} bytes"
+        );
+
+        // Hash the object as it streams back instead of buffering all of it.
+        use sha2::{Digest, Sha256};
+        let client = crate::client_for(&crate::ClientArgs::default(), false);
+        let (len, digest) = crate::rt().block_on(async {
+            let mut body = client
+                .get_object()
+                .bucket(bucket)
+                .key("big.bin")
+                .send()
+                .await
+                .expect("get")
+                .body;
+            let (mut len, mut hasher) = (0, Sha256::new());
+            while let Some(chunk) = body.try_next().await.expect("read") {
+                len += chunk.len() as u64;
+                hasher.update(&chunk);
+            }
+            (len, hasher.finalize())
+        });
+        let zeros = vec![0u8; 1024 * 1024];
+        let mut expected = Sha256::new();
+        for _ in 0..size / zeros.len() as u64 {
+            expected.update(&zeros);
+        }
+        assert_eq!(len, size);
+        assert_eq!(digest, expected.finalize());
+    }
+
+    #[pg_test(error = "\"/etc/passwd\" is not inside a directory listed in s3_io.upload_paths")]
+    fn put_object_from_file_needs_upload_paths() {
+        crate::s3_put_object_from_file(
+            Some("b"),
+            "passwd",
+            "/etc/passwd",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[pg_test]
+    fn copy_object_directives() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "copy-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "src.txt", "Hi".as_bytes());
+
+        let tags = pgrx::JsonB(serde_json::json!({"cost-center": "data eng"}));
+        let meta = pgrx::JsonB(serde_json::json!({"origin": "test"}));
+        crate::s3_copy_object(
+            Some(bucket),
+            "src.txt",
+            Some(bucket),
+            "tagged.txt",
+            None,
+            None,
+            None,
+            None,
+            None,
+            "REPLACE",
+            Some(meta),
+            Some("text/plain"),
+            "REPLACE",
+            Some(tags),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            true,
+        );
+        // Defaults carry tags and metadata over from the source.
+        crate::s3_copy_object(
+            Some(bucket),
+            "tagged.txt",
+            Some(bucket),
+            "copied.txt",
+            None,
+            None,
+            None,
+            None,
+            None,
+            "COPY",
+            None,
+            None,
+            "COPY",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            true,
+        );
+
+        let client = crate::client_for(&crate::ClientArgs::default(), false);
+        crate::rt().block_on(async {
+            let tagging = client
+                .get_object_tagging()
+                .bucket(bucket)
+                .key("copied.txt")
+                .send()
+                .await
+                .expect("get tagging");
+            let tag = &tagging.tag_set()[0];
+            assert_eq!((tag.key(), tag.value()), ("cost-center", "data eng"));
+
+            let head = client
+                .head_object()
+                .bucket(bucket)
+                .key("copied.txt")
+                .send()
+                .await
+                .expect("head");
+            assert_eq!(head.content_type(), Some("text/plain"));
+            assert_eq!(
+                head.metadata()
+                    .and_then(|m| m.get("origin"))
+                    .map(String::as_str),
+                Some("test")
+            );
+        });
+    }
+
+    #[pg_test]
+    fn head_object_version() {
+        use aws_sdk_s3::types::{BucketVersioningStatus, VersioningConfiguration};
+
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "versioned-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let client = crate::client_for(&crate::ClientArgs::default(), false);
+        crate::rt()
+            .block_on(
+                client
+                    .put_bucket_versioning()
+                    .bucket(bucket)
+                    .versioning_configuration(
+                        VersioningConfiguration::builder()
+                            .status(BucketVersioningStatus::Enabled)
+                            .build(),
+                    )
+                    .send(),
+            )
+            .expect("enable versioning");
+
+        put(bucket, "v.txt", "one".as_bytes());
+        put(bucket, "v.txt", "two".as_bytes());
+
+        let rows: Vec<_> = crate::s3_head_object_version(
+            Some(bucket),
+            "v.txt",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .collect();
+        assert_eq!(rows.len(), 1);
+        let (version_id, content_length, _, etag, last_modified, .., replication_status) = &rows[0];
+        assert!(version_id.as_deref().is_some_and(|v| v != "null"));
+        assert_eq!(*content_length, Some(3));
+        assert!(etag.is_some());
+        assert!(last_modified.is_some());
+        // No replication rule covers the bucket.
+        assert_eq!(*replication_status, None);
+
+        let missing = crate::s3_head_object_version(
+            Some(bucket),
+            "nope.txt",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(missing.count(), 0);
+    }
+
+    #[pg_test]
+    fn multipart_streaming_upload() {
+        use crate::multipart::*;
+
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "stream-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+
+        let upload_id = s3_multipart_begin(
+            Some(bucket),
+            "stream.bin",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let chunk = vec![7u8; 3 * 1024 * 1024];
+        assert_eq!(s3_multipart_upload_part(&upload_id, &chunk), 0);
+        assert_eq!(s3_multipart_upload_part(&upload_id, &chunk), 1);
+        assert_eq!(s3_multipart_upload_part(&upload_id, b"tail"), 1);
+        let etag = s3_multipart_complete(&upload_id);
+        assert!(etag.ends_with("-2"));
+
+        let data = get(bucket, "stream.bin");
+        assert_eq!(data.len(), 2 * chunk.len() + 4);
+        assert!(data.ends_with(b"tail"));
+    }
+
+    #[pg_test]
+    fn multipart_upload_survives_errors_midway() {
+        use crate::multipart::*;
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "stream-cancel";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let upload_id = s3_multipart_begin(
+            Some(bucket),
+            "cancel.bin",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(s3_multipart_upload_part(&upload_id, b"abc"), 0);
+
+        // An error raised while the upload is checked out, as a cancel or
+        // statement_timeout inside a request would, keeps it registered.
+        PgTryBuilder::new(|| {
+            let mut upload = take_upload(&upload_id);
+            upload.buffer.extend_from_slice(b"def");
+            pgrx::error!("canceling statement due to user request");
+        })
+        .catch_others(|_| ())
+        .execute();
+        assert_eq!(uploads().lock().unwrap()[&upload_id].buffer, b"abcdef");
+
+        assert!(s3_multipart_complete(&upload_id).ends_with("-1"));
+        assert!(!uploads().lock().unwrap().contains_key(&upload_id));
+        assert_eq!(get(bucket, "cancel.bin"), b"abcdef");
+    }
+
+    #[pg_test]
+    fn multipart_low_level_api() {
+        use crate::multipart::*;
+
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "mpu-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+
+        let upload_id = s3_create_multipart_upload(
+            Some(bucket),
+            "mpu.bin",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let first = s3_upload_part(
+            Some(bucket),
+            "mpu.bin",
+            &upload_id,
+            1,
+            vec![1u8; MIN_PART_SIZE],
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let last = s3_upload_part(
+            Some(bucket),
+            "mpu.bin",
+            &upload_id,
+            2,
+            b"end".to_vec(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let etag = s3_complete_multipart_upload(
+            Some(bucket),
+            "mpu.bin",
+            &upload_id,
+            vec![first, last],
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert!(etag.ends_with("-2"));
+
+        let data = get(bucket, "mpu.bin");
+        assert_eq!(data.len(), MIN_PART_SIZE + 3);
+    }
+
+    #[pg_test]
+    fn compression_round_trip() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "test-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let data = "compress me ".repeat(1000).into_bytes();
+        for codec in ["gzip", "zstd"] {
+            let key = format!("data.{codec}");
+            crate::s3_put_object(
+                Some(bucket),
+                &key,
+                data.clone(),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(codec),
+                Some(3),
+                None,
+                None,
+                None,
+                None,
+                None,
+                false,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                false,
+            );
+            assert_eq!(get(bucket, &key), data);
+
+            let raw = crate::s3_get_object(
+                Some(bucket),
+                &key,
+                None,
+                None,
+                None,
+                None,
+                None,
+                "none",
+                None,
+                None,
+                false,
+                false,
+                None,
+                None,
+            );
+            assert!(raw.len() < data.len());
+            assert_eq!(
+                crate::Codec::parse(codec)
+                    .unwrap()
+                    .decompress(&raw)
+                    .unwrap(),
+                data
+            );
+        }
+    }
+
+    #[pg_test]
+    fn compressed_uploads_keep_content_type() {
+        use crate::Codec;
+
+        assert_eq!(Codec::Gzip.content_encoding(Some("text/csv")), Some("gzip"));
+        assert_eq!(Codec::Zstd.content_encoding(None), Some("zstd"));
+        assert_eq!(Codec::Gzip.content_encoding(Some("application/gzip")), None);
+        assert_eq!(
+            Codec::Gzip.content_encoding(Some("Application/X-Gzip; charset=binary")),
+            None
+        );
+        assert_eq!(
+            Codec::Zstd.content_encoding(Some("application/gzip")),
+            Some("zstd")
+        );
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "content-encoding";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let csv = "id,name\n1,a\n".repeat(100);
+        Spi::run(&format!(
+            "SELECT s3_put_object_text('{bucket}', 'served.csv', '{csv}', \
+                                       content_type => 'text/csv', compress => 'gzip')"
+        ))
+        .unwrap();
+        Spi::run(&format!(
+            "SELECT s3_put_object_text('{bucket}', 'download.csv.gz', '{csv}', \
+                                       content_type => 'application/gzip', compress => 'gzip')"
+        ))
+        .unwrap();
+
+        let client = crate::client_for(&crate::ClientArgs::default(), false);
+        let head = |key| {
+            let head = crate::rt()
+                .block_on(client.head_object().bucket(bucket).key(key).send())
+                .expect("head");
+            (
+                head.content_type().map(str::to_owned),
+                head.content_encoding().map(str::to_owned),
+            )
+        };
+        assert_eq!(
+            head("served.csv"),
+            (Some("text/csv".to_owned()), Some("gzip".to_owned()))
+        );
+        assert_eq!(
+            head("download.csv.gz"),
+            (Some("application/gzip".to_owned()), None)
+        );
+        assert_eq!(get(bucket, "served.csv"), csv.as_bytes());
+        assert_eq!(get(bucket, "download.csv.gz"), csv.as_bytes());
+    }
+
+    #[pg_test]
+    fn auto_decompress_sniffs_gzip() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "auto-decompress";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+
+        let data = b"legacy,gzip,without,header\n".repeat(50);
+        let gz = crate::Codec::Gzip.compress(&data, None).unwrap();
+        put(bucket, "legacy.csv", &gz);
+        put(bucket, "plain.csv", &data);
+
+        let auto = |key| {
+            crate::s3_get_object(
+                Some(bucket),
+                key,
+                None,
+                None,
+                None,
+                None,
+                None,
+                "auto",
+                None,
+                None,
+                false,
+                false,
+                None,
+                None,
+            )
+        };
+        assert_eq!(get(bucket, "legacy.csv"), gz);
+        assert_eq!(auto("legacy.csv"), data);
+        assert_eq!(auto("plain.csv"), data);
+    }
+
+    #[pg_test(error = "canceling statement due to statement timeout")]
+    fn statement_timeout_cancels_hung_request() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        // Accepts connections but never answers.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        Spi::run("SET statement_timeout = '500ms'").unwrap();
+        crate::s3_object_exists_lazy(
+            Some("b"),
+            "k",
+            Some(&endpoint),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+        );
+    }
+
+    #[pg_test]
+    fn object_url_styles() {
+        assert_eq!(
+            crate::s3_object_url(
+                Some("bucket"),
+                "dir/a b.txt",
+                Some("http://127.0.0.1:9000/"),
+                None
+            ),
+            "http://127.0.0.1:9000/bucket/dir/a%20b.txt"
+        );
+        assert_eq!(
+            crate::object_url("https://s3.eu-west-1.amazonaws.com", "bucket", "k+1", false),
+            "https://bucket.s3.eu-west-1.amazonaws.com/k%2B1"
+        );
+    }
+
+    #[pg_test]
+    fn peek_text_reads_prefix() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "test-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "data.csv", "größe;wert\n1;2\n".as_bytes());
+        put(bucket, "empty.csv", b"");
+
+        let peek = |key, max_bytes| {
+            crate::s3_peek_text(
+                Some(bucket),
+                key,
+                max_bytes,
+                "UTF8",
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+        // "ö" is two bytes; a cut through it is dropped.
+        assert_eq!(peek("data.csv", 3), "gr");
+        assert_eq!(peek("data.csv", 4), "grö");
+        assert_eq!(peek("data.csv", 1000), "größe;wert\n1;2\n");
+        assert_eq!(peek("empty.csv", 10), "");
+    }
+
+    #[pg_test]
+    fn client_cache_is_bounded() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        crate::s3_reset_clients();
+        Spi::run("SET s3_io.client_cache_size = 2").unwrap();
+        for region in ["eu-west-1", "eu-west-2", "eu-west-3"] {
+            crate::client_for(
+                &crate::ClientArgs {
+                    region: Some(region),
+                    ..Default::default()
+                },
+                false,
+            );
+        }
+        assert_eq!(crate::s3_reset_clients(), 2);
+        assert_eq!(crate::s3_reset_clients(), 0);
+    }
+
+    #[pg_test]
+    fn path_style_per_call() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        crate::s3_reset_clients();
+        Spi::run("SET s3_io.force_path_style = off").unwrap();
+        for force_path_style in [Some(true), None] {
+            crate::client_for(
+                &crate::ClientArgs {
+                    force_path_style,
+                    ..Default::default()
+                },
+                false,
+            );
+        }
+        assert_eq!(crate::s3_reset_clients(), 2);
+
+        assert_eq!(
+            crate::s3_object_url(Some("b"), "k", Some("https://s3.example.com"), None),
+            "https://b.s3.example.com/k"
+        );
+        assert_eq!(
+            crate::s3_object_url(Some("b"), "k", Some("https://s3.example.com"), Some(true)),
+            "https://s3.example.com/b/k"
+        );
+    }
+
+    #[pg_test]
+    fn list_objects_with_owner() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "test-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "l/one.txt", b"1");
+        put(bucket, "l/two.txt", b"22");
+        put(bucket, "other.txt", b"3");
+
+        let list = |fetch_owner| -> Vec<_> {
+            crate::list::s3_list_objects(
+                Some(bucket),
+                "l/",
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                fetch_owner,
+                false,
+                None,
+            )
+            .collect()
+        };
+        let rows = list(false);
+        let keys: Vec<_> = rows.iter().map(|r| (r.0.as_str(), r.1)).collect();
+        assert_eq!(keys, [("l/one.txt", Some(1)), ("l/two.txt", Some(2))]);
+        assert!(rows.iter().all(|r| r.5.is_none()));
+
+        assert!(list(true).iter().all(|r| r.5.is_some()));
+    }
+
+    #[pg_test]
+    fn list_objects_with_metadata() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "manifest";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "plain.txt", b"1");
+        crate::s3_put_object(
+            Some(bucket),
+            "packed.txt",
+            b"2".to_vec(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some("gzip"),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+        );
+
+        let rows: Vec<_> = crate::list::s3_list_objects(
+            Some(bucket),
+            "",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+            true,
+            None,
+        )
+        .map(|r| (r.0, r.7.map(|m| m.0)))
+        .collect();
+        assert_eq!(
+            rows,
+            [
+                (
+                    "packed.txt".to_owned(),
+                    Some(serde_json::json!({"s3-io-codec": "gzip"}))
+                ),
+                ("plain.txt".to_owned(), Some(serde_json::json!({}))),
+            ]
+        );
+    }
+
+    #[pg_test]
+    fn walk_descends_prefixes() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "test-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        for key in ["a/1.txt", "a/b/2.txt", "a/b/c/3.txt", "top.txt"] {
+            put(bucket, key, b"x");
+        }
+        let walk = |max_depth, max_rows| -> Vec<(String, bool, i32)> {
+            crate::list::s3_walk(
+                Some(bucket),
+                "",
+                max_depth,
+                max_rows,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .collect()
+        };
+
+        let rows = walk(2, 100);
+        let rows: Vec<_> = rows.iter().map(|(k, p, d)| (k.as_str(), *p, *d)).collect();
+        assert_eq!(
+            rows,
+            [
+                ("a/", true, 1),
+                ("a/1.txt", false, 2),
+                ("a/b/", true, 2),
+                ("top.txt", false, 1),
+            ]
+        );
+        assert_eq!(walk(1, 100).len(), 2);
+        assert_eq!(walk(10, 3).len(), 3);
+    }
+
+    #[pg_test]
+    fn get_object_verifies_checksum() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "test-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        // The SDK sends a CRC32 checksum with every PutObject, which S3 stores.
+        put(bucket, "c.txt", b"checked");
+
+        let rows: Vec<_> = crate::s3_get_object_verified(
+            Some(bucket),
+            "c.txt",
+            None,
+            None,
+            None,
+            None,
+            None,
+            "none",
+            None,
+            None,
+        )
+        .collect();
+        assert_eq!(rows.len(), 1);
+        let (data, algorithm, checksum) = &rows[0];
+        assert_eq!(data, b"checked");
+        assert_eq!(algorithm, "CRC32");
+        assert!(!checksum.is_empty());
+    }
+
+    #[pg_test]
+    fn try_variants_report_errors() {
+        use crate::try_ops::*;
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "test-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let (etag, _, _, code, message) = s3_try_put_object(
+            Some(bucket),
+            "t.txt",
+            b"try".to_vec(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .next()
+        .unwrap();
+        assert!(etag.is_some() && code.is_none() && message.is_none());
+
+        let try_get = |key| {
+            s3_try_get_object(
+                Some(bucket),
+                key,
+                None,
+                None,
+                None,
+                None,
+                None,
+                "metadata",
+                None,
+                None,
+                false,
+            )
+            .next()
+            .unwrap()
+        };
+        assert_eq!(try_get("t.txt").0.as_deref(), Some(&b"try"[..]));
+
+        let (deleted, ..) = s3_try_delete_object(
+            Some(bucket),
+            "t.txt",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .next()
+        .unwrap();
+        assert_eq!(deleted, Some(true));
+
+        let (data, code, message) = try_get("t.txt");
+        assert!(data.is_none());
+        assert_eq!(code.as_deref(), Some("NoSuchKey"));
+        assert!(message.unwrap().contains("GetObject failed"));
+    }
+
+    #[pg_test]
+    fn select_local_fallback() {
+        use crate::select::{select_local, InputFormat};
+
+        let csv = b"Name,Kind\nfoo,a\nbar,b\nbaz,a\n";
+        let format = InputFormat::Csv {
+            header: true,
+            delimiter: b',',
+        };
+        let rows = select_local(
+            csv,
+            format,
+            "SELECT s.name FROM S3Object s WHERE s.kind = 'a'",
+        )
+        .unwrap();
+        assert_eq!(
+            rows,
+            [
+                serde_json::json!({"name": "foo"}),
+                serde_json::json!({"name": "baz"})
+            ]
+        );
+
+        let json = b"{\"id\": 1, \"tag\": \"x\"}\n{\"id\": 2}\n";
+        let rows = select_local(
+            json,
+            InputFormat::Json,
+            "SELECT s.id FROM s3object s WHERE s.tag IS NULL",
+        )
+        .unwrap();
+        assert_eq!(rows, [serde_json::json!({"id": "2"})]);
+    }
+
+    #[pg_test]
+    fn user_agent_suffix_builds_own_client() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        crate::s3_reset_clients();
+        crate::client_for(&crate::ClientArgs::default(), false);
+        Spi::run("SET s3_io.user_agent_suffix = 'etl-loader'").unwrap();
+        crate::client_for(&crate::ClientArgs::default(), false);
+        assert_eq!(crate::s3_reset_clients(), 2);
+    }
+
+    #[pg_test]
+    fn user_agent_suffix_is_validated() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        Spi::run("SET s3_io.user_agent_suffix = 'not valid'").unwrap();
+        let error = PgTryBuilder::new(|| {
+            crate::client_for(&crate::ClientArgs::default(), false);
+            None
+        })
+        .catch_others(|e| match e {
+            pg_sys::panic::CaughtError::ErrorReport(report) => Some(report.message().to_owned()),
+            e => e.rethrow(),
+        })
+        .execute();
+        assert_eq!(
+            error.as_deref(),
+            Some("s3_io.user_agent_suffix may only contain letters, digits and !#$%&'*+-.^_`|~")
+        );
+
+        // The failed call leaves the client cache usable.
+        Spi::run("SET s3_io.user_agent_suffix = 'etl-loader'").unwrap();
+        let bucket = "agent-after-error";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "k.txt", b"ok");
+        assert_eq!(get(bucket, "k.txt"), b"ok");
+    }
+
+    #[pg_test]
+    fn normalize_endpoint_cases() {
+        use crate::normalize_endpoint;
+
+        assert_eq!(
+            normalize_endpoint("s3.example.com").unwrap(),
+            "https://s3.example.com"
+        );
+        assert_eq!(
+            normalize_endpoint("https://s3.example.com//").unwrap(),
+            "https://s3.example.com"
+        );
+        assert_eq!(
+            normalize_endpoint(" http://127.0.0.1:9000/ ").unwrap(),
+            "http://127.0.0.1:9000"
+        );
+        assert_eq!(
+            normalize_endpoint("http://gw.local/s3/").unwrap(),
+            "http://gw.local/s3"
+        );
+        assert!(normalize_endpoint("").is_err());
+        assert!(normalize_endpoint("ftp://s3.example.com").is_err());
+        assert!(normalize_endpoint("https://s3.example.com:notaport").is_err());
+        assert!(normalize_endpoint("https://s3.example.com/?x=1").is_err());
+    }
+
+    #[pg_test]
+    fn s3_uris() {
+        use crate::uri::{parse_s3_uri, resolve};
+
+        assert_eq!(
+            parse_s3_uri("s3://bucket/dir/file.csv").unwrap(),
+            ("bucket".into(), "dir/file.csv".into())
+        );
+        assert_eq!(
+            parse_s3_uri("S3://bucket").unwrap(),
+            ("bucket".into(), "".into())
+        );
+        assert!(parse_s3_uri("https://bucket/key").is_err());
+        assert!(parse_s3_uri("s3:///key").is_err());
+
+        assert_eq!(
+            resolve(Some("bucket"), "key"),
+            ("bucket".into(), "key".into())
+        );
+        assert_eq!(
+            resolve(Some("s3://bucket/dir/"), "key"),
+            ("bucket".into(), "dir/key".into())
+        );
+        assert_eq!(
+            resolve(Some("s3://bucket/dir/key"), ""),
+            ("bucket".into(), "dir/key".into())
+        );
+    }
+
+    #[pg_test]
+    fn restore_header_parsing() {
+        assert_eq!(
+            crate::parse_restore(r#"ongoing-request="true""#),
+            (true, None)
+        );
+        let (ongoing, expiry) = crate::parse_restore(
+            r#"ongoing-request="false", expiry-date="Fri, 21 Dec 2012 00:00:00 GMT""#,
+        );
+        assert!(!ongoing);
+        let expected = crate::to_timestamptz(&aws_smithy_types::DateTime::from_secs(1_356_048_000));
+        assert_eq!(expiry, expected);
+    }
+
+    #[pg_test]
+    fn expiration_header_parsing() {
+        let (date, rule) = crate::parse_expiration(Some(
+            r#"expiry-date="Fri, 21 Dec 2012 00:00:00 GMT", rule-id="tmp-cleanup""#,
+        ));
+        let expected = crate::to_timestamptz(&aws_smithy_types::DateTime::from_secs(1_356_048_000));
+        assert_eq!(date, expected);
+        assert_eq!(rule.as_deref(), Some("tmp-cleanup"));
+        assert_eq!(crate::parse_expiration(None), (None, None));
+    }
+
+    #[pg_test]
+    fn put_objects_in_bulk() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "test-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        Spi::run("SET s3_io.bulk_concurrency = 2").unwrap();
+        let uploaded = Spi::get_one::<i64>(
+            "SELECT count(*) FROM s3_put_objects('test-bucket', \
+                 ARRAY['s/1', 's/2', 's/3', 's/4', 's/5'], \
+                 ARRAY['1', '2', '3', '4', '5']::bytea[]) \
+             WHERE etag IS NOT NULL AND error IS NULL",
+        )
+        .unwrap();
+        assert_eq!(uploaded, Some(5));
+        assert_eq!(get(bucket, "s/4"), b"4");
+
+        let failed = Spi::get_one::<String>(
+            "SELECT error FROM s3_put_objects('no-such-bucket', ARRAY['k'], ARRAY['x']::bytea[])",
+        )
+        .unwrap();
+        assert!(failed.unwrap().contains("NoSuchBucket"));
+
+        use crate::bulk::OnError;
+        assert_eq!(OnError::parse("RAISE"), Ok(OnError::Raise));
+        assert!(OnError::parse("skip").is_err());
+        let ignored = Spi::get_one::<i64>(
+            "SELECT count(*) FROM s3_put_objects('no-such-bucket', ARRAY['a', 'b'], \
+                 ARRAY['1', '2']::bytea[], on_error => 'ignore') WHERE error IS NOT NULL",
+        )
+        .unwrap();
+        assert_eq!(ignored, Some(2));
+    }
+
+    #[pg_test]
+    fn pool_settings_build_own_client() {
+        let _minio = MinioServer::start().expect("minio up");
+        crate::s3_create_bucket("pool", None, None, None, None, None, None);
+
+        crate::s3_reset_clients();
+        put("pool", "a", b"kept alive");
+        Spi::run("SET s3_io.pool_max_idle_per_host = 0").unwrap();
+        Spi::run("SET s3_io.pool_idle_timeout = 0").unwrap();
+        assert_eq!(get("pool", "a"), b"kept alive");
+        assert_eq!(crate::s3_reset_clients(), 2);
+    }
+
+    #[pg_test]
+    fn version_names_extension_and_sdk() {
+        let version = Spi::get_one::<String>("SELECT s3_io_version()")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            version,
+            format!(
+                "s3_io {} (aws-sdk-s3 {})",
+                env!("CARGO_PKG_VERSION"),
+                aws_sdk_s3::meta::PKG_VERSION
+            )
+        );
+    }
+
+    #[pg_test]
+    fn debug_request_redacts_secrets() {
+        let request = crate::diagnostics::s3_debug_request(
+            Some("bucket"),
+            "dir/key.txt",
+            "getobject",
+            Some("http://s3.example.test"),
+            Some("AKIDDEBUG"),
+            Some("debug-secret"),
+            Some("debug-token"),
+            Some("eu-central-1"),
+            Some(false),
+        );
+        assert!(request.starts_with(
+            "addressing: virtual-hosted-style\nGET http://bucket.s3.example.test/dir/key.txt"
+        ));
+        assert!(request.contains("Credential=<redacted>/"));
+        assert!(request.contains("/eu-central-1/s3/aws4_request"));
+        assert!(request.contains("Signature=<redacted>"));
+        assert!(request.contains("x-amz-security-token: <redacted>"));
+        for secret in ["AKIDDEBUG", "debug-secret", "debug-token"] {
+            assert!(!request.contains(secret), "{secret} leaked: {request}");
+        }
+    }
+
+    #[pg_test]
+    fn read_csv_gz_types_rows() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "csv-gz";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+
+        let mut csv = String::from("id;name;score\n");
+        for i in 1..=2500 {
+            csv.push_str(&format!(
+                "{i};row {i};{}\n",
+                if i % 2 == 0 { "1.5" } else { "" }
+            ));
+        }
+        let gz = crate::Codec::Gzip.compress(csv.as_bytes(), None).unwrap();
+        put(bucket, "vendor.csv.gz", &gz);
+
+        let rows: Vec<_> = crate::csv_gz::s3_read_csv_gz(
+            Some(bucket),
+            "vendor.csv.gz",
+            "id int, name text, score numeric",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            true,
+            ";",
+            "",
+            "\"",
+            None,
+        )
+        .map(|row| row.0)
+        .collect();
+        assert_eq!(rows.len(), 2500);
+        assert_eq!(
+            rows[0],
+            serde_json::json!({"id": 1, "name": "row 1", "score": null})
+        );
+        assert_eq!(
+            rows[2499],
+            serde_json::json!({"id": 2500, "name": "row 2500", "score": 1.5})
+        );
+    }
+
+    #[pg_test]
+    fn missing_endpoint_means_aws() {
+        let saved = std::env::var_os("S3_ENDPOINT_URL");
+        std::env::remove_var("S3_ENDPOINT_URL");
+        let request = crate::diagnostics::s3_debug_request(
+            Some("bucket"),
+            "key",
+            "HeadObject",
+            None,
+            Some("AKIDAWS"),
+            Some("aws-secret"),
+            None,
+            Some("eu-west-1"),
+            Some(false),
+        );
+        let url = crate::s3_object_url(Some("bucket"), "key", None, Some(false));
+        if let Some(saved) = saved {
+            std::env::set_var("S3_ENDPOINT_URL", saved);
+        }
+
+        assert!(request.contains("HEAD https://bucket.s3.eu-west-1.amazonaws.com/key"));
+        assert_eq!(url, "https://bucket.s3.amazonaws.com/key");
+    }
+
+    #[pg_test]
+    fn aws_and_custom_endpoints_are_cached_apart() {
+        let saved = std::env::var_os("S3_ENDPOINT_URL");
+        std::env::remove_var("S3_ENDPOINT_URL");
+        let args = crate::ClientArgs {
+            access_key: Some("AKIDAWS"),
+            secret_key: Some("aws-secret"),
+            ..Default::default()
+        };
+        crate::s3_reset_clients();
+        crate::client_for(&args, false);
+        crate::client_for(
+            &crate::ClientArgs {
+                endpoint_url: Some("https://s3.amazonaws.com"),
+                ..args
+            },
+            false,
+        );
+        crate::client_for(&args, false);
+        if let Some(saved) = saved {
+            std::env::set_var("S3_ENDPOINT_URL", saved);
+        }
+
+        assert_eq!(crate::s3_reset_clients(), 2);
+    }
+
+    #[pg_test]
+    fn append_object_small_and_large() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "append";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let append = |key, data: &[u8], if_match| {
+            crate::append::s3_append_object(
+                Some(bucket),
+                key,
+                data.to_vec(),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                if_match,
+            )
+        };
+
+        append("log.txt", b"line 1\n", None);
+        let etag = append("log.txt", b"line 2\n", None);
+        append("log.txt", b"line 3\n", Some(&etag));
+        assert_eq!(get(bucket, "log.txt"), b"line 1\nline 2\nline 3\n");
+
+        let large = vec![b'x'; crate::multipart::MIN_PART_SIZE + 1];
+        put(bucket, "large.bin", &large);
+        append("large.bin", b"tail", None);
+        let data = get(bucket, "large.bin");
+        assert_eq!(data.len(), large.len() + 4);
+        assert!(data.ends_with(b"xtail"));
+    }
+
+    #[pg_test(error = "s3://append-race/log.txt does not have the expected ETag")]
+    fn append_object_if_match_detects_writers() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "append-race";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "log.txt", b"first\n");
+        crate::append::s3_append_object(
+            Some(bucket),
+            "log.txt",
+            b"second\n".to_vec(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some("stale-etag"),
+        );
+    }
+
+    #[pg_test]
+    fn bucket_without_encryption_has_no_row() {
+        let _minio = MinioServer::start().expect("minio up");
+        crate::s3_create_bucket("plain", None, None, None, None, None, None);
+
+        let rules =
+            crate::bucket::s3_get_bucket_encryption("plain", None, None, None, None, None, None);
+        assert_eq!(rules.count(), 0);
+    }
+
+    #[pg_test(error = "sse_algorithm aws:kms requires a kms_key_id")]
+    fn bucket_encryption_kms_needs_key() {
+        crate::bucket::s3_put_bucket_encryption(
+            "b", "aws:kms", None, None, None, None, None, None, None,
+        );
+    }
+
+    #[pg_test]
+    fn get_objects_in_bulk() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "bulk-get";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        for i in 0..20 {
+            put(
+                bucket,
+                &format!("fragment-{i}"),
+                format!("part {i}").as_bytes(),
+            );
+        }
+        Spi::run("SET s3_io.bulk_concurrency = 4").unwrap();
+
+        let mut keys: Vec<_> = (0..20).rev().map(|i| format!("fragment-{i}")).collect();
+        keys.insert(3, "missing".to_owned());
+        let rows: Vec<_> = crate::bulk::s3_get_objects(
+            Some(bucket),
+            keys.clone(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "ignore",
+        )
+        .collect();
+
+        assert_eq!(rows.iter().map(|r| r.0.clone()).collect::<Vec<_>>(), keys);
+        assert_eq!(rows[0].1.as_deref(), Some(&b"part 19"[..]));
+        assert!(rows[3].1.is_none() && rows[3].2.is_some());
+        assert!(rows.iter().filter(|r| r.2.is_some()).count() == 1);
+    }
+
+    #[pg_test]
+    fn bucket_round_trip_with_explicit_addressing() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "round-trip";
+        let region = Some("us-east-1");
+
+        assert!(crate::s3_create_bucket(
+            bucket,
+            None,
+            None,
+            None,
+            None,
+            region,
+            Some(true)
+        ));
+        crate::s3_put_object(
+            Some(bucket),
+            "k",
+            b"v".to_vec(),
+            None,
+            None,
+            None,
+            None,
+            region,
+            None,
+            None,
+            None,
+            None,
+            Some(true),
+            None,
+            None,
+            None,
+            false,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+        );
+        let data = crate::s3_get_object(
+            Some(bucket),
+            "k",
+            None,
+            None,
+            None,
+            None,
+            region,
+            "metadata",
+            None,
+            Some(true),
+            false,
+            false,
+            None,
+            None,
+        );
+        assert_eq!(data, b"v");
+        assert!(crate::s3_delete_object(
+            Some(bucket),
+            "k",
+            None,
+            None,
+            None,
+            None,
+            region,
+            None,
+            Some(true),
+            false,
+            None,
+        ));
+
+        assert!(crate::location_constraint("us-east-1").is_none());
+        assert_eq!(
+            crate::location_constraint("eu-central-1").map(|c| c.as_str().to_owned()),
+            Some("eu-central-1".to_owned())
+        );
+    }
+
+    #[pg_test]
+    fn sse_kms_encryption_context() {
+        use crate::sse::Encryption;
+        use serde_json::json;
+
+        let context = json!({"tenant": "t1", "app": "etl"});
+        assert!(Encryption::new(None, None, Some(&context)).is_err());
+        assert!(Encryption::new(Some("AES256"), None, Some(&context)).is_err());
+        assert!(Encryption::new(Some("aws:kms"), None, Some(&json!({"n": 1}))).is_err());
+        assert!(Encryption::new(None, None, None).unwrap().is_none());
+
+        let encryption = Encryption::new(Some("aws:kms"), Some("key-id"), Some(&context))
+            .unwrap()
+            .unwrap();
+        let encoded = aws_smithy_types::base64::encode(r#"{"app":"etl","tenant":"t1"}"#);
+        assert!(format!("{encryption:?}").contains(&encoded));
+    }
+
+    #[pg_test]
+    fn dry_run_writes_nothing() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "dry-run";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "keep.txt", b"kept");
+
+        let etag = crate::s3_put_object(
+            Some(bucket),
+            "new.txt",
+            b"new".to_vec(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            true,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+        );
+        assert!(etag.is_none());
+        assert!(crate::s3_delete_object(
+            Some(bucket),
+            "keep.txt",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            true,
+            None,
+        ));
+
+        let exists = |key| {
+            crate::s3_object_exists_lazy(
+                Some(bucket),
+                key,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                false,
+            )
+        };
+        assert!(!exists("new.txt"));
+        assert!(exists("keep.txt"));
+    }
+
+    #[pg_test]
+    fn read_csv_gz_copy_options() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "csv-gz-options";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let read = |csv: &str, null_string, quote, escape| -> Vec<serde_json::Value> {
+            let gz = crate::Codec::Gzip.compress(csv.as_bytes(), None).unwrap();
+            put(bucket, "data.csv.gz", &gz);
+            crate::csv_gz::s3_read_csv_gz(
+                Some(bucket),
+                "data.csv.gz",
+                "id int, note text",
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                false,
+                ",",
+                null_string,
+                quote,
+                escape,
+            )
+            .map(|row| row.0)
+            .collect()
+        };
+        use serde_json::json;
+
+        let rows = read(
+            "1,\"a, b\"\n2,\"two\nlines\"\n3,\"say \"\"hi\"\"\"\n4,\\N\n5,\n",
+            "\\N",
+            "\"",
+            None,
+        );
+        assert_eq!(
+            rows,
+            [
+                json!({"id": 1, "note": "a, b"}),
+                json!({"id": 2, "note": "two\nlines"}),
+                json!({"id": 3, "note": "say \"hi\""}),
+                json!({"id": 4, "note": null}),
+                json!({"id": 5, "note": ""}),
+            ]
+        );
+
+        let rows = read("1,'it\\'s, here'\n", "", "'", Some("\\"));
+        assert_eq!(rows, [json!({"id": 1, "note": "it's, here"})]);
+    }
+
+    #[pg_test]
+    fn retry_log_reads_error_codes() {
+        let body = "<?xml version=\"1.0\"?><Error><Code>SlowDown</Code>\
+                    <Message>Please reduce your request rate.</Message></Error>";
+        assert_eq!(crate::retries::error_code(body), Some("SlowDown"));
+        assert_eq!(crate::retries::error_code("Service Unavailable"), None);
+    }
+
+    #[pg_test]
+    fn put_object_with_canned_acl() {
+        use aws_sdk_s3::types::ObjectCannedAcl;
+
+        assert_eq!(
+            crate::parse_canned_acl("bucket-owner-full-control"),
+            Ok(ObjectCannedAcl::BucketOwnerFullControl)
+        );
+        assert!(crate::parse_canned_acl("public").is_err());
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "canned-acl";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let etag = crate::s3_put_object(
+            Some(bucket),
+            "private.txt",
+            b"mine".to_vec(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+            Some("private"),
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+        );
+        assert!(etag.is_some());
+    }
+
+    #[pg_test]
+    fn list_modified_since_filters_by_time() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "arrivals";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "in/2024-06-01.csv", b"1");
+        put(bucket, "in/2024-06-02.csv", b"2");
+
+        let at = |offset: &str| {
+            Spi::get_one::<TimestampWithTimeZone>(&format!("SELECT now() + interval '{offset}'"))
+                .unwrap()
+                .unwrap()
+        };
+        let list = |since, start_after| {
+            crate::list::s3_list_modified_since(
+                Some(bucket),
+                "in/",
+                since,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                start_after,
+                None,
+            )
+            .map(|r| r.0)
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            list(at("-1 hour"), None),
+            ["in/2024-06-01.csv", "in/2024-06-02.csv"]
+        );
+        assert_eq!(
+            list(at("-1 hour"), Some("in/2024-06-01.csv")),
+            ["in/2024-06-02.csv"]
+        );
+        assert!(list(at("1 hour"), None).is_empty());
+    }
+
+    #[pg_test]
+    fn presign_get_with_response_overrides() {
+        let _minio = MinioServer::start().expect("minio up");
+        assert!(crate::presign::check_content_type("text/plain; charset=utf-8").is_ok());
+        assert!(crate::presign::check_content_type("csv").is_err());
+
+        let url = crate::presign::s3_presign_get(
+            Some("downloads"),
+            "2024/report.csv",
+            600,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some("text/csv"),
+            Some("attachment; filename=\"Q2 report.csv\""),
+        );
+        assert!(url.contains("/downloads/2024/report.csv?"), "{url}");
+        assert!(url.contains("X-Amz-Expires=600"), "{url}");
+        assert!(url.contains("response-content-type=text%2Fcsv"), "{url}");
+        assert!(
+            url.contains(
+                "response-content-disposition=attachment%3B%20filename%3D%22Q2%20report.csv%22"
+            ),
+            "{url}"
+        );
+    }
+
+    #[pg_test]
+    fn put_object_with_url_returns_link() {
+        use crate::presign::UrlKind;
+
+        assert_eq!(UrlKind::parse("Public"), Ok(UrlKind::Public));
+        assert!(UrlKind::parse("signed").is_err());
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "linked";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+
+        let (etag, url) = Spi::get_two::<String, String>(&format!(
+            "SELECT etag, url FROM s3_put_object_with_url('{bucket}', 'reports/q2 summary.csv', \
+                                                          'a,b\n'::bytea, expires_in => 600)"
+        ))
+        .unwrap();
+        assert_eq!(etag.map(|e| e.len()), Some(32));
+        let url = url.unwrap();
+        assert!(url.contains("X-Amz-Expires=600"), "{url}");
+        let body = reqwest::blocking::get(&url).unwrap().bytes().unwrap();
+        assert_eq!(body.as_ref(), b"a,b\n");
+
+        let url = Spi::get_one::<String>(&format!(
+            "SELECT url FROM s3_put_object_with_url('{bucket}', 'public.txt', 'x'::bytea, \
+                                                    url_kind => 'public')"
+        ))
+        .unwrap();
+        assert_eq!(
+            url,
+            Some(crate::s3_object_url(Some(bucket), "public.txt", None, None))
+        );
+    }
+
+    #[pg_test]
+    fn legal_hold_needs_object_lock() {
+        use crate::legal_hold::lock_missing;
+
+        let aws = Some("Bucket is missing Object Lock Configuration");
+        let minio = Some("Bucket is missing ObjectLockConfiguration");
+        assert!(lock_missing(Some("InvalidRequest"), aws));
+        assert!(lock_missing(Some("InvalidRequest"), minio));
+        assert!(!lock_missing(Some("AccessDenied"), aws));
+        assert!(!lock_missing(
+            Some("InvalidRequest"),
+            Some("Invalid version id")
+        ));
+    }
+
+    #[pg_test]
+    fn put_row_json_from_trigger() {
+        use crate::row_json::render_key;
+        use serde_json::json;
+
+        let row = json!({"id": 7, "kind": "signup", "note": null});
+        assert_eq!(
+            render_key("events/{kind}/{id}.json", &row).unwrap(),
+            "events/signup/7.json"
+        );
+        assert!(render_key("events/{note}.json", &row).is_err());
+        assert!(render_key("events/{id.json", &row).is_err());
+
+        let _minio = MinioServer::start().expect("minio up");
+        crate::s3_create_bucket("cdc", None, None, None, None, None, None);
+        Spi::run(
+            "CREATE TABLE events (id int, kind text);
+             CREATE FUNCTION events_to_s3() RETURNS trigger LANGUAGE plpgsql AS $$
+             BEGIN
+                 PERFORM s3_put_row_json('cdc', 'events/{id}.json', NEW);
+                 RETURN NEW;
+             END $$;
+             CREATE TRIGGER events_to_s3 AFTER INSERT ON events
+                 FOR EACH ROW EXECUTE FUNCTION events_to_s3();
+             INSERT INTO events VALUES (1, 'signup');",
+        )
+        .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&get("cdc", "events/1.json")).unwrap();
+        assert_eq!(body, json!({"id": 1, "kind": "signup"}));
+    }
+
+    #[pg_test]
+    fn access_point_arns_as_bucket() {
+        let debug = |bucket| {
+            crate::diagnostics::s3_debug_request(
+                bucket,
+                "dir/key.txt",
+                "GetObject",
+                None,
+                Some("AKIDDEBUG"),
+                Some("debug-secret"),
+                None,
+                Some("eu-central-1"),
+                None,
+            )
+        };
+        // Path-style is the default, but ARNs are always virtual-hosted-style
+        // and resolved in their own region.
+        let request = debug(Some(
+            "arn:aws:s3:us-west-2:123456789012:accesspoint/reports",
+        ));
+        assert!(
+            request.starts_with(
+                "addressing: virtual-hosted-style\n\
+                 GET https://reports-123456789012.s3-accesspoint.us-west-2.amazonaws.com/dir/key.txt"
+            ),
+            "{request}"
+        );
+        assert!(request.contains("/us-west-2/s3/aws4_request"), "{request}");
+
+        let request = debug(Some(
+            "arn:aws:s3::123456789012:accesspoint/mfzwi23gnjvgw.mrap",
+        ));
+        assert!(
+            request.contains(
+                "GET https://mfzwi23gnjvgw.mrap.accesspoint.s3-global.amazonaws.com/dir/key.txt"
+            ),
+            "{request}"
+        );
+        assert!(request.contains("AWS4-ECDSA-P256-SHA256"), "{request}");
+    }
+
+    #[pg_test]
+    fn get_object_info_reports_length() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "info";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "data.bin", b"0123456789");
+
+        let info = |range| {
+            crate::s3_get_object_info(
+                Some(bucket),
+                "data.bin",
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                range,
+            )
+            .next()
+            .unwrap()
+        };
+        let (data, length, _, etag) = info(None);
+        assert_eq!((data.as_slice(), length), (&b"0123456789"[..], Some(10)));
+        assert!(etag.is_some_and(|e| !e.contains('"')));
+
+        let (data, length, ..) = info(Some("bytes=2-5"));
+        assert_eq!((data.as_slice(), length), (&b"2345"[..], Some(4)));
+    }
+
+    #[pg_test]
+    fn tail_reads_suffix() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "tail";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "app.log", b"line 1\nline 2\n");
+        put(bucket, "empty.log", b"");
+
+        let tail = |key, last_bytes| {
+            crate::s3_tail(
+                Some(bucket),
+                key,
+                last_bytes,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+        assert_eq!(tail("app.log", 7), b"line 2\n");
+        assert_eq!(tail("app.log", 1000), b"line 1\nline 2\n");
+        assert_eq!(tail("empty.log", 10), b"");
+    }
+
+    #[pg_test]
+    fn list_range_is_inclusive() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "partitions";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        for hour in ["00", "06", "12", "18"] {
+            put(bucket, &format!("day=2024-06-01/{hour}.csv"), b"x");
+        }
+        put(bucket, "day=2024-06-02/00.csv", b"x");
+
+        let list = |start_key, end_key| {
+            crate::list::s3_list_range(
+                Some(bucket),
+                start_key,
+                end_key,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .map(|r| r.0)
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            list("day=2024-06-01/06.csv", "day=2024-06-01/12.csv"),
+            ["day=2024-06-01/06.csv", "day=2024-06-01/12.csv"]
+        );
+        assert_eq!(
+            list("day=2024-06-01/12", "day=2024-06-02"),
+            ["day=2024-06-01/12.csv", "day=2024-06-01/18.csv"]
+        );
+        assert!(list("day=2024-06-03", "day=2024-06-04").is_empty());
+    }
+
+    #[pg_test]
+    fn region_auto_is_signed_as_is() {
+        let _minio = MinioServer::start_in_region(Some("auto")).expect("minio up");
+        assert!(crate::location_constraint("auto").is_none());
+
+        let region = Some("auto");
+        crate::s3_create_bucket("auto-region", None, None, None, None, region, None);
+        crate::s3_put_object(
+            Some("auto-region"),
+            "k.txt",
+            b"signed for auto".to_vec(),
+            None,
+            None,
+            None,
+            None,
+            region,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+        );
+        let data = crate::s3_get_object(
+            Some("auto-region"),
+            "k.txt",
+            None,
+            None,
+            None,
+            None,
+            region,
+            "metadata",
+            None,
+            None,
+            false,
+            false,
+            None,
+            None,
+        );
+        assert_eq!(data, b"signed for auto");
+    }
+
+    #[pg_test]
+    fn refresh_credentials_drops_clients() {
+        let _minio = MinioServer::start().expect("minio up");
+        crate::s3_reset_clients();
+        assert!(!crate::s3_refresh_credentials());
+
+        crate::client_for(&crate::ClientArgs::default(), false);
+        assert!(crate::s3_refresh_credentials());
+        assert!(!crate::s3_refresh_credentials());
+    }
+
+    #[pg_test]
+    fn copy_object_with_checksum() {
+        let _minio = MinioServer::start().expect("minio up");
+        assert!(crate::parse_checksum_algorithm("crc32c").is_ok());
+        assert!(crate::parse_checksum_algorithm("MD5").is_err());
+
+        let bucket = "copy-checksum";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "src.txt", b"checked");
+
+        let (etag, algorithm, checksum) = crate::s3_copy_object_verified(
+            Some(bucket),
+            "src.txt",
+            Some(bucket),
+            "dst.txt",
+            "CRC32C",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .next()
+        .unwrap();
+        assert!(!etag.is_empty());
+        assert_eq!(algorithm, "CRC32C");
+        // A CRC32C is four bytes, base64-encoded.
+        assert_eq!(
+            aws_smithy_types::base64::decode(&checksum).unwrap().len(),
+            4
+        );
+    }
+
+    #[pg_test(error = "No region given, and s3_io.require_region is on")]
+    fn require_region_rejects_missing_region() {
+        let _minio = MinioServer::start().expect("minio up");
+        Spi::run("SET s3_io.require_region = on").unwrap();
+        crate::client_for(
+            &crate::ClientArgs {
+                region: Some("eu-west-1"),
+                ..Default::default()
+            },
+            false,
+        );
+        crate::client_for(&crate::ClientArgs::default(), false);
+    }
+
+    #[pg_test]
+    fn put_parts_merges_small_elements() {
+        use crate::multipart::{part_groups, MIN_PART_SIZE};
+
+        let mib = 1024 * 1024;
+        assert_eq!(part_groups([]).len(), 1);
+        assert_eq!(part_groups([10, 20]).first(), Some(&(0..2)));
+        assert_eq!(
+            part_groups([MIN_PART_SIZE, 3 * mib, 3 * mib, mib]),
+            [0..1, 1..3, 3..4]
+        );
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "put-parts";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let etag = Spi::get_one::<String>(&format!(
+            "SELECT s3_put_parts('{bucket}', 'export.csv', \
+                 ARRAY[convert_to(repeat('a', {n}), 'UTF8'), \
+                       convert_to(repeat('b', {n}), 'UTF8'), 'tail']::bytea[])",
+            n = 3 * mib
+        ))
+        .unwrap()
+        .unwrap();
+        // The two 3 MiB elements make the first part, "tail" the second.
+        assert!(etag.ends_with("-2"), "{etag}");
+
+        let data = get(bucket, "export.csv");
+        assert_eq!(data.len(), 6 * mib + 4);
+        assert!(data.ends_with(b"btail"));
+    }
+
+    #[pg_test]
+    fn anonymous_reads_public_objects() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "anonymous-reads";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "public/hello.txt", b"hello");
+        let policy = format!(
+            r#"{{"Version":"2012-10-17","Statement":[{{"Effect":"Allow","Principal":{{"AWS":["*"]}},"Action":["s3:GetObject"],"Resource":["arn:aws:s3:::{bucket}/public/*"]}}]}}"#
+        );
+        let client = crate::client_for(&crate::ClientArgs::default(), false);
+        crate::rt()
+            .block_on(
+                client
+                    .put_bucket_policy()
+                    .bucket(bucket)
+                    .policy(policy)
+                    .send(),
+            )
+            .expect("put bucket policy");
+
+        crate::s3_reset_clients();
+        let data = Spi::get_one::<Vec<u8>>(&format!(
+            "SELECT s3_get_object('{bucket}', 'public/hello.txt', anonymous => true)"
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(data, b"hello");
+        let exists = Spi::get_one::<bool>(&format!(
+            "SELECT s3_object_exists_lazy('{bucket}', 'public/hello.txt', anonymous => true)"
+        ))
+        .unwrap();
+        assert_eq!(exists, Some(true));
+        // The unsigned client does not replace the signing one.
+        crate::client_for(&crate::ClientArgs::default(), false);
+        assert_eq!(crate::s3_reset_clients(), 2);
+    }
+
+    #[pg_test]
+    fn if_match_guards_delete_and_copy() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "if-match";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "src.txt", b"new");
+        let etag = put(bucket, "lock.json", b"old");
+
+        // A stale ETag is a serialization_failure, which callers can catch.
+        Spi::run(&format!(
+            "DO $$ BEGIN \
+                 PERFORM s3_copy_object('{bucket}', 'src.txt', '{bucket}', 'lock.json', \
+                                        if_match => 'stale'); \
+                 RAISE 'copied over a stale ETag'; \
+             EXCEPTION WHEN serialization_failure THEN NULL; \
+             END $$"
+        ))
+        .unwrap();
+        assert_eq!(get(bucket, "lock.json"), b"old");
+
+        Spi::run(&format!(
+            "SELECT s3_copy_object('{bucket}', 'src.txt', '{bucket}', 'lock.json', \
+                                   if_match => '{etag}')"
+        ))
+        .unwrap();
+        assert_eq!(get(bucket, "lock.json"), b"new");
+
+        let etag = put(bucket, "lock.json", b"mine");
+        let deleted = Spi::get_one::<bool>(&format!(
+            "SELECT s3_delete_object('{bucket}', 'lock.json', if_match => '\"{etag}\"')"
+        ))
+        .unwrap();
+        assert_eq!(deleted, Some(true));
+    }
+
+    #[pg_test]
+    fn export_csv_quotes_fields() {
+        use crate::csv_export::CsvFormat;
+
+        let columns = ["id".to_owned(), "note".to_owned()];
+        let line = |format: &CsvFormat, fields: [Option<&str>; 2]| {
+            let mut out = Vec::new();
+            format.write_record(&mut out, fields);
+            String::from_utf8(out).unwrap()
+        };
+        let csv = CsvFormat::new(b',', b'"', None, "", &columns, &[]).unwrap();
+        assert_eq!(line(&csv, [Some("1"), Some("plain")]), "1,plain\n");
+        assert_eq!(
+            line(&csv, [Some("2"), Some("say \"hi\"")]),
+            "2,\"say \"\"hi\"\"\"\n"
+        );
+        assert_eq!(line(&csv, [Some("3"), Some("a\nb")]), "3,\"a\nb\"\n");
+        assert_eq!(line(&csv, [Some("4"), Some("a,b")]), "4,\"a,b\"\n");
+        assert_eq!(line(&csv, [Some("5"), Some("")]), "5,\"\"\n");
+        assert_eq!(line(&csv, [Some("6"), None]), "6,\n");
+
+        let forced = ["note".to_owned()];
+        let tsv = CsvFormat::new(b'\t', b'\'', Some(b'\\'), "NULL", &columns, &forced).unwrap();
+        assert_eq!(line(&tsv, [Some("7"), Some("it's")]), "7\t'it\\'s'\n");
+        assert_eq!(line(&tsv, [Some("NULL"), None]), "'NULL'\tNULL\n");
+        assert_eq!(
+            line(&tsv, [Some("a\tb"), Some("c\\d")]),
+            "'a\tb'\t'c\\\\d'\n"
+        );
+
+        assert!(CsvFormat::new(b',', b',', None, "", &columns, &[]).is_err());
+        assert!(CsvFormat::new(b',', b'"', None, "", &columns, &["missing".to_owned()]).is_err());
+    }
+
+    #[pg_test]
+    fn export_csv_uploads_query() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "export-csv";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+
+        Spi::run(&format!(
+            "SELECT s3_export_csv('{bucket}', 'small.csv', \
+                 $q$SELECT * FROM (VALUES (1, 'a \"b\"'), (2, NULL), (3, E'x\\ny')) AS t(id, note);$q$, \
+                 null_as => 'NULL', force_quote => '{{id}}')"
+        ))
+        .unwrap();
+        assert_eq!(
+            get(bucket, "small.csv"),
+            b"id,note\n\"1\",\"a \"\"b\"\"\"\n\"2\",NULL\n\"3\",\"x\ny\"\n"
+        );
+
+        // Large enough for a multipart upload of two parts.
+        let etag = Spi::get_one::<String>(&format!(
+            "SELECT s3_export_csv('{bucket}', 'big.csv', \
+                 'SELECT i, repeat(''x'', 1000) FROM generate_series(1, 11000) AS i', \
+                 header => false)"
+        ))
+        .unwrap()
+        .unwrap();
+        assert!(etag.ends_with("-2"), "{etag}");
+        let data = get(bucket, "big.csv");
+        assert_eq!(data.iter().filter(|&&b| b == b'\n').count(), 11000);
+        assert!(data.ends_with(format!("11000,{}\n", "x".repeat(1000)).as_bytes()));
+    }
+
+    #[pg_test]
+    fn runtime_flavor_guc() {
+        Spi::run("SET s3_io.runtime_flavor = multi_thread").unwrap();
+        Spi::run("SET s3_io.runtime_threads = 2").unwrap();
+        let flavor = Spi::get_one::<String>("SHOW s3_io.runtime_flavor").unwrap();
+        assert_eq!(flavor.as_deref(), Some("multi_thread"));
+        assert_eq!(
+            crate::guc::RUNTIME_FLAVOR.get(),
+            crate::guc::RuntimeFlavor::MultiThread
+        );
+    }
+
+    #[pg_test(error = "invalid value for parameter \"s3_io.runtime_flavor\": \"single\"")]
+    fn runtime_flavor_rejects_unknown() {
+        Spi::run("SET s3_io.runtime_flavor = single").unwrap();
+    }
+
+    #[pg_test]
+    fn retry_mode_is_used_for_new_clients() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "retry-mode";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+
+        crate::s3_reset_clients();
+        put(bucket, "a.txt", b"standard");
+        Spi::run("SET s3_io.retry_mode = adaptive").unwrap();
+        assert_eq!(
+            crate::guc::RETRY_MODE.get(),
+            crate::guc::RetryMode::Adaptive
+        );
+        put(bucket, "b.txt", b"adaptive");
+        assert_eq!(get(bucket, "b.txt"), b"adaptive");
+        // One client per retry mode.
+        assert_eq!(crate::s3_reset_clients(), 2);
+        Spi::run("RESET s3_io.retry_mode").unwrap();
+    }
+
+    #[pg_test]
+    fn get_object_to_file_resumes() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "resume";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let etag = put(bucket, "big.bin", &data);
+
+        let dir = tempfile::tempdir().expect("temp dir");
+        let dest = dir.path().join("big.bin");
+        let dest_path = dest.to_str().unwrap();
+        let etag_path = format!("{dest_path}.s3etag");
+        Spi::run(&format!(
+            "SET s3_io.download_paths = '{}'",
+            dir.path().display()
+        ))
+        .unwrap();
+        let download = || {
+            crate::s3_get_object_to_file(
+                Some(bucket),
+                "big.bin",
+                dest_path,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                true,
+            )
+        };
+
+        // A download that broke off after 40000 bytes continues from there.
+        std::fs::write(&dest, &data[..40_000]).unwrap();
+        std::fs::write(&etag_path, format!("\"{etag}\"")).unwrap();
+        assert_eq!(download(), 60_000);
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+        assert!(!std::path::Path::new(&etag_path).exists());
+
+        // One of an object that has changed since starts over.
+        std::fs::write(&dest, b"stale").unwrap();
+        std::fs::write(&etag_path, "\"0123456789abcdef0123456789abcdef\"").unwrap();
+        assert_eq!(download(), 100_000);
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+
+        // Without the ETag file there is nothing to resume.
+        std::fs::write(&dest, &data[..10]).unwrap();
+        assert_eq!(download(), 100_000);
+    }
+
+    #[pg_test]
+    fn get_object_to_file_in_parts() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "parallel-get";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 253) as u8).collect();
+        put(bucket, "big.bin", &data);
+        put(bucket, "small.bin", b"tiny");
+
+        let dir = tempfile::tempdir().expect("temp dir");
+        Spi::run(&format!(
+            "SET s3_io.download_paths = '{}'",
+            dir.path().display()
+        ))
+        .unwrap();
+        let download = |key: &str, name: &str| {
+            let dest = dir.path().join(name);
+            let written = crate::s3_get_object_to_file(
+                Some(bucket),
+                key,
+                dest.to_str().unwrap(),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                true,
+            );
+            (written, std::fs::read(&dest).unwrap())
+        };
+        let single = download("big.bin", "single.bin");
+
+        Spi::run("SET s3_io.download_concurrency = 3").unwrap();
+        Spi::run("SET s3_io.download_part_size = '16kB'").unwrap();
+        // A longer file left at the destination is cut to the object's size.
+        std::fs::write(dir.path().join("parts.bin"), vec![0xff; 150_000]).unwrap();
+        let parts = download("big.bin", "parts.bin");
+        assert_eq!(parts, single);
+        assert_eq!(parts, (100_000, data));
+        assert!(!dir.path().join("parts.bin.s3etag").exists());
+        assert!(!dir.path().join("parts.bin.part").exists());
+
+        // A failed download leaves an existing file as it was.
+        std::fs::write(dir.path().join("kept.bin"), b"keep").unwrap();
+        std::fs::create_dir(dir.path().join("kept.bin.part")).unwrap();
+        let failed = PgTryBuilder::new(|| {
+            download("big.bin", "kept.bin");
+            false
+        })
+        .catch_others(|_| true)
+        .execute();
+        assert!(failed);
+        assert_eq!(std::fs::read(dir.path().join("kept.bin")).unwrap(), b"keep");
+
+        // Objects that fit in one part take the single-stream path.
+        assert_eq!(download("small.bin", "small.bin"), (4, b"tiny".to_vec()));
+    }
+
+    #[pg_test]
+    fn get_object_range_forms() {
+        use crate::{parse_content_range, range_header, returned_range};
+
+        assert_eq!(range_header(Some(0), Some(99), None).unwrap(), "bytes=0-99");
+        assert_eq!(range_header(Some(1000), None, None).unwrap(), "bytes=1000-");
+        assert_eq!(range_header(None, None, Some(500)).unwrap(), "bytes=-500");
+        assert!(range_header(Some(10), Some(9), None).is_err());
+        assert!(range_header(None, Some(9), None).is_err());
+        assert!(range_header(Some(0), None, Some(5)).is_err());
+        assert!(range_header(None, None, Some(0)).is_err());
+        assert!(range_header(None, None, None).is_err());
+        assert_eq!(
+            parse_content_range("bytes 1000-1499/5000"),
+            Some((1000, 1499, Some(5000)))
+        );
+        assert_eq!(parse_content_range("bytes 0-9/*"), Some((0, 9, None)));
+        assert_eq!(parse_content_range("bytes */5000"), None);
+        assert_eq!(
+            returned_range(Some((2, 4, Some(10))), 3),
+            (2, Some(4), Some(10))
+        );
+        assert_eq!(returned_range(None, 10), (0, Some(9), Some(10)));
+        assert_eq!(returned_range(None, 0), (0, None, Some(0)));
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "ranges";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "digits.txt", b"0123456789");
+        let range = |start: Option<i64>, end: Option<i64>, suffix: Option<i64>| {
+            let row = Spi::get_three_with_args::<Vec<u8>, i64, i64>(
+                &format!(
+                    "SELECT data, range_start, total_size \
+                     FROM s3_get_object_range('{bucket}', 'digits.txt', $1, $2, $3)"
+                ),
+                &[start.into(), end.into(), suffix.into()],
+            )
+            .unwrap();
+            (row.0.unwrap(), row.1.unwrap(), row.2.unwrap())
+        };
+        assert_eq!(
+            range(Some(2i64), Some(4i64), None::<i64>),
+            (b"234".to_vec(), 2, 10)
+        );
+        assert_eq!(range(Some(7), None, None), (b"789".to_vec(), 7, 10));
+        assert_eq!(range(None, None, Some(4)), (b"6789".to_vec(), 6, 10));
+    }
+
+    #[pg_test]
+    fn update_object_metadata_keeps_the_rest() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "update-metadata";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let client = crate::client_for(&crate::ClientArgs::default(), false);
+        crate::rt()
+            .block_on(
+                client
+                    .put_object()
+                    .bucket(bucket)
+                    .key("report.csv")
+                    .body(b"a,b\n".to_vec().into())
+                    .content_type("application/octet-stream")
+                    .cache_control("max-age=60")
+                    .metadata("origin", "etl")
+                    .tagging("team=data")
+                    .send(),
+            )
+            .expect("put");
+
+        Spi::run(&format!(
+            "SELECT s3_update_object_metadata('{bucket}', 'report.csv', content_type => 'text/csv')"
+        ))
+        .unwrap();
+
+        crate::rt().block_on(async {
+            let head = client
+                .head_object()
+                .bucket(bucket)
+                .key("report.csv")
+                .send()
+                .await
+                .expect("head");
+            assert_eq!(head.content_type(), Some("text/csv"));
+            assert_eq!(head.cache_control(), Some("max-age=60"));
+            assert_eq!(
+                head.metadata()
+                    .and_then(|m| m.get("origin"))
+                    .map(String::as_str),
+                Some("etl")
+            );
+            let tagging = client
+                .get_object_tagging()
+                .bucket(bucket)
+                .key("report.csv")
+                .send()
+                .await
+                .expect("get tagging");
+            let tag = &tagging.tag_set()[0];
+            assert_eq!((tag.key(), tag.value()), ("team", "data"));
+        });
+        assert_eq!(get(bucket, "report.csv"), b"a,b\n");
+    }
+
+    #[pg_test]
+    fn copy_object_preserves_storage_class() {
+        use aws_sdk_s3::types::StorageClass;
+
+        assert_eq!(
+            crate::parse_storage_class("GLACIER"),
+            Ok(StorageClass::Glacier)
+        );
+        assert!(crate::parse_storage_class("glacier").is_err());
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "copy-storage-class";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let client = crate::client_for(&crate::ClientArgs::default(), false);
+        crate::rt()
+            .block_on(
+                client
+                    .put_object()
+                    .bucket(bucket)
+                    .key("cold.bin")
+                    .body(b"cold".to_vec().into())
+                    .storage_class(StorageClass::ReducedRedundancy)
+                    .send(),
+            )
+            .expect("put");
+        let storage_class = |key| {
+            crate::rt()
+                .block_on(client.head_object().bucket(bucket).key(key).send())
+                .expect("head")
+                .storage_class()
+                .cloned()
+        };
+
+        Spi::run(&format!(
+            "SELECT s3_copy_object('{bucket}', 'cold.bin', '{bucket}', 'kept.bin')"
+        ))
+        .unwrap();
+        assert_eq!(storage_class("kept.bin"), storage_class("cold.bin"));
+
+        Spi::run(&format!(
+            "SELECT s3_copy_object('{bucket}', 'cold.bin', '{bucket}', 'standard.bin', \
+                                   preserve_storage_class => false)"
+        ))
+        .unwrap();
+        assert!(matches!(
+            storage_class("standard.bin"),
+            None | Some(StorageClass::Standard)
+        ));
+
+        Spi::run(&format!(
+            "SELECT * FROM s3_copy_objects('{bucket}', '{bucket}', ARRAY['cold.bin'], \
+                                           key_prefix_map => '{{\"cold\": \"bulk\"}}', \
+                                           on_error => 'raise')"
+        ))
+        .unwrap();
+        assert_eq!(storage_class("bulk.bin"), storage_class("cold.bin"));
+    }
+
+    #[pg_test]
+    fn errors_raise_by_category() {
+        use crate::error::{ErrorCategory, S3Error};
+
+        let error = |code: Option<&str>, status: Option<u16>| S3Error {
+            code: code.map(str::to_owned),
+            status,
+            ..S3Error::new("failed")
+        };
+        let category = |code, status| error(code, status).category();
+        assert_eq!(
+            category(Some("DispatchFailure"), None),
+            Some(ErrorCategory::Network)
+        );
+        assert_eq!(
+            category(Some("InvalidAccessKeyId"), Some(403)),
+            Some(ErrorCategory::Auth)
+        );
+        assert_eq!(category(None, Some(403)), Some(ErrorCategory::Auth));
+        assert_eq!(
+            category(Some("NoSuchKey"), Some(404)),
+            Some(ErrorCategory::NotFound)
+        );
+        assert_eq!(
+            category(Some("SlowDown"), Some(503)),
+            Some(ErrorCategory::Throttling)
+        );
+        assert_eq!(
+            category(Some("InternalError"), Some(500)),
+            Some(ErrorCategory::ServerError)
+        );
+        assert_eq!(
+            category(Some("InvalidArgument"), Some(400)),
+            Some(ErrorCategory::ClientError)
+        );
+        assert_eq!(category(None, None), None);
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "categories";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let raises = |call: &str, condition: &str| {
+            Spi::run(&format!(
+                "DO $$ BEGIN PERFORM {call}; RAISE 'no error'; \
+                 EXCEPTION WHEN {condition} THEN NULL; END $$"
+            ))
+            .unwrap_or_else(|e| panic!("{call} did not raise {condition}: {e}"));
+        };
+        raises(
+            &format!("s3_get_object('{bucket}', 'missing.txt')"),
+            "undefined_object",
+        );
+        raises(
+            &format!("s3_get_object('{bucket}', 'missing.txt', secret_key => 'wrong')"),
+            "invalid_authorization_specification",
+        );
+        raises(
+            &format!(
+                "s3_get_object('{bucket}', 'missing.txt', endpoint_url => 'http://127.0.0.1:1')"
+            ),
+            "connection_failure",
+        );
+    }
+
+    #[pg_test]
+    fn keys_with_special_characters_round_trip() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "odd-keys";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let keys = [
+            "keys/with space.txt",
+            "keys/plus+sign.txt",
+            "keys/hash#tag.txt",
+            "keys/percent%20literal.txt",
+            "keys/query?a=b&c=d.txt",
+            "keys/ünïcödé/emoji 😀.txt",
+        ];
+        for key in keys {
+            put(bucket, key, key.as_bytes());
+            assert_eq!(get(bucket, key), key.as_bytes(), "{key}");
+            assert!(
+                crate::s3_object_exists_lazy(
+                    Some(bucket),
+                    key,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    false
+                ),
+                "{key}"
+            );
+            let copied = Spi::get_one_with_args::<String>(
+                &format!("SELECT s3_copy_object('{bucket}', $1, '{bucket}', $1 || '.copy')"),
+                &[key.into()],
+            )
+            .unwrap();
+            assert!(copied.is_some(), "{key}");
+            assert_eq!(get(bucket, &format!("{key}.copy")), key.as_bytes(), "{key}");
+        }
+
+        let listed = Spi::get_one::<Vec<String>>(&format!(
+            "SELECT array_agg(key ORDER BY key COLLATE \"C\") \
+             FROM s3_list_objects('{bucket}', prefix => 'keys/') WHERE key NOT LIKE '%.copy'"
+        ))
+        .unwrap()
+        .unwrap();
+        let mut expected = keys.map(str::to_owned).to_vec();
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        for key in keys {
+            let deleted = Spi::get_one_with_args::<bool>(
+                &format!("SELECT s3_delete_object('{bucket}', $1)"),
+                &[key.into()],
+            )
+            .unwrap();
+            assert_eq!(deleted, Some(true));
+            assert!(!crate::s3_object_exists_lazy(
+                Some(bucket),
+                key,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                false
+            ));
+        }
+    }
+
+    #[pg_test]
+    fn download_file_into_allowed_paths() {
+        let check_download_path =
+            |path: &std::path::Path, allowed| crate::download::check_path(path, allowed, "paths");
+
+        let dir = tempfile::tempdir().expect("temp dir");
+        let allowed = dir.path().to_str().unwrap();
+        let inside = dir.path().join("ref.bin");
+        assert!(check_download_path(&inside, allowed).is_ok());
+        assert!(check_download_path(&inside, "").is_err());
+        assert!(check_download_path(std::path::Path::new("ref.bin"), allowed).is_err());
+        let escape = dir.path().join("..").join("ref.bin");
+        assert!(check_download_path(&escape, allowed).is_err());
+        assert!(check_download_path(std::path::Path::new("/tmp/ref.bin"), allowed).is_err());
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "staging";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 241) as u8).collect();
+        put(bucket, "ref.bin", &data);
+
+        Spi::run(&format!(
+            "SET s3_io.download_paths = '/nonexistent, {allowed}'"
+        ))
+        .unwrap();
+        let dest = inside.to_str().unwrap();
+        let size = Spi::get_one::<i64>(&format!(
+            "SELECT size FROM s3_download_file('{bucket}', 'ref.bin', '{dest}')"
+        ))
+        .unwrap();
+        assert_eq!(size, Some(data.len() as i64));
+        assert_eq!(std::fs::read(dest).unwrap(), data);
+        assert!(!std::path::Path::new(&format!("{dest}.part")).exists());
+
+        // A failed download leaves neither the file nor its partial copy behind.
+        let missing = dir.path().join("missing.bin");
+        let missing = missing.to_str().unwrap();
+        Spi::run(&format!(
+            "DO $$ BEGIN PERFORM s3_download_file('{bucket}', 'missing.bin', '{missing}'); \
+             EXCEPTION WHEN undefined_object THEN NULL; END $$"
+        ))
+        .unwrap();
+        assert!(!std::path::Path::new(missing).exists());
+        assert!(!std::path::Path::new(&format!("{missing}.part")).exists());
+    }
+
+    #[pg_test]
+    fn put_object_text_uploads_utf8() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "put-text";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+
+        Spi::run(&format!(
+            "SELECT s3_put_object_text('{bucket}', 'row.json', \
+                                       jsonb_build_object('name', 'Zoë')::text, \
+                                       content_type => 'application/json')"
+        ))
+        .unwrap();
+        assert_eq!(get(bucket, "row.json"), r#"{"name": "Zoë"}"#.as_bytes());
+
+        Spi::run(&format!(
+            "SELECT s3_put_object_text('{bucket}', 'plain.txt', 'a,b')"
+        ))
+        .unwrap();
+        let content_type = Spi::get_one::<String>(&format!(
+            "SELECT content_type FROM s3_get_object_info('{bucket}', 'plain.txt')"
+        ))
+        .unwrap();
+        assert_eq!(content_type.as_deref(), Some("text/plain; charset=utf-8"));
+    }
+
+    #[pg_test]
+    fn bucket_region_is_discovered() {
+        use crate::bucket::location_region;
+
+        assert_eq!(location_region(None), "us-east-1");
+        assert_eq!(location_region(Some("")), "us-east-1");
+        assert_eq!(location_region(Some("EU")), "eu-west-1");
+        assert_eq!(location_region(Some("ap-south-1")), "ap-south-1");
+
+        let _minio = MinioServer::start_in_region(Some("eu-central-1")).expect("minio up");
+        let region = Some("eu-central-1");
+        crate::s3_create_bucket("located", None, None, None, None, region, None);
+        assert_eq!(
+            crate::bucket::s3_get_bucket_region("located", None, None, None, None, region, None),
+            "eu-central-1"
+        );
+    }
+
+    #[pg_test]
+    fn list_max_pages_caps_listing() {
+        use crate::list::page_limit;
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "test-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        for i in 0..1001 {
+            put(bucket, &format!("many/{i:04}"), b"x");
+        }
+        let list = |max_pages| {
+            crate::list::s3_list_objects(
+                Some(bucket),
+                "many/",
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                false,
+                false,
+                max_pages,
+            )
+            .count()
+        };
+
+        assert_eq!(page_limit(Some(0)), usize::MAX);
+        assert_eq!(page_limit(None), usize::MAX);
+        assert_eq!(list(None), 1001);
+        assert_eq!(list(Some(1)), 1000);
+        assert_eq!(list(Some(2)), 1001);
+
+        Spi::run("SET s3_io.list_max_pages = 1").unwrap();
+        assert_eq!(page_limit(None), 1);
+        assert_eq!(list(None), 1000);
+        assert_eq!(list(Some(0)), 1001);
+    }
+
+    #[pg_test(error = "max_pages must not be negative")]
+    fn list_max_pages_rejects_negative() {
+        crate::list::page_limit(Some(-1));
+    }
+
+    #[pg_test]
+    fn write_parquet_maps_types() {
+        use parquet::{
+            file::reader::{FileReader, SerializedFileReader},
+            record::Field,
+        };
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "export-parquet";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+
+        Spi::run(&format!(
+            "SELECT s3_write_parquet('{bucket}', 'types.parquet', $q$\
+                 SELECT * FROM (VALUES \
+                   (1::int2, 2, 3::int8, 1.5::real, 2.25::float8, 12.34::numeric(5, 2), \
+                    'a'::text, '{{\"k\": 1}}'::jsonb, '\\x0102'::bytea, date '1970-01-02', \
+                    timestamp '1970-01-01 00:00:01', timestamptz '1970-01-01 00:00:01+00', true), \
+                   (NULL, NULL, NULL, NULL, NULL, NULL, NULL, NULL, NULL, NULL, NULL, NULL, NULL)) \
+                 AS t(s, i, l, r, d, n, t, j, b, dt, ts, tz, ok);$q$, row_group_size => 1)"
+        ))
+        .unwrap();
+
+        let reader = SerializedFileReader::new(bytes::Bytes::from(get(bucket, "types.parquet")))
+            .expect("a Parquet file");
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        let rows: Vec<Vec<(String, Field)>> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().into_columns())
+            .collect();
+        let names: Vec<_> = rows[0].iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["s", "i", "l", "r", "d", "n", "t", "j", "b", "dt", "ts", "tz", "ok"]
+        );
+        let values: Vec<_> = rows[0].iter().map(|(_, field)| field.clone()).collect();
+        assert_eq!(values[0], Field::Short(1));
+        assert_eq!(values[1], Field::Int(2));
+        assert_eq!(values[2], Field::Long(3));
+        assert_eq!(values[3], Field::Float(1.5));
+        assert_eq!(values[4], Field::Double(2.25));
+        assert_eq!(values[5].to_string(), "12.34");
+        assert_eq!(values[6], Field::Str("a".to_owned()));
+        assert_eq!(values[7], Field::Str("{\"k\": 1}".to_owned()));
+        assert_eq!(values[8], Field::Bytes(vec![1, 2].into()));
+        assert_eq!(values[9], Field::Date(1));
+        assert_eq!(values[10], Field::TimestampMicros(1_000_000));
+        assert_eq!(values[11], Field::TimestampMicros(1_000_000));
+        assert_eq!(values[12], Field::Bool(true));
+        assert!(rows[1].iter().all(|(_, field)| *field == Field::Null));
+
+        // Row groups of about 1 MB, streamed as a multipart upload.
+        let etag = Spi::get_one::<String>(&format!(
+            "SELECT s3_write_parquet('{bucket}', 'big.parquet', \
+                 'SELECT i, repeat(''x'', 1000) AS x FROM generate_series(1, 11000) AS i', \
+                 compression => 'none', row_group_size => 1000)"
+        ))
+        .unwrap()
+        .unwrap();
+        assert!(etag.contains('-'), "{etag}");
+        let reader = SerializedFileReader::new(bytes::Bytes::from(get(bucket, "big.parquet")))
+            .expect("a Parquet file");
+        assert_eq!(reader.metadata().num_row_groups(), 11);
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 11000);
+    }
+
+    #[pg_test]
+    fn write_parquet_checks_types() {
+        use crate::parquet_export::{decimal_units, ColumnKind};
+
+        assert_eq!(decimal_units("12.34", 2), Ok(1234));
+        assert_eq!(decimal_units("-0.5", 2), Ok(-50));
+        assert_eq!(decimal_units("7", 3), Ok(7000));
+        assert!(decimal_units("NaN", 2).is_err());
+        assert!(decimal_units("1.234", 2).is_err());
+
+        // numeric(20, 4)
+        assert_eq!(
+            ColumnKind::of("n", pg_sys::NUMERICOID, (20 << 16 | 4) + 4),
+            Ok(ColumnKind::Decimal {
+                precision: 20,
+                scale: 4
+            })
+        );
+        assert_eq!(
+            ColumnKind::of("n", pg_sys::NUMERICOID, -1).unwrap_err(),
+            "column \"n\" is numeric, but Parquet needs a numeric with a precision of at most 38 \
+             and a scale between 0 and the precision; cast it, e.g. to numeric(38, 9)"
+        );
+        let err = ColumnKind::of("p", pg_sys::POINTOID, -1).unwrap_err();
+        assert!(
+            err.starts_with("column \"p\" has type point, which cannot"),
+            "{err}"
+        );
+    }
+
+    #[pg_test]
+    fn read_parquet_returns_rows() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "read-parquet";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        Spi::run(&format!(
+            "SELECT s3_write_parquet('{bucket}', 'rows.parquet', $q$\
+                 SELECT i AS \"Id\", (i * 1.5)::numeric(10, 2) AS amount, \
+                        CASE WHEN i % 2 = 0 THEN 'even' END AS note, \
+                        timestamptz '2024-06-01 00:00:00+00' + i * interval '1 hour' AS at \
+                 FROM generate_series(1, 2500) AS i$q$, row_group_size => 1000)"
+        ))
+        .unwrap();
+
+        // Columns in any order, matched ignoring case, converted to the listed types.
+        let read = |select: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT {select} FROM s3_read_parquet('{bucket}', 'rows.parquet') \
+                     AS t(note text, id bigint, at timestamptz, amount numeric)"
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        assert_eq!(read("count(*)::text"), "2500");
+        assert_eq!(read("sum(id)::text"), "3126250");
+        assert_eq!(
+            read("string_agg(note, ',') FILTER (WHERE id <= 4)"),
+            "even,even"
+        );
+        assert_eq!(
+            read("max(amount)::text || ' ' || max(at AT TIME ZONE 'UTC')::text"),
+            "3750.00 2024-09-13 04:00:00"
+        );
+    }
+
+    #[pg_test(error = "column \"nope\" is not in the Parquet file, which has i")]
+    fn read_parquet_rejects_unknown_columns() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "read-parquet";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        Spi::run(&format!(
+            "SELECT s3_write_parquet('{bucket}', 'one.parquet', 'SELECT 1 AS i')"
+        ))
+        .unwrap();
+        Spi::run(&format!(
+            "SELECT * FROM s3_read_parquet('{bucket}', 'one.parquet') AS t(i int, nope text)"
+        ))
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn read_parquet_converts_fields() {
+        use crate::parquet_read::field_text;
+        use parquet::record::Field;
+
+        let text = |field: &Field, typ| field_text(field, typ).unwrap();
+        assert_eq!(text(&Field::Null, pg_sys::INT4OID), None);
+        assert_eq!(text(&Field::Int(7), pg_sys::INT8OID).as_deref(), Some("7"));
+        assert_eq!(
+            text(&Field::Str("a b".to_owned()), pg_sys::TEXTOID).as_deref(),
+            Some("a b")
+        );
+        assert_eq!(
+            text(&Field::Bytes(vec![1, 0xab].into()), pg_sys::BYTEAOID).as_deref(),
+            Some("\\x01ab")
+        );
+        assert_eq!(
+            text(&Field::Bytes(b"abc".to_vec().into()), pg_sys::TEXTOID).as_deref(),
+            Some("abc")
+        );
+        assert!(field_text(&Field::Bytes(vec![0xff].into()), pg_sys::TEXTOID).is_err());
+        assert_eq!(
+            text(&Field::Date(1), pg_sys::DATEOID).as_deref(),
+            Some("1970-01-02")
+        );
+        assert_eq!(
+            text(&Field::TimestampMicros(1_000_001), pg_sys::TIMESTAMPTZOID).as_deref(),
+            Some("1970-01-01 00:00:01.000001 +00:00")
+        );
+    }
+
+    #[pg_test]
+    fn exports_set_content_types() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "export-types";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        Spi::run(&format!(
+            "SELECT s3_export_csv('{bucket}', 'a.csv', 'SELECT 1 AS id'), \
+                    s3_write_parquet('{bucket}', 'a.parquet', 'SELECT 1 AS id'), \
+                    s3_put_row_json('{bucket}', 'a.json', ROW(1)), \
+                    s3_export_csv('{bucket}', 'b.csv', 'SELECT 1 AS id', content_type => NULL), \
+                    s3_export_csv('{bucket}', 'c.txt', 'SELECT 1 AS id', \
+                                  content_type => 'text/plain; charset=utf-8'), \
+                    s3_put_row_json('{bucket}', 'b.json', ROW(1), \
+                                    content_type => 'application/x-ndjson')"
+        ))
+        .unwrap();
+        let content_type = |key: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT content_type FROM s3_get_object_info('{bucket}', '{key}')"
+            ))
+            .unwrap()
+        };
+        assert_eq!(content_type("a.csv").as_deref(), Some("text/csv"));
+        assert_eq!(
+            content_type("a.parquet").as_deref(),
+            Some("application/vnd.apache.parquet")
+        );
+        assert_eq!(content_type("a.json").as_deref(), Some("application/json"));
+        assert_eq!(content_type("b.csv").as_deref(), Some("text/csv"));
+        assert_eq!(
+            content_type("c.txt").as_deref(),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(
+            content_type("b.json").as_deref(),
+            Some("application/x-ndjson")
+        );
+    }
+
+    #[pg_test]
+    fn idempotency_key_skips_repeated_puts() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "idempotent";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let put = |data: &str, key: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT s3_put_object_text('{bucket}', 'export.csv', '{data}', \
+                                           idempotency_key => '{key}')"
+            ))
+            .unwrap()
+            .unwrap()
+        };
+
+        let first = put("a", "run-1");
+        assert_eq!(put("b", "run-1"), first);
+        assert_eq!(get(bucket, "export.csv"), b"a");
+
+        let second = put("c", "run-2");
+        assert_ne!(second, first);
+        assert_eq!(get(bucket, "export.csv"), b"c");
+        let metadata = Spi::get_one::<pgrx::JsonB>(&format!(
+            "SELECT metadata FROM s3_list_objects('{bucket}', 'export.csv', fetch_metadata => true)"
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(metadata.0["s3-io-idempotency-key"], "run-2");
+    }
+
+    #[pg_test(error = "idempotency_key must be non-empty printable ASCII without spaces")]
+    fn idempotency_key_must_be_ascii() {
+        Spi::run("SELECT s3_put_object('b', 'k', 'x'::bytea, idempotency_key => 'run 1')").unwrap();
+    }
+
+    #[pg_test]
+    fn website_redirect_location_round_trips() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "redirect-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        Spi::run(&format!(
+            "SELECT s3_put_object('{bucket}', 'old-page.html', ''::bytea, \
+                                  website_redirect_location => '/new-page.html')"
+        ))
+        .unwrap();
+
+        let rows: Vec<_> = crate::s3_head_object_version(
+            Some(bucket),
+            "old-page.html",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .collect();
+        assert_eq!(rows.len(), 1);
+        let (_, content_length, .., website_redirect_location, _, _) = &rows[0];
+        assert_eq!(*content_length, Some(0));
+        assert_eq!(website_redirect_location.as_deref(), Some("/new-page.html"));
+    }
+
+    #[pg_test]
+    fn prefix_fingerprint_tracks_changes() {
+        use crate::list::s3_prefix_fingerprint;
+
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "fingerprint-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let fingerprint =
+            || s3_prefix_fingerprint(Some(bucket), "in/", None, None, None, None, None, None);
+
+        let empty = fingerprint();
+        assert_eq!(empty, crate::list::fingerprint(&[]));
+
+        put(bucket, "in/a.csv", b"1");
+        put(bucket, "in/b.csv", b"2");
+        let first = fingerprint();
+        assert_ne!(first, empty);
+        assert_eq!(fingerprint(), first);
+
+        put(bucket, "out/c.csv", b"3");
+        assert_eq!(fingerprint(), first);
+
+        put(bucket, "in/b.csv", b"22");
+        let rewritten = fingerprint();
+        assert_ne!(rewritten, first);
+
+        assert!(crate::s3_delete_object(
+            Some(bucket),
+            "in/a.csv",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+            None,
+        ));
+        assert_ne!(fingerprint(), rewritten);
+    }
+
+    #[pg_test]
+    fn signing_name_is_used_for_new_clients() {
+        use crate::try_ops::s3_try_put_object;
+
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "signing-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let try_put = || {
+            let (etag, _, _, code, _) = s3_try_put_object(
+                Some(bucket),
+                "s.txt",
+                b"signed".to_vec(),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .next()
+            .unwrap();
+            (etag, code)
+        };
+
+        // MinIO only accepts signatures for s3; cached clients must not hide the change.
+        Spi::run("SET s3_io.signing_name = 'execute-api'").unwrap();
+        let (etag, code) = try_put();
+        assert!(etag.is_none());
+        assert!(code.is_some());
+
+        Spi::run("SET s3_io.signing_name = 's3'").unwrap();
+        let (etag, code) = try_put();
+        assert!(etag.is_some() && code.is_none());
+
+        Spi::run("RESET s3_io.signing_name").unwrap();
+        assert!(try_put().0.is_some());
     }
-}
 
-mod testutils;
+    #[pg_test]
+    fn read_lines_splits_on_line_endings() {
+        let _minio = MinioServer::start().expect("minio up");
 
-#[cfg(any(test, feature = "pg_test"))]
-#[pg_schema]
-mod tests {
-    use crate::testutils::minio_test::MinioServer;
-    use pgrx::prelude::*;
+        let bucket = "lines-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let lines = |key| {
+            crate::lines::s3_read_lines(
+                Some(bucket),
+                key,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                "metadata",
+            )
+            .collect::<Vec<_>>()
+        };
+
+        put(bucket, "mixed.log", "first\r\nsecond\n\nlast".as_bytes());
+        assert_eq!(lines("mixed.log"), ["first", "second", "", "last"]);
+
+        put(bucket, "ended.log", "Zoë\nend\r\n".as_bytes());
+        assert_eq!(lines("ended.log"), ["Zoë", "end"]);
+
+        put(bucket, "empty.log", b"");
+        assert!(lines("empty.log").is_empty());
+
+        let long = "x".repeat(100_000);
+        put(bucket, "long.log", format!("{long}\n{long}").as_bytes());
+        assert_eq!(lines("long.log"), [long.clone(), long]);
+    }
 
     #[pg_test]
-    fn exists_true_and_false() {
+    fn read_lines_decompresses_while_streaming() {
+        use crate::{compression::Decompress, Codec};
+        use std::io::Read;
+
+        let text = "alpha\nbeta\n".repeat(2000);
+        let gz = Codec::Gzip.compress(text.as_bytes(), None).unwrap();
+        let zst = Codec::Zstd.compress(text.as_bytes(), None).unwrap();
+        let stream = |mode: Decompress, marker, data: &[u8]| {
+            let mut out = Vec::new();
+            mode.stream(marker, None, data)
+                .unwrap()
+                .read_to_end(&mut out)
+                .unwrap();
+            out
+        };
+        assert_eq!(
+            stream(Decompress::Metadata, Some("zstd"), &zst),
+            text.as_bytes()
+        );
+        assert_eq!(stream(Decompress::Metadata, None, &gz), gz);
+        assert_eq!(stream(Decompress::Auto, None, &gz), text.as_bytes());
+        assert_eq!(stream(Decompress::None, Some("gzip"), &gz), gz);
+
         let _minio = MinioServer::start().expect("minio up");
+        let bucket = "compressed-lines";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        Spi::run(&format!(
+            "SELECT s3_put_object_text('{bucket}', 'log.zst', '{text}', compress => 'zstd')"
+        ))
+        .unwrap();
+        put(bucket, "legacy.log", &gz);
+        let lines = |key, decompress| {
+            crate::lines::s3_read_lines(
+                Some(bucket),
+                key,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                decompress,
+            )
+            .collect::<Vec<_>>()
+        };
 
-        let bucket = "test-bucket";
-        crate::s3_create_bucket(bucket, None, None, None, None, None);
-        crate::s3_put_object(
-            bucket,
-            "hello.txt",
-            "Hi".into(),
+        let expected: Vec<_> = text.lines().map(str::to_owned).collect();
+        assert_eq!(lines("log.zst", "metadata"), expected);
+        assert_eq!(lines("legacy.log", "auto"), expected);
+    }
+
+    #[pg_test(error = "Line 2 of s3://lines-bucket/binary.log is not valid UTF-8")]
+    fn read_lines_rejects_invalid_utf8() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "lines-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "binary.log", b"ok\n\xff\xfe\n");
+        crate::lines::s3_read_lines(
+            Some(bucket),
+            "binary.log",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "metadata",
+        )
+        .for_each(drop);
+    }
+
+    #[pg_test]
+    fn expires_header_round_trips() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "expires-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        Spi::run(&format!(
+            "SELECT s3_put_object_text('{bucket}', 'page.html', '<p>hi</p>', \
+                                       expires => '2030-01-02 03:04:05.678+02')"
+        ))
+        .unwrap();
+        put(bucket, "plain.html", b"<p>hi</p>");
+
+        let expires = |key| {
+            let rows: Vec<_> = crate::s3_head_object_version(
+                Some(bucket),
+                key,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .collect();
+            rows.into_iter().next().unwrap().8
+        };
+        // HTTP dates have whole seconds.
+        let expected =
+            Spi::get_one::<TimestampWithTimeZone>("SELECT timestamptz '2030-01-02 01:04:05+00'")
+                .unwrap();
+        assert_eq!(expires("page.html"), expected);
+        assert_eq!(expires("plain.html"), None);
+    }
+
+    #[pg_test(error = "expires must be a finite timestamp")]
+    fn expires_must_be_finite() {
+        Spi::run("SELECT s3_put_object('b', 'k', 'x'::bytea, expires => 'infinity')").unwrap();
+    }
+
+    #[pg_test]
+    fn delete_prefix_deletes_in_batches() {
+        use crate::bulk::s3_delete_prefix;
+
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "delete-prefix-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        // More than one ListObjectsV2 page and DeleteObjects request.
+        for i in 0..1005 {
+            put(bucket, &format!("old/{i:04}.csv"), b"x");
+        }
+        put(bucket, "keep/a.csv", b"x");
+        put(bucket, "older.csv", b"x");
+
+        let deleted = s3_delete_prefix(
+            Some(bucket),
+            "old/",
+            None,
             None,
             None,
             None,
             None,
             None,
             None,
+            false,
+            "raise",
         );
-        assert!(crate::s3_object_exists_lazy(
-            bucket,
-            "hello.txt",
+        assert_eq!(deleted, 1005);
+        let keys: Vec<_> = crate::list::s3_list_objects(
+            Some(bucket),
+            "",
             None,
             None,
             None,
             None,
-            None
-        ));
+            None,
+            None,
+            false,
+            false,
+            None,
+        )
+        .map(|row| row.0)
+        .collect();
+        assert_eq!(keys, ["keep/a.csv", "older.csv"]);
+
+        let deleted = s3_delete_prefix(
+            Some(bucket),
+            "old/",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+            "raise",
+        );
+        assert_eq!(deleted, 0);
+
+        let deleted = s3_delete_prefix(
+            Some(bucket),
+            "",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            true,
+            "raise",
+        );
+        assert_eq!(deleted, 2);
+    }
+
+    #[pg_test(
+        error = "an empty prefix deletes every object in bucket \"b\"; pass confirm => true to do that"
+    )]
+    fn delete_prefix_needs_confirm_for_whole_bucket() {
+        crate::bulk::s3_delete_prefix(
+            Some("b"),
+            "",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+            "warn",
+        );
+    }
+
+    #[pg_test(
+        error = "S3 transfer stalled: no data sent or received for 1 s (s3_io.stall_timeout)"
+    )]
+    fn stall_timeout_gives_up_on_silent_endpoint() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        // Accepts connections but never answers.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        Spi::run("SET s3_io.stall_timeout = 1").unwrap();
+        crate::s3_object_exists_lazy(
+            Some("b"),
+            "k",
+            Some(&endpoint),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+        );
+    }
+
+    #[pg_test]
+    fn stall_timeout_allows_moving_transfers() {
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "stall-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        Spi::run("SET s3_io.stall_timeout = 1").unwrap();
+        let data: Vec<u8> = (0..8 << 20).map(|i| (i % 251) as u8).collect();
+        put(bucket, "big.bin", &data);
+        assert_eq!(get(bucket, "big.bin"), data);
+    }
+
+    #[pg_test]
+    fn presign_prefix_signs_every_object() {
+        use crate::presign::s3_presign_prefix;
+
+        let _minio = MinioServer::start().expect("minio up");
+
+        let bucket = "presign-prefix-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        for name in ["c", "a", "b"] {
+            put(bucket, &format!("set/{name}.csv"), name.as_bytes());
+        }
+        put(bucket, "other/d.csv", b"d");
+
+        let urls = |max_keys| {
+            s3_presign_prefix(
+                Some(bucket),
+                "set/",
+                600,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                max_keys,
+            )
+            .collect::<Vec<_>>()
+        };
+        let all = urls(1000);
+        let keys: Vec<_> = all.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["set/a.csv", "set/b.csv", "set/c.csv"]);
+        for (key, url) in &all {
+            assert!(url.contains("X-Amz-Expires=600"), "{url}");
+            let body = reqwest::blocking::get(url).unwrap().bytes().unwrap();
+            assert_eq!(body.as_ref(), get(bucket, key));
+        }
+
+        let capped = urls(2);
+        assert_eq!(capped.len(), 2);
+        assert_eq!(capped[1].0, "set/b.csv");
+    }
+
+    #[pg_test]
+    fn customer_keys_are_checked() {
+        use crate::sse::CustomerKey;
+
+        let key = [7u8; 32];
+        assert!(CustomerKey::new(None, None).unwrap().is_none());
+        assert!(CustomerKey::new(Some(&key), None).unwrap().is_some());
+        assert!(CustomerKey::new(Some(&key), Some("aes256"))
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            CustomerKey::new(Some(&key[..16]), None).unwrap_err(),
+            "sse_customer_key must be 32 bytes (AES-256), got 16"
+        );
+        assert_eq!(
+            CustomerKey::new(Some(&key), Some("aws:kms")).unwrap_err(),
+            "sse_customer_algorithm must be AES256, got \"aws:kms\""
+        );
+        assert_eq!(
+            CustomerKey::new(None, Some("AES256")).unwrap_err(),
+            "sse_customer_algorithm requires sse_customer_key"
+        );
+        // The key never shows up in debug output.
+        let debug = format!("{:?}", CustomerKey::new(Some(&key), None).unwrap());
+        assert!(!debug.contains("BwcH"), "{debug}");
+    }
+
+    #[pg_test(error = "server_side_encryption and sse_customer_key cannot be combined")]
+    fn customer_key_excludes_server_side_encryption() {
+        Spi::run(
+            "SELECT s3_put_object('b', 'k', 'x'::bytea, server_side_encryption => 'AES256', \
+                                  sse_customer_key => decode(repeat('00', 32), 'hex'))",
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn copy_objects_in_bulk() {
+        use crate::bulk::{destination_key, s3_copy_objects};
+
+        let map = [
+            ("raw/".to_owned(), "archive/".to_owned()),
+            ("raw/2024/".to_owned(), "archive/y2024/".to_owned()),
+        ];
+        assert_eq!(
+            destination_key("raw/2023/a.csv", &map),
+            "archive/2023/a.csv"
+        );
+        assert_eq!(
+            destination_key("raw/2024/b.csv", &map),
+            "archive/y2024/b.csv"
+        );
+        assert_eq!(destination_key("other/c.csv", &map), "other/c.csv");
+
+        let _minio = MinioServer::start().expect("minio up");
+
+        let (src, dst) = ("copy-src", "copy-dst");
+        crate::s3_create_bucket(src, None, None, None, None, None, None);
+        crate::s3_create_bucket(dst, None, None, None, None, None, None);
+        let keys: Vec<String> = (0..40).map(|i| format!("raw/{i:02} a.csv")).collect();
+        for key in &keys {
+            put(src, key, key.as_bytes());
+        }
+        let mut requested = keys.clone();
+        requested.insert(3, "raw/missing.csv".to_owned());
+
+        Spi::run("SET s3_io.bulk_concurrency = 4").unwrap();
+        let rows: Vec<_> = s3_copy_objects(
+            Some(src),
+            Some(dst),
+            requested.clone(),
+            Some(pgrx::JsonB(serde_json::json!({"raw/": "moved/"}))),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "ignore",
+            true,
+        )
+        .collect();
+        let returned: Vec<_> = rows.iter().map(|(key, ..)| key.clone()).collect();
+        assert_eq!(returned, requested);
+        for (key, etag, error) in &rows {
+            if key == "raw/missing.csv" {
+                assert!(etag.is_none() && error.is_some());
+                continue;
+            }
+            assert!(etag.is_some() && error.is_none(), "{key}: {error:?}");
+            let moved = key.replacen("raw/", "moved/", 1);
+            assert_eq!(get(dst, &moved), key.as_bytes());
+        }
+    }
+
+    #[pg_test]
+    fn client_from_config() {
+        use crate::client_config::ClientConfig;
+        use serde_json::json;
+
+        let parse = |config| ClientConfig::parse(&config).map(|c| c.hash());
+        assert_eq!(
+            parse(json!({"endpoint_url": "localhost:9000"})).unwrap_err(),
+            "config needs a \"region\""
+        );
+        assert!(parse(json!({"region": "eu-west-1", "retries": 3}))
+            .unwrap_err()
+            .starts_with("config has unknown field \"retries\""));
+        assert_eq!(
+            parse(json!({"region": "eu-west-1", "access_key": "ak"})).unwrap_err(),
+            "config needs both \"access_key\" and \"secret_key\", or neither"
+        );
+        assert_eq!(
+            parse(json!({"region": "eu-west-1", "read_timeout": 0})).unwrap_err(),
+            "config field \"read_timeout\" must be a positive number of seconds"
+        );
+        assert_eq!(
+            parse(json!({"region": "eu-west-1", "max_attempts": 0})).unwrap_err(),
+            "config field \"max_attempts\" must be a positive integer"
+        );
+        assert_ne!(
+            parse(json!({"region": "eu-west-1"})).unwrap(),
+            parse(json!({"region": "eu-west-1", "connect_timeout": 2.5})).unwrap()
+        );
+
+        let _minio = MinioServer::start().expect("minio up");
+        let env = |name| std::env::var(name).unwrap();
+        let (access_key, secret_key) = (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY"));
+        let config = |secret_key: String| {
+            pgrx::JsonB(json!({
+                "endpoint_url": env("S3_ENDPOINT_URL"),
+                "region": "us-east-1",
+                "force_path_style": true,
+                "access_key": access_key,
+                "secret_key": secret_key,
+                "connect_timeout": 2,
+                "read_timeout": 30,
+                "max_attempts": 2,
+            }))
+        };
+        let list = || {
+            crate::try_run(&crate::ClientArgs::default(), |client| async move {
+                client
+                    .list_buckets()
+                    .send()
+                    .await
+                    .map_err(|err| crate::error::sdk_error("ListBuckets", err))
+            })
+        };
+
+        // The config's keys are enough without credentials in the environment.
+        std::env::remove_var("AWS_ACCESS_KEY_ID");
+        std::env::remove_var("AWS_SECRET_ACCESS_KEY");
+        let hash =
+            crate::client_config::s3_client_from_config(Some(config(secret_key.clone()))).unwrap();
+        assert_eq!(hash.len(), 64);
+        let listed = list();
+        std::env::set_var("AWS_ACCESS_KEY_ID", &access_key);
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", &secret_key);
+        assert!(listed.is_ok());
+
+        // Calls without connection args now use the config's credentials.
+        let other = crate::client_config::s3_client_from_config(Some(config("wrong".to_owned())));
+        assert_ne!(other.as_deref(), Some(hash.as_str()));
+        assert!(list().is_err());
+
+        assert_eq!(crate::client_config::s3_client_from_config(None), None);
+        assert!(list().is_ok());
+    }
+
+    #[pg_test]
+    fn object_ready_needs_min_size() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "ready";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "placeholder.csv", b"");
+        put(bucket, "data.csv", b"a,b\n1,2\n");
+
+        let ready = |key, min_size| {
+            crate::s3_object_ready(
+                Some(bucket),
+                key,
+                min_size,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+        assert!(!ready("placeholder.csv", 1));
+        assert!(ready("placeholder.csv", 0));
+        assert!(ready("data.csv", 1));
+        assert!(ready("data.csv", 8));
+        assert!(!ready("data.csv", 9));
+        assert!(!ready("missing.csv", 0));
+    }
+
+    #[pg_test]
+    fn check_credentials_tells_invalid_from_unreachable() {
+        use crate::{credentials::verdict, error::S3Error};
+
+        let error = |code: &str| S3Error {
+            code: Some(code.to_owned()),
+            ..S3Error::new("failed")
+        };
+        assert_eq!(
+            verdict(error("InvalidAccessKeyId")).unwrap(),
+            (false, Some("InvalidAccessKeyId".to_owned()))
+        );
+        assert_eq!(
+            verdict(error("AccessDenied")).unwrap(),
+            (true, Some("AccessDenied".to_owned()))
+        );
+        assert!(verdict(error("DispatchFailure")).is_err());
+        assert!(verdict(S3Error::new("no code")).is_err());
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "credentials";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let detail = |sql: &str| {
+            Spi::get_two::<bool, String>(&format!(
+                "SELECT valid, reason FROM s3_check_credentials_detail({sql})"
+            ))
+            .unwrap()
+        };
+
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT s3_check_credentials()").unwrap(),
+            Some(true)
+        );
+        assert_eq!(detail(&format!("'{bucket}'")), (Some(true), None));
+        assert_eq!(
+            detail("access_key => 'nobody', secret_key => 'secret'"),
+            (Some(false), Some("InvalidAccessKeyId".to_owned()))
+        );
+        let access_key = std::env::var("AWS_ACCESS_KEY_ID").unwrap();
+        assert_eq!(
+            detail(&format!(
+                "'{bucket}', access_key => '{access_key}', secret_key => 'wrong-secret'"
+            )),
+            (Some(false), Some("SignatureDoesNotMatch".to_owned()))
+        );
+    }
+
+    #[pg_test]
+    fn head_objects_for_a_manifest() {
+        use crate::bulk::s3_head_objects;
+
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "manifest";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let mut etags = Vec::new();
+        for i in 0..30 {
+            etags.push(put(bucket, &format!("part-{i:02}"), &vec![b'x'; i]));
+        }
+        let mut keys: Vec<String> = (0..30).rev().map(|i| format!("part-{i:02}")).collect();
+        keys.insert(5, "part-missing".to_owned());
+
+        Spi::run("SET s3_io.bulk_concurrency = 4").unwrap();
+        let rows: Vec<_> = s3_head_objects(
+            Some(bucket),
+            keys.clone(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "raise",
+        )
+        .collect();
+        assert_eq!(rows.iter().map(|r| r.0.clone()).collect::<Vec<_>>(), keys);
+        for (key, exists, size, etag, error) in rows {
+            assert_eq!(error, None);
+            if key == "part-missing" {
+                assert_eq!((exists, size, etag), (Some(false), None, None));
+                continue;
+            }
+            let i: usize = key["part-".len()..].parse().unwrap();
+            assert_eq!(exists, Some(true));
+            assert_eq!(size, Some(i as i64));
+            assert_eq!(etag.as_deref(), Some(etags[i].as_str()));
+        }
+
+        // Being refused is an error, not a missing object.
+        let rows: Vec<_> = s3_head_objects(
+            Some(bucket),
+            vec!["part-01".to_owned()],
+            None,
+            Some("nobody"),
+            Some("wrong-secret"),
+            None,
+            None,
+            None,
+            None,
+            "ignore",
+        )
+        .collect();
+        assert_eq!(rows[0].1, None);
+        assert!(rows[0].4.is_some());
+    }
+
+    #[pg_test]
+    fn default_bucket_fills_in_null_bucket() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "default-bucket";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        Spi::run(&format!("SET s3_io.default_bucket = '{bucket}'")).unwrap();
+
+        Spi::run("SELECT s3_put_object(NULL, 'd.txt', 'hello'::bytea)").unwrap();
+        assert_eq!(get(bucket, "d.txt"), b"hello");
+        let data = Spi::get_one::<Vec<u8>>("SELECT s3_get_object(object_key => 'd.txt')")
+            .unwrap()
+            .unwrap();
+        assert_eq!(data, b"hello");
+        let listed = Spi::get_one::<i64>("SELECT count(*) FROM s3_list_objects()")
+            .unwrap()
+            .unwrap();
+        assert_eq!(listed, 1);
+        let exists = Spi::get_one::<bool>("SELECT s3_object_exists_lazy(object_key => 'd.txt')")
+            .unwrap()
+            .unwrap();
+        assert!(exists);
+        let ready = Spi::get_one::<bool>("SELECT s3_object_ready(NULL, 'd.txt')")
+            .unwrap()
+            .unwrap();
+        assert!(ready);
+        let text = Spi::get_one::<String>("SELECT s3_get_object_text(object_key => 'd.txt')")
+            .unwrap()
+            .unwrap();
+        assert_eq!(text, "hello");
+        Spi::run("SELECT s3_copy_object(NULL, 'd.txt', NULL, 'e.txt')").unwrap();
+        let size = Spi::get_one::<i64>(
+            "SELECT content_length FROM s3_get_object_info(object_key => 'e.txt')",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(size, 5);
+        let peeked = Spi::get_one::<String>("SELECT s3_peek_text(NULL, 'e.txt', 4)")
+            .unwrap()
+            .unwrap();
+        assert_eq!(peeked, "hell");
+        Spi::run("SELECT s3_delete_object(NULL, 'e.txt')").unwrap();
+
+        // An explicit bucket still wins.
+        Spi::run("SET s3_io.default_bucket = 'no-such-bucket'").unwrap();
+        assert_eq!(get(bucket, "d.txt"), b"hello");
+
+        Spi::run(&format!("SET s3_io.default_bucket = '{bucket}'")).unwrap();
+        Spi::run("SELECT s3_delete_object(NULL, 'd.txt')").unwrap();
+        let missing =
+            Spi::get_one::<i64>("SELECT count(*) FROM s3_head_object_version(NULL, 'd.txt')")
+                .unwrap()
+                .unwrap();
+        assert_eq!(missing, 0);
+    }
+
+    #[pg_test(error = "No bucket given, and s3_io.default_bucket is not set")]
+    fn null_bucket_needs_default_bucket() {
+        let _minio = MinioServer::start().expect("minio up");
+        Spi::run("SELECT s3_get_object(NULL, 'd.txt')").unwrap();
+    }
+
+    #[pg_test]
+    fn audit_table_logs_operations() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "audited";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        Spi::run(
+            "CREATE TABLE s3_audit (timestamp timestamptz, operation text, bucket text, \
+             key text, bytes bigint, duration_ms double precision, result text, error_code text)",
+        )
+        .unwrap();
+        Spi::run("SET s3_io.audit_table = 's3_audit'").unwrap();
+
+        put(bucket, "dir/a b.txt", b"hello");
+        assert_eq!(get(bucket, "dir/a b.txt"), b"hello");
         assert!(!crate::s3_object_exists_lazy(
-            bucket, "nope.txt", None, None, None, None, None
+            Some(bucket),
+            "missing.txt",
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            false
         ));
 
-        log!("tests done");
+        let row = |operation: &str| {
+            Spi::get_three::<String, i64, String>(&format!(
+                "SELECT coalesce(key, ''), coalesce(bytes, -1), result || '/' || coalesce(error_code, '') \
+                 FROM s3_audit WHERE operation = '{operation}' AND bucket = '{bucket}' \
+                   AND timestamp <= now() + interval '1 minute' AND duration_ms >= 0"
+            ))
+            .unwrap()
+        };
+        assert_eq!(
+            row("PutObject"),
+            (Some("dir/a b.txt".into()), Some(5), Some("ok/".into()))
+        );
+        assert_eq!(
+            row("GetObject"),
+            (Some("dir/a b.txt".into()), Some(5), Some("ok/".into()))
+        );
+        assert_eq!(
+            row("HeadObject"),
+            (
+                Some("missing.txt".into()),
+                Some(-1),
+                Some("error/HTTP 404".into())
+            )
+        );
+        let leaked = Spi::get_one::<i64>(
+            "SELECT count(*) FROM s3_audit WHERE s3_audit::text LIKE '%minio12345%'",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(leaked, 0);
+
+        // A broken audit table does not fail the operation.
+        Spi::run("SET s3_io.audit_table = 'no_such_table'").unwrap();
+        put(bucket, "still.txt", b"works");
+        assert_eq!(get(bucket, "still.txt"), b"works");
     }
 
     #[pg_test]
-    fn create_bucket() {
+    fn get_object_text_detects_encoding() {
+        use crate::encoding::sniff;
+
+        assert_eq!(sniff(b"\xEF\xBB\xBFhi", None).0, "UTF8");
+        assert_eq!(
+            sniff(b"\xFF\xFEh\0", Some("text/plain; charset=latin1")).0,
+            "UTF16LE"
+        );
+        assert_eq!(
+            sniff(b"hi", Some("text/csv; Charset=\"Windows-1252\"")).0,
+            "WIN1252"
+        );
+        assert_eq!(sniff(b"hi", Some("text/plain; charset=us-ascii")).0, "UTF8");
+        assert_eq!(sniff(b"hi", Some("text/plain")).0, "UTF8");
+
         let _minio = MinioServer::start().expect("minio up");
+        let bucket = "text-encodings";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        let text = |key: &str, hex: &str, content_type: &str| {
+            Spi::run(&format!(
+                "SELECT s3_put_object('{bucket}', '{key}', '\\x{hex}'::bytea, \
+                     content_type => '{content_type}')"
+            ))
+            .unwrap();
+            Spi::get_one::<String>(&format!(
+                "SELECT s3_get_object_text('{bucket}', '{key}', encoding => 'auto')"
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        // "hé" in UTF-16LE with a byte order mark, despite the Content-Type.
+        assert_eq!(
+            text("bom16", "fffe6800e900", "text/plain; charset=utf-8"),
+            "hé"
+        );
+        assert_eq!(text("bom8", "efbbbf68c3a9", "text/plain"), "hé");
+        assert_eq!(
+            text("latin1", "68e9", "text/plain; charset=ISO-8859-1"),
+            "hé"
+        );
+        assert_eq!(text("plain", "68c3a9", "application/octet-stream"), "hé");
 
-        let bucket = "tbk";
-        assert!(crate::s3_create_bucket(
-            bucket, None, None, None, None, None
-        ));
+        let explicit = Spi::get_one::<String>(&format!(
+            "SELECT s3_get_object_text('{bucket}', 'latin1', encoding => 'LATIN1')"
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(explicit, "hé");
+    }
+
+    #[pg_test(
+        error = "Cannot decode s3://text-invalid/bad.txt as UTF8 (by default): Invalid UTF8 data at byte 1"
+    )]
+    fn get_object_text_rejects_undecodable_bytes() {
+        let _minio = MinioServer::start().expect("minio up");
+        let bucket = "text-invalid";
+        crate::s3_create_bucket(bucket, None, None, None, None, None, None);
+        put(bucket, "bad.txt", b"h\xe9");
+        Spi::run(&format!(
+            "SELECT s3_get_object_text('{bucket}', 'bad.txt', encoding => 'auto')"
+        ))
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn put_object_ensures_bucket() {
+        let _minio = MinioServer::start().expect("minio up");
+        let put_to = |bucket: &str, key: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT s3_put_object('{bucket}', '{key}', 'hi'::bytea, ensure_bucket => true)"
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        assert!(!put_to("tenant-a", "first.txt").is_empty());
+        assert!(!put_to("tenant-a", "second.txt").is_empty());
+        assert_eq!(get("tenant-a", "first.txt"), b"hi");
+        assert_eq!(get("tenant-a", "second.txt"), b"hi");
+
+        // Two creators racing for the same bucket both succeed.
+        let results = crate::run(&crate::ClientArgs::default(), |client| async move {
+            let mut tasks = tokio::task::JoinSet::new();
+            for _ in 0..2 {
+                let client = client.clone();
+                tasks
+                    .spawn(async move { crate::ensure_bucket(&client, "tenant-race", None).await });
+            }
+            Ok(tasks.join_all().await)
+        });
+        assert!(results.iter().all(Result::is_ok));
+        assert!(!put_to("tenant-race", "k.txt").is_empty());
+    }
+
+    #[pg_test]
+    fn regional_endpoint_rewrites_aws_only() {
+        assert_eq!(
+            crate::regional_endpoint("s3.amazonaws.com", "eu-west-1"),
+            "https://s3.eu-west-1.amazonaws.com"
+        );
+        assert_eq!(
+            crate::regional_endpoint("https://s3.us-east-1.amazonaws.com", "eu-west-1"),
+            "https://s3.eu-west-1.amazonaws.com"
+        );
+        assert_eq!(
+            crate::regional_endpoint("http://127.0.0.1:9000", "eu-west-1"),
+            "http://127.0.0.1:9000"
+        );
     }
 }
 
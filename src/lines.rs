@@ -0,0 +1,96 @@
+use pgrx::prelude::*;
+use std::io::BufRead;
+
+use crate::{
+    compression::{Decompress, CODEC_METADATA_KEY},
+    csv_gz::BodyReader,
+    error::{note_request_ids, sdk_error},
+    run, uri, ClientArgs,
+};
+
+/// The lines of a UTF-8 text object, one row each, without their `\n` or
+/// `\r\n` line ending. A last line without a line ending is returned too.
+///
+/// The body is downloaded and decompressed as in `s3_get_object` as rows are
+/// consumed, so only the current line is held in memory.
+#[pg_extern]
+pub(crate) fn s3_read_lines(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    decompress: default!(&str, "'metadata'"),
+) -> SetOfIterator<'static, String> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+
+    let decompress = Decompress::parse(decompress).unwrap_or_else(|e| pgrx::error!("{e}"));
+
+    let out = run(&args, |client| async move {
+        let out = client
+            .get_object()
+            .bucket(bucket)
+            .key(object_key)
+            .send()
+            .await
+            .map_err(|err| sdk_error("GetObject", err))?;
+        note_request_ids("GetObject", &out);
+        Ok(out)
+    });
+    let marker = out
+        .metadata()
+        .and_then(|m| m.get(CODEC_METADATA_KEY))
+        .cloned();
+    let content_encoding = out.content_encoding().map(str::to_owned);
+    let body = decompress
+        .stream(
+            marker.as_deref(),
+            content_encoding.as_deref(),
+            BodyReader::new(out.body),
+        )
+        .unwrap_or_else(|e| pgrx::error!("{e}"));
+    let mut body = std::io::BufReader::new(body);
+
+    let uri = format!("s3://{bucket}/{object_key}");
+    let mut number = 0;
+    let mut line = Vec::new();
+    SetOfIterator::new(std::iter::from_fn(move || {
+        line.clear();
+        let n = body
+            .read_until(b'\n', &mut line)
+            .unwrap_or_else(|e| pgrx::error!("Cannot read {uri}: {e}"));
+        if n == 0 {
+            return None;
+        }
+        number += 1;
+        Some(
+            line_text(&line)
+                .unwrap_or_else(|e| pgrx::error!("Line {number} of {uri} {e}"))
+                .to_owned(),
+        )
+    }))
+}
+
+/// `line` without its line ending, if it is text Postgres can store.
+fn line_text(line: &[u8]) -> Result<&str, &'static str> {
+    let line = match line.strip_suffix(b"\n") {
+        Some(line) => line.strip_suffix(b"\r").unwrap_or(line),
+        None => line,
+    };
+    if line.contains(&0) {
+        return Err("contains a NUL byte");
+    }
+    std::str::from_utf8(line).map_err(|_| "is not valid UTF-8")
+}
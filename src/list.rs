@@ -0,0 +1,564 @@
+use pgrx::prelude::*;
+
+use crate::{
+    bulk::{fan_out, OnError},
+    error::{note_request_ids, sdk_error, S3Error},
+    guc, head_object, run, to_timestamptz, uri, ClientArgs,
+};
+
+/// An entry directly below a prefix: an object key or a common prefix ending in the delimiter.
+pub(crate) struct Entry {
+    pub key: String,
+    pub is_prefix: bool,
+}
+
+/// List the objects and common prefixes directly below `prefix`, in key order.
+///
+/// Stops after `limit` entries. The object named like `prefix` itself (a
+/// "folder marker") is skipped.
+pub(crate) async fn list_level(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    prefix: &str,
+    delimiter: &str,
+    limit: usize,
+) -> Result<Vec<Entry>, S3Error> {
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(prefix)
+        .delimiter(delimiter)
+        .into_paginator()
+        .send();
+
+    let mut entries = Vec::new();
+    while entries.len() < limit {
+        let Some(page) = pages.next().await else {
+            break;
+        };
+        let page = page.map_err(|err| sdk_error("ListObjectsV2", err))?;
+        note_request_ids("ListObjectsV2", &page);
+
+        let objects = page.contents().iter().filter_map(|o| o.key());
+        let prefixes = page.common_prefixes().iter().filter_map(|p| p.prefix());
+        let mut level: Vec<_> = objects
+            .filter(|key| *key != prefix)
+            .map(|key| Entry {
+                key: key.to_owned(),
+                is_prefix: false,
+            })
+            .chain(prefixes.map(|key| Entry {
+                key: key.to_owned(),
+                is_prefix: true,
+            }))
+            .collect();
+        level.sort_by(|a, b| a.key.cmp(&b.key));
+        entries.extend(level);
+    }
+    entries.truncate(limit);
+    Ok(entries)
+}
+
+/// All objects whose key starts with `prefix`, with their listing details.
+///
+/// The owner columns are only filled with `fetch_owner`, which makes S3 do
+/// extra work per object. `metadata` is only filled with `fetch_metadata`,
+/// which costs a HeadObject per object, run `s3_io.bulk_concurrency` at a time.
+///
+/// At most `max_pages` pages of up to 1000 keys are fetched (by default
+/// `s3_io.list_max_pages`, 0 meaning no limit); a WARNING says when that cut
+/// the listing short.
+#[pg_extern]
+pub(crate) fn s3_list_objects(
+    bucket: default!(Option<&str>, "NULL"),
+    prefix: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    fetch_owner: default!(bool, "false"),
+    fetch_metadata: default!(bool, "false"),
+    max_pages: default!(Option<i32>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(key, String),
+        name!(size, Option<i64>),
+        name!(last_modified, Option<TimestampWithTimeZone>),
+        name!(etag, Option<String>),
+        name!(storage_class, Option<String>),
+        name!(owner_id, Option<String>),
+        name!(owner_display_name, Option<String>),
+        name!(metadata, Option<pgrx::JsonB>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let bucket = uri::bucket_or_default(bucket);
+    let bucket = bucket.as_str();
+    let max_pages = page_limit(max_pages);
+
+    let (objects, truncated) = run(&args, |client| async move {
+        let mut pages = client
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .fetch_owner(fetch_owner)
+            .into_paginator()
+            .send();
+
+        let mut objects = Vec::new();
+        let mut truncated = false;
+        let mut fetched = 0;
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|err| sdk_error("ListObjectsV2", err))?;
+            note_request_ids("ListObjectsV2", &page);
+            objects.extend(page.contents().iter().cloned());
+            fetched += 1;
+            if at_limit(&page, fetched, max_pages) {
+                truncated = true;
+                break;
+            }
+        }
+        Ok((objects, truncated))
+    });
+
+    if truncated {
+        warn_truncated("s3_list_objects", max_pages);
+    }
+    let metadata = if fetch_metadata {
+        let keys = objects.iter().filter_map(|o| o.key()).map(str::to_owned);
+        user_metadata(&args, bucket, keys.collect())
+    } else {
+        vec![None; objects.len()]
+    };
+    TableIterator::new(
+        objects
+            .into_iter()
+            .filter(|o| o.key().is_some())
+            .zip(metadata)
+            .map(|(o, metadata)| {
+                let owner = o.owner();
+                (
+                    o.key().unwrap_or_default().to_owned(),
+                    o.size(),
+                    o.last_modified().and_then(to_timestamptz),
+                    o.e_tag().map(|e| e.trim_matches('"').to_owned()),
+                    o.storage_class().map(|c| c.as_str().to_owned()),
+                    owner.and_then(|o| o.id()).map(str::to_owned),
+                    owner.and_then(|o| o.display_name()).map(str::to_owned),
+                    metadata.map(pgrx::JsonB),
+                )
+            }),
+    )
+}
+
+/// Objects below `prefix` modified after `since`.
+///
+/// S3 cannot filter by time, so the whole prefix is listed and filtered while
+/// paging. When keys sort by time (e.g. `2024/06/01/...`), `start_after` skips
+/// listing the keys up to and including it. `max_pages` caps the pages
+/// fetched, as for `s3_list_objects`.
+#[pg_extern]
+pub(crate) fn s3_list_modified_since(
+    bucket: Option<&str>,
+    prefix: &str,
+    since: TimestampWithTimeZone,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    start_after: default!(Option<&str>, "NULL"),
+    max_pages: default!(Option<i32>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(key, String),
+        name!(size, Option<i64>),
+        name!(last_modified, TimestampWithTimeZone),
+        name!(etag, Option<String>),
+        name!(storage_class, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let bucket = uri::bucket_or_default(bucket);
+    let bucket = bucket.as_str();
+    let since = since.into_inner();
+    let max_pages = page_limit(max_pages);
+
+    let (objects, truncated) = run(&args, |client| async move {
+        let mut pages = client
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_start_after(start_after.map(str::to_owned))
+            .into_paginator()
+            .send();
+
+        let mut objects = Vec::new();
+        let mut fetched = 0;
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|err| sdk_error("ListObjectsV2", err))?;
+            note_request_ids("ListObjectsV2", &page);
+            objects.extend(page.contents().iter().filter_map(|o| {
+                let modified = o.last_modified().and_then(to_timestamptz)?;
+                if modified.into_inner() <= since {
+                    return None;
+                }
+                Some((
+                    o.key()?.to_owned(),
+                    o.size(),
+                    modified,
+                    o.e_tag().map(|e| e.trim_matches('"').to_owned()),
+                    o.storage_class().map(|c| c.as_str().to_owned()),
+                ))
+            }));
+            fetched += 1;
+            if at_limit(&page, fetched, max_pages) {
+                return Ok((objects, true));
+            }
+        }
+        Ok((objects, false))
+    });
+
+    if truncated {
+        warn_truncated("s3_list_modified_since", max_pages);
+    }
+    TableIterator::new(objects)
+}
+
+/// Objects with keys from `start_key` to `end_key`, both inclusive, in key order.
+///
+/// Listing starts right before `start_key` and stops at the first page past
+/// `end_key`, so only the pages covering the range are fetched. `max_pages`
+/// caps them further, as for `s3_list_objects`.
+#[pg_extern]
+pub(crate) fn s3_list_range(
+    bucket: Option<&str>,
+    start_key: &str,
+    end_key: &str,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    max_pages: default!(Option<i32>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(key, String),
+        name!(size, Option<i64>),
+        name!(last_modified, Option<TimestampWithTimeZone>),
+        name!(etag, Option<String>),
+        name!(storage_class, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let bucket = uri::bucket_or_default(bucket);
+    let bucket = bucket.as_str();
+    if start_key > end_key {
+        pgrx::error!("start_key must not sort after end_key");
+    }
+    // StartAfter is exclusive; starting after `start_key` minus its last
+    // character includes it, at the cost of a few keys filtered out below.
+    let start_after = start_key
+        .char_indices()
+        .last()
+        .map(|(i, _)| &start_key[..i])
+        .filter(|s| !s.is_empty());
+    let max_pages = page_limit(max_pages);
+
+    let (objects, truncated) = run(&args, |client| async move {
+        let mut pages = client
+            .list_objects_v2()
+            .bucket(bucket)
+            .set_start_after(start_after.map(str::to_owned))
+            .into_paginator()
+            .send();
+
+        let mut objects = Vec::new();
+        let mut fetched = 0;
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|err| sdk_error("ListObjectsV2", err))?;
+            note_request_ids("ListObjectsV2", &page);
+            let mut past_end = false;
+            for o in page.contents() {
+                let Some(key) = o.key() else { continue };
+                if key > end_key {
+                    past_end = true;
+                    break;
+                }
+                if key >= start_key {
+                    objects.push((
+                        key.to_owned(),
+                        o.size(),
+                        o.last_modified().and_then(to_timestamptz),
+                        o.e_tag().map(|e| e.trim_matches('"').to_owned()),
+                        o.storage_class().map(|c| c.as_str().to_owned()),
+                    ));
+                }
+            }
+            if past_end {
+                break;
+            }
+            fetched += 1;
+            if at_limit(&page, fetched, max_pages) {
+                return Ok((objects, true));
+            }
+        }
+        Ok((objects, false))
+    });
+
+    if truncated {
+        warn_truncated("s3_list_range", max_pages);
+    }
+    TableIterator::new(objects)
+}
+
+/// A hash of every object below `prefix`: equal fingerprints mean no object
+/// was added, removed or rewritten since.
+///
+/// The hex SHA-256 covers each object's key, ETag and size, in key order. The
+/// whole prefix is listed every time, 1000 keys per request, regardless of
+/// `s3_io.list_max_pages`: a fingerprint of part of the prefix would miss changes.
+#[pg_extern]
+pub(crate) fn s3_prefix_fingerprint(
+    bucket: default!(Option<&str>, "NULL"),
+    prefix: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let bucket = uri::bucket_or_default(bucket);
+    let bucket = bucket.as_str();
+
+    let mut objects = run(&args, |client| async move {
+        let mut pages = client
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .into_paginator()
+            .send();
+
+        let mut objects = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|err| sdk_error("ListObjectsV2", err))?;
+            note_request_ids("ListObjectsV2", &page);
+            objects.extend(page.contents().iter().filter_map(|o| {
+                Some((
+                    o.key()?.to_owned(),
+                    o.e_tag().map(|e| e.trim_matches('"').to_owned()),
+                    o.size(),
+                ))
+            }));
+        }
+        Ok(objects)
+    });
+    // Listings are in key order on S3, but not on every compatible store.
+    objects.sort();
+    fingerprint(&objects)
+}
+
+/// Hex SHA-256 over `(key, etag, size)` tuples, each field length-prefixed so
+/// that no two different listings hash the same input.
+pub(crate) fn fingerprint(objects: &[(String, Option<String>, Option<i64>)]) -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut hash = Sha256::new();
+    for (key, etag, size) in objects {
+        for field in [Some(key.as_str()), etag.as_deref()] {
+            match field {
+                Some(f) => {
+                    hash.update([1]);
+                    hash.update((f.len() as u64).to_be_bytes());
+                    hash.update(f);
+                }
+                None => hash.update([0]),
+            }
+        }
+        match size {
+            Some(size) => {
+                hash.update([1]);
+                hash.update(size.to_be_bytes());
+            }
+            None => hash.update([0]),
+        }
+    }
+    hash.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Pages a listing may fetch: `max_pages`, or `s3_io.list_max_pages` if NULL, 0 meaning no limit.
+pub(crate) fn page_limit(max_pages: Option<i32>) -> usize {
+    let max_pages = max_pages.unwrap_or_else(|| guc::LIST_MAX_PAGES.get());
+    match max_pages {
+        ..0 => pgrx::error!("max_pages must not be negative"),
+        0 => usize::MAX,
+        n => n as usize,
+    }
+}
+
+/// Whether a listing must stop after `page`, the `fetched`th, though more pages follow.
+fn at_limit(
+    page: &aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output,
+    fetched: usize,
+    max_pages: usize,
+) -> bool {
+    fetched >= max_pages && page.is_truncated().unwrap_or_default()
+}
+
+fn warn_truncated(function: &str, max_pages: usize) {
+    pgrx::warning!(
+        "{function} stopped after max_pages = {max_pages} pages, the result is incomplete"
+    );
+}
+
+/// User metadata of `keys` as JSON objects, in order, from HeadObjects run
+/// `s3_io.bulk_concurrency` at a time; any that fail raise an error.
+///
+/// Objects deleted since they were listed get `None`.
+fn user_metadata(
+    args: &ClientArgs,
+    bucket: &str,
+    keys: Vec<String>,
+) -> Vec<Option<serde_json::Value>> {
+    let items = keys.into_iter().map(|key| (key, ())).collect();
+    fan_out(
+        args,
+        bucket,
+        "HEAD requests",
+        OnError::Raise,
+        items,
+        |client, key, ()| {
+            let bucket = bucket.to_owned();
+            async move {
+                let head = head_object(&client, &bucket, &key, None).await?;
+                Ok(head.map(|h| metadata_json(h.metadata())))
+            }
+        },
+    )
+    .into_iter()
+    .map(|(_, metadata)| metadata.ok().flatten())
+    .collect()
+}
+
+fn metadata_json(
+    metadata: Option<&std::collections::HashMap<String, String>>,
+) -> serde_json::Value {
+    serde_json::Value::Object(
+        metadata
+            .into_iter()
+            .flatten()
+            .map(|(k, v)| (k.clone(), serde_json::Value::from(v.as_str())))
+            .collect(),
+    )
+}
+
+/// Walk the tree below `prefix`, descending into common prefixes up to `max_depth` levels.
+///
+/// Rows come in depth-first order, each prefix followed by its contents;
+/// entries directly below `prefix` have depth 1. At most `max_rows` rows are
+/// returned.
+#[pg_extern]
+pub(crate) fn s3_walk(
+    bucket: default!(Option<&str>, "NULL"),
+    prefix: default!(&str, "''"),
+    max_depth: default!(i32, "1"),
+    max_rows: default!(i32, "10000"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(key, String),
+        name!(is_prefix, bool),
+        name!(depth, i32),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let bucket = uri::bucket_or_default(bucket);
+    let bucket = bucket.as_str();
+    if max_depth < 1 {
+        pgrx::error!("max_depth must be at least 1");
+    }
+    if max_rows < 0 {
+        pgrx::error!("max_rows must not be negative");
+    }
+    let max_rows = max_rows as usize;
+
+    let (rows, truncated) = run(&args, |client| async move {
+        let mut rows = Vec::new();
+        // Entries still to emit, the next one last.
+        let mut pending: Vec<(Entry, i32)> = Vec::new();
+        let push_level = |pending: &mut Vec<_>, level: Vec<Entry>, depth| {
+            pending.extend(level.into_iter().rev().map(|e| (e, depth)));
+        };
+
+        let level = list_level(&client, bucket, prefix, "/", max_rows + 1).await?;
+        push_level(&mut pending, level, 1);
+
+        while let Some((entry, depth)) = pending.pop() {
+            if rows.len() == max_rows {
+                return Ok((rows, true));
+            }
+            if entry.is_prefix && depth < max_depth {
+                let remaining = max_rows - rows.len();
+                let level = list_level(&client, bucket, &entry.key, "/", remaining).await?;
+                push_level(&mut pending, level, depth + 1);
+            }
+            rows.push((entry.key, entry.is_prefix, depth));
+        }
+        Ok((rows, false))
+    });
+
+    if truncated {
+        notice!("s3_walk stopped after max_rows = {max_rows} rows");
+    }
+    TableIterator::new(rows)
+}
@@ -0,0 +1,750 @@
+use pgrx::prelude::*;
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{Mutex, OnceLock},
+};
+
+use crate::{
+    block_on,
+    error::{note_request_ids, sdk_error, S3Error},
+    put_object, run, uri, ClientArgs,
+};
+
+/// S3 rejects multipart parts smaller than this, except for the last one.
+pub(crate) const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Highest part number S3 accepts.
+pub(crate) const MAX_PARTS: i32 = 10_000;
+
+/// A multipart upload fed chunk by chunk from SQL within this backend.
+pub(crate) struct StreamingUpload {
+    pub(crate) client: aws_sdk_s3::Client,
+    pub(crate) bucket: String,
+    pub(crate) key: String,
+    pub(crate) buffer: Vec<u8>,
+    pub(crate) parts: Vec<aws_sdk_s3::types::CompletedPart>,
+}
+
+pub(crate) fn uploads() -> &'static Mutex<HashMap<String, StreamingUpload>> {
+    static UPLOADS: OnceLock<Mutex<HashMap<String, StreamingUpload>>> = OnceLock::new();
+    UPLOADS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// A streaming upload taken out of the session's uploads for one call.
+///
+/// It is registered again when dropped, also when the call raises midway, e.g.
+/// on cancel or `statement_timeout`, so it can still be continued or aborted;
+/// [`Checkout::finish`] removes it for good.
+pub(crate) struct Checkout {
+    upload_id: String,
+    upload: Option<StreamingUpload>,
+}
+
+impl Checkout {
+    /// Forget the upload, once it is completed or aborted.
+    pub(crate) fn finish(mut self) {
+        self.upload = None;
+    }
+}
+
+impl std::ops::Deref for Checkout {
+    type Target = StreamingUpload;
+
+    fn deref(&self) -> &StreamingUpload {
+        self.upload.as_ref().expect("upload is checked out")
+    }
+}
+
+impl std::ops::DerefMut for Checkout {
+    fn deref_mut(&mut self) -> &mut StreamingUpload {
+        self.upload.as_mut().expect("upload is checked out")
+    }
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            if let Ok(mut uploads) = uploads().lock() {
+                uploads.insert(std::mem::take(&mut self.upload_id), upload);
+            }
+        }
+    }
+}
+
+pub(crate) fn take_upload(upload_id: &str) -> Checkout {
+    match uploads().lock().unwrap().remove(upload_id) {
+        Some(upload) => Checkout {
+            upload_id: upload_id.to_owned(),
+            upload: Some(upload),
+        },
+        None => pgrx::error!("No multipart upload {upload_id:?} in this session"),
+    }
+}
+
+/// Start a multipart upload whose data is appended with `s3_multipart_upload_part`.
+///
+/// Returns the upload id. The upload lives in this backend only and is not
+/// tied to the transaction: finish it with `s3_multipart_complete` or
+/// `s3_multipart_abort`, otherwise S3 keeps the uploaded parts.
+#[pg_extern]
+pub(crate) fn s3_multipart_begin(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    content_type: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+
+    // Keep the client that succeeded, so parts follow a region redirect.
+    let (client, upload_id) = run(&args, |client| async move {
+        let upload_id = create_upload(&client, bucket, object_key, content_type).await?;
+        Ok((client, upload_id))
+    });
+
+    uploads().lock().unwrap().insert(
+        upload_id.clone(),
+        StreamingUpload {
+            client,
+            bucket: bucket.to_string(),
+            key: object_key.to_string(),
+            buffer: Vec::new(),
+            parts: Vec::new(),
+        },
+    );
+    upload_id
+}
+
+/// Append `data` to a streaming upload.
+///
+/// Chunks may have any size; they are buffered and sent as parts of at least
+/// 5 MiB. Returns the number of parts sent to S3 so far.
+#[pg_extern]
+pub(crate) fn s3_multipart_upload_part(upload_id: &str, data: &[u8]) -> i32 {
+    let mut upload = take_upload(upload_id);
+    upload.buffer.extend_from_slice(data);
+    let result = if upload.buffer.len() >= MIN_PART_SIZE {
+        block_on(flush_part(&mut upload, upload_id))
+    } else {
+        Ok(())
+    };
+    let sent = upload.parts.len() as i32;
+    drop(upload);
+    result.unwrap_or_else(|e| e.raise());
+    sent
+}
+
+/// Send the remaining data and complete the upload, returning the object's ETag.
+#[pg_extern]
+pub(crate) fn s3_multipart_complete(upload_id: &str) -> String {
+    let mut upload = take_upload(upload_id);
+
+    let result = block_on(async {
+        // The last part may be short, and S3 needs at least one part.
+        if !upload.buffer.is_empty() || upload.parts.is_empty() {
+            flush_part(&mut upload, upload_id).await?;
+        }
+
+        complete_upload(
+            &upload.client,
+            &upload.bucket,
+            &upload.key,
+            upload_id,
+            upload.parts.clone(),
+            None,
+        )
+        .await
+    });
+
+    match result {
+        Ok(etag) => {
+            upload.finish();
+            etag
+        }
+        Err(e) => {
+            drop(upload);
+            e.raise()
+        }
+    }
+}
+
+/// Abort a streaming upload and discard the parts S3 already stored.
+#[pg_extern]
+pub(crate) fn s3_multipart_abort(upload_id: &str) -> bool {
+    let upload = take_upload(upload_id);
+
+    let result = block_on(abort_upload(
+        &upload.client,
+        &upload.bucket,
+        &upload.key,
+        upload_id,
+    ));
+
+    match result {
+        Ok(aborted) => {
+            upload.finish();
+            aborted
+        }
+        Err(e) => {
+            drop(upload);
+            e.raise()
+        }
+    }
+}
+
+/// Upload the buffered data as the next part; the buffer is only cleared once
+/// the part is stored, so it survives failures and cancellation.
+async fn flush_part(upload: &mut StreamingUpload, upload_id: &str) -> Result<(), S3Error> {
+    let part_number = upload.parts.len() as i32 + 1;
+    let body = bytes::Bytes::copy_from_slice(&upload.buffer);
+
+    match upload
+        .client
+        .upload_part()
+        .bucket(&upload.bucket)
+        .key(&upload.key)
+        .upload_id(upload_id)
+        .part_number(part_number)
+        .body(body.into())
+        .send()
+        .await
+    {
+        Ok(out) => {
+            note_request_ids("UploadPart", &out);
+            upload.buffer.clear();
+            upload.parts.push(
+                aws_sdk_s3::types::CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(out.e_tag().map(str::to_owned))
+                    .build(),
+            );
+            Ok(())
+        }
+        Err(err) => Err(sdk_error("UploadPart", err)),
+    }
+}
+
+/// Upload the elements of `parts`, in order, as one object and return its ETag.
+///
+/// Each element of at least 5 MiB becomes its own multipart part. Smaller
+/// elements are merged with the ones following them until the merged part
+/// reaches 5 MiB; only the last part may stay smaller.
+#[pg_extern]
+pub(crate) fn s3_put_parts(
+    bucket: Option<&str>,
+    object_key: &str,
+    parts: Array<'_, &[u8]>,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    content_type: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    if parts.contains_nulls() {
+        pgrx::error!("parts must not contain NULLs");
+    }
+    let elements: Vec<&[u8]> = parts.iter().flatten().collect();
+    let groups = part_groups(elements.iter().map(|e| e.len()));
+    if groups.len() > MAX_PARTS as usize {
+        pgrx::error!(
+            "parts make {} multipart parts, more than the {MAX_PARTS} S3 allows",
+            groups.len()
+        );
+    }
+
+    // Keep the client that succeeded, so parts follow a region redirect.
+    let (client, upload_id) = run(&args, |client| async move {
+        let upload_id = create_upload(&client, bucket, object_key, content_type).await?;
+        Ok((client, upload_id))
+    });
+
+    let result = block_on(async {
+        let mut completed = Vec::with_capacity(groups.len());
+        for (part_number, group) in (1..).zip(groups) {
+            let body = bytes::Bytes::from(elements[group].concat());
+            completed.push(
+                upload_part(&client, bucket, object_key, &upload_id, part_number, body).await?,
+            );
+        }
+        complete_upload(&client, bucket, object_key, &upload_id, completed, None).await
+    });
+    if result.is_err() {
+        // Keep the original error; a failed abort only leaves parts behind.
+        let _ = block_on(abort_upload(&client, bucket, object_key, &upload_id));
+    }
+    result.unwrap_or_else(|e| e.raise())
+}
+
+/// Group consecutive elements of the given sizes into multipart parts: an
+/// element is added to the current part until that reaches [`MIN_PART_SIZE`].
+///
+/// There is always at least one part, as S3 requires.
+pub(crate) fn part_groups(sizes: impl IntoIterator<Item = usize>) -> Vec<Range<usize>> {
+    let mut groups = Vec::new();
+    let (mut start, mut size) = (0, 0);
+    let mut end = 0;
+    for (i, len) in sizes.into_iter().enumerate() {
+        end = i + 1;
+        size += len;
+        if size >= MIN_PART_SIZE {
+            groups.push(start..end);
+            (start, size) = (end, 0);
+        }
+    }
+    if start < end || groups.is_empty() {
+        groups.push(start..end);
+    }
+    groups
+}
+
+/// Start a multipart upload and return its upload id.
+///
+/// Together with `s3_upload_part`, `s3_complete_multipart_upload` and
+/// `s3_abort_multipart_upload` this exposes the raw S3 multipart API.
+#[pg_extern]
+pub(crate) fn s3_create_multipart_upload(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    content_type: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+
+    run(&args, |client| async move {
+        create_upload(&client, bucket, object_key, content_type).await
+    })
+}
+
+/// Upload part `part_number` (1..10000) of a multipart upload and return its ETag.
+///
+/// All parts but the last must be at least 5 MiB; this is checked when the
+/// upload is completed.
+#[pg_extern]
+pub(crate) fn s3_upload_part(
+    bucket: Option<&str>,
+    object_key: &str,
+    upload_id: &str,
+    part_number: i32,
+    data: Vec<u8>,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> String {
+    if !(1..=MAX_PARTS).contains(&part_number) {
+        pgrx::error!("part_number must be between 1 and {MAX_PARTS}, got {part_number}");
+    }
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let data = bytes::Bytes::from(data);
+
+    run(&args, |client| {
+        let body = aws_sdk_s3::primitives::ByteStream::from(data.clone());
+        async move {
+            match client
+                .upload_part()
+                .bucket(bucket)
+                .key(object_key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(body)
+                .send()
+                .await
+            {
+                Ok(out) => {
+                    note_request_ids("UploadPart", &out);
+                    Ok(out
+                        .e_tag()
+                        .unwrap_or_default()
+                        .trim_matches('"')
+                        .to_string())
+                }
+                Err(err) => Err(sdk_error("UploadPart", err)),
+            }
+        }
+    })
+}
+
+/// Complete a multipart upload from the part ETags in part order, returning the object's ETag.
+///
+/// `part_etags[1]` is the ETag of part 1, and so on.
+#[pg_extern]
+pub(crate) fn s3_complete_multipart_upload(
+    bucket: Option<&str>,
+    object_key: &str,
+    upload_id: &str,
+    part_etags: Vec<String>,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> String {
+    if part_etags.is_empty() || part_etags.len() > MAX_PARTS as usize {
+        pgrx::error!(
+            "part_etags must contain between 1 and {MAX_PARTS} entries, got {}",
+            part_etags.len()
+        );
+    }
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let parts: Vec<_> = part_etags
+        .iter()
+        .zip(1..)
+        .map(|(etag, part_number)| {
+            aws_sdk_s3::types::CompletedPart::builder()
+                .part_number(part_number)
+                .e_tag(etag)
+                .build()
+        })
+        .collect();
+
+    run(&args, |client| {
+        let parts = parts.clone();
+        async move {
+            check_part_sizes(&client, bucket, object_key, upload_id, parts.len()).await?;
+            complete_upload(&client, bucket, object_key, upload_id, parts, None).await
+        }
+    })
+}
+
+/// Abort a multipart upload, discarding its uploaded parts.
+#[pg_extern]
+pub(crate) fn s3_abort_multipart_upload(
+    bucket: Option<&str>,
+    object_key: &str,
+    upload_id: &str,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> bool {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+
+    run(&args, |client| async move {
+        abort_upload(&client, bucket, object_key, upload_id).await
+    })
+}
+
+pub(crate) async fn create_upload(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    content_type: Option<&str>,
+) -> Result<String, S3Error> {
+    match client
+        .create_multipart_upload()
+        .bucket(bucket)
+        .key(object_key)
+        .set_content_type(content_type.map(str::to_owned))
+        .send()
+        .await
+    {
+        Ok(out) => {
+            note_request_ids("CreateMultipartUpload", &out);
+            Ok(out.upload_id().unwrap_or_default().to_string())
+        }
+        Err(err) => Err(sdk_error("CreateMultipartUpload", err)),
+    }
+}
+
+/// Upload `body` as part `part_number` of an upload.
+pub(crate) async fn upload_part(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    upload_id: &str,
+    part_number: i32,
+    body: bytes::Bytes,
+) -> Result<aws_sdk_s3::types::CompletedPart, S3Error> {
+    let out = client
+        .upload_part()
+        .bucket(bucket)
+        .key(object_key)
+        .upload_id(upload_id)
+        .part_number(part_number)
+        .body(body.into())
+        .send()
+        .await
+        .map_err(|err| sdk_error("UploadPart", err))?;
+    note_request_ids("UploadPart", &out);
+    Ok(aws_sdk_s3::types::CompletedPart::builder()
+        .part_number(part_number)
+        .set_e_tag(out.e_tag().map(str::to_owned))
+        .build())
+}
+
+/// Complete an upload, only replacing an object with ETag `if_match` if given.
+pub(crate) async fn complete_upload(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    upload_id: &str,
+    parts: Vec<aws_sdk_s3::types::CompletedPart>,
+    if_match: Option<&str>,
+) -> Result<String, S3Error> {
+    let completed = aws_sdk_s3::types::CompletedMultipartUpload::builder()
+        .set_parts(Some(parts))
+        .build();
+    match client
+        .complete_multipart_upload()
+        .bucket(bucket)
+        .key(object_key)
+        .upload_id(upload_id)
+        .multipart_upload(completed)
+        .set_if_match(if_match.map(str::to_owned))
+        .send()
+        .await
+    {
+        Ok(out) => {
+            note_request_ids("CompleteMultipartUpload", &out);
+            Ok(out
+                .e_tag()
+                .unwrap_or_default()
+                .trim_matches('"')
+                .to_string())
+        }
+        Err(err) => Err(sdk_error("CompleteMultipartUpload", err)),
+    }
+}
+
+pub(crate) async fn abort_upload(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    upload_id: &str,
+) -> Result<bool, S3Error> {
+    match client
+        .abort_multipart_upload()
+        .bucket(bucket)
+        .key(object_key)
+        .upload_id(upload_id)
+        .send()
+        .await
+    {
+        Ok(out) => {
+            note_request_ids("AbortMultipartUpload", &out);
+            Ok(true)
+        }
+        Err(err) => Err(sdk_error("AbortMultipartUpload", err)),
+    }
+}
+
+/// Fail early with a clear message if any of the first `part_count` parts
+/// except the last is below the S3 minimum part size.
+async fn check_part_sizes(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    upload_id: &str,
+    part_count: usize,
+) -> Result<(), S3Error> {
+    let mut parts = client
+        .list_parts()
+        .bucket(bucket)
+        .key(object_key)
+        .upload_id(upload_id)
+        .into_paginator()
+        .items()
+        .send();
+
+    while let Some(part) = parts.next().await {
+        let part = part.map_err(|err| sdk_error("ListParts", err))?;
+        let number = part.part_number().unwrap_or_default();
+        let size = part.size().unwrap_or_default();
+        if (number as usize) < part_count && (size as usize) < MIN_PART_SIZE {
+            return Err(S3Error::new(format!(
+                "Part {number} is {size} bytes; all parts but the last must be at least {MIN_PART_SIZE} bytes"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// An object written in order by an export, sent with one PutObject if it
+/// stays below [`MIN_PART_SIZE`] and as a multipart upload otherwise.
+pub(crate) struct ObjectWriter<'a> {
+    args: ClientArgs<'a>,
+    bucket: &'a str,
+    object_key: &'a str,
+    content_type: &'a str,
+    /// The client that created the upload, and its id.
+    upload: Option<(aws_sdk_s3::Client, String)>,
+    parts: Vec<aws_sdk_s3::types::CompletedPart>,
+    buffer: Vec<u8>,
+}
+
+impl<'a> ObjectWriter<'a> {
+    pub(crate) fn new(
+        args: ClientArgs<'a>,
+        bucket: &'a str,
+        object_key: &'a str,
+        content_type: &'a str,
+    ) -> Self {
+        Self {
+            args,
+            bucket,
+            object_key,
+            content_type,
+            upload: None,
+            parts: Vec::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// The data not sent yet, to append to.
+    pub(crate) fn buffer(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+
+    /// Send the buffered data as the next part once it is large enough.
+    pub(crate) fn flush(&mut self) -> Result<(), S3Error> {
+        if self.buffer.len() >= MIN_PART_SIZE {
+            self.send_part()?;
+        }
+        Ok(())
+    }
+
+    /// Upload the buffered data as the next part, starting the upload if needed.
+    fn send_part(&mut self) -> Result<(), S3Error> {
+        let (bucket, object_key, content_type) = (self.bucket, self.object_key, self.content_type);
+        if self.upload.is_none() {
+            // Keep the client that succeeded, so parts follow a region redirect.
+            self.upload = Some(run(&self.args, |client| async move {
+                let upload_id =
+                    create_upload(&client, bucket, object_key, Some(content_type)).await?;
+                Ok((client, upload_id))
+            }));
+        }
+        let (client, upload_id) = self.upload.as_ref().expect("upload started");
+        let body = bytes::Bytes::from(std::mem::take(&mut self.buffer));
+        let part_number = self.parts.len() as i32 + 1;
+        let part = block_on(upload_part(
+            client,
+            bucket,
+            object_key,
+            upload_id,
+            part_number,
+            body,
+        ))?;
+        self.parts.push(part);
+        Ok(())
+    }
+
+    /// Upload what is left and return the object's ETag.
+    pub(crate) fn finish(&mut self) -> Result<String, S3Error> {
+        let (bucket, object_key, content_type) = (self.bucket, self.object_key, self.content_type);
+        if self.upload.is_none() {
+            let body = bytes::Bytes::from(std::mem::take(&mut self.buffer));
+            let out = run(&self.args, |client| {
+                put_object(
+                    client,
+                    bucket,
+                    object_key,
+                    body.clone(),
+                    Some(content_type),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                )
+            });
+            return Ok(out.e_tag().unwrap_or_default().trim_matches('"').to_owned());
+        }
+        if !self.buffer.is_empty() {
+            self.send_part()?;
+        }
+        let (client, upload_id) = self.upload.as_ref().expect("upload started");
+        let parts = std::mem::take(&mut self.parts);
+        block_on(complete_upload(
+            client, bucket, object_key, upload_id, parts, None,
+        ))
+    }
+
+    /// Abort the multipart upload, if one was started.
+    pub(crate) fn abort(&self) {
+        // A failed abort only leaves parts behind.
+        if let Some((client, upload_id)) = &self.upload {
+            let _ = block_on(abort_upload(
+                client,
+                self.bucket,
+                self.object_key,
+                upload_id,
+            ));
+        }
+    }
+}
@@ -0,0 +1,498 @@
+use pgrx::{prelude::*, PgTupleDesc};
+use std::sync::Arc;
+
+use parquet::{
+    basic::{Compression, LogicalType, Repetition, TimeUnit, Type as PhysicalType},
+    data_type::{
+        BoolType, ByteArray, ByteArrayType, DoubleType, FixedLenByteArray, FixedLenByteArrayType,
+        FloatType, Int32Type, Int64Type,
+    },
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::types::Type,
+};
+
+use crate::{error::S3Error, multipart::ObjectWriter, uri, ClientArgs};
+
+/// Rows fetched from the query's cursor at a time.
+const BATCH_ROWS: i64 = 1000;
+
+/// Content type of exports without a `content_type` argument.
+const CONTENT_TYPE: &str = "application/vnd.apache.parquet";
+
+/// Export the result of `query` as a Parquet file and return its ETag.
+///
+/// Columns keep their names and become optional Parquet columns; see
+/// [`ColumnKind`] for the types that can be written. Rows are collected into
+/// row groups of `row_group_size` rows, each written out as soon as it is
+/// full: up to 5 MiB the file is sent with one PutObject, larger exports are
+/// streamed as a multipart upload. The object's content type is
+/// `application/vnd.apache.parquet` unless `content_type` is given.
+#[pg_extern]
+pub(crate) fn s3_write_parquet(
+    bucket: Option<&str>,
+    object_key: &str,
+    query: &str,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    compression: default!(&str, "'snappy'"),
+    row_group_size: default!(i32, "100000"),
+    content_type: default!(Option<&str>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    if row_group_size < 1 {
+        pgrx::error!("row_group_size must be at least 1");
+    }
+    let compression = parse_compression(compression).unwrap_or_else(|e| pgrx::error!("{e}"));
+    let query = query.trim_end().trim_end_matches(';');
+    let columns = columns(query).unwrap_or_else(|e| pgrx::error!("{e}"));
+    let schema = Type::group_type_builder("schema")
+        .with_fields(
+            columns
+                .iter()
+                .map(|(name, kind)| kind.parquet_type(name).map(Arc::new))
+                .collect::<Result<_, _>>()
+                .unwrap_or_else(|e| pgrx::error!("Cannot build Parquet schema: {e}")),
+        )
+        .build()
+        .unwrap_or_else(|e| pgrx::error!("Cannot build Parquet schema: {e}"));
+    let properties = WriterProperties::builder()
+        .set_compression(compression)
+        .set_max_row_group_size(row_group_size as usize)
+        .set_created_by(format!("s3_io {}", env!("CARGO_PKG_VERSION")))
+        .build();
+
+    // Every column converted to what its Parquet column is written from.
+    let aliases: Vec<String> = (1..=columns.len()).map(|i| format!("c{i}")).collect();
+    let value_query = format!(
+        "SELECT {} FROM ({query}) AS q({})",
+        columns
+            .iter()
+            .zip(&aliases)
+            .map(|((_, kind), alias)| kind.select_expr(alias))
+            .collect::<Vec<_>>()
+            .join(", "),
+        aliases.join(", ")
+    );
+
+    let mut export = ObjectWriter::new(
+        args,
+        bucket,
+        object_key,
+        content_type.unwrap_or(CONTENT_TYPE),
+    );
+    let result = SerializedFileWriter::new(Vec::new(), Arc::new(schema), Arc::new(properties))
+        .map_err(parquet_error)
+        .and_then(|mut writer| {
+            let mut group = RowGroup::new(&columns);
+            Spi::connect(|client| {
+                let mut cursor = client.open_cursor(value_query.as_str(), &[]);
+                loop {
+                    let rows = cursor
+                        .fetch(BATCH_ROWS)
+                        .map_err(|e| S3Error::new(format!("Cannot run query: {e}")))?;
+                    if rows.is_empty() {
+                        return Ok::<_, S3Error>(());
+                    }
+                    for row in rows {
+                        group.push(&row)?;
+                        if group.rows == row_group_size as usize {
+                            group.write(&mut writer)?;
+                            export.buffer().append(writer.inner_mut());
+                            export.flush()?;
+                        }
+                    }
+                }
+            })?;
+            if group.rows > 0 {
+                group.write(&mut writer)?;
+            }
+            export
+                .buffer()
+                .append(&mut writer.into_inner().map_err(parquet_error)?);
+            export.finish()
+        });
+    if result.is_err() {
+        export.abort();
+    }
+    result.unwrap_or_else(|e| e.raise())
+}
+
+fn parquet_error(e: parquet::errors::ParquetError) -> S3Error {
+    S3Error::new(format!("Cannot write Parquet: {e}"))
+}
+
+/// Compression codec of the data pages, by name.
+fn parse_compression(name: &str) -> Result<Compression, String> {
+    match name.to_ascii_lowercase().as_str() {
+        "none" | "uncompressed" => Ok(Compression::UNCOMPRESSED),
+        "snappy" => Ok(Compression::SNAPPY),
+        "gzip" => Ok(Compression::GZIP(Default::default())),
+        "lz4" => Ok(Compression::LZ4_RAW),
+        "zstd" => Ok(Compression::ZSTD(Default::default())),
+        _ => Err(format!(
+            "compression must be none, snappy, gzip, lz4 or zstd, got {name:?}"
+        )),
+    }
+}
+
+/// Names and kinds of the columns `query` returns, in order.
+fn columns(query: &str) -> Result<Vec<(String, ColumnKind)>, String> {
+    let described = Spi::connect(|client| {
+        client.select(&format!("SELECT * FROM ({query}) AS q LIMIT 0"), None, &[])?;
+        // SpiTupleTable does not expose type modifiers, which numeric needs;
+        // the descriptor of the result just fetched has them.
+        let tupdesc = unsafe { PgTupleDesc::from_pg_unchecked((*pg_sys::SPI_tuptable).tupdesc) };
+        Ok::<_, pgrx::spi::Error>(
+            tupdesc
+                .iter()
+                .filter(|a| !a.is_dropped())
+                .map(|a| (a.name().to_owned(), a.type_oid().value(), a.type_mod()))
+                .collect::<Vec<_>>(),
+        )
+    })
+    .map_err(|e| format!("Invalid query: {e}"))?;
+    described
+        .into_iter()
+        .map(|(name, oid, typmod)| {
+            let kind = ColumnKind::of(&name, oid, typmod)?;
+            Ok((name, kind))
+        })
+        .collect()
+}
+
+/// The Postgres types that can be written to Parquet, and how.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ColumnKind {
+    /// `boolean` as BOOLEAN.
+    Boolean,
+    /// `smallint` as INT32 annotated INT(16).
+    Int16,
+    /// `integer` as INT32.
+    Int32,
+    /// `bigint` as INT64.
+    Int64,
+    /// `real` as FLOAT.
+    Float,
+    /// `double precision` as DOUBLE.
+    Double,
+    /// `numeric(p, s)` as DECIMAL(p, s): INT32 up to 9 digits, INT64 up to 18,
+    /// a 16-byte FIXED_LEN_BYTE_ARRAY up to 38.
+    Decimal { precision: i32, scale: i32 },
+    /// `text`, `varchar`, `char` and `name` as UTF-8 strings.
+    Text,
+    /// `json` and `jsonb` as JSON strings.
+    Json,
+    /// `bytea` as BYTE_ARRAY.
+    Bytea,
+    /// `date` as DATE, days since 1970-01-01.
+    Date,
+    /// `timestamp` as TIMESTAMP(MICROS) not adjusted to UTC.
+    Timestamp,
+    /// `timestamptz` as TIMESTAMP(MICROS) adjusted to UTC.
+    TimestampTz,
+}
+
+impl ColumnKind {
+    /// How column `name` of type `oid` with modifier `typmod` is written.
+    pub(crate) fn of(name: &str, oid: pg_sys::Oid, typmod: i32) -> Result<Self, String> {
+        Ok(match oid {
+            pg_sys::BOOLOID => Self::Boolean,
+            pg_sys::INT2OID => Self::Int16,
+            pg_sys::INT4OID => Self::Int32,
+            pg_sys::INT8OID => Self::Int64,
+            pg_sys::FLOAT4OID => Self::Float,
+            pg_sys::FLOAT8OID => Self::Double,
+            pg_sys::TEXTOID | pg_sys::VARCHAROID | pg_sys::BPCHAROID | pg_sys::NAMEOID => {
+                Self::Text
+            }
+            pg_sys::JSONOID | pg_sys::JSONBOID => Self::Json,
+            pg_sys::BYTEAOID => Self::Bytea,
+            pg_sys::DATEOID => Self::Date,
+            pg_sys::TIMESTAMPOID => Self::Timestamp,
+            pg_sys::TIMESTAMPTZOID => Self::TimestampTz,
+            pg_sys::NUMERICOID => {
+                // typmod is ((precision << 16) | scale) + VARHDRSZ, -1 if unconstrained.
+                let (precision, scale) = if typmod < 4 {
+                    (0, 0)
+                } else {
+                    let typmod = typmod - 4;
+                    ((typmod >> 16) & 0xffff, ((typmod & 0x7ff) ^ 1024) - 1024)
+                };
+                if !(1..=38).contains(&precision) || !(0..=precision).contains(&scale) {
+                    return Err(format!(
+                        "column {name:?} is {}, but Parquet needs a numeric with a precision \
+                         of at most 38 and a scale between 0 and the precision; cast it, e.g. \
+                         to numeric(38, 9)",
+                        type_name(oid, typmod)
+                    ));
+                }
+                Self::Decimal { precision, scale }
+            }
+            _ => {
+                return Err(format!(
+                    "column {name:?} has type {}, which cannot be written to Parquet; cast it \
+                     to boolean, an integer, real, double precision, numeric(p, s), text, \
+                     json, bytea, date, timestamp or timestamptz",
+                    type_name(oid, typmod)
+                ))
+            }
+        })
+    }
+
+    /// SQL turning column `alias` into the value fetched for this kind.
+    fn select_expr(&self, alias: &str) -> String {
+        match self {
+            Self::Int16 => format!("{alias}::int4"),
+            Self::Decimal { .. } | Self::Text | Self::Json => format!("{alias}::text"),
+            Self::Date => format!("{alias} - date '1970-01-01'"),
+            Self::Timestamp | Self::TimestampTz => {
+                format!("(extract(epoch FROM {alias}) * 1000000)::int8")
+            }
+            _ => alias.to_owned(),
+        }
+    }
+
+    /// The optional Parquet column `name` of this kind.
+    fn parquet_type(&self, name: &str) -> parquet::errors::Result<Type> {
+        let (physical, logical) = match *self {
+            Self::Boolean => (PhysicalType::BOOLEAN, None),
+            Self::Int16 => (
+                PhysicalType::INT32,
+                Some(LogicalType::Integer {
+                    bit_width: 16,
+                    is_signed: true,
+                }),
+            ),
+            Self::Int32 => (PhysicalType::INT32, None),
+            Self::Int64 => (PhysicalType::INT64, None),
+            Self::Float => (PhysicalType::FLOAT, None),
+            Self::Double => (PhysicalType::DOUBLE, None),
+            Self::Decimal { precision, scale } => {
+                return Type::primitive_type_builder(name, decimal_physical_type(precision))
+                    .with_repetition(Repetition::OPTIONAL)
+                    .with_logical_type(Some(LogicalType::Decimal { scale, precision }))
+                    .with_precision(precision)
+                    .with_scale(scale)
+                    .with_length(if precision > 18 { 16 } else { -1 })
+                    .build();
+            }
+            Self::Text => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+            Self::Json => (PhysicalType::BYTE_ARRAY, Some(LogicalType::Json)),
+            Self::Bytea => (PhysicalType::BYTE_ARRAY, None),
+            Self::Date => (PhysicalType::INT32, Some(LogicalType::Date)),
+            Self::Timestamp | Self::TimestampTz => (
+                PhysicalType::INT64,
+                Some(LogicalType::Timestamp {
+                    is_adjusted_to_u_t_c: *self == Self::TimestampTz,
+                    unit: TimeUnit::MICROS,
+                }),
+            ),
+        };
+        Type::primitive_type_builder(name, physical)
+            .with_repetition(Repetition::OPTIONAL)
+            .with_logical_type(logical)
+            .build()
+    }
+}
+
+fn decimal_physical_type(precision: i32) -> PhysicalType {
+    match precision {
+        ..=9 => PhysicalType::INT32,
+        10..=18 => PhysicalType::INT64,
+        _ => PhysicalType::FIXED_LEN_BYTE_ARRAY,
+    }
+}
+
+/// `numeric` or `numeric(p,s)`, as Postgres spells the type.
+fn type_name(oid: pg_sys::Oid, typmod: i32) -> String {
+    unsafe {
+        std::ffi::CStr::from_ptr(pg_sys::format_type_with_typemod(oid, typmod))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// The text of a `numeric(p, scale)` as an integer number of 10^-scale units.
+pub(crate) fn decimal_units(text: &str, scale: i32) -> Result<i128, String> {
+    let invalid = || format!("numeric {text} cannot be written as a Parquet DECIMAL");
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+    let padding = (scale as usize)
+        .checked_sub(frac.len())
+        .ok_or_else(invalid)?;
+    let mut units: i128 = 0;
+    for b in int
+        .bytes()
+        .chain(frac.bytes())
+        .chain(std::iter::repeat_n(b'0', padding))
+    {
+        if !b.is_ascii_digit() {
+            return Err(invalid());
+        }
+        units = units
+            .checked_mul(10)
+            .and_then(|u| u.checked_add(i128::from(b - b'0')))
+            .ok_or_else(invalid)?;
+    }
+    Ok(if negative { -units } else { units })
+}
+
+/// Values of one column of a row group, in its physical type.
+enum Values {
+    Boolean(Vec<bool>),
+    Int32(Vec<i32>),
+    Int64(Vec<i64>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+    Bytes(Vec<ByteArray>),
+    Fixed(Vec<FixedLenByteArray>),
+}
+
+/// The rows of a row group not written yet, column by column.
+struct RowGroup {
+    kinds: Vec<ColumnKind>,
+    values: Vec<Values>,
+    /// Definition levels: 1 for a value, 0 for NULL.
+    levels: Vec<Vec<i16>>,
+    rows: usize,
+}
+
+impl RowGroup {
+    fn new(columns: &[(String, ColumnKind)]) -> Self {
+        let kinds: Vec<_> = columns.iter().map(|(_, kind)| *kind).collect();
+        let mut group = Self {
+            values: Vec::new(),
+            levels: vec![Vec::new(); kinds.len()],
+            kinds,
+            rows: 0,
+        };
+        group.values = group.kinds.iter().map(Self::empty).collect();
+        group
+    }
+
+    fn empty(kind: &ColumnKind) -> Values {
+        match kind {
+            ColumnKind::Boolean => Values::Boolean(Vec::new()),
+            ColumnKind::Int16 | ColumnKind::Int32 | ColumnKind::Date => Values::Int32(Vec::new()),
+            ColumnKind::Int64 | ColumnKind::Timestamp | ColumnKind::TimestampTz => {
+                Values::Int64(Vec::new())
+            }
+            ColumnKind::Float => Values::Float(Vec::new()),
+            ColumnKind::Double => Values::Double(Vec::new()),
+            ColumnKind::Decimal { precision, .. } => match decimal_physical_type(*precision) {
+                PhysicalType::INT32 => Values::Int32(Vec::new()),
+                PhysicalType::INT64 => Values::Int64(Vec::new()),
+                _ => Values::Fixed(Vec::new()),
+            },
+            ColumnKind::Text | ColumnKind::Json | ColumnKind::Bytea => Values::Bytes(Vec::new()),
+        }
+    }
+
+    /// Add the values of `row`, as converted by [`ColumnKind::select_expr`].
+    fn push(&mut self, row: &pgrx::spi::SpiHeapTupleData) -> Result<(), S3Error> {
+        let read_error =
+            |e: pgrx::spi::Error| S3Error::new(format!("Cannot read query result: {e}"));
+        for (i, (kind, values)) in self.kinds.iter().zip(&mut self.values).enumerate() {
+            let ordinal = i + 1;
+            let present = match (kind, values) {
+                (ColumnKind::Boolean, Values::Boolean(v)) => row
+                    .get::<bool>(ordinal)
+                    .map_err(read_error)?
+                    .map(|x| v.push(x)),
+                (ColumnKind::Decimal { scale, .. }, values) => {
+                    match row.get::<String>(ordinal).map_err(read_error)? {
+                        None => None,
+                        Some(text) => {
+                            let units = decimal_units(&text, *scale).map_err(S3Error::new)?;
+                            match values {
+                                Values::Int32(v) => v.push(units as i32),
+                                Values::Int64(v) => v.push(units as i64),
+                                Values::Fixed(v) => v.push(units.to_be_bytes().to_vec().into()),
+                                _ => unreachable!("decimal values"),
+                            }
+                            Some(())
+                        }
+                    }
+                }
+                (_, Values::Int32(v)) => row
+                    .get::<i32>(ordinal)
+                    .map_err(read_error)?
+                    .map(|x| v.push(x)),
+                (_, Values::Int64(v)) => row
+                    .get::<i64>(ordinal)
+                    .map_err(read_error)?
+                    .map(|x| v.push(x)),
+                (_, Values::Float(v)) => row
+                    .get::<f32>(ordinal)
+                    .map_err(read_error)?
+                    .map(|x| v.push(x)),
+                (_, Values::Double(v)) => row
+                    .get::<f64>(ordinal)
+                    .map_err(read_error)?
+                    .map(|x| v.push(x)),
+                (ColumnKind::Bytea, Values::Bytes(v)) => row
+                    .get::<Vec<u8>>(ordinal)
+                    .map_err(read_error)?
+                    .map(|x| v.push(x.into())),
+                (_, Values::Bytes(v)) => row
+                    .get::<String>(ordinal)
+                    .map_err(read_error)?
+                    .map(|x| v.push(x.into_bytes().into())),
+                (_, Values::Boolean(_) | Values::Fixed(_)) => unreachable!("column values"),
+            };
+            self.levels[i].push(i16::from(present.is_some()));
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Write the collected rows as the next row group of `writer`, and start over.
+    fn write(&mut self, writer: &mut SerializedFileWriter<Vec<u8>>) -> Result<(), S3Error> {
+        let mut group = writer.next_row_group().map_err(parquet_error)?;
+        for (values, levels) in self.values.iter().zip(&self.levels) {
+            let mut column = group
+                .next_column()
+                .map_err(parquet_error)?
+                .expect("a column writer per column");
+            let levels = Some(levels.as_slice());
+            match values {
+                Values::Boolean(v) => column.typed::<BoolType>().write_batch(v, levels, None),
+                Values::Int32(v) => column.typed::<Int32Type>().write_batch(v, levels, None),
+                Values::Int64(v) => column.typed::<Int64Type>().write_batch(v, levels, None),
+                Values::Float(v) => column.typed::<FloatType>().write_batch(v, levels, None),
+                Values::Double(v) => column.typed::<DoubleType>().write_batch(v, levels, None),
+                Values::Bytes(v) => column.typed::<ByteArrayType>().write_batch(v, levels, None),
+                Values::Fixed(v) => column
+                    .typed::<FixedLenByteArrayType>()
+                    .write_batch(v, levels, None),
+            }
+            .map_err(parquet_error)?;
+            column.close().map_err(parquet_error)?;
+        }
+        group.close().map_err(parquet_error)?;
+        writer
+            .flush()
+            .map_err(|e| S3Error::new(format!("Cannot write Parquet: {e}")))?;
+
+        self.values = self.kinds.iter().map(Self::empty).collect();
+        self.levels.iter_mut().for_each(Vec::clear);
+        self.rows = 0;
+        Ok(())
+    }
+}
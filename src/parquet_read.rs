@@ -0,0 +1,412 @@
+use pgrx::{prelude::*, PgMemoryContexts, PgTupleDesc};
+use std::{ffi::CString, sync::Arc};
+
+use bytes::{Buf, Bytes};
+use parquet::{
+    errors::ParquetError,
+    file::{
+        metadata::{FooterTail, ParquetMetaData, ParquetMetaDataReader},
+        properties::ReaderProperties,
+        reader::{ChunkReader, Length, RowGroupReader},
+        serialized_reader::SerializedRowGroupReader,
+    },
+    record::Field,
+    schema::types::Type,
+};
+
+use crate::{
+    block_on,
+    error::{note_request_ids, sdk_error, S3Error},
+    parse_content_range, read_body, run, uri, ClientArgs,
+};
+
+/// Bytes fetched from the end of the file first, which usually covers the footer.
+const FOOTER_GUESS: i64 = 64 * 1024;
+
+/// Rows of a Parquet file, as the columns of the column definition list.
+///
+/// Columns are matched to the file's top-level columns by name, exactly or
+/// else ignoring case, and only those are read. Values are converted with the
+/// input function of the column's type; see [`field_text`]. The footer is
+/// read first, then one row group at a time, each with a GetObject of its
+/// byte range pinned to the footer's ETag.
+#[pg_extern(sql = r#"
+CREATE FUNCTION "s3_read_parquet"(
+    "bucket" TEXT,
+    "object_key" TEXT,
+    "endpoint_url" TEXT DEFAULT NULL,
+    "access_key" TEXT DEFAULT NULL,
+    "secret_key" TEXT DEFAULT NULL,
+    "session_token" TEXT DEFAULT NULL,
+    "region" TEXT DEFAULT NULL,
+    "force_path_style" bool DEFAULT NULL
+) RETURNS SETOF record
+LANGUAGE c
+AS 'MODULE_PATHNAME', 's3_read_parquet_wrapper';
+"#)]
+pub(crate) fn s3_read_parquet(
+    bucket: Option<&str>,
+    object_key: &str,
+    endpoint_url: Option<&str>,
+    access_key: Option<&str>,
+    secret_key: Option<&str>,
+    session_token: Option<&str>,
+    region: Option<&str>,
+    force_path_style: Option<bool>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let mut output = unsafe { Output::start(fcinfo) };
+
+    let (client, etag, metadata) = run(&args, |client| async move {
+        let (etag, metadata) = read_metadata(&client, bucket, object_key).await?;
+        Ok((client, etag, metadata))
+    });
+    let fields = metadata.file_metadata().schema().get_fields();
+    let columns: Vec<_> = output
+        .columns
+        .iter()
+        .map(|(name, _)| {
+            fields
+                .iter()
+                .position(|f| f.name() == name)
+                .or_else(|| {
+                    fields
+                        .iter()
+                        .position(|f| f.name().eq_ignore_ascii_case(name))
+                })
+                .unwrap_or_else(|| {
+                    let names: Vec<_> = fields.iter().map(|f| f.name()).collect();
+                    pgrx::error!(
+                        "column {name:?} is not in the Parquet file, which has {}",
+                        names.join(", ")
+                    )
+                })
+        })
+        .collect();
+    // Read each file column once, in file order.
+    let mut read: Vec<usize> = columns.clone();
+    read.sort_unstable();
+    read.dedup();
+    let projection = Type::group_type_builder("schema")
+        .with_fields(read.iter().map(|&i| fields[i].clone()).collect())
+        .build()
+        .unwrap_or_else(|e| pgrx::error!("Cannot read Parquet: {e}"));
+    let positions: Vec<usize> = columns
+        .iter()
+        .map(|c| read.binary_search(c).expect("column is read"))
+        .collect();
+
+    let properties = Arc::new(ReaderProperties::builder().build());
+    let mut row_context = PgMemoryContexts::new("s3_read_parquet row");
+    for group in metadata.row_groups() {
+        let (start, end) = group
+            .columns()
+            .iter()
+            .map(|c| {
+                let (start, len) = c.byte_range();
+                (start, start + len)
+            })
+            .fold((u64::MAX, 0), |(s, e), (start, end)| {
+                (s.min(start), e.max(end))
+            });
+        if start >= end {
+            continue;
+        }
+        let range = block_on(get_range(
+            &client,
+            bucket,
+            object_key,
+            &etag,
+            format!("bytes={start}-{}", end - 1),
+        ))
+        .unwrap_or_else(|e| e.raise());
+        let chunk = Arc::new(FileRange {
+            offset: start,
+            data: range,
+        });
+        let rows = SerializedRowGroupReader::new(chunk, group, None, properties.clone())
+            .and_then(|reader| {
+                reader
+                    .get_row_iter(Some(projection.clone()))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .unwrap_or_else(|e| pgrx::error!("Cannot read Parquet: {e}"));
+        for row in rows {
+            let row = row.into_columns();
+            unsafe {
+                row_context.switch_to(|_| {
+                    output.put(positions.iter().map(|&p| &row[p].1));
+                });
+                row_context.reset();
+            }
+        }
+    }
+}
+
+/// The tuplestore rows are returned in, with the column definition list.
+struct Output {
+    store: *mut pg_sys::Tuplestorestate,
+    desc: pg_sys::TupleDesc,
+    /// Name and input function of each column.
+    columns: Vec<(String, Input)>,
+}
+
+struct Input {
+    typ: pg_sys::Oid,
+    function: pg_sys::FmgrInfo,
+    io_param: pg_sys::Oid,
+    typmod: i32,
+}
+
+impl Output {
+    /// Set up `fcinfo` to return the rows in a tuplestore (materialize mode).
+    unsafe fn start(fcinfo: pg_sys::FunctionCallInfo) -> Self {
+        unsafe {
+            let rsinfo = (*fcinfo).resultinfo as *mut pg_sys::ReturnSetInfo;
+            if rsinfo.is_null()
+                || !pgrx::is_a(rsinfo.cast(), pg_sys::NodeTag::T_ReturnSetInfo)
+                || (*rsinfo).allowedModes & pg_sys::SetFunctionReturnMode::SFRM_Materialize as i32
+                    == 0
+            {
+                pgrx::error!("s3_read_parquet must be called in FROM");
+            }
+            let mut desc = std::ptr::null_mut();
+            if pg_sys::get_call_result_type(fcinfo, std::ptr::null_mut(), &mut desc)
+                != pg_sys::TypeFuncClass::TYPEFUNC_COMPOSITE
+            {
+                pgrx::error!(
+                    "s3_read_parquet needs a column definition list, e.g. AS t(id bigint, name text)"
+                );
+            }
+            let per_query = (*(*rsinfo).econtext).ecxt_per_query_memory;
+            let (store, desc) = PgMemoryContexts::For(per_query).switch_to(|_| {
+                (
+                    pg_sys::tuplestore_begin_heap(false, false, pg_sys::work_mem),
+                    pg_sys::CreateTupleDescCopy(desc),
+                )
+            });
+            (*rsinfo).returnMode = pg_sys::SetFunctionReturnMode::SFRM_Materialize;
+            (*rsinfo).setResult = store;
+            (*rsinfo).setDesc = desc;
+
+            let columns = PgTupleDesc::from_pg_unchecked(desc)
+                .iter()
+                .map(|a| {
+                    let typ = a.type_oid().value();
+                    let (mut function_oid, mut io_param) =
+                        (pg_sys::Oid::INVALID, pg_sys::Oid::INVALID);
+                    pg_sys::getTypeInputInfo(typ, &mut function_oid, &mut io_param);
+                    let mut function = pg_sys::FmgrInfo::default();
+                    pg_sys::fmgr_info_cxt(function_oid, &mut function, per_query);
+                    let input = Input {
+                        typ,
+                        function,
+                        io_param,
+                        typmod: a.type_mod(),
+                    };
+                    (a.name().to_owned(), input)
+                })
+                .collect();
+            Self {
+                store,
+                desc,
+                columns,
+            }
+        }
+    }
+
+    /// Add a row of `fields`, one per column.
+    unsafe fn put<'a>(&mut self, fields: impl Iterator<Item = &'a Field>) {
+        let mut values = Vec::with_capacity(self.columns.len());
+        let mut nulls = Vec::with_capacity(self.columns.len());
+        for ((name, input), field) in self.columns.iter_mut().zip(fields) {
+            let text = field_text(field, input.typ)
+                .unwrap_or_else(|e| pgrx::error!("column {name:?}: {e}"));
+            match text {
+                None => {
+                    values.push(pg_sys::Datum::from(0));
+                    nulls.push(true);
+                }
+                Some(text) => {
+                    let text = CString::new(text)
+                        .unwrap_or_else(|_| pgrx::error!("column {name:?}: value contains NUL"));
+                    values.push(unsafe {
+                        pg_sys::InputFunctionCall(
+                            &mut input.function,
+                            text.as_ptr() as *mut _,
+                            input.io_param,
+                            input.typmod,
+                        )
+                    });
+                    nulls.push(false);
+                }
+            }
+        }
+        unsafe {
+            pg_sys::tuplestore_putvalues(
+                self.store,
+                self.desc,
+                values.as_mut_ptr(),
+                nulls.as_mut_ptr(),
+            )
+        };
+    }
+}
+
+/// `field` as the text input of type `typ`, `None` for NULL.
+///
+/// Strings are taken as they are, binary values as `bytea` (`\x...`) or else
+/// as UTF-8, nested values (lists, maps and groups) as JSON, and everything
+/// else as it prints: numbers and decimals plainly, dates as `YYYY-MM-DD`,
+/// times as `HH:MM:SS.ffffff`, and timestamps in UTC with a `+00:00` offset.
+pub(crate) fn field_text(field: &Field, typ: pg_sys::Oid) -> Result<Option<String>, String> {
+    Ok(Some(match field {
+        Field::Null => return Ok(None),
+        Field::Str(s) => s.clone(),
+        Field::Bytes(b) if typ == pg_sys::BYTEAOID => {
+            let mut hex = String::with_capacity(2 + 2 * b.len());
+            hex.push_str("\\x");
+            for byte in b.data() {
+                hex.push_str(&format!("{byte:02x}"));
+            }
+            hex
+        }
+        Field::Bytes(b) => std::str::from_utf8(b.data())
+            .map_err(|_| "binary value is not UTF-8; read it as bytea".to_owned())?
+            .to_owned(),
+        Field::Group(_) | Field::ListInternal(_) | Field::MapInternal(_) => {
+            field.to_json_value().to_string()
+        }
+        _ => field.to_string(),
+    }))
+}
+
+/// The ETag and metadata of a Parquet object, from its footer.
+async fn read_metadata(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+) -> Result<(String, ParquetMetaData), S3Error> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        S3Error::new(format!(
+            "s3://{bucket}/{object_key} is not a Parquet file: {e}"
+        ))
+    };
+    let (mut tail, etag, size) = get_tail(client, bucket, object_key, None, FOOTER_GUESS).await?;
+    let footer: [u8; 8] = tail
+        .len()
+        .checked_sub(8)
+        .map(|at| tail[at..].try_into().expect("8 bytes"))
+        .ok_or_else(|| invalid(&"too short"))?;
+    let footer = FooterTail::try_from(footer).map_err(|e| invalid(&e))?;
+    let needed = footer.metadata_length() + 8;
+    if needed > tail.len() {
+        if needed as i64 > size {
+            return Err(invalid(&"metadata longer than the file"));
+        }
+        (tail, _, _) = get_tail(client, bucket, object_key, Some(&etag), needed as i64).await?;
+    }
+    let metadata = &tail[tail.len() - needed..tail.len() - 8];
+    let metadata = ParquetMetaDataReader::decode_metadata(metadata).map_err(|e| invalid(&e))?;
+    Ok((etag, metadata))
+}
+
+/// The last `len` bytes of an object, with its ETag and size.
+async fn get_tail(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    if_match: Option<&str>,
+    len: i64,
+) -> Result<(Vec<u8>, String, i64), S3Error> {
+    let out = client
+        .get_object()
+        .bucket(bucket)
+        .key(object_key)
+        .range(format!("bytes=-{len}"))
+        .set_if_match(if_match.map(str::to_owned))
+        .send()
+        .await
+        .map_err(|err| sdk_error("GetObject", err))?;
+    note_request_ids("GetObject", &out);
+    let etag = out.e_tag().unwrap_or_default().to_owned();
+    let total = out
+        .content_range()
+        .and_then(parse_content_range)
+        .and_then(|(_, _, total)| total);
+    let data = read_body(out.body, out.content_length)
+        .await
+        .map_err(|e| S3Error::new(format!("Collect error: {e:?}")))?;
+    // Without Content-Range the provider ignored the range and sent it all.
+    let size = total.unwrap_or(data.len() as i64);
+    Ok((data, etag, size))
+}
+
+/// A byte range of an object, which must still have ETag `etag`.
+async fn get_range(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    etag: &str,
+    range: String,
+) -> Result<Bytes, S3Error> {
+    let out = client
+        .get_object()
+        .bucket(bucket)
+        .key(object_key)
+        .range(range)
+        .if_match(etag)
+        .send()
+        .await
+        .map_err(|err| sdk_error("GetObject", err))?;
+    note_request_ids("GetObject", &out);
+    let data = read_body(out.body, out.content_length)
+        .await
+        .map_err(|e| S3Error::new(format!("Collect error: {e:?}")))?;
+    Ok(data.into())
+}
+
+/// The bytes of a file from `offset` on, addressed by their position in the file.
+struct FileRange {
+    offset: u64,
+    data: Bytes,
+}
+
+impl Length for FileRange {
+    fn len(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+impl ChunkReader for FileRange {
+    type T = bytes::buf::Reader<Bytes>;
+
+    fn get_read(&self, start: u64) -> parquet::errors::Result<Self::T> {
+        let length = self.len().saturating_sub(start) as usize;
+        Ok(self.get_bytes(start, length)?.reader())
+    }
+
+    fn get_bytes(&self, start: u64, length: usize) -> parquet::errors::Result<Bytes> {
+        start
+            .checked_sub(self.offset)
+            .map(|at| at as usize)
+            .filter(|at| at + length <= self.data.len())
+            .map(|at| self.data.slice(at..at + length))
+            .ok_or_else(|| {
+                ParquetError::General(format!(
+                    "bytes {start}..{} are outside the row group",
+                    start + length as u64
+                ))
+            })
+    }
+}
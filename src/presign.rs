@@ -0,0 +1,302 @@
+use pgrx::prelude::*;
+use std::time::Duration;
+
+use aws_sdk_s3::presigning::PresigningConfig;
+
+use crate::{
+    compress_body,
+    error::{note_request_ids, sdk_error, S3Error},
+    put_object, run, s3_object_url, uri, ClientArgs,
+};
+
+/// Longest validity SigV4 allows for a presigned URL.
+const MAX_EXPIRES_IN: i32 = 7 * 24 * 3600;
+
+/// Presigned GetObject URL of an object, valid for `expires_in` seconds.
+///
+/// `response_content_type` and `response_content_disposition` override the
+/// headers S3 sends with the download, whatever the stored metadata says, e.g.
+/// `attachment; filename="report.csv"` to make browsers save it under that name.
+#[pg_extern]
+pub(crate) fn s3_presign_get(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    expires_in: default!(i32, 3600),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    response_content_type: default!(Option<&str>, "NULL"),
+    response_content_disposition: default!(Option<&str>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let presigning = presigning_config(expires_in);
+    if let Some(content_type) = response_content_type {
+        check_content_type(content_type).unwrap_or_else(|e| pgrx::error!("{e}"));
+    }
+    if let Some(disposition) = response_content_disposition {
+        check_header_value("response_content_disposition", disposition)
+            .unwrap_or_else(|e| pgrx::error!("{e}"));
+    }
+
+    run(&args, |client| {
+        let presigning = presigning.clone();
+        async move {
+            presign_get(
+                &client,
+                bucket,
+                object_key,
+                presigning,
+                response_content_type,
+                response_content_disposition,
+            )
+            .await
+        }
+    })
+}
+
+/// Presigned GetObject URLs of the objects below `prefix`, valid for
+/// `expires_in` seconds, in key order.
+///
+/// At most `max_keys` objects are listed, with a WARNING if there are more.
+/// Unlike the bulk functions, URLs are signed one after another rather than
+/// `s3_io.bulk_concurrency` at a time: signing is local and needs no requests,
+/// so only the listing takes time and running it concurrently gains nothing.
+#[pg_extern]
+pub(crate) fn s3_presign_prefix(
+    bucket: Option<&str>,
+    prefix: &str,
+    expires_in: default!(i32, 3600),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    max_keys: default!(i32, 1000),
+) -> TableIterator<'static, (name!(key, String), name!(url, String))> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let bucket = uri::bucket_or_default(bucket);
+    let bucket = bucket.as_str();
+    let presigning = presigning_config(expires_in);
+    if max_keys < 1 {
+        pgrx::error!("max_keys must be positive, got {max_keys}");
+    }
+    let max_keys = max_keys as usize;
+
+    let (urls, more) = run(&args, |client| {
+        let presigning = presigning.clone();
+        async move {
+            let mut pages = client
+                .list_objects_v2()
+                .bucket(bucket)
+                .prefix(prefix)
+                .max_keys(max_keys.min(1000) as i32)
+                .into_paginator()
+                .send();
+
+            let mut keys = Vec::new();
+            let mut more = false;
+            while let Some(page) = pages.next().await {
+                let page = page.map_err(|err| sdk_error("ListObjectsV2", err))?;
+                note_request_ids("ListObjectsV2", &page);
+                keys.extend(
+                    page.contents()
+                        .iter()
+                        .filter_map(|o| o.key())
+                        .map(str::to_owned),
+                );
+                if keys.len() >= max_keys {
+                    more = keys.len() > max_keys || page.is_truncated() == Some(true);
+                    keys.truncate(max_keys);
+                    break;
+                }
+            }
+
+            let mut urls = Vec::with_capacity(keys.len());
+            for key in keys {
+                let url =
+                    presign_get(&client, bucket, &key, presigning.clone(), None, None).await?;
+                urls.push((key, url));
+            }
+            Ok((urls, more))
+        }
+    });
+
+    if more {
+        pgrx::warning!(
+            "s3_presign_prefix stopped after max_keys = {max_keys} keys, more objects are below {prefix:?}"
+        );
+    }
+    TableIterator::new(urls)
+}
+
+/// Kind of URL `s3_put_object_with_url` returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UrlKind {
+    /// Presigned GetObject URL, as from `s3_presign_get`.
+    Presigned,
+    /// Unsigned URL, as from `s3_object_url`, for buckets that allow public reads.
+    Public,
+}
+
+impl UrlKind {
+    pub(crate) fn parse(kind: &str) -> Result<Self, String> {
+        match kind.to_ascii_lowercase().as_str() {
+            "presigned" => Ok(Self::Presigned),
+            "public" => Ok(Self::Public),
+            other => Err(format!(
+                "url_kind must be presigned or public, got {other:?}"
+            )),
+        }
+    }
+}
+
+/// Upload `data` like `s3_put_object` and return the ETag together with a URL
+/// of the object to store right away: presigned for `expires_in` seconds, or
+/// with `url_kind => 'public'` the unsigned URL.
+#[pg_extern]
+fn s3_put_object_with_url(
+    bucket: Option<&str>,
+    object_key: &str,
+    data: Vec<u8>,
+    url_kind: default!(&str, "'presigned'"),
+    expires_in: default!(i32, 3600),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    content_type: default!(Option<&str>, "NULL"),
+    compress: default!(Option<&str>, "NULL"),
+    compression_level: default!(Option<i32>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> TableIterator<'static, (name!(etag, String), name!(url, String))> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let url_kind = UrlKind::parse(url_kind).unwrap_or_else(|e| pgrx::error!("{e}"));
+    let presigning = (url_kind == UrlKind::Presigned).then(|| presigning_config(expires_in));
+    let (data, codec) = compress_body(data, compress, compression_level);
+
+    let (etag, url) = run(&args, |client| {
+        let (data, presigning) = (data.clone(), presigning.clone());
+        async move {
+            let out = put_object(
+                client.clone(),
+                bucket,
+                object_key,
+                data,
+                content_type,
+                codec,
+                expected_bucket_owner,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .await?;
+            let url = match presigning {
+                Some(presigning) => {
+                    Some(presign_get(&client, bucket, object_key, presigning, None, None).await?)
+                }
+                None => None,
+            };
+            Ok((
+                out.e_tag().unwrap_or_default().trim_matches('"').to_owned(),
+                url,
+            ))
+        }
+    });
+    let url = url
+        .unwrap_or_else(|| s3_object_url(Some(bucket), object_key, endpoint_url, force_path_style));
+    TableIterator::once((etag, url))
+}
+
+/// Check `expires_in` and turn it into a presigning config.
+fn presigning_config(expires_in: i32) -> PresigningConfig {
+    if !(1..=MAX_EXPIRES_IN).contains(&expires_in) {
+        pgrx::error!("expires_in must be between 1 and {MAX_EXPIRES_IN} seconds, got {expires_in}");
+    }
+    PresigningConfig::expires_in(Duration::from_secs(expires_in as u64))
+        .unwrap_or_else(|e| pgrx::error!("{e}"))
+}
+
+/// Presign a GetObject of `object_key`, with optional response header overrides.
+async fn presign_get(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    presigning: PresigningConfig,
+    response_content_type: Option<&str>,
+    response_content_disposition: Option<&str>,
+) -> Result<String, S3Error> {
+    let request = client
+        .get_object()
+        .bucket(bucket)
+        .key(object_key)
+        .set_response_content_type(response_content_type.map(str::to_owned))
+        .set_response_content_disposition(response_content_disposition.map(str::to_owned))
+        .presigned(presigning)
+        .await
+        .map_err(|e| S3Error::new(format!("Cannot presign GetObject: {e}")))?;
+    Ok(request.uri().to_owned())
+}
+
+/// Reject values that cannot be sent as an HTTP header.
+fn check_header_value(what: &str, value: &str) -> Result<(), String> {
+    if value.is_empty() || value.chars().any(|c| c.is_control()) {
+        return Err(format!(
+            "{what} must be a non-empty header value without control characters, got {value:?}"
+        ));
+    }
+    Ok(())
+}
+
+/// Require a media type such as `text/csv` or `text/plain; charset=utf-8`.
+pub(crate) fn check_content_type(value: &str) -> Result<(), String> {
+    check_header_value("response_content_type", value)?;
+    let essence = value.split(';').next().unwrap_or_default().trim();
+    let valid = essence.split_once('/').is_some_and(|(kind, subtype)| {
+        let token = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+        };
+        token(kind) && token(subtype)
+    });
+    if !valid {
+        return Err(format!(
+            "response_content_type must be a media type like text/csv, got {value:?}"
+        ));
+    }
+    Ok(())
+}
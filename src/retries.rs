@@ -0,0 +1,157 @@
+//! Logging of the SDK's request retries, so throttling during bulk loads is visible.
+
+use pgrx::prelude::*;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use aws_smithy_runtime_api::{
+    box_error::BoxError,
+    client::{
+        interceptors::{
+            context::{BeforeTransmitInterceptorContextRef, Error, FinalizerInterceptorContextRef},
+            Intercept,
+        },
+        orchestrator::OrchestratorError,
+        retries::RequestAttempts,
+        runtime_components::RuntimeComponents,
+    },
+};
+use aws_smithy_types::config_bag::{ConfigBag, Storable, StoreReplace};
+
+use crate::guc;
+
+/// Minimum time between two retry messages; retries in between are counted.
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the previous attempt of an operation failed.
+#[derive(Debug)]
+struct FailedAttempt {
+    code: String,
+    at: Instant,
+}
+
+impl Storable for FailedAttempt {
+    type Storer = StoreReplace<Self>;
+}
+
+/// Records every retry of an operation for [`log_retries`].
+#[derive(Debug)]
+pub(crate) struct RetryLog;
+
+impl Intercept for RetryLog {
+    fn name(&self) -> &'static str {
+        "RetryLog"
+    }
+
+    fn read_before_attempt(
+        &self,
+        _context: &BeforeTransmitInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let attempt = cfg.load::<RequestAttempts>().map_or(1, |a| a.attempts());
+        if attempt > 1 {
+            let message = match cfg.load::<FailedAttempt>() {
+                Some(failed) => format!(
+                    "S3 request attempt {attempt} after {} (backed off {} ms)",
+                    failed.code,
+                    failed.at.elapsed().as_millis()
+                ),
+                None => format!("S3 request attempt {attempt} after attempt timeout"),
+            };
+            PENDING.lock().unwrap().push(message);
+        }
+        Ok(())
+    }
+
+    fn read_after_attempt(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let Some(Err(err)) = context.output_or_error() else {
+            return Ok(());
+        };
+        cfg.interceptor_state().store_put(FailedAttempt {
+            code: failure_code(context, err),
+            at: Instant::now(),
+        });
+        Ok(())
+    }
+}
+
+/// The S3 error code of a failed attempt or operation, `HTTP <status>` for
+/// responses without one, or how the request failed without a response.
+pub(crate) fn failure_code(
+    context: &FinalizerInterceptorContextRef<'_>,
+    err: &OrchestratorError<Error>,
+) -> String {
+    context
+        .response()
+        .and_then(|r| {
+            let body = r.body().bytes().and_then(|b| std::str::from_utf8(b).ok());
+            body.and_then(error_code)
+                .map(str::to_owned)
+                .or_else(|| Some(format!("HTTP {}", r.status().as_u16())))
+        })
+        .unwrap_or_else(|| {
+            if err.is_timeout_error() {
+                "Timeout".to_owned()
+            } else {
+                "DispatchFailure".to_owned()
+            }
+        })
+}
+
+/// The `<Code>` of an S3 XML error body.
+pub(crate) fn error_code(body: &str) -> Option<&str> {
+    let start = body.find("<Code>")? + "<Code>".len();
+    let len = body[start..].find("</Code>")?;
+    Some(&body[start..start + len])
+}
+
+// Retries seen by the interceptor, logged on the backend thread.
+static PENDING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct Throttle {
+    last: Option<Instant>,
+    suppressed: usize,
+}
+
+static THROTTLE: Mutex<Throttle> = Mutex::new(Throttle {
+    last: None,
+    suppressed: 0,
+});
+
+/// Log the retries recorded since the last call, at most one message per
+/// [`LOG_INTERVAL`], as NOTICE or, with `s3_io.retry_notices` off, DEBUG1.
+pub(crate) fn log_retries() {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+    let Some(latest) = pending.last() else {
+        return;
+    };
+    let mut throttle = THROTTLE.lock().unwrap();
+    throttle.suppressed += pending.len() - 1;
+    if throttle
+        .last
+        .is_some_and(|last| last.elapsed() < LOG_INTERVAL)
+    {
+        throttle.suppressed += 1;
+        return;
+    }
+    let message = match std::mem::take(&mut throttle.suppressed) {
+        0 => latest.clone(),
+        n => format!("{latest} ({n} more retries since the last message)"),
+    };
+    throttle.last = Some(Instant::now());
+    drop(throttle);
+
+    if guc::RETRY_NOTICES.get() {
+        notice!("{message}");
+    } else {
+        debug1!("{message}");
+    }
+}
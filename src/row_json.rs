@@ -0,0 +1,93 @@
+use pgrx::{datum::DatumWithOid, prelude::*, AnyElement};
+
+use crate::{put_object, run, uri, ClientArgs};
+
+/// Content type of rows uploaded without a `content_type` argument.
+const CONTENT_TYPE: &str = "application/json";
+
+/// Upload a row as a JSON object under a key built from its columns, e.g. in a
+/// trigger: `s3_put_row_json('cdc', 'events/{id}.json', NEW)`. Returns the ETag.
+///
+/// Each `{column}` in `key_template` is replaced by the column's value; NULL
+/// or missing columns are an error. The object's content type is
+/// `application/json` unless `content_type` is given. The upload is not
+/// transactional: it stays even if the calling transaction rolls back.
+#[pg_extern]
+pub(crate) fn s3_put_row_json(
+    bucket: Option<&str>,
+    key_template: &str,
+    row: AnyElement,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    content_type: default!(Option<&str>, "NULL"),
+) -> String {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let bucket = uri::bucket_or_default(bucket);
+    let bucket = bucket.as_str();
+    let oid = row.oid();
+    // SAFETY: the datum is of the argument's actual type, `oid`.
+    let json = Spi::get_one_with_args::<pgrx::JsonB>(
+        "SELECT to_jsonb($1)",
+        &[unsafe { DatumWithOid::new(row, oid) }],
+    )
+    .unwrap_or_else(|e| pgrx::error!("Cannot convert row to JSON: {e}"))
+    .map(|j| j.0)
+    .unwrap_or_else(|| pgrx::error!("row must not be NULL"));
+    let key = render_key(key_template, &json).unwrap_or_else(|e| pgrx::error!("{e}"));
+    let body = bytes::Bytes::from(serde_json::to_vec(&json).expect("JSON serializes"));
+
+    let out = run(&args, |client| {
+        put_object(
+            client,
+            bucket,
+            &key,
+            body.clone(),
+            Some(content_type.unwrap_or(CONTENT_TYPE)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+    });
+    out.e_tag().unwrap_or_default().trim_matches('"').to_owned()
+}
+
+/// Replace each `{column}` in `template` by that field of `row`.
+pub(crate) fn render_key(template: &str, row: &serde_json::Value) -> Result<String, String> {
+    let mut key = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        key.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('}') else {
+            return Err(format!("Unterminated {{ in key_template {template:?}"));
+        };
+        let column = &rest[start + 1..start + len];
+        match row.get(column) {
+            Some(serde_json::Value::String(s)) => key.push_str(s),
+            Some(serde_json::Value::Null) | None => {
+                return Err(format!(
+                    "key_template column {column:?} is missing or NULL in the row"
+                ))
+            }
+            Some(value) => key.push_str(&value.to_string()),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    key.push_str(rest);
+    Ok(key)
+}
@@ -0,0 +1,281 @@
+use pgrx::prelude::*;
+use std::collections::BTreeSet;
+
+use aws_sdk_s3::types::{
+    CsvInput, ExpressionType, FileHeaderInfo, InputSerialization, JsonInput, JsonOutput, JsonType,
+    OutputSerialization, SelectObjectContentEventStream,
+};
+
+use crate::{
+    compression::Decompress,
+    error::{note_request_ids, sdk_error, S3Error},
+    get_object, run, try_run, uri, ClientArgs,
+};
+
+/// Error codes of providers that do not implement SelectObjectContent.
+const UNSUPPORTED_CODES: &[&str] = &["NotImplemented", "MethodNotAllowed", "XNotImplemented"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum InputFormat {
+    /// CSV, with column names from the header line or positional `_1`, `_2`, ...
+    Csv { header: bool, delimiter: u8 },
+    /// One JSON object per line.
+    Json,
+}
+
+impl InputFormat {
+    fn parse(format: &str, header: bool, delimiter: &str) -> Result<Self, String> {
+        match format.to_ascii_uppercase().as_str() {
+            "CSV" => match delimiter.as_bytes() {
+                [d] => Ok(Self::Csv {
+                    header,
+                    delimiter: *d,
+                }),
+                _ => Err(format!(
+                    "csv_delimiter must be a single byte, got {delimiter:?}"
+                )),
+            },
+            "JSON" => Ok(Self::Json),
+            other => Err(format!(
+                "Unknown input_format {other:?}, expected CSV or JSON"
+            )),
+        }
+    }
+
+    fn serialization(self) -> InputSerialization {
+        let builder = InputSerialization::builder();
+        match self {
+            Self::Csv { header, delimiter } => builder.csv(
+                CsvInput::builder()
+                    .file_header_info(if header {
+                        FileHeaderInfo::Use
+                    } else {
+                        FileHeaderInfo::None
+                    })
+                    .field_delimiter(char::from(delimiter).to_string())
+                    .build(),
+            ),
+            Self::Json => builder.json(JsonInput::builder().r#type(JsonType::Lines).build()),
+        }
+        .build()
+    }
+}
+
+/// Run an S3 Select `expression` (e.g. `SELECT s.id FROM s3object s WHERE s.kind = 'a'`)
+/// on a CSV or JSON-lines object and return the matching records as JSON objects.
+///
+/// With `local_fallback`, providers without S3 Select are handled by downloading
+/// the object and running the expression in Postgres over a `s3object` relation
+/// of text columns.
+#[pg_extern]
+pub(crate) fn s3_select(
+    bucket: Option<&str>,
+    object_key: &str,
+    expression: &str,
+    input_format: default!(&str, "'CSV'"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    csv_header: default!(bool, "true"),
+    csv_delimiter: default!(&str, "','"),
+    local_fallback: default!(bool, "false"),
+) -> SetOfIterator<'static, pgrx::JsonB> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let format = InputFormat::parse(input_format, csv_header, csv_delimiter)
+        .unwrap_or_else(|e| pgrx::error!("{e}"));
+
+    let remote = try_run(&args, |client| async move {
+        select_remote(&client, bucket, object_key, expression, format).await
+    });
+    let records = match remote {
+        Ok(records) => records,
+        Err(e) if local_fallback && is_unsupported(&e) => {
+            let (data, _) = run(&args, |client| async move {
+                get_object(
+                    &client,
+                    bucket,
+                    object_key,
+                    Decompress::Metadata,
+                    None,
+                    false,
+                    None,
+                )
+                .await
+            });
+            select_local(&data, format, expression).unwrap_or_else(|e| pgrx::error!("{e}"))
+        }
+        Err(e) => e.raise(),
+    };
+    SetOfIterator::new(records.into_iter().map(pgrx::JsonB))
+}
+
+fn is_unsupported(err: &S3Error) -> bool {
+    err.code
+        .as_deref()
+        .is_some_and(|code| UNSUPPORTED_CODES.contains(&code))
+}
+
+async fn select_remote(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    object_key: &str,
+    expression: &str,
+    format: InputFormat,
+) -> Result<Vec<serde_json::Value>, S3Error> {
+    let mut out = client
+        .select_object_content()
+        .bucket(bucket)
+        .key(object_key)
+        .expression(expression)
+        .expression_type(ExpressionType::Sql)
+        .input_serialization(format.serialization())
+        .output_serialization(
+            OutputSerialization::builder()
+                .json(JsonOutput::builder().record_delimiter("\n").build())
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(|err| sdk_error("SelectObjectContent", err))?;
+    note_request_ids("SelectObjectContent", &out);
+
+    // Records events split the output at arbitrary bytes; cut it at newlines.
+    let mut buffer = Vec::new();
+    let mut records = Vec::new();
+    loop {
+        let event = out
+            .payload
+            .recv()
+            .await
+            .map_err(|e| S3Error::new(format!("SelectObjectContent stream failed: {e:?}")))?;
+        match event {
+            Some(SelectObjectContentEventStream::Records(r)) => {
+                if let Some(payload) = r.payload {
+                    buffer.extend_from_slice(payload.as_ref());
+                }
+                while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=end).collect();
+                    records.push(parse_record(&line)?);
+                }
+            }
+            Some(SelectObjectContentEventStream::End(_)) | None => break,
+            Some(_) => {}
+        }
+    }
+    if !buffer.iter().all(u8::is_ascii_whitespace) {
+        records.push(parse_record(&buffer)?);
+    }
+    Ok(records)
+}
+
+fn parse_record(line: &[u8]) -> Result<serde_json::Value, S3Error> {
+    serde_json::from_slice(line).map_err(|e| S3Error::new(format!("Invalid S3 Select record: {e}")))
+}
+
+/// Run `expression` in Postgres over the records of `data`, exposed as a
+/// `s3object` relation with one text column per field.
+pub(crate) fn select_local(
+    data: &[u8],
+    format: InputFormat,
+    expression: &str,
+) -> Result<Vec<serde_json::Value>, String> {
+    let (columns, rows) = parse_records(data, format)?;
+    if columns.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let column_defs = columns
+        .iter()
+        .map(|c| format!("{} text", spi::quote_identifier(c)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let query = format!(
+        "SELECT to_jsonb(q) FROM (WITH s3object AS \
+         (SELECT * FROM jsonb_to_recordset($1) AS t({column_defs})) {expression}) q"
+    );
+
+    Spi::connect(|client| {
+        let rows = pgrx::JsonB(serde_json::Value::Array(rows));
+        client
+            .select(&query, None, &[rows.into()])?
+            .map(|row| row.get::<pgrx::JsonB>(1).map(|v| v.map(|j| j.0)))
+            .filter_map(Result::transpose)
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .map_err(|e| format!("Local S3 Select failed: {e}"))
+}
+
+/// Parse CSV or JSON lines into JSON objects with string values, plus the
+/// (lowercased, as S3 Select matches them case-insensitively) column names.
+fn parse_records(
+    data: &[u8],
+    format: InputFormat,
+) -> Result<(Vec<String>, Vec<serde_json::Value>), String> {
+    let mut columns = BTreeSet::new();
+    let mut rows = Vec::new();
+    match format {
+        InputFormat::Csv { header, delimiter } => {
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(header)
+                .delimiter(delimiter)
+                .flexible(true)
+                .from_reader(data);
+            let names: Vec<String> = if header {
+                let headers = reader.headers().map_err(|e| format!("Invalid CSV: {e}"))?;
+                headers.iter().map(str::to_lowercase).collect()
+            } else {
+                Vec::new()
+            };
+            for record in reader.records() {
+                let record = record.map_err(|e| format!("Invalid CSV: {e}"))?;
+                let row: serde_json::Map<_, _> = record
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| {
+                        let name = names.get(i).cloned().unwrap_or(format!("_{}", i + 1));
+                        (name, serde_json::Value::from(v))
+                    })
+                    .collect();
+                columns.extend(row.keys().cloned());
+                rows.push(serde_json::Value::Object(row));
+            }
+        }
+        InputFormat::Json => {
+            for (n, line) in data.split(|&b| b == b'\n').enumerate() {
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let value: serde_json::Value = serde_json::from_slice(line)
+                    .map_err(|e| format!("Invalid JSON on line {}: {e}", n + 1))?;
+                let serde_json::Value::Object(obj) = value else {
+                    return Err(format!("Line {} is not a JSON object", n + 1));
+                };
+                let row: serde_json::Map<_, _> = obj
+                    .into_iter()
+                    .map(|(k, v)| {
+                        let v = match v {
+                            serde_json::Value::String(s) => s,
+                            other => other.to_string(),
+                        };
+                        (k.to_lowercase(), serde_json::Value::from(v))
+                    })
+                    .collect();
+                columns.extend(row.keys().cloned());
+                rows.push(serde_json::Value::Object(row));
+            }
+        }
+    }
+    Ok((columns.into_iter().collect(), rows))
+}
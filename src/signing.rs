@@ -0,0 +1,84 @@
+use aws_sdk_s3::config::endpoint::{Params, ResolveEndpoint};
+use aws_smithy_runtime_api::client::endpoint::EndpointFuture;
+use aws_smithy_types::{
+    endpoint::{Endpoint, EndpointAuthScheme},
+    Document,
+};
+
+/// Property of an endpoint's auth schemes naming the service requests are signed for.
+const SIGNING_NAME: &str = "signingName";
+
+/// An endpoint resolver that signs requests for `signing_name` instead of
+/// `s3`, for gateways in front of S3 that check the service name, e.g. API
+/// Gateway's `execute-api`.
+#[derive(Debug)]
+pub(crate) struct SigningNameResolver<R> {
+    signing_name: String,
+    inner: R,
+}
+
+impl<R> SigningNameResolver<R> {
+    pub(crate) fn new(signing_name: String, inner: R) -> Self {
+        Self {
+            signing_name,
+            inner,
+        }
+    }
+}
+
+impl<R: ResolveEndpoint> ResolveEndpoint for SigningNameResolver<R> {
+    fn resolve_endpoint<'a>(&'a self, params: &'a Params) -> EndpointFuture<'a> {
+        EndpointFuture::new(async move {
+            let endpoint = self.inner.resolve_endpoint(params).await?;
+            Ok(with_signing_name(&endpoint, &self.signing_name))
+        })
+    }
+}
+
+/// `endpoint` with the signing name of each of its auth schemes replaced.
+///
+/// The SDK describes auth schemes either as typed schemes or as the
+/// `authSchemes` property; both are rewritten.
+fn with_signing_name(endpoint: &Endpoint, signing_name: &str) -> Endpoint {
+    let mut builder = Endpoint::builder().url(endpoint.url().to_owned());
+    for (name, values) in endpoint.headers() {
+        for value in values {
+            builder = builder.header(name.to_owned(), value.to_owned());
+        }
+    }
+    for (key, value) in endpoint.properties() {
+        let value = match (key.as_ref(), value) {
+            ("authSchemes", Document::Array(schemes)) => Document::Array(
+                schemes
+                    .iter()
+                    .map(|scheme| renamed(scheme.clone(), signing_name))
+                    .collect(),
+            ),
+            _ => value.clone(),
+        };
+        builder = builder.property(key.clone(), value);
+    }
+    for scheme in endpoint.auth_schemes() {
+        let Document::Object(properties) = renamed(scheme.as_document(), signing_name) else {
+            continue;
+        };
+        let mut typed =
+            EndpointAuthScheme::with_capacity(scheme.name().to_owned(), properties.len());
+        for (key, value) in properties.into_iter().filter(|(key, _)| key != "name") {
+            typed = typed.put(key, value);
+        }
+        builder = builder.auth_scheme(typed);
+    }
+    builder.build()
+}
+
+/// An auth scheme `scheme` with its signing name set to `signing_name`.
+fn renamed(scheme: Document, signing_name: &str) -> Document {
+    match scheme {
+        Document::Object(mut properties) => {
+            properties.insert(SIGNING_NAME.to_owned(), signing_name.to_owned().into());
+            Document::Object(properties)
+        }
+        other => other,
+    }
+}
@@ -0,0 +1,168 @@
+use aws_sdk_s3::{
+    operation::{
+        copy_object::builders::CopyObjectFluentBuilder,
+        get_object::builders::GetObjectFluentBuilder,
+        head_object::builders::HeadObjectFluentBuilder,
+        put_object::builders::PutObjectFluentBuilder,
+    },
+    types::ServerSideEncryption,
+};
+use std::fmt;
+
+use crate::string_map;
+
+/// Parse a server-side encryption algorithm: `AES256`, `aws:kms` or `aws:kms:dsse`.
+pub(crate) fn parse_algorithm(name: &str) -> Result<ServerSideEncryption, String> {
+    match name.to_ascii_lowercase().as_str() {
+        "aes256" => Ok(ServerSideEncryption::Aes256),
+        "aws:kms" => Ok(ServerSideEncryption::AwsKms),
+        "aws:kms:dsse" => Ok(ServerSideEncryption::AwsKmsDsse),
+        _ => Err(format!(
+            "sse_algorithm must be AES256, aws:kms or aws:kms:dsse, got {name:?}"
+        )),
+    }
+}
+
+pub(crate) fn is_kms(algorithm: &ServerSideEncryption) -> bool {
+    matches!(
+        algorithm,
+        ServerSideEncryption::AwsKms | ServerSideEncryption::AwsKmsDsse
+    )
+}
+
+/// Server-side encryption requested for an upload.
+#[derive(Clone, Debug)]
+pub(crate) struct Encryption {
+    algorithm: ServerSideEncryption,
+    kms_key_id: Option<String>,
+    /// Base64 of the JSON encryption context, as sent in `x-amz-server-side-encryption-context`.
+    context: Option<String>,
+}
+
+impl Encryption {
+    /// Validate the encryption arguments of a put; `None` if no algorithm is given.
+    ///
+    /// A KMS key id and encryption context are only accepted with a KMS algorithm.
+    pub fn new(
+        algorithm: Option<&str>,
+        kms_key_id: Option<&str>,
+        context: Option<&serde_json::Value>,
+    ) -> Result<Option<Self>, String> {
+        let Some(algorithm) = algorithm else {
+            if kms_key_id.is_some() || context.is_some() {
+                return Err("sse_kms_key_id and sse_kms_encryption_context require \
+                     server_side_encryption => 'aws:kms'"
+                    .to_owned());
+            }
+            return Ok(None);
+        };
+        let algorithm = parse_algorithm(algorithm)?;
+        if !is_kms(&algorithm) && (kms_key_id.is_some() || context.is_some()) {
+            return Err(format!(
+                "sse_kms_key_id and sse_kms_encryption_context require a KMS algorithm, got {}",
+                algorithm.as_str()
+            ));
+        }
+        let context = context
+            .map(|c| string_map(c, "sse_kms_encryption_context"))
+            .transpose()?
+            .map(|map| {
+                let sorted: std::collections::BTreeMap<_, _> = map.into_iter().collect();
+                let json = serde_json::to_string(&sorted).expect("string map serializes");
+                aws_smithy_types::base64::encode(json)
+            });
+        Ok(Some(Self {
+            algorithm,
+            kms_key_id: kms_key_id.map(str::to_owned),
+            context,
+        }))
+    }
+
+    pub fn apply(&self, req: PutObjectFluentBuilder) -> PutObjectFluentBuilder {
+        req.server_side_encryption(self.algorithm.clone())
+            .set_ssekms_key_id(self.kms_key_id.clone())
+            .set_ssekms_encryption_context(self.context.clone())
+    }
+}
+
+/// A customer-provided key (SSE-C): S3 encrypts with it but does not store it,
+/// so every request reading or writing the object must send it again.
+///
+/// `Debug` leaves out the key, so it cannot end up in a log message.
+#[derive(Clone)]
+pub(crate) struct CustomerKey {
+    /// Base64 of the 256-bit key.
+    key: String,
+    /// Base64 of the key's MD5, which S3 uses to check the key arrived intact.
+    key_md5: String,
+}
+
+impl fmt::Debug for CustomerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustomerKey").finish_non_exhaustive()
+    }
+}
+
+impl CustomerKey {
+    /// Validate the SSE-C arguments; `None` if no key is given.
+    ///
+    /// S3 only supports 256-bit keys with `AES256`, which is also the default `algorithm`.
+    pub fn new(key: Option<&[u8]>, algorithm: Option<&str>) -> Result<Option<Self>, String> {
+        if let Some(algorithm) = algorithm {
+            if key.is_none() {
+                return Err("sse_customer_algorithm requires sse_customer_key".to_owned());
+            }
+            if !algorithm.eq_ignore_ascii_case("AES256") {
+                return Err(format!(
+                    "sse_customer_algorithm must be AES256, got {algorithm:?}"
+                ));
+            }
+        }
+        let Some(key) = key else {
+            return Ok(None);
+        };
+        if key.len() != 32 {
+            return Err(format!(
+                "sse_customer_key must be 32 bytes (AES-256), got {}",
+                key.len()
+            ));
+        }
+        use md5::{Digest, Md5};
+        Ok(Some(Self {
+            key: aws_smithy_types::base64::encode(key),
+            key_md5: aws_smithy_types::base64::encode(Md5::digest(key)),
+        }))
+    }
+
+    pub fn put(&self, req: PutObjectFluentBuilder) -> PutObjectFluentBuilder {
+        req.sse_customer_algorithm("AES256")
+            .sse_customer_key(&self.key)
+            .sse_customer_key_md5(&self.key_md5)
+    }
+
+    pub fn get(&self, req: GetObjectFluentBuilder) -> GetObjectFluentBuilder {
+        req.sse_customer_algorithm("AES256")
+            .sse_customer_key(&self.key)
+            .sse_customer_key_md5(&self.key_md5)
+    }
+
+    pub fn head(&self, req: HeadObjectFluentBuilder) -> HeadObjectFluentBuilder {
+        req.sse_customer_algorithm("AES256")
+            .sse_customer_key(&self.key)
+            .sse_customer_key_md5(&self.key_md5)
+    }
+
+    /// Encrypt the copy with this key.
+    pub fn copy_to(&self, req: CopyObjectFluentBuilder) -> CopyObjectFluentBuilder {
+        req.sse_customer_algorithm("AES256")
+            .sse_customer_key(&self.key)
+            .sse_customer_key_md5(&self.key_md5)
+    }
+
+    /// Decrypt the copy source with this key.
+    pub fn copy_from(&self, req: CopyObjectFluentBuilder) -> CopyObjectFluentBuilder {
+        req.copy_source_sse_customer_algorithm("AES256")
+            .copy_source_sse_customer_key(&self.key)
+            .copy_source_sse_customer_key_md5(&self.key_md5)
+    }
+}
@@ -0,0 +1,117 @@
+//! Detection of transfers that stopped moving data, for `s3_io.stall_timeout`.
+//!
+//! Request and response bodies are wrapped so that every chunk sent or
+//! received records the time; [`block_on`](crate::block_on) fails the
+//! statement once that time is older than the timeout.
+
+use std::{
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use aws_smithy_runtime_api::{
+    box_error::BoxError,
+    client::{
+        interceptors::{
+            context::{
+                BeforeDeserializationInterceptorContextMut, BeforeTransmitInterceptorContextMut,
+            },
+            Intercept,
+        },
+        runtime_components::RuntimeComponents,
+    },
+};
+use aws_smithy_types::{body::SdkBody, config_bag::ConfigBag};
+use bytes::Bytes;
+use http_body::{Body, Frame, SizeHint};
+
+use crate::guc;
+
+// When data last moved, or the current wait began.
+static LAST_PROGRESS: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// The configured stall timeout, if any.
+pub(crate) fn limit() -> Option<Duration> {
+    let secs = guc::STALL_TIMEOUT.get();
+    (secs > 0).then(|| Duration::from_secs(secs as u64))
+}
+
+/// Count from now, e.g. when starting to wait for a request.
+pub(crate) fn reset() {
+    *LAST_PROGRESS.lock().unwrap() = Some(Instant::now());
+}
+
+/// How long no data has moved.
+pub(crate) fn idle() -> Duration {
+    LAST_PROGRESS
+        .lock()
+        .unwrap()
+        .map_or(Duration::ZERO, |last| last.elapsed())
+}
+
+/// Wraps every request and response body to record when data moves.
+#[derive(Debug)]
+pub(crate) struct ProgressLog;
+
+impl Intercept for ProgressLog {
+    fn name(&self) -> &'static str {
+        "ProgressLog"
+    }
+
+    fn modify_before_transmit(
+        &self,
+        context: &mut BeforeTransmitInterceptorContextMut<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let body = context.request_mut().body_mut();
+        *body = std::mem::replace(body, SdkBody::taken()).map_preserve_contents(tracked);
+        Ok(())
+    }
+
+    fn modify_before_deserialization(
+        &self,
+        context: &mut BeforeDeserializationInterceptorContextMut<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let body = context.response_mut().body_mut();
+        *body = std::mem::replace(body, SdkBody::taken()).map_preserve_contents(tracked);
+        Ok(())
+    }
+}
+
+fn tracked(body: SdkBody) -> SdkBody {
+    SdkBody::from_body_1_x(ProgressBody(body))
+}
+
+/// A body recording the time whenever a chunk of it is read.
+struct ProgressBody(SdkBody);
+
+impl Body for ProgressBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = Pin::new(&mut self.0).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if frame.data_ref().is_some_and(|data| !data.is_empty()) {
+                reset();
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.0.size_hint()
+    }
+}
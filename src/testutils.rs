@@ -27,6 +27,11 @@ pub mod minio_test {
     impl MinioServer {
         /// Start MinIO listening on a random port
         pub fn start() -> anyhow::Result<Self> {
+            Self::start_in_region(None)
+        }
+
+        /// Start MinIO, checking that requests are signed for `region` if given
+        pub fn start_in_region(region: Option<&str>) -> anyhow::Result<Self> {
             static MINIO_BIN: OnceLock<PathBuf> = OnceLock::new();
             let bin = MINIO_BIN.get_or_init(|| ensure_minio_binary().unwrap());
             let data = tempfile::tempdir()?;
@@ -52,6 +57,9 @@ pub mod minio_test {
                 .stdin(Stdio::null())
                 .stdout(Stdio::null())
                 .stderr(Stdio::null());
+            if let Some(region) = region {
+                cmd.env("MINIO_REGION", region);
+            }
             let child = cmd
                 .spawn()
                 .map_err(|e| anyhow::anyhow!("spawn minio: {e}"))?;
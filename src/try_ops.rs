@@ -0,0 +1,169 @@
+//! Variants of the object functions that report S3 failures as a row instead
+//! of raising, so a batch over many keys can go on past individual errors.
+//!
+//! Invalid arguments and missing configuration still raise.
+
+use pgrx::prelude::*;
+
+use crate::{
+    compress_body, compression::Decompress, delete_object, error::S3Error, get_object,
+    parse_expiration, put_object, try_run, uri, ClientArgs,
+};
+
+/// Split a result into `(value, error_code, error_message)`.
+fn outcome<T>(result: Result<T, S3Error>) -> (Option<T>, Option<String>, Option<String>) {
+    match result {
+        Ok(v) => (Some(v), None, None),
+        Err(e) => (None, e.code, Some(e.message)),
+    }
+}
+
+#[pg_extern]
+pub(crate) fn s3_try_get_object(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    decompress: default!(&str, "'metadata'"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+    verify_checksum: default!(bool, "false"),
+) -> TableIterator<
+    'static,
+    (
+        name!(data, Option<Vec<u8>>),
+        name!(error_code, Option<String>),
+        name!(error_message, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let decompress = Decompress::parse(decompress).unwrap_or_else(|e| pgrx::error!("{e}"));
+
+    let result = try_run(&args, |client| async move {
+        get_object(
+            &client,
+            bucket,
+            object_key,
+            decompress,
+            expected_bucket_owner,
+            verify_checksum,
+            None,
+        )
+        .await
+    });
+    TableIterator::once(outcome(result.map(|(data, _)| data)))
+}
+
+#[pg_extern]
+pub(crate) fn s3_try_put_object(
+    bucket: Option<&str>,
+    object_key: &str,
+    data: Vec<u8>,
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    content_type: default!(Option<&str>, "NULL"),
+    compress: default!(Option<&str>, "NULL"),
+    compression_level: default!(Option<i32>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(etag, Option<String>),
+        name!(expiry_date, Option<TimestampWithTimeZone>),
+        name!(expiration_rule_id, Option<String>),
+        name!(error_code, Option<String>),
+        name!(error_message, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+    let (data, codec) = compress_body(data, compress, compression_level);
+
+    let result = try_run(&args, |client| {
+        put_object(
+            client,
+            bucket,
+            object_key,
+            data.clone(),
+            content_type,
+            codec,
+            expected_bucket_owner,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+    });
+    let (etag, code, message) = outcome(result);
+    let (expiry_date, expiration_rule_id) =
+        parse_expiration(etag.as_ref().and_then(|out| out.expiration()));
+    let etag = etag.map(|out| {
+        out.e_tag()
+            .unwrap_or_default()
+            .trim_matches('"')
+            .to_string()
+    });
+    TableIterator::once((etag, expiry_date, expiration_rule_id, code, message))
+}
+
+#[pg_extern]
+pub(crate) fn s3_try_delete_object(
+    bucket: default!(Option<&str>, "NULL"),
+    object_key: default!(&str, "''"),
+    endpoint_url: default!(Option<&str>, "NULL"),
+    access_key: default!(Option<&str>, "NULL"),
+    secret_key: default!(Option<&str>, "NULL"),
+    session_token: default!(Option<&str>, "NULL"),
+    region: default!(Option<&str>, "NULL"),
+    expected_bucket_owner: default!(Option<&str>, "NULL"),
+    force_path_style: default!(Option<bool>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(deleted, Option<bool>),
+        name!(error_code, Option<String>),
+        name!(error_message, Option<String>),
+    ),
+> {
+    let args = ClientArgs {
+        endpoint_url,
+        access_key,
+        secret_key,
+        session_token,
+        region,
+        force_path_style,
+    };
+    let (bucket, object_key) = uri::resolve(bucket, object_key);
+    let (bucket, object_key) = (bucket.as_str(), object_key.as_str());
+
+    let result = try_run(&args, |client| {
+        delete_object(client, bucket, object_key, expected_bucket_owner, None)
+    });
+    TableIterator::once(outcome(result))
+}
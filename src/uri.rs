@@ -0,0 +1,61 @@
+use pgrx::prelude::*;
+
+use crate::guc;
+
+/// Split `s3://bucket/key` into bucket and key. The key may be empty.
+pub(crate) fn parse_s3_uri(uri: &str) -> Result<(String, String), String> {
+    let rest = uri
+        .get(..5)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("s3://"))
+        .map(|_| &uri[5..])
+        .ok_or_else(|| format!("Invalid S3 URI {uri:?}: must start with s3://"))?;
+    let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+    if bucket.is_empty() {
+        return Err(format!("Invalid S3 URI {uri:?}: missing bucket"));
+    }
+    if bucket.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(format!(
+            "Invalid S3 URI {uri:?}: bad bucket name {bucket:?}"
+        ));
+    }
+    Ok((bucket.to_owned(), key.to_owned()))
+}
+
+/// The `bucket` argument, or `s3_io.default_bucket` if it is NULL.
+pub(crate) fn bucket_or_default(bucket: Option<&str>) -> String {
+    if let Some(bucket) = bucket {
+        return bucket.to_owned();
+    }
+    guc::DEFAULT_BUCKET
+        .get()
+        .map(|s| s.to_string_lossy().into_owned())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| pgrx::error!("No bucket given, and s3_io.default_bucket is not set"))
+}
+
+/// Resolve the `bucket` and `object_key` arguments of an object function.
+///
+/// `bucket` may also be an `s3://bucket/prefix` URI, with `object_key`
+/// appended to the prefix; pass an empty `object_key` if the URI names the
+/// whole object. A NULL `bucket` falls back to `s3_io.default_bucket`.
+pub(crate) fn resolve(bucket: Option<&str>, object_key: &str) -> (String, String) {
+    let bucket = bucket_or_default(bucket);
+    let bucket = bucket.as_str();
+    if !bucket
+        .get(..5)
+        .is_some_and(|s| s.eq_ignore_ascii_case("s3://"))
+    {
+        return (bucket.to_owned(), object_key.to_owned());
+    }
+    let (bucket, prefix) = parse_s3_uri(bucket).unwrap_or_else(|e| pgrx::error!("{e}"));
+    let key = prefix + object_key;
+    if key.is_empty() {
+        pgrx::error!("Missing object key for s3://{bucket}");
+    }
+    (bucket, key)
+}
+
+#[pg_extern]
+fn s3_parse_uri(uri: &str) -> TableIterator<'static, (name!(bucket, String), name!(key, String))> {
+    TableIterator::once(parse_s3_uri(uri).unwrap_or_else(|e| pgrx::error!("{e}")))
+}